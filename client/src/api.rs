@@ -1,43 +1,300 @@
 use std::fs::File;
-use std::io::{BufWriter, Write};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::time::Duration;
 
 use anyhow::{anyhow, bail, Result};
-use ed25519_dalek::Signature;
-use reqwest::blocking::Client;
-use reqwest::header::HeaderName;
+use ed25519_dalek::{Signature, VerifyingKey};
+use reqwest::blocking::{Body, Client, RequestBuilder, Response};
+use reqwest::header::{HeaderName, ACCEPT, CONTENT_TYPE, RANGE};
 use reqwest::StatusCode;
 use shared::consts::*;
 use url::Url;
 
-use shared::SignedRequest;
+use ed25519_dalek::ed25519::signature::digest::Update;
+use shared::chunks::{BlockHashes, ChunkManifest};
+use shared::hasher::Hasher;
+use shared::listing::{
+    FileEntry, PruneReport, PubkeyEntry, ShareEntry, SignatureEntry, TrimReport,
+};
+use shared::outboard::Outboard;
+use shared::{response, SignatureScheme, SignedRequest};
+
+use crate::error::{ServerError, VerificationError};
+use crate::output::verbose;
+use crate::verify::{BlockVerifier, ExpectedBlocks};
+
+/// Optional parameters of an upload.
+#[derive(Debug, Clone, Default)]
+pub struct PushOptions {
+    /// Signature of the stored version the upload is expected to replace. The server rejects the
+    /// upload if the stored file differs.
+    pub expected_signature: Option<Signature>,
+    /// Digest the file signature was made over. Servers configured to trust it store the file
+    /// without hashing it again.
+    pub file_digest: Option<Vec<u8>>,
+}
+
+/// Signature of a stored file as returned by [`Api::signature`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredSignature {
+    pub signature: Signature,
+    /// Seconds since the Unix epoch when the file was uploaded, `None` if the server doesn't
+    /// know. Not covered by the response signature.
+    pub uploaded_at: Option<u64>,
+    /// Hex encoded digest the server recorded for the stored contents, `None` for files it
+    /// didn't verify. Not covered by the response signature either.
+    pub digest: Option<String>,
+}
 
 pub trait Api {
-    fn push(&self, request: &SignedRequest, file_signature: &Signature, file: File) -> Result<()>;
-    fn pull(&self, request: &SignedRequest, file: &File) -> Result<Signature>;
+    /// Uploads the `size` bytes read from `body` in a single request.
+    fn push(
+        &self,
+        request: &SignedRequest,
+        file_signature: &Signature,
+        body: impl Read + Send + 'static,
+        size: u64,
+        options: &PushOptions,
+    ) -> Result<()>;
+    fn push_chunk(
+        &self,
+        request: &SignedRequest,
+        index: u32,
+        chunk_hash: &str,
+        data: Vec<u8>,
+    ) -> Result<()>;
+    /// Asks the server to assemble the previously pushed chunks into the file.
+    fn push_manifest(
+        &self,
+        request: &SignedRequest,
+        file_signature: &Signature,
+        manifest: &ChunkManifest,
+        options: &PushOptions,
+    ) -> Result<()>;
+    /// Applies the uploads and deletes of `body`, a JSON `BatchPlan`, all at once. The uploads
+    /// must have been pushed in chunks beforehand. The request must be signed with
+    /// `signed_body_name(body)` as the filename.
+    fn batch(&self, request: &SignedRequest, body: Vec<u8>) -> Result<()>;
+    /// Downloads the file into `file`. When `offset` is non-zero, only the remainder starting at
+    /// `offset` is requested and appended to `file`. With `expected` blocks, the download is
+    /// aborted at the first block not matching them and `file` is truncated to the verified
+    /// blocks.
+    fn pull(
+        &self,
+        request: &SignedRequest,
+        file: &mut File,
+        offset: u64,
+        expected: Option<&ExpectedBlocks>,
+    ) -> Result<Signature>;
+    /// Downloads the whole file into `file` without looking for its signature, for pulls that
+    /// skip verification.
+    fn pull_unverified(&self, request: &SignedRequest, file: &mut File) -> Result<()>;
+    /// Downloads the file without storing it, returning the digest of the data and the signature
+    /// stored on the server.
+    fn pull_digest(&self, request: &SignedRequest) -> Result<(Hasher, Signature)>;
+    /// Returns the block hashes of the stored file, or `None` if the server has none for it.
+    fn block_hashes(&self, request: &SignedRequest) -> Result<Option<BlockHashes>>;
+    /// Returns the outboard of the stored file, or `None` if the server has none for it. Its
+    /// root still has to be checked against the file signature.
+    fn outboard(&self, request: &SignedRequest) -> Result<Option<Outboard>>;
+    /// Returns the stored signature of the file without downloading it, or `None` if the server
+    /// doesn't have the file.
+    fn signature(&self, request: &SignedRequest) -> Result<Option<StoredSignature>>;
+    /// Returns the stored signatures and sizes of the files listed in `body`, a JSON array of
+    /// filenames. The request must be signed with `signed_body_name(body)` as the filename.
+    fn signatures(&self, request: &SignedRequest, body: Vec<u8>) -> Result<Vec<SignatureEntry>>;
+    /// Lists the stored files, only the ones uploaded at or after `since` (seconds since the Unix
+    /// epoch) when it's set, calling `on_file` with each one as it arrives. Streamed listings come
+    /// in no particular order. The request must be signed with an empty filename.
+    fn list_each(
+        &self,
+        request: &SignedRequest,
+        since: Option<u64>,
+        on_file: &mut dyn FnMut(FileEntry) -> Result<()>,
+    ) -> Result<()>;
+    /// Like [`Api::list_each`], but returns the files in filename order.
+    fn list(&self, request: &SignedRequest, since: Option<u64>) -> Result<Vec<FileEntry>> {
+        let mut files = Vec::new();
+        self.list_each(request, since, &mut |file| {
+            files.push(file);
+            Ok(())
+        })?;
+        files.sort_by(|a, b| a.filename.cmp(&b.filename));
+        Ok(files)
+    }
+    /// Renames the file. `destination` must be signed with the same time as `request`.
+    fn rename(&self, request: &SignedRequest, destination: &SignedRequest) -> Result<()>;
+    /// Copies the file. `destination` must be signed with the same time as `request`.
+    fn copy(&self, request: &SignedRequest, destination: &SignedRequest) -> Result<()>;
+    fn delete(&self, request: &SignedRequest) -> Result<()>;
+    /// Replaces the stored signature of the file with `file_signature` without uploading it
+    /// again. The server checks the signature against the stored data.
+    fn update_signature(
+        &self,
+        request: &SignedRequest,
+        file_signature: &Signature,
+        options: &PushOptions,
+    ) -> Result<()>;
+    /// Lists the pubkeys using the server. The request must be signed by an admin with an empty
+    /// filename.
+    fn admin_users(&self, request: &SignedRequest) -> Result<Vec<PubkeyEntry>>;
+    /// Removes empty directories and orphaned signature files from the server storage. The
+    /// request must be signed by an admin with an empty filename.
+    fn admin_trim(&self, request: &SignedRequest) -> Result<TrimReport>;
+    /// Deletes the files uploaded before a cutoff. The request must be signed with the cutoff in
+    /// seconds since the Unix epoch as the filename.
+    fn prune(&self, request: &SignedRequest) -> Result<PruneReport>;
+    /// Creates a link downloading the file without authentication, expiring after `expires_in`
+    /// seconds or the server's longest allowed duration, or after `max_downloads` downloads.
+    fn share(
+        &self,
+        request: &SignedRequest,
+        expires_in: Option<u64>,
+        max_downloads: Option<u64>,
+    ) -> Result<ShareEntry>;
+    /// Lists the caller's working share links. The request must be signed with an empty
+    /// filename.
+    fn shares(&self, request: &SignedRequest) -> Result<Vec<ShareEntry>>;
+    /// Removes a share link. The request must be signed with its token as the filename.
+    fn revoke_share(&self, request: &SignedRequest) -> Result<()>;
 }
 
+/// Size of the buffer downloads are copied through unless configured otherwise.
+pub const DEFAULT_DOWNLOAD_BUFFER_SIZE: usize = 64 * 1024;
+
 pub struct HttpClient {
     client: Client,
     server_url: Url,
+    server_pubkey: Option<VerifyingKey>,
+    download_buffer_size: usize,
+    file_passphrase: Option<String>,
 }
 
 impl HttpClient {
-    pub fn new(server_url: Url) -> Self {
-        let client = Client::new();
+    /// Creates the client with a `timeout` for every request, or the reqwest default of 30
+    /// seconds when it's `None`.
+    pub fn new(server_url: Url, timeout: Option<Duration>) -> Result<Self> {
+        let mut builder = Client::builder();
+        if let Some(timeout) = timeout {
+            builder = builder.timeout(timeout);
+        }
+        let client = builder.build()?;
 
-        Self { client, server_url }
+        Ok(Self {
+            client,
+            server_url,
+            server_pubkey: None,
+            download_buffer_size: DEFAULT_DOWNLOAD_BUFFER_SIZE,
+            file_passphrase: None,
+        })
     }
-}
 
-impl Api for HttpClient {
-    fn push(&self, request: &SignedRequest, file_signature: &Signature, file: File) -> Result<()> {
+    /// Sets the size of the buffer downloaded data is read into before being written out, which
+    /// bounds the memory a download uses regardless of the file size.
+    pub fn with_download_buffer_size(mut self, download_buffer_size: usize) -> Self {
+        self.download_buffer_size = download_buffer_size.max(1);
+        self
+    }
+
+    /// Copies the body through a buffer of `download_buffer_size`, never holding more of it.
+    fn copy_body(&self, response: &mut Response, writer: &mut impl Write) -> std::io::Result<()> {
+        let mut buffer = vec![0; self.download_buffer_size];
+        loop {
+            let size = match response.read(&mut buffer) {
+                Ok(0) => return writer.flush(),
+                Ok(size) => size,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            writer.write_all(&buffer[..size])?;
+        }
+    }
+
+    /// Sends `file_passphrase` with uploads, so the server only serves the stored file to
+    /// downloads sending it too, and with downloads.
+    pub fn with_file_passphrase(mut self, file_passphrase: Option<String>) -> Self {
+        self.file_passphrase = file_passphrase;
+        self
+    }
+
+    fn with_file_passphrase_header(&self, builder: RequestBuilder) -> RequestBuilder {
+        match &self.file_passphrase {
+            Some(file_passphrase) => builder.header(
+                HeaderName::from_static(PARAM_FILE_PASSPHRASE),
+                file_passphrase,
+            ),
+            None => builder,
+        }
+    }
+
+    /// Pins the server pubkey, so signed responses are only accepted with a valid signature by
+    /// it.
+    pub fn with_server_pubkey(mut self, server_pubkey: Option<VerifyingKey>) -> Self {
+        self.server_pubkey = server_pubkey;
+        self
+    }
+
+    /// Reads the response body, checking its signature when the server pubkey is pinned.
+    fn signed_body(&self, request: &SignedRequest, response: Response) -> Result<Vec<u8>> {
+        let response_signature = Self::response_signature(&response)?;
+        let body = response.bytes()?.to_vec();
+        self.check_response_signature(request, response_signature, &body)?;
+        Ok(body)
+    }
+
+    fn response_signature(response: &Response) -> Result<Option<Signature>> {
+        response
+            .headers()
+            .get(PARAM_RESPONSE_SIGNATURE)
+            .map(|signature| {
+                Ok(Signature::from_slice(
+                    &bs58::decode(signature.to_str()?).into_vec()?,
+                )?)
+            })
+            .transpose()
+    }
+
+    /// Fails unless `body` is signed by the pinned server pubkey, if there's one.
+    fn check_response_signature(
+        &self,
+        request: &SignedRequest,
+        response_signature: Option<Signature>,
+        body: &[u8],
+    ) -> Result<()> {
+        let Some(server_pubkey) = &self.server_pubkey else {
+            return Ok(());
+        };
+        let response_signature = response_signature.ok_or(VerificationError(
+            "Response isn't signed by the server".to_string(),
+        ))?;
+        response::verify_response(
+            server_pubkey,
+            request.signature(),
+            body,
+            &response_signature,
+        )
+        .map_err(|_| VerificationError("Invalid server signature of the response".to_string()))?;
+        Ok(())
+    }
+
+    /// URL of `method`, failing unless `request` is signed for it, since the server rejects
+    /// signatures made for other methods.
+    fn endpoint(&self, method: &str, request: &SignedRequest) -> Result<Url> {
+        if request.method() != method {
+            bail!(
+                "Request signed for {} can't be sent to {method}",
+                request.method()
+            );
+        }
+        Ok(self.server_url.join(method)?)
+    }
+
+    /// Adds the request authentication headers.
+    fn with_auth_headers(builder: RequestBuilder, request: &SignedRequest) -> RequestBuilder {
         let pubkey_b58 = bs58::encode(request.pubkey()).into_string();
         let request_signature_b58 = bs58::encode(request.signature().to_bytes()).into_string();
-        let file_signature_b58 = bs58::encode(file_signature.to_bytes()).into_string();
 
-        let response = self
-            .client
-            .post(self.server_url.join(METHOD_UPLOAD)?)
+        builder
             .header(HeaderName::from_static(PARAM_FILENAME), request.filename())
             .header(HeaderName::from_static(PARAM_PUBKEY), pubkey_b58)
             .header(HeaderName::from_static(PARAM_TIME), request.time())
@@ -45,58 +302,616 @@ impl Api for HttpClient {
                 HeaderName::from_static(PARAM_REQUEST_SIGNATURE),
                 request_signature_b58,
             )
+            .header(
+                HeaderName::from_static(PARAM_REQUEST_SIGNATURE_SCHEME),
+                request.scheme().as_str(),
+            )
+            .header(
+                HeaderName::from_static(PARAM_CLIENT_VERSION),
+                CLIENT_VERSION,
+            )
+    }
+
+    /// Sends a rename or copy request.
+    fn transfer(
+        &self,
+        method: &str,
+        request: &SignedRequest,
+        destination: &SignedRequest,
+    ) -> Result<()> {
+        if destination.method() != method {
+            bail!(
+                "Destination signed for {} can't be sent to {method}",
+                destination.method()
+            );
+        }
+        let destination_signature_b58 =
+            bs58::encode(destination.signature().to_bytes()).into_string();
+
+        let response =
+            Self::with_auth_headers(self.client.post(self.endpoint(method, request)?), request)
+                .header(
+                    HeaderName::from_static(PARAM_DESTINATION),
+                    destination.filename(),
+                )
+                .header(
+                    HeaderName::from_static(PARAM_DESTINATION_SIGNATURE),
+                    destination_signature_b58,
+                )
+                .send()?;
+
+        if response.status() != StatusCode::OK {
+            return Err(Self::server_error(response));
+        }
+
+        Ok(())
+    }
+
+    fn with_push_options(builder: RequestBuilder, options: &PushOptions) -> RequestBuilder {
+        let builder = match &options.expected_signature {
+            Some(expected_signature) => builder.header(
+                HeaderName::from_static(PARAM_IF_MATCH),
+                bs58::encode(expected_signature.to_bytes()).into_string(),
+            ),
+            None => builder,
+        };
+        match &options.file_digest {
+            Some(file_digest) => builder.header(
+                HeaderName::from_static(PARAM_FILE_DIGEST),
+                bs58::encode(file_digest).into_string(),
+            ),
+            None => builder,
+        }
+    }
+
+    /// Prints the storage usage the server reports when it enforces a quota.
+    fn report_quota(response: &Response) {
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+        };
+        if let (Some(used), Some(quota)) = (header(PARAM_BYTES_USED), header(PARAM_BYTES_QUOTA)) {
+            verbose!("Storage used: {used} of {quota} bytes");
+        }
+    }
+
+    fn server_error(response: Response) -> anyhow::Error {
+        let status = response.status();
+        let message = response.text().unwrap_or_default();
+        ServerError::new(status, message).into()
+    }
+
+    /// Reads the file signature, failing if the server reports a scheme the client can't check.
+    fn file_signature(response: &Response) -> Result<Signature> {
+        let scheme = response
+            .headers()
+            .get(PARAM_FILE_SIGNATURE_SCHEME)
+            .map(|scheme| scheme.to_str())
+            .transpose()?;
+        let scheme = SignatureScheme::from_header(scheme, SignatureScheme::FILE_DEFAULT)?;
+        if !SignatureScheme::FILE_SCHEMES.contains(&scheme) {
+            return Err(
+                VerificationError(format!("Unsupported file signature scheme: {scheme}")).into(),
+            );
+        }
+
+        let file_signature_b58 = response
+            .headers()
+            .get(PARAM_FILE_SIGNATURE)
+            .ok_or(anyhow!("Header not found: {PARAM_FILE_SIGNATURE}"))?
+            .to_str()?;
+        Ok(Signature::from_slice(
+            &bs58::decode(file_signature_b58).into_vec()?,
+        )?)
+    }
+}
+
+impl Api for HttpClient {
+    fn push(
+        &self,
+        request: &SignedRequest,
+        file_signature: &Signature,
+        body: impl Read + Send + 'static,
+        size: u64,
+        options: &PushOptions,
+    ) -> Result<()> {
+        let file_signature_b58 = bs58::encode(file_signature.to_bytes()).into_string();
+
+        let response = self
+            .with_file_passphrase_header(Self::with_push_options(
+                Self::with_auth_headers(
+                    self.client.post(self.endpoint(METHOD_UPLOAD, request)?),
+                    request,
+                ),
+                options,
+            ))
             .header(
                 HeaderName::from_static(PARAM_FILE_SIGNATURE),
                 file_signature_b58,
             )
-            .body(file)
+            .header(
+                HeaderName::from_static(PARAM_FILE_SIGNATURE_SCHEME),
+                SignatureScheme::FILE_DEFAULT.as_str(),
+            )
+            .body(Body::sized(body, size))
             .send()?;
 
-        if response.status() == StatusCode::OK {
-            println!("OK");
-            std::io::stdout().flush().ok();
-        } else {
-            eprintln!("Server returned error status code: {}", response.status());
-            eprintln!("{}", response.text()?);
-            std::io::stderr().flush().ok();
+        if response.status() != StatusCode::OK {
+            return Err(Self::server_error(response));
         }
 
+        Self::report_quota(&response);
+
         Ok(())
     }
 
-    fn pull(&self, request: &SignedRequest, file: &File) -> Result<Signature> {
-        let pubkey_b58 = bs58::encode(request.pubkey()).into_string();
-        let request_signature_b58 = bs58::encode(request.signature().to_bytes()).into_string();
+    fn push_chunk(
+        &self,
+        request: &SignedRequest,
+        index: u32,
+        chunk_hash: &str,
+        data: Vec<u8>,
+    ) -> Result<()> {
+        let response = Self::with_auth_headers(
+            self.client
+                .post(self.endpoint(METHOD_UPLOAD_CHUNK, request)?),
+            request,
+        )
+        .header(HeaderName::from_static(PARAM_CHUNK_INDEX), index)
+        .header(HeaderName::from_static(PARAM_CHUNK_HASH), chunk_hash)
+        .body(data)
+        .send()?;
 
-        let mut response = self
-            .client
-            .get(self.server_url.join(METHOD_DOWNLOAD)?)
-            .header(HeaderName::from_static(PARAM_FILENAME), request.filename())
-            .header(HeaderName::from_static(PARAM_PUBKEY), pubkey_b58)
-            .header(HeaderName::from_static(PARAM_TIME), request.time())
+        if response.status() != StatusCode::OK {
+            return Err(Self::server_error(response));
+        }
+
+        Ok(())
+    }
+
+    fn push_manifest(
+        &self,
+        request: &SignedRequest,
+        file_signature: &Signature,
+        manifest: &ChunkManifest,
+        options: &PushOptions,
+    ) -> Result<()> {
+        let file_signature_b58 = bs58::encode(file_signature.to_bytes()).into_string();
+
+        let response = self
+            .with_file_passphrase_header(Self::with_push_options(
+                Self::with_auth_headers(
+                    self.client
+                        .post(self.endpoint(METHOD_UPLOAD_MANIFEST, request)?),
+                    request,
+                ),
+                options,
+            ))
             .header(
-                HeaderName::from_static(PARAM_REQUEST_SIGNATURE),
-                request_signature_b58,
+                HeaderName::from_static(PARAM_FILE_SIGNATURE),
+                file_signature_b58,
+            )
+            .header(
+                HeaderName::from_static(PARAM_FILE_SIGNATURE_SCHEME),
+                SignatureScheme::FILE_DEFAULT.as_str(),
             )
+            .json(manifest)
             .send()?;
 
         if response.status() != StatusCode::OK {
-            bail!(
-                "Server returned error status code: {}\n{}",
-                response.status(),
-                response.text()?
-            );
+            return Err(Self::server_error(response));
         }
 
-        let file_signature_b58 = response
-            .headers()
-            .get(PARAM_FILE_SIGNATURE)
-            .ok_or(anyhow!("Header not found: {PARAM_FILE_SIGNATURE}"))?
-            .to_str()?;
-        let file_signature = Signature::from_slice(&bs58::decode(file_signature_b58).into_vec()?)?;
+        Self::report_quota(&response);
+
+        Ok(())
+    }
+
+    fn batch(&self, request: &SignedRequest, body: Vec<u8>) -> Result<()> {
+        let response = Self::with_auth_headers(
+            self.client.post(self.endpoint(METHOD_BATCH, request)?),
+            request,
+        )
+        .header(CONTENT_TYPE, "application/json")
+        .body(body)
+        .send()?;
+
+        if response.status() != StatusCode::OK {
+            return Err(Self::server_error(response));
+        }
 
-        response.copy_to(&mut BufWriter::new(file))?;
+        Self::report_quota(&response);
+
+        Ok(())
+    }
+
+    fn pull(
+        &self,
+        request: &SignedRequest,
+        file: &mut File,
+        offset: u64,
+        expected: Option<&ExpectedBlocks>,
+    ) -> Result<Signature> {
+        let mut request_builder = self.with_file_passphrase_header(Self::with_auth_headers(
+            self.client.get(self.endpoint(METHOD_DOWNLOAD, request)?),
+            request,
+        ));
+        if offset > 0 {
+            request_builder = request_builder.header(RANGE, format!("bytes={offset}-"));
+        }
+
+        let mut response = request_builder.send()?;
+
+        let start = match response.status() {
+            StatusCode::PARTIAL_CONTENT => {
+                file.seek(SeekFrom::Start(offset))?;
+                offset
+            }
+            StatusCode::OK => {
+                file.set_len(0)?;
+                file.seek(SeekFrom::Start(0))?;
+                0
+            }
+            StatusCode::RANGE_NOT_SATISFIABLE if offset > 0 => {
+                // Partial file is not shorter than the remote one, so it can't be resumed
+                file.set_len(0)?;
+                return self.pull(request, file, 0, expected);
+            }
+            _ => return Err(Self::server_error(response)),
+        };
+
+        Self::report_quota(&response);
+        let file_signature = Self::file_signature(&response)?;
+
+        let Some(expected) = expected else {
+            self.copy_body(&mut response, file)?;
+            return Ok(file_signature);
+        };
+
+        let mut verifier = BlockVerifier::new(file, expected, start)?;
+        if let Err(err) = self.copy_body(&mut response, &mut verifier) {
+            let verified_len = verifier.verified_len();
+            drop(verifier);
+            // Keeps the verified blocks, so the next pull resumes after them
+            file.set_len(verified_len)?;
+            return Err(match err.kind() {
+                ErrorKind::InvalidData => VerificationError(err.to_string()).into(),
+                _ => err.into(),
+            });
+        }
+        verifier.finish()?;
 
         Ok(file_signature)
     }
+
+    fn pull_unverified(&self, request: &SignedRequest, file: &mut File) -> Result<()> {
+        let mut response = self
+            .with_file_passphrase_header(Self::with_auth_headers(
+                self.client.get(self.endpoint(METHOD_DOWNLOAD, request)?),
+                request,
+            ))
+            .send()?;
+
+        if response.status() != StatusCode::OK {
+            return Err(Self::server_error(response));
+        }
+
+        Self::report_quota(&response);
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        self.copy_body(&mut response, file)?;
+        Ok(())
+    }
+
+    fn pull_digest(&self, request: &SignedRequest) -> Result<(Hasher, Signature)> {
+        let mut response = self
+            .with_file_passphrase_header(Self::with_auth_headers(
+                self.client.get(self.endpoint(METHOD_DOWNLOAD, request)?),
+                request,
+            ))
+            .send()?;
+
+        if response.status() != StatusCode::OK {
+            return Err(Self::server_error(response));
+        }
+
+        let file_signature = Self::file_signature(&response)?;
+        let mut hasher = Hasher::default();
+        let mut buffer = vec![0; self.download_buffer_size];
+        loop {
+            let size = response.read(&mut buffer)?;
+            if size == 0 {
+                break;
+            }
+            hasher.update(&buffer[..size]);
+        }
+
+        Ok((hasher, file_signature))
+    }
+
+    fn block_hashes(&self, request: &SignedRequest) -> Result<Option<BlockHashes>> {
+        let response = Self::with_auth_headers(
+            self.client
+                .get(self.endpoint(METHOD_BLOCK_HASHES, request)?),
+            request,
+        )
+        .send()?;
+
+        match response.status() {
+            StatusCode::OK => Ok(Some(serde_json::from_slice(
+                &self.signed_body(request, response)?,
+            )?)),
+            StatusCode::NOT_FOUND => Ok(None),
+            _ => Err(Self::server_error(response)),
+        }
+    }
+
+    fn outboard(&self, request: &SignedRequest) -> Result<Option<Outboard>> {
+        let response = Self::with_auth_headers(
+            self.client.get(self.endpoint(METHOD_OUTBOARD, request)?),
+            request,
+        )
+        .send()?;
+
+        match response.status() {
+            StatusCode::OK => Ok(Some(serde_json::from_slice(
+                &self.signed_body(request, response)?,
+            )?)),
+            StatusCode::NOT_FOUND => Ok(None),
+            _ => Err(Self::server_error(response)),
+        }
+    }
+
+    fn signature(&self, request: &SignedRequest) -> Result<Option<StoredSignature>> {
+        let response = Self::with_auth_headers(
+            self.client.get(self.endpoint(METHOD_SIGNATURE, request)?),
+            request,
+        )
+        .send()?;
+
+        match response.status() {
+            StatusCode::OK => {
+                let file_signature = Self::file_signature(&response)?;
+                self.check_response_signature(
+                    request,
+                    Self::response_signature(&response)?,
+                    &file_signature.to_bytes(),
+                )?;
+                let header = |name| {
+                    response
+                        .headers()
+                        .get(name)
+                        .and_then(|value| value.to_str().ok())
+                };
+                Ok(Some(StoredSignature {
+                    signature: file_signature,
+                    uploaded_at: header(PARAM_UPLOADED_AT).and_then(|value| value.parse().ok()),
+                    digest: header(PARAM_FILE_DIGEST_HEX).map(str::to_string),
+                }))
+            }
+            StatusCode::NOT_FOUND => Ok(None),
+            _ => Err(Self::server_error(response)),
+        }
+    }
+
+    fn signatures(&self, request: &SignedRequest, body: Vec<u8>) -> Result<Vec<SignatureEntry>> {
+        let response = Self::with_auth_headers(
+            self.client.post(self.endpoint(METHOD_SIGNATURES, request)?),
+            request,
+        )
+        .header(CONTENT_TYPE, "application/json")
+        .body(body)
+        .send()?;
+
+        if response.status() != StatusCode::OK {
+            return Err(Self::server_error(response));
+        }
+
+        Ok(serde_json::from_slice(
+            &self.signed_body(request, response)?,
+        )?)
+    }
+
+    fn list_each(
+        &self,
+        request: &SignedRequest,
+        since: Option<u64>,
+        on_file: &mut dyn FnMut(FileEntry) -> Result<()>,
+    ) -> Result<()> {
+        let mut builder = Self::with_auth_headers(
+            self.client.get(self.endpoint(METHOD_LIST, request)?),
+            request,
+        );
+        if let Some(since) = since {
+            builder = builder.header(HeaderName::from_static(PARAM_SINCE), since);
+        }
+        // Streamed listings aren't signed, so a pinned server is asked for the whole listing
+        if self.server_pubkey.is_none() {
+            builder = builder.header(ACCEPT, CONTENT_TYPE_NDJSON);
+        }
+        let response = builder.send()?;
+
+        if response.status() != StatusCode::OK {
+            return Err(Self::server_error(response));
+        }
+
+        let streamed = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .is_some_and(|content_type| content_type == CONTENT_TYPE_NDJSON);
+        if !streamed {
+            let files: Vec<FileEntry> =
+                serde_json::from_slice(&self.signed_body(request, response)?)?;
+            return files.into_iter().try_for_each(on_file);
+        }
+        self.check_response_signature(request, None, &[])?;
+        for line in BufReader::new(response).lines() {
+            let line = line?;
+            if !line.is_empty() {
+                on_file(serde_json::from_str(&line)?)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn rename(&self, request: &SignedRequest, destination: &SignedRequest) -> Result<()> {
+        self.transfer(METHOD_RENAME, request, destination)
+    }
+
+    fn copy(&self, request: &SignedRequest, destination: &SignedRequest) -> Result<()> {
+        self.transfer(METHOD_COPY, request, destination)
+    }
+
+    fn delete(&self, request: &SignedRequest) -> Result<()> {
+        let response = Self::with_auth_headers(
+            self.client.post(self.endpoint(METHOD_DELETE, request)?),
+            request,
+        )
+        .send()?;
+
+        if response.status() != StatusCode::OK {
+            return Err(Self::server_error(response));
+        }
+
+        Ok(())
+    }
+
+    fn update_signature(
+        &self,
+        request: &SignedRequest,
+        file_signature: &Signature,
+        options: &PushOptions,
+    ) -> Result<()> {
+        let response = Self::with_push_options(
+            Self::with_auth_headers(
+                self.client
+                    .post(self.endpoint(METHOD_UPDATE_SIGNATURE, request)?),
+                request,
+            ),
+            options,
+        )
+        .header(
+            HeaderName::from_static(PARAM_FILE_SIGNATURE),
+            bs58::encode(file_signature.to_bytes()).into_string(),
+        )
+        .header(
+            HeaderName::from_static(PARAM_FILE_SIGNATURE_SCHEME),
+            SignatureScheme::FILE_DEFAULT.as_str(),
+        )
+        .send()?;
+
+        if response.status() != StatusCode::OK {
+            return Err(Self::server_error(response));
+        }
+
+        Ok(())
+    }
+
+    fn admin_users(&self, request: &SignedRequest) -> Result<Vec<PubkeyEntry>> {
+        let response = Self::with_auth_headers(
+            self.client.get(self.endpoint(METHOD_ADMIN_USERS, request)?),
+            request,
+        )
+        .send()?;
+
+        if response.status() != StatusCode::OK {
+            return Err(Self::server_error(response));
+        }
+
+        Ok(serde_json::from_slice(
+            &self.signed_body(request, response)?,
+        )?)
+    }
+
+    fn admin_trim(&self, request: &SignedRequest) -> Result<TrimReport> {
+        let response = Self::with_auth_headers(
+            self.client.post(self.endpoint(METHOD_ADMIN_TRIM, request)?),
+            request,
+        )
+        .send()?;
+
+        if response.status() != StatusCode::OK {
+            return Err(Self::server_error(response));
+        }
+
+        Ok(serde_json::from_slice(
+            &self.signed_body(request, response)?,
+        )?)
+    }
+
+    fn prune(&self, request: &SignedRequest) -> Result<PruneReport> {
+        let response = Self::with_auth_headers(
+            self.client.post(self.endpoint(METHOD_PRUNE, request)?),
+            request,
+        )
+        .send()?;
+
+        if response.status() != StatusCode::OK {
+            return Err(Self::server_error(response));
+        }
+
+        Ok(serde_json::from_slice(
+            &self.signed_body(request, response)?,
+        )?)
+    }
+
+    fn share(
+        &self,
+        request: &SignedRequest,
+        expires_in: Option<u64>,
+        max_downloads: Option<u64>,
+    ) -> Result<ShareEntry> {
+        let mut builder = Self::with_auth_headers(
+            self.client.post(self.endpoint(METHOD_SHARE, request)?),
+            request,
+        );
+        if let Some(expires_in) = expires_in {
+            builder = builder.header(HeaderName::from_static(PARAM_EXPIRES_IN), expires_in);
+        }
+        if let Some(max_downloads) = max_downloads {
+            builder = builder.header(HeaderName::from_static(PARAM_MAX_DOWNLOADS), max_downloads);
+        }
+        let response = builder.send()?;
+
+        if response.status() != StatusCode::OK {
+            return Err(Self::server_error(response));
+        }
+
+        Ok(serde_json::from_slice(
+            &self.signed_body(request, response)?,
+        )?)
+    }
+
+    fn shares(&self, request: &SignedRequest) -> Result<Vec<ShareEntry>> {
+        let response = Self::with_auth_headers(
+            self.client.get(self.endpoint(METHOD_SHARES, request)?),
+            request,
+        )
+        .send()?;
+
+        if response.status() != StatusCode::OK {
+            return Err(Self::server_error(response));
+        }
+
+        Ok(serde_json::from_slice(
+            &self.signed_body(request, response)?,
+        )?)
+    }
+
+    fn revoke_share(&self, request: &SignedRequest) -> Result<()> {
+        let response = Self::with_auth_headers(
+            self.client
+                .post(self.endpoint(METHOD_REVOKE_SHARE, request)?),
+            request,
+        )
+        .send()?;
+
+        if response.status() != StatusCode::OK {
+            return Err(Self::server_error(response));
+        }
+
+        Ok(())
+    }
 }
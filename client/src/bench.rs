@@ -0,0 +1,152 @@
+use std::io::Cursor;
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use ed25519_dalek::ed25519::signature::digest::Update;
+use ed25519_dalek::{DigestSigner, SigningKey};
+use rand::{Rng, RngCore};
+use url::Url;
+
+use shared::consts::*;
+use shared::hasher::Hasher;
+use shared::SignableRequest;
+
+use crate::api::{Api, PushOptions};
+use crate::error::VerificationError;
+use crate::output::{progress, progressln};
+
+/// How long opening a connection may take before the round fails.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Measurements of all rounds, in the unit they are printed in.
+#[derive(Default)]
+struct Samples(Vec<f64>);
+
+impl Samples {
+    fn add(&mut self, value: f64) {
+        self.0.push(value);
+    }
+
+    /// Prints the minimum, average and maximum as a table row.
+    fn print(&self, name: &str, unit: &str) {
+        let min = self.0.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = self.0.iter().copied().fold(0.0, f64::max);
+        let avg = self.0.iter().sum::<f64>() / self.0.len() as f64;
+        println!("{name:<12}{min:>10.1}{avg:>10.1}{max:>10.1}  {unit}");
+    }
+}
+
+/// Pushes and pulls a random payload of `size` bytes `rounds` times under a temporary filename,
+/// deleted afterwards, and prints the connection time, the time to the first byte of a response
+/// without a body, and the upload and download throughput.
+pub fn bench(
+    server_url: &Url,
+    size: u64,
+    rounds: u32,
+    signing_key: &SigningKey,
+    api: &impl Api,
+) -> Result<()> {
+    let mut payload = vec![0; size as usize];
+    rand::thread_rng().fill_bytes(&mut payload);
+    let mut digest = Hasher::default();
+    digest.update(&payload);
+    let file_signature = signing_key.sign_digest(digest);
+    let filename = format!("cloud-bench-{:016x}.bin", rand::thread_rng().gen::<u64>());
+
+    let mut connect = Samples::default();
+    let mut first_byte = Samples::default();
+    let mut upload = Samples::default();
+    let mut download = Samples::default();
+    let mut result: Result<()> = Ok(());
+    for round in 1..=rounds {
+        progress!("Round {round} of {rounds}... ");
+        result = (|| {
+            connect.add(millis(time_connect(server_url)?));
+
+            let request =
+                SignableRequest::new(METHOD_UPLOAD, filename.clone(), signing_key.verifying_key())?
+                    .sign(signing_key)?;
+            let started = Instant::now();
+            api.push(
+                &request,
+                &file_signature,
+                Cursor::new(payload.clone()),
+                size,
+                &PushOptions::default(),
+            )?;
+            upload.add(throughput(size, started.elapsed()));
+
+            let request = SignableRequest::new(
+                METHOD_SIGNATURE,
+                filename.clone(),
+                signing_key.verifying_key(),
+            )?
+            .sign(signing_key)?;
+            let started = Instant::now();
+            api.signature(&request)?;
+            first_byte.add(millis(started.elapsed()));
+
+            let request = SignableRequest::new(
+                METHOD_DOWNLOAD,
+                filename.clone(),
+                signing_key.verifying_key(),
+            )?
+            .sign(signing_key)?;
+            let started = Instant::now();
+            let (digest, _) = api.pull_digest(&request)?;
+            download.add(throughput(size, started.elapsed()));
+            if signing_key.sign_digest(digest) != file_signature {
+                return Err(VerificationError(
+                    "Downloaded payload doesn't match the uploaded one".to_string(),
+                )
+                .into());
+            }
+            Ok(())
+        })();
+        if result.is_err() {
+            progressln!("FAILED");
+            break;
+        }
+        progressln!("OK");
+    }
+
+    // Removed even after a failed round, so no payload is left behind
+    let removed = if upload.0.is_empty() {
+        Ok(())
+    } else {
+        SignableRequest::new(METHOD_DELETE, filename, signing_key.verifying_key())
+            .and_then(|request| request.sign(signing_key))
+            .and_then(|request| api.delete(&request))
+    };
+    result?;
+    removed?;
+
+    println!("{:<12}{:>10}{:>10}{:>10}", "", "min", "avg", "max");
+    connect.print("connect", "ms");
+    first_byte.print("first byte", "ms");
+    upload.print("upload", "MiB/s");
+    download.print("download", "MiB/s");
+    Ok(())
+}
+
+/// Time taken to open a TCP connection to the server, not counting the name lookup.
+fn time_connect(server_url: &Url) -> Result<Duration> {
+    let addr = server_url
+        .socket_addrs(|| None)?
+        .into_iter()
+        .next()
+        .ok_or(anyhow!("Server address not found: {server_url}"))?;
+    let started = Instant::now();
+    TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+    Ok(started.elapsed())
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// MiB per second.
+fn throughput(size: u64, duration: Duration) -> f64 {
+    size as f64 / (1024.0 * 1024.0) / duration.as_secs_f64()
+}
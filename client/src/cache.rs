@@ -0,0 +1,167 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs::{File, Metadata};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use anyhow::Result;
+use ed25519_dalek::{Signature, VerifyingKey};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::output::verbose;
+
+/// Signatures of local files known to match the server, so `scan` and `diff` don't hash files
+/// unchanged since they were pushed or last compared. A file counts as unchanged while its size
+/// and modification time stay the same, like for `make`.
+#[derive(Default, Serialize, Deserialize)]
+pub struct PushCache {
+    /// Base58 pubkey the signatures were made with. Recording a signature made with another key
+    /// drops the ones recorded before.
+    pubkey: String,
+    files: BTreeMap<PathBuf, CacheEntry>,
+    /// Where the cache is saved, `None` when it's disabled.
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    #[serde(skip)]
+    changed: bool,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+struct CacheEntry {
+    size: u64,
+    /// Nanoseconds since the Unix epoch.
+    modified: u64,
+    /// Base58 file signature.
+    signature: String,
+}
+
+impl CacheEntry {
+    /// `None` for files whose modification time isn't available.
+    fn new(metadata: &Metadata, signature: String) -> Option<Self> {
+        let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(Self {
+            size: metadata.len(),
+            modified: modified.as_nanos().try_into().ok()?,
+            signature,
+        })
+    }
+}
+
+impl PushCache {
+    /// Reads the cache saved at `path`, starting empty if there's none or it's unreadable.
+    /// Without a path the cache stays empty and nothing is saved.
+    pub fn load(path: Option<&Path>) -> Self {
+        let Some(path) = path else {
+            return Self::default();
+        };
+        let mut cache = File::open(path)
+            .ok()
+            .and_then(|file| serde_json::from_reader::<_, Self>(file).ok())
+            .unwrap_or_default();
+        cache.path = Some(path.to_path_buf());
+        cache
+    }
+
+    /// Signature recorded for the file at `path`, if it hasn't changed since. Entries of changed
+    /// files are dropped.
+    pub fn signature(&mut self, path: &Path, pubkey: &VerifyingKey) -> Option<Signature> {
+        if self.path.is_none() || self.pubkey != encode_pubkey(pubkey) {
+            return None;
+        }
+        let key = std::fs::canonicalize(path).ok()?;
+        let entry = self.files.get(&key)?;
+        let current = std::fs::metadata(&key)
+            .ok()
+            .and_then(|metadata| CacheEntry::new(&metadata, entry.signature.clone()));
+        if current.as_ref() != Some(entry) {
+            verbose!("{} changed since it was cached", path.display());
+            self.files.remove(&key);
+            self.changed = true;
+            return None;
+        }
+        Signature::from_slice(&bs58::decode(&entry.signature).into_vec().ok()?).ok()
+    }
+
+    /// Records the signature of the file at `path`, which was hashed when it had `metadata`. Not
+    /// recorded if the file changed since.
+    pub fn record(
+        &mut self,
+        path: &Path,
+        metadata: &Metadata,
+        pubkey: &VerifyingKey,
+        signature: &Signature,
+    ) {
+        if self.path.is_none() {
+            return;
+        }
+        let Ok(key) = std::fs::canonicalize(path) else {
+            return;
+        };
+        let signature = bs58::encode(signature.to_bytes()).into_string();
+        let (Some(entry), Some(current)) = (
+            CacheEntry::new(metadata, signature.clone()),
+            std::fs::metadata(&key)
+                .ok()
+                .and_then(|metadata| CacheEntry::new(&metadata, signature)),
+        ) else {
+            return;
+        };
+        if entry != current {
+            verbose!(
+                "{} changed while it was hashed, not caching it",
+                path.display()
+            );
+            return;
+        }
+
+        let pubkey = encode_pubkey(pubkey);
+        if self.pubkey != pubkey {
+            self.pubkey = pubkey;
+            self.files.clear();
+        }
+        if self.files.get(&key) != Some(&entry) {
+            self.files.insert(key, entry);
+            self.changed = true;
+        }
+    }
+
+    /// Paths and signatures of the files recorded for `pubkey`.
+    pub fn entries(&self, pubkey: &VerifyingKey) -> Vec<(PathBuf, Signature)> {
+        if self.path.is_none() || self.pubkey != encode_pubkey(pubkey) {
+            return Vec::new();
+        }
+        self.files
+            .iter()
+            .filter_map(|(path, entry)| {
+                let signature = bs58::decode(&entry.signature).into_vec().ok()?;
+                Some((path.clone(), Signature::from_slice(&signature).ok()?))
+            })
+            .collect()
+    }
+
+    /// Drops the entry of the file at `path`, as returned by [`PushCache::entries`].
+    pub fn forget(&mut self, path: &Path) {
+        if self.files.remove(path).is_some() {
+            self.changed = true;
+        }
+    }
+
+    /// Writes the cache back if it changed, replacing the previous file atomically.
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = self.path.as_ref().filter(|_| self.changed) else {
+            return Ok(());
+        };
+        let mut temp_path = OsString::from(path.as_os_str());
+        temp_path.push(".tmp");
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.into_inner()?.sync_all()?;
+        std::fs::rename(&temp_path, path)?;
+        Ok(())
+    }
+}
+
+fn encode_pubkey(pubkey: &VerifyingKey) -> String {
+    bs58::encode(pubkey.as_bytes()).into_string()
+}
@@ -0,0 +1,120 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::Path;
+
+use anyhow::Result;
+use ed25519_dalek::{DigestSigner, SigningKey};
+use serde_derive::Serialize;
+
+use shared::consts::METHOD_LIST;
+use shared::SignableRequest;
+
+use crate::api::Api;
+use crate::cache::PushCache;
+use crate::calc_digest;
+use crate::output::{progressln, verbose};
+use crate::scan::{fetch_signatures, filename, local_files};
+
+/// Differences between a local directory and the remote files, each list in name order.
+#[derive(Default, Serialize)]
+pub struct Diff {
+    pub local_only: Vec<String>,
+    pub remote_only: Vec<String>,
+    pub different: Vec<String>,
+}
+
+impl Diff {
+    /// Prints a line per differing file, or the whole diff as JSON with `json`.
+    pub fn print(&self, json: bool) -> Result<()> {
+        if json {
+            println!("{}", serde_json::to_string(self)?);
+            return Ok(());
+        }
+
+        for filename in &self.local_only {
+            println!("LOCAL-ONLY {filename}");
+        }
+        for filename in &self.remote_only {
+            println!("REMOTE-ONLY {filename}");
+        }
+        for filename in &self.different {
+            println!("DIFFERENT {filename}");
+        }
+        Ok(())
+    }
+}
+
+/// Matches the files directly in `dir` with the remote ones by name, and compares the files
+/// present on both sides by size and then signature. Signatures in `cache` are used instead of
+/// hashing, and the files found to be the same are added to it. Nothing is changed on either side.
+pub fn diff(
+    dir: impl AsRef<Path>,
+    follow_symlinks: bool,
+    cache: &mut PushCache,
+    signing_key: &SigningKey,
+    api: &impl Api,
+) -> Result<Diff> {
+    let local = local_files(dir, follow_symlinks)?
+        .into_iter()
+        .map(|path| Ok((filename(&path)?, path)))
+        .collect::<Result<BTreeMap<_, _>>>()?;
+    let request = SignableRequest::new(METHOD_LIST, String::new(), signing_key.verifying_key())?
+        .sign(signing_key)?;
+    let remote = api
+        .list(&request, None)?
+        .into_iter()
+        .map(|file| (file.filename, file.size))
+        .collect::<BTreeMap<_, _>>();
+
+    progressln!(
+        "Comparing {} local and {} remote files",
+        local.len(),
+        remote.len()
+    );
+
+    let mut diff = Diff {
+        remote_only: remote
+            .keys()
+            .filter(|filename| !local.contains_key(*filename))
+            .cloned()
+            .collect(),
+        ..Default::default()
+    };
+    let mut same_size = Vec::new();
+    for (filename, path) in &local {
+        match remote.get(filename) {
+            None => diff.local_only.push(filename.clone()),
+            Some(&size) if size != std::fs::metadata(path)?.len() => {
+                diff.different.push(filename.clone())
+            }
+            Some(_) => same_size.push(filename.clone()),
+        }
+    }
+
+    // Files deleted on the server since the listing have no signature and count as different
+    let signatures = fetch_signatures(&same_size, signing_key, api)?;
+    let pubkey = signing_key.verifying_key();
+    for (filename, signature) in same_size.into_iter().zip(signatures) {
+        let path = &local[&filename];
+        let local_signature = match cache.signature(path, &pubkey) {
+            Some(local_signature) => local_signature,
+            None => {
+                let metadata = std::fs::metadata(path)?;
+                let local_signature = signing_key.sign_digest(calc_digest(&mut File::open(path)?)?);
+                if signature == Some(local_signature) {
+                    cache.record(path, &metadata, &pubkey, &local_signature);
+                }
+                local_signature
+            }
+        };
+        if signature == Some(local_signature) {
+            verbose!("SAME {filename}");
+        } else {
+            diff.different.push(filename);
+        }
+    }
+    diff.different.sort();
+    cache.save()?;
+
+    Ok(diff)
+}
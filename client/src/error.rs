@@ -0,0 +1,107 @@
+use std::fmt::{Display, Formatter};
+use std::io::ErrorKind;
+
+use reqwest::StatusCode;
+
+/// Exit codes telling scripts what kind of failure happened. Usage errors exit with 2 from clap.
+pub const EXIT_FAILURE: i32 = 1;
+pub const EXIT_CONFIG: i32 = 3;
+/// Missing keypair, rejected request signature or clocks out of sync.
+pub const EXIT_AUTH: i32 = 4;
+pub const EXIT_NOT_FOUND: i32 = 5;
+pub const EXIT_NETWORK: i32 = 6;
+/// Data doesn't match its signature or the hashes stored on the server.
+pub const EXIT_MISMATCH: i32 = 7;
+/// Any other error status returned by the server.
+pub const EXIT_SERVER: i32 = 8;
+
+/// Error status returned by the server.
+#[derive(Debug)]
+pub struct ServerError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ServerError {
+    pub fn new(status: StatusCode, message: String) -> Self {
+        Self { status, message }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+}
+
+impl Display for ServerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Server returned error status code: {}\n{}",
+            self.status, self.message
+        )
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+#[derive(Debug)]
+pub struct VerificationError(pub String);
+
+impl Display for VerificationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for VerificationError {}
+
+/// Context of errors reading the client config.
+#[derive(Debug)]
+pub struct ConfigError(pub &'static str);
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// The keyring has no keypair yet.
+#[derive(Debug)]
+pub struct NoKeypairError;
+
+impl Display for NoKeypairError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "No keypair found. Run `cloud regenerate-keys` to create one first."
+        )
+    }
+}
+
+impl std::error::Error for NoKeypairError {}
+
+/// Picks the exit code for the error that made the command fail.
+pub fn exit_code(err: &anyhow::Error) -> i32 {
+    if err.downcast_ref::<ConfigError>().is_some() {
+        EXIT_CONFIG
+    } else if err.downcast_ref::<NoKeypairError>().is_some() {
+        EXIT_AUTH
+    } else if err.downcast_ref::<VerificationError>().is_some() {
+        EXIT_MISMATCH
+    } else if let Some(server_error) = err.downcast_ref::<ServerError>() {
+        match server_error.status() {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => EXIT_AUTH,
+            StatusCode::NOT_FOUND => EXIT_NOT_FOUND,
+            _ => EXIT_SERVER,
+        }
+    } else if err.downcast_ref::<reqwest::Error>().is_some() {
+        EXIT_NETWORK
+    } else if err
+        .downcast_ref::<std::io::Error>()
+        .is_some_and(|err| err.kind() == ErrorKind::NotFound)
+    {
+        EXIT_NOT_FOUND
+    } else {
+        EXIT_FAILURE
+    }
+}
@@ -1,12 +1,18 @@
+use std::io::Write;
+use std::path::Path;
+
 use anyhow::Result;
 use ed25519_dalek::{SecretKey, SigningKey};
 use keyring::{Entry, Error};
 use rand::rngs::OsRng;
 use zeroize::Zeroize;
 
+use crate::error::NoKeypairError;
+
 pub trait KeyStore {
     fn regenerate_keypair(&self) -> Result<()>;
     fn get_signing_key(&self) -> Result<SigningKey>;
+    fn has_keypair(&self) -> Result<bool>;
 }
 
 const SERVICE_NAME: &str = "cloud-cli";
@@ -39,27 +45,71 @@ impl KeyStore for Keyring {
 
     fn get_signing_key(&self) -> Result<SigningKey> {
         let entry = Entry::new(SERVICE_NAME, USER_NAME)?;
-        let mut secret_base58 = entry.get_password()?;
-
-        let result = bs58::decode(&secret_base58).into_vec();
-        secret_base58.zeroize();
-
-        let mut secret = match result {
-            Ok(secret) => secret,
+        let secret_base58 = match entry.get_password() {
+            Ok(secret_base58) => secret_base58,
+            Err(Error::NoEntry) => Err(NoKeypairError)?,
             Err(err) => Err(err)?,
         };
 
-        let result: std::result::Result<SecretKey, _> = secret.as_slice().try_into();
-        secret.zeroize();
+        decode_signing_key(secret_base58)
+    }
 
-        let mut secret_key = match result {
-            Ok(secret_key) => secret_key,
+    fn has_keypair(&self) -> Result<bool> {
+        let entry = Entry::new(SERVICE_NAME, USER_NAME)?;
+        match entry.get_password() {
+            Ok(mut secret_base58) => {
+                secret_base58.zeroize();
+                Ok(true)
+            }
+            Err(Error::NoEntry) => Ok(false),
             Err(err) => Err(err)?,
-        };
+        }
+    }
+}
 
-        let signing_key = SigningKey::from_bytes(&secret_key);
-        secret_key.zeroize();
+/// Decodes a base58 secret key, zeroizing every intermediate copy of it.
+fn decode_signing_key(mut secret_base58: String) -> Result<SigningKey> {
+    let result = bs58::decode(secret_base58.trim()).into_vec();
+    secret_base58.zeroize();
 
-        Ok(signing_key)
-    }
+    let mut secret = match result {
+        Ok(secret) => secret,
+        Err(err) => Err(err)?,
+    };
+
+    let result: std::result::Result<SecretKey, _> = secret.as_slice().try_into();
+    secret.zeroize();
+
+    let mut secret_key = match result {
+        Ok(secret_key) => secret_key,
+        Err(err) => Err(err)?,
+    };
+
+    let signing_key = SigningKey::from_bytes(&secret_key);
+    secret_key.zeroize();
+
+    Ok(signing_key)
+}
+
+/// Reads a secret key written by [`write_key_file`].
+pub fn read_key_file(path: impl AsRef<Path>) -> Result<SigningKey> {
+    decode_signing_key(std::fs::read_to_string(path)?)
+}
+
+/// Writes the secret key in base58 to a new file readable only by the current user.
+pub fn write_key_file(path: impl AsRef<Path>, signing_key: &SigningKey) -> Result<()> {
+    let mut options = std::fs::File::options();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+
+    let mut secret = signing_key.to_bytes();
+    let mut secret_base58 = bs58::encode(&secret).into_string();
+    secret.zeroize();
+
+    let result = writeln!(file, "{secret_base58}");
+    secret_base58.zeroize();
+
+    Ok(result?)
 }
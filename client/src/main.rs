@@ -1,119 +1,1437 @@
+use std::ffi::OsString;
 use std::fs::File;
-use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::io::{BufReader, IsTerminal, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use anyhow::{anyhow, bail, Result};
-use clap::{arg, Command};
-use ed25519_dalek::ed25519::signature::digest::Update;
-use ed25519_dalek::DigestSigner;
-use reqwest::Url;
-use tempfile::NamedTempFile;
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{arg, ArgMatches, Command};
+use ed25519_dalek::ed25519::signature::digest::{FixedOutput, Update};
+use ed25519_dalek::{DigestSigner, Signature, SigningKey, VerifyingKey};
+use reqwest::{StatusCode, Url};
 
-use shared::hasher::Hasher;
-use shared::SignableRequest;
+use shared::chunks::{encode_chunk_hash, BatchPlan, BatchUpload, ChunkManifest};
+use shared::consts::*;
+use shared::hasher::{self, Hasher, Prehashed};
+use shared::listing::signed_body_name;
+use shared::{SignableRequest, SignatureScheme, SignedRequest};
 
-use crate::api::{Api, HttpClient};
+use crate::api::{Api, HttpClient, PushOptions};
+use crate::cache::PushCache;
+use crate::error::{ConfigError, NoKeypairError, ServerError, VerificationError};
 use crate::keystore::{KeyStore, Keyring};
+use crate::output::{progress, progressln, verbose, Verbosity};
+use crate::resume::{resume_state_path, ResumeState};
+use crate::verify::ExpectedBlocks;
 
 mod api;
+mod bench;
+mod cache;
+mod diff;
+mod error;
 mod keystore;
+mod outbox;
+mod output;
+mod rekey;
+mod resume;
+mod scan;
+mod verify;
 
-#[derive(serde::Deserialize)]
+/// How many times a chunk upload is attempted before the whole push fails.
+const CHUNK_ATTEMPTS: u32 = 3;
+
+/// Size of the chunks `batch` and `push --single-pass` upload files in unless given.
+const DEFAULT_CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+
+#[derive(serde::Serialize, serde::Deserialize)]
 struct Config {
     pub server_url: Url,
     pub download_dir: PathBuf,
+    /// Timeout of each request in seconds, 30 when not set.
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+    /// Base58 pubkey of the server. When set, responses to `list`, `admin users` and the signature
+    /// and block hash lookups must be signed by it.
+    #[serde(default)]
+    pub expected_server_pubkey: Option<String>,
+    /// Bytes of a download held in memory at once, 64 KiB when not set.
+    #[serde(default)]
+    pub download_buffer_size: Option<usize>,
+    /// Directory partial downloads are kept in until they're verified, instead of next to the
+    /// destination. Should be on the same filesystem as `download_dir`, so finishing a download
+    /// is a rename rather than a copy.
+    #[serde(default)]
+    pub staging_dir: Option<PathBuf>,
+    /// Also compare the digest of each pulled file with the one the server recorded at upload,
+    /// in case it stored a valid signature with the wrong contents.
+    #[serde(default)]
+    pub verify_digest: bool,
+    /// File the signatures of pushed files are remembered in along with their size and
+    /// modification time, so `scan` and `diff` don't hash files unchanged since. Disabled when
+    /// not set.
+    #[serde(default)]
+    pub push_cache: Option<PathBuf>,
+    /// File pushes failing because the server can't be reached are queued in, for `flush` to
+    /// submit later. Such pushes fail when not set.
+    #[serde(default)]
+    pub outbox: Option<PathBuf>,
+}
+
+impl Config {
+    /// Config written by `init`, listing the optional fields unset so they're easy to fill in.
+    fn template(server_url: Url, download_dir: PathBuf) -> Self {
+        Self {
+            server_url,
+            download_dir,
+            timeout_secs: None,
+            expected_server_pubkey: None,
+            download_buffer_size: None,
+            staging_dir: None,
+            verify_digest: false,
+            push_cache: None,
+            outbox: None,
+        }
+    }
+
+    /// Makes the relative paths in the config relative to `dir`, the directory of the config file,
+    /// so they don't depend on where the client is run from.
+    fn resolve_paths(&mut self, dir: &Path) {
+        self.download_dir = dir.join(&self.download_dir);
+        for path in [
+            &mut self.staging_dir,
+            &mut self.push_cache,
+            &mut self.outbox,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            *path = dir.join(&*path);
+        }
+    }
 }
 
 fn cli() -> Command {
     Command::new("cloud")
         .about("Private cloud CLI")
         .subcommand_required(true)
+        .arg(arg!(-v --verbose "Print timings and request details").global(true))
+        .arg(
+            arg!(--config <PATH> "Config file, relative paths in it are resolved against its directory")
+                .global(true)
+                .default_value("client_config.json"),
+        )
+        .arg(
+            arg!(-q --quiet "Print only the results")
+                .global(true)
+                .conflicts_with("verbose"),
+        )
+        .arg(
+            arg!(--timeout <SECS> "Timeout of each request in seconds, overrides the config. Retried chunks get the timeout per attempt")
+                .global(true)
+                .value_parser(clap::value_parser!(u64)),
+        )
+        .subcommand(
+            Command::new("init")
+                .about("Write a config file listing every field, at the --config path")
+                .arg(arg!(--"server-url" <URL> "URL of the server").default_value("http://127.0.0.1:3030"))
+                .arg(arg!(--"download-dir" <PATH> "Directory pulled files are saved to").default_value("downloads"))
+                .arg(arg!(-f --force "Replace an existing config file")),
+        )
         .subcommand(
             Command::new("regenerate-keys")
-                .about("Regenerate access keypair. Previous keypair will be lost!"),
+                .about("Regenerate access keypair. Previous keypair will be lost!")
+                .arg(arg!(-y --yes "Don't ask for confirmation"))
+                .arg(arg!(--backup <PATH> "Save the previous secret key to a new file before replacing it")),
         )
         .subcommand(
             Command::new("push")
                 .about("Upload file to private cloud")
                 .arg(arg!(<PATH> "Path of file to upload"))
+                .arg(arg!(--name <FILENAME> "Remote filename, defaults to the local filename"))
+                .arg(arg!(--prefix <DIR> "Remote directory to store the file in, e.g. backups/2024, created as needed"))
+                .arg(arg!(--"if-match" <SIGNATURE> "Only replace the stored file if its signature matches"))
+                .arg(arg!(--digest <DIGEST> "Hex digest of the file computed beforehand, as printed by `hash`, so the file is read only once. Must match the file"))
+                .arg(
+                    arg!(--"chunk-size" <BYTES> "Upload the file in chunks of this size, retrying each chunk independently. The file is hashed while uploading, so it's read only once")
+                        .value_parser(clap::value_parser!(u64).range(1..)),
+                )
+                .arg(arg!(--"single-pass" "Read the file only once, hashing it while uploading in chunks of the default size unless --chunk-size is given").conflicts_with("digest"))
+                .arg(arg!(--"passphrase-file" <PATH> "Protect the file with the passphrase on the first line of this file, which pulls of it then have to give"))
+                .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("flush")
+                .about("Push the files queued in the outbox while the server couldn't be reached"),
+        )
+        .subcommand(
+            Command::new("update-signature")
+                .about("Store a new signature for a file whose contents on the server match the local file, without uploading it")
+                .arg(arg!(<PATH> "Path of the local copy of the file"))
+                .arg(arg!(--name <FILENAME> "Remote filename, defaults to the local filename"))
+                .arg(arg!(--"if-match" <SIGNATURE> "Only update the signature if the stored one matches"))
+                .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("hash")
+                .about("Print the digest and signature `push` would send for a file, without uploading it")
+                .arg(arg!(<PATH> "Path of file to hash"))
+                .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("checksum")
+                .about("Check a local file against a signature recorded earlier, without contacting the server")
+                .arg(arg!(<PATH> "Path of file to check"))
+                .arg(arg!(--expect <SIGNATURE> "Base58 file signature the file should have").required(true))
                 .arg_required_else_help(true),
         )
         .subcommand(
             Command::new("pull")
                 .about("Download file from private cloud")
                 .arg(arg!(<FILENAME> "Filename to download"))
+                .arg(
+                    arg!(--overwrite <WHEN> "Replace an existing local file: never, always, or if-newer than the server copy; asks when unset and run in a terminal, otherwise never")
+                        .value_parser(["never", "always", "if-newer"]),
+                )
+                .arg(arg!(--"no-verify" "Save the file without checking its signature, e.g. when a proxy strips the signature headers. Its integrity isn't verified!"))
+                .arg(arg!(--"passphrase-file" <PATH> "Read the passphrase of a protected file from the first line of this file"))
+                .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("check")
+                .about("Download file and verify its signature without saving it")
+                .arg(arg!(<FILENAME> "Filename to check"))
+                .arg(arg!(--"passphrase-file" <PATH> "Read the passphrase of a protected file from the first line of this file"))
+                .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("list")
+                .about("List files stored in private cloud")
+                .arg(arg!(--since <TIME> "Only list files uploaded since this RFC 3339 time or duration ago, e.g. 2d")),
+        )
+        .subcommand(
+            Command::new("admin")
+                .about("Server administration, requires an admin keypair")
+                .subcommand_required(true)
+                .subcommand(Command::new("users").about("List pubkeys storing files on the server"))
+                .subcommand(Command::new("trim").about("Remove empty directories and orphaned signature files from the server storage")),
+        )
+        .subcommand(
+            Command::new("prune")
+                .about("Delete all files uploaded before a time from private cloud")
+                .arg(arg!(--before <TIME> "RFC 3339 time or duration ago, e.g. 90d").required(true)),
+        )
+        .subcommand(
+            Command::new("set-server")
+                .about("Point the config file at a new server URL, dropping push cache entries the new server doesn't confirm")
+                .arg(arg!(<URL> "New server URL"))
+                .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("rename")
+                .about("Rename file in private cloud")
+                .arg(arg!(<FROM> "Current filename"))
+                .arg(arg!(<TO> "New filename"))
+                .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("copy")
+                .about("Copy file in private cloud")
+                .arg(arg!(<FROM> "Filename to copy"))
+                .arg(arg!(<TO> "Filename of the copy"))
+                .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("delete")
+                .about("Delete file from private cloud")
+                .arg(arg!(<FILENAME> "Filename to delete"))
+                .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("bench")
+                .about("Measure latency and throughput to the server by pushing, pulling and deleting a temporary file")
+                .arg(
+                    arg!(--size <BYTES> "Size of the payload")
+                        .value_parser(clap::value_parser!(u64).range(1..))
+                        .default_value("1048576"),
+                )
+                .arg(
+                    arg!(--rounds <COUNT> "Number of times the payload is pushed and pulled")
+                        .value_parser(clap::value_parser!(u32).range(1..))
+                        .default_value("3"),
+                ),
+        )
+        .subcommand(
+            Command::new("share")
+                .about("Print a link downloading a file without a keypair until it expires")
+                .arg(arg!(<FILENAME> "Filename to share"))
+                .arg(arg!(--"expires-in" <DURATION> "How long the link works, e.g. 12h. The server's longest allowed duration when not set"))
+                .arg(
+                    arg!(--"max-downloads" <COUNT> "Stop the link working after this many downloads, each range request counting as one")
+                        .value_parser(clap::value_parser!(u64).range(1..)),
+                )
+                .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("shares")
+                .about("Manage the share links created with `share`")
+                .subcommand_required(true)
+                .subcommand(Command::new("list").about("List working share links with their expiry and downloads"))
+                .subcommand(
+                    Command::new("revoke")
+                        .about("Stop a share link working right away")
+                        .arg(arg!(<TOKEN> "Token of the link, as listed by `shares list`, or the whole link"))
+                        .arg_required_else_help(true),
+                ),
+        )
+        .subcommand(
+            Command::new("batch")
+                .about("Upload and delete several files at once, applying either all of the changes or none")
+                .arg(arg!([PATH] ... "Paths of files to upload"))
+                .arg(arg!(--delete <FILENAME> ... "Filename to delete, may be repeated"))
+                .arg(
+                    arg!(--"chunk-size" <BYTES> "Size of the chunks the files are uploaded in")
+                        .value_parser(clap::value_parser!(u64).range(1..)),
+                )
+                .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("rekey")
+                .about("Move all files stored under a previous keypair to the current one")
+                .arg(arg!(--"old-key" <PATH> "Secret key file saved by `regenerate-keys --backup`").required(true))
+                .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("diff")
+                .about("List the files differing between a local directory and private cloud, changing nothing")
+                .arg(arg!(<DIR> "Directory to compare"))
+                .arg(arg!(--json "Print the differences as a JSON object"))
+                .arg(arg!(--"follow-symlinks" "Include the files symlinks point to, even outside the directory"))
+                .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("scan")
+                .about("Verify that downloaded files still match the ones in private cloud")
+                .arg(arg!(<DIR> "Directory to scan"))
+                .arg(arg!(--"follow-symlinks" "Include the files symlinks point to, even outside the directory"))
+                .arg(
+                    arg!(-j --jobs <JOBS> "Number of files to check in parallel")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("4"),
+                )
                 .arg_required_else_help(true),
         )
 }
 
-fn push(path: impl AsRef<Path>, keystore: impl KeyStore, api: impl Api) -> Result<()> {
+/// Writes `config` to `config_path`, unless a file is already there and `force` isn't set.
+fn init(config_path: &Path, config: &Config, force: bool) -> Result<()> {
+    if config_path.exists() && !force {
+        bail!(
+            "{} already exists, pass --force to replace it",
+            config_path.display()
+        );
+    }
+
+    let mut file = File::create(config_path)?;
+    serde_json::to_writer_pretty(&mut file, config)?;
+    writeln!(file)?;
+    println!("{}", config_path.display());
+
+    Ok(())
+}
+
+fn regenerate_keys(yes: bool, backup: Option<&Path>, keystore: impl KeyStore) -> Result<()> {
+    // Only a readable key can be backed up, an unreadable one is about to be replaced anyway
+    let old_key = keystore.get_signing_key().ok();
+    if let Some(old_key) = &old_key {
+        progressln!(
+            "Current pubkey: {}",
+            bs58::encode(old_key.verifying_key().as_bytes()).into_string()
+        );
+    }
+
+    if let Some(backup) = backup {
+        let old_key = old_key
+            .as_ref()
+            .ok_or(anyhow!("There is no keypair to back up"))?;
+        keystore::write_key_file(backup, old_key)?;
+        progressln!("Previous secret key saved to {}", backup.display());
+    }
+
+    if !yes
+        && !confirm(
+            "Files stored under the current pubkey will become inaccessible. Continue? [y/N] ",
+        )?
+    {
+        bail!("Aborted by user");
+    }
+
+    keystore.regenerate_keypair()?;
+    progressln!("New keypair generated successfully!");
+    let new_key = keystore.get_signing_key()?;
+    println!(
+        "{}",
+        bs58::encode(new_key.verifying_key().as_bytes()).into_string()
+    );
+
+    Ok(())
+}
+
+/// Asks a yes/no question on stderr, treating anything but `y` or `yes` as no.
+fn confirm(prompt: &str) -> Result<bool> {
+    eprint!("{prompt}");
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+fn push(
+    path: impl AsRef<Path>,
+    remote_name: Option<&str>,
+    chunk_size: Option<u64>,
+    digest: Option<Prehashed>,
+    options: &PushOptions,
+    keystore: impl KeyStore,
+    api: impl Api,
+) -> Result<Signature> {
     let mut file = File::open(&path)?;
-    let filename = path
-        .as_ref()
-        .file_name()
-        .ok_or(anyhow!("Filename not found in the path"))?
-        .to_string_lossy();
-    println!("File: {filename}, {} bytes", file.metadata()?.len(),);
+    let filename = remote_filename(path.as_ref(), remote_name)?;
+    let size = file.metadata()?.len();
+    progressln!("File: {filename}, {size} bytes");
+    let signing_key = keystore.get_signing_key()?;
+
+    let digest = match (digest, chunk_size) {
+        (Some(digest), _) => {
+            verbose!("Using the given digest, the file isn't hashed");
+            digest
+        }
+        (None, Some(chunk_size)) => {
+            let file_signature =
+                push_single_pass(&filename, file, chunk_size, options, &signing_key, &api)?;
+            println!("{filename}");
+            return Ok(file_signature);
+        }
+        (None, None) => {
+            progress!("Calculating digest... ");
+            let started = Instant::now();
+            let digest = calc_digest(&mut file)?;
+            progressln!("OK");
+            verbose!("Digest calculated in {:?}", started.elapsed());
+            file.seek(SeekFrom::Start(0))?;
+            Prehashed::from_slice(&digest.finalize_fixed())?
+        }
+    };
 
-    print!("Calculating signatures... ");
-    std::io::stdout().flush().ok();
+    let body = PrehashedBody {
+        reader: file,
+        size,
+        digest,
+    };
+    let file_signature = push_prehashed(&filename, body, chunk_size, options, &signing_key, &api)?;
+    println!("{filename}");
 
-    let digest = calc_digest(&mut file)?;
+    Ok(file_signature)
+}
+
+/// Name a local file is stored under: `remote_name` when given, the local filename otherwise.
+fn remote_filename(path: &Path, remote_name: Option<&str>) -> Result<String> {
+    let filename = match remote_name {
+        Some(remote_name) => shared::normalize_filename(remote_name),
+        None => shared::normalize_filename(
+            &path
+                .file_name()
+                .ok_or(anyhow!("Filename not found in the path"))?
+                .to_string_lossy(),
+        ),
+    };
+    shared::validate_filename(&filename)?;
+    Ok(filename)
+}
+
+/// `remote_name`, or the local filename, under the remote directory `prefix`. The prefix is
+/// checked like a filename, so it can't escape the pubkey directory either.
+fn prefixed_filename(prefix: &str, path: &Path, remote_name: Option<&str>) -> Result<String> {
+    let prefix = shared::normalize_filename(prefix.strip_suffix('/').unwrap_or(prefix));
+    shared::validate_filename(&prefix).context("Invalid prefix")?;
+    Ok(format!("{prefix}/{}", remote_filename(path, remote_name)?))
+}
+
+/// Signs the local file and stores the signature on the server for the file stored there, which
+/// must have the same contents. Fixes the signature without uploading the file again.
+fn update_signature(
+    path: impl AsRef<Path>,
+    remote_name: Option<&str>,
+    options: &PushOptions,
+    keystore: impl KeyStore,
+    api: impl Api,
+) -> Result<()> {
+    let mut file = File::open(&path)?;
+    let filename = remote_filename(path.as_ref(), remote_name)?;
     let signing_key = keystore.get_signing_key()?;
+
+    progress!("Calculating digest... ");
+    let digest = calc_digest(&mut file)?;
+    progressln!("OK");
+    let file_signature = signing_key.sign_digest(digest);
+
+    let request = SignableRequest::new(
+        METHOD_UPDATE_SIGNATURE,
+        filename.clone(),
+        signing_key.verifying_key(),
+    )?
+    .sign(&signing_key)?;
+    print_request_details(&request);
+    progress!("Updating signature... ");
+    api.update_signature(&request, &file_signature, options)?;
+    progressln!("OK");
+    println!("{filename}");
+
+    Ok(())
+}
+
+/// Data to upload along with its digest computed beforehand, so it's read only once.
+struct PrehashedBody<R> {
+    reader: R,
+    size: u64,
+    /// Must be the digest of exactly the `size` bytes `reader` yields, which isn't checked before
+    /// uploading. The server rejects a mismatching upload, unless it trusts client digests and
+    /// stores it as unverified, leaving the mismatch to be found on download.
+    digest: Prehashed,
+}
+
+/// Signs the known digest of `body` and uploads it, without the extra pass over the data `push`
+/// makes to hash it. For pipelines that hash the data as they produce it. Returns the file
+/// signature.
+fn push_prehashed(
+    filename: &str,
+    body: PrehashedBody<impl Read + Send + 'static>,
+    chunk_size: Option<u64>,
+    options: &PushOptions,
+    signing_key: &SigningKey,
+    api: &impl Api,
+) -> Result<Signature> {
+    let file_signature = signing_key.sign_digest(body.digest.clone());
+    let options = PushOptions {
+        file_digest: Some(body.digest.finalize_fixed().to_vec()),
+        ..options.clone()
+    };
+
+    let request = SignableRequest::new(
+        METHOD_UPLOAD,
+        filename.to_string(),
+        signing_key.verifying_key(),
+    )?;
+    let request = request.sign(signing_key)?;
+    print_request_details(&request);
+
+    progress!("Pushing file... ");
+    let started = Instant::now();
+
+    match chunk_size {
+        Some(chunk_size) => push_chunks(
+            &request,
+            &file_signature,
+            body.reader,
+            chunk_size,
+            &options,
+            signing_key,
+            api,
+        )?,
+        None => api.push(&request, &file_signature, body.reader, body.size, &options)?,
+    }
+
+    progressln!("OK");
+    verbose!("File pushed in {:?}", started.elapsed());
+
+    Ok(file_signature)
+}
+
+fn push_chunks(
+    request: &SignedRequest,
+    file_signature: &Signature,
+    body: impl Read,
+    chunk_size: u64,
+    options: &PushOptions,
+    signing_key: &SigningKey,
+    api: &impl Api,
+) -> Result<()> {
+    let (manifest, _) = upload_chunks(request.filename(), body, chunk_size, signing_key, api)?;
+    let manifest_request = SignableRequest::new(
+        METHOD_UPLOAD_MANIFEST,
+        request.filename().to_string(),
+        signing_key.verifying_key(),
+    )?
+    .sign(signing_key)?;
+    api.push_manifest(&manifest_request, file_signature, &manifest, options)
+}
+
+/// Uploads `body` in chunks of `chunk_size` bytes, retrying each chunk independently, and returns
+/// the manifest to assemble them with along with the digest of the whole body, so it's read once.
+fn upload_chunks(
+    filename: &str,
+    body: impl Read,
+    chunk_size: u64,
+    signing_key: &SigningKey,
+    api: &impl Api,
+) -> Result<(ChunkManifest, Hasher)> {
+    let mut reader = BufReader::new(body);
+    let mut manifest = ChunkManifest {
+        total_size: 0,
+        chunks: Vec::new(),
+    };
+    let mut file_hasher = Hasher::default();
+
+    for index in 0.. {
+        let mut data = Vec::new();
+        reader.by_ref().take(chunk_size).read_to_end(&mut data)?;
+        if data.is_empty() && index > 0 {
+            break;
+        }
+
+        file_hasher.update(&data);
+        let mut hasher = Hasher::default();
+        hasher.update(&data);
+        let chunk_hash = encode_chunk_hash(hasher);
+        manifest.total_size += data.len() as u64;
+        manifest.chunks.push(chunk_hash.clone());
+
+        for attempt in 1..=CHUNK_ATTEMPTS {
+            // Each chunk is signed separately, so the upload can take longer than the time window
+            let chunk_request = SignableRequest::new(
+                METHOD_UPLOAD_CHUNK,
+                filename.to_string(),
+                signing_key.verifying_key(),
+            )?
+            .sign(signing_key)?;
+            match api.push_chunk(&chunk_request, index, &chunk_hash, data.clone()) {
+                Ok(()) => break,
+                Err(err) if attempt < CHUNK_ATTEMPTS => {
+                    verbose!("Chunk {index} attempt {attempt} failed: {err}");
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        verbose!("Chunk {index} pushed, {} bytes", data.len());
+    }
+
+    Ok((manifest, file_hasher))
+}
+
+/// Uploads the file in chunks while hashing it and signs the digest once all chunks are sent, so
+/// the file is read only once. The manifest carrying the signature completes the upload.
+fn push_single_pass(
+    filename: &str,
+    body: impl Read,
+    chunk_size: u64,
+    options: &PushOptions,
+    signing_key: &SigningKey,
+    api: &impl Api,
+) -> Result<Signature> {
+    progress!("Pushing file... ");
+    let started = Instant::now();
+
+    let (manifest, digest) = upload_chunks(filename, body, chunk_size, signing_key, api)?;
+    let options = PushOptions {
+        file_digest: Some(digest.clone().finalize_fixed().to_vec()),
+        ..options.clone()
+    };
     let file_signature = signing_key.sign_digest(digest);
+    let manifest_request = SignableRequest::new(
+        METHOD_UPLOAD_MANIFEST,
+        filename.to_string(),
+        signing_key.verifying_key(),
+    )?
+    .sign(signing_key)?;
+    print_request_details(&manifest_request);
+    api.push_manifest(&manifest_request, &file_signature, &manifest, &options)?;
+
+    progressln!("OK");
+    verbose!("File pushed in {:?}", started.elapsed());
+
+    Ok(file_signature)
+}
+
+/// Uploads the files at `paths` and deletes `deletes` in one batch, so either all of the changes
+/// are applied or none. The files are pushed in chunks first and only committed at the end, then
+/// recorded in `cache`.
+fn batch(
+    paths: &[PathBuf],
+    deletes: &[String],
+    chunk_size: u64,
+    cache: &mut PushCache,
+    keystore: impl KeyStore,
+    api: impl Api,
+) -> Result<()> {
+    let signing_key = keystore.get_signing_key()?;
+    let mut plan = BatchPlan::default();
+    let mut pushed = Vec::with_capacity(paths.len());
+
+    for path in paths {
+        let filename = shared::normalize_filename(
+            &path
+                .file_name()
+                .ok_or(anyhow!("Filename not found in the path"))?
+                .to_string_lossy(),
+        );
+        shared::validate_filename(&filename)?;
+        let file = File::open(path)?;
+        let metadata = file.metadata()?;
+        progress!("Pushing {filename}... ");
+        let (manifest, digest) = upload_chunks(&filename, file, chunk_size, &signing_key, &api)?;
+        let file_signature = signing_key.sign_digest(digest);
+        progressln!("OK");
+        pushed.push((metadata, file_signature));
+        plan.uploads.push(BatchUpload {
+            filename,
+            file_signature: bs58::encode(file_signature.to_bytes()).into_string(),
+            file_signature_scheme: Some(SignatureScheme::FILE_DEFAULT.to_string()),
+            manifest,
+        });
+    }
+    for filename in deletes {
+        let filename = shared::normalize_filename(filename);
+        shared::validate_filename(&filename)?;
+        plan.deletes.push(filename);
+    }
+
+    let body = serde_json::to_vec(&plan)?;
+    let request = SignableRequest::new(
+        METHOD_BATCH,
+        signed_body_name(&body),
+        signing_key.verifying_key(),
+    )?
+    .sign(&signing_key)?;
+    print_request_details(&request);
+    progress!("Committing batch... ");
+    api.batch(&request, body)?;
+    progressln!("OK");
+
+    for (path, (metadata, file_signature)) in paths.iter().zip(&pushed) {
+        cache.record(path, metadata, &signing_key.verifying_key(), file_signature);
+    }
+    cache.save()?;
+
+    for upload in &plan.uploads {
+        println!("{}", upload.filename);
+    }
+    for filename in &plan.deletes {
+        println!("{filename}");
+    }
+
+    Ok(())
+}
+
+fn hash(path: impl AsRef<Path>, keystore: impl KeyStore) -> Result<()> {
+    let digest = calc_digest(&mut File::open(path)?)?;
+    let signing_key = keystore.get_signing_key()?;
+    let file_signature = signing_key.sign_digest(digest.clone());
+
+    println!("digest: {}", hasher::encode_hex(digest));
+    println!(
+        "signature: {}",
+        bs58::encode(file_signature.to_bytes()).into_string()
+    );
+
+    Ok(())
+}
+
+/// Compares the signature of the file at `path` with `expected` without any request, to check a
+/// local copy against a signature noted down before.
+fn checksum(path: impl AsRef<Path>, expected: &Signature, keystore: impl KeyStore) -> Result<()> {
+    let path = path.as_ref();
+    let signing_key = keystore.get_signing_key()?;
+
+    progress!("Calculating signature... ");
+    let signature = signing_key.sign_digest(calc_digest(&mut File::open(path)?)?);
+    if signature != *expected {
+        progressln!("FAILED");
+        return Err(VerificationError(format!(
+            "{} doesn't match the expected signature",
+            path.display()
+        ))
+        .into());
+    }
+
+    progressln!("OK");
+    println!("OK {}", path.display());
+
+    Ok(())
+}
+
+fn list(since: Option<u64>, keystore: impl KeyStore, api: impl Api) -> Result<()> {
+    let signing_key = keystore.get_signing_key()?;
+    let request = SignableRequest::new(METHOD_LIST, String::new(), signing_key.verifying_key())?
+        .sign(&signing_key)?;
+    print_request_details(&request);
+
+    api.list_each(&request, since, &mut |file| {
+        println!("{}\t{}", file.size, file.filename);
+        Ok(())
+    })?;
+
+    Ok(())
+}
+
+fn admin_users(keystore: impl KeyStore, api: impl Api) -> Result<()> {
+    let signing_key = keystore.get_signing_key()?;
+    let request = SignableRequest::new(
+        METHOD_ADMIN_USERS,
+        String::new(),
+        signing_key.verifying_key(),
+    )?
+    .sign(&signing_key)?;
+    print_request_details(&request);
+
+    for user in api.admin_users(&request)? {
+        println!("{}\t{}\t{}", user.pubkey, user.files, user.size);
+    }
+
+    Ok(())
+}
+
+fn admin_trim(keystore: impl KeyStore, api: impl Api) -> Result<()> {
+    let signing_key = keystore.get_signing_key()?;
+    let request = SignableRequest::new(
+        METHOD_ADMIN_TRIM,
+        String::new(),
+        signing_key.verifying_key(),
+    )?
+    .sign(&signing_key)?;
+    print_request_details(&request);
+
+    let report = api.admin_trim(&request)?;
+    println!(
+        "Removed {} empty directories and {} orphaned signature files",
+        report.removed_dirs, report.removed_signatures
+    );
 
-    let request = SignableRequest::new(filename.to_string(), signing_key.verifying_key())?;
+    Ok(())
+}
+
+fn prune(before: u64, keystore: impl KeyStore, api: impl Api) -> Result<()> {
+    let signing_key = keystore.get_signing_key()?;
+    let request = SignableRequest::new(
+        METHOD_PRUNE,
+        before.to_string(),
+        signing_key.verifying_key(),
+    )?
+    .sign(&signing_key)?;
+    print_request_details(&request);
+
+    let report = api.prune(&request)?;
+    println!("Removed {} files", report.removed);
+
+    Ok(())
+}
+
+/// Points the config file at `server_url`. Signatures in the push cache are checked against the
+/// new server first and the ones it doesn't store for the same filename are dropped, so `scan`
+/// and `diff` don't rely on entries recorded for the old server. Nothing is checked without a
+/// cache.
+fn set_server(
+    config_path: &Path,
+    server_url: &Url,
+    cache: &mut PushCache,
+    keystore: impl KeyStore,
+    api: impl Api,
+) -> Result<()> {
+    let signing_key = keystore.get_signing_key()?;
+    let entries = cache.entries(&signing_key.verifying_key());
+    if !entries.is_empty() {
+        progress!("Checking {} cached files... ", entries.len());
+        let filenames = entries
+            .iter()
+            .map(|(path, _)| scan::filename(path))
+            .collect::<Result<Vec<_>>>()?;
+        let server_signatures = scan::fetch_signatures(&filenames, &signing_key, &api)?;
+        progressln!("OK");
+
+        let mut dropped = 0;
+        for ((path, signature), server_signature) in entries.iter().zip(server_signatures) {
+            if server_signature != Some(*signature) {
+                verbose!("{} doesn't match the new server", path.display());
+                cache.forget(path);
+                dropped += 1;
+            }
+        }
+        println!(
+            "{} cached files confirmed, {dropped} dropped",
+            entries.len() - dropped
+        );
+    }
+
+    let mut config: serde_json::Map<String, serde_json::Value> = serde_json::from_reader(
+        File::open(config_path).context(ConfigError("Unable to open config file"))?,
+    )
+    .context(ConfigError("Unable to parse config file"))?;
+    config.insert("server_url".to_string(), server_url.as_str().into());
+    let mut temp_path = OsString::from(config_path.as_os_str());
+    temp_path.push(".tmp");
+    let mut file = File::create(&temp_path)?;
+    serde_json::to_writer_pretty(&mut file, &config)?;
+    writeln!(file)?;
+    file.sync_all()?;
+    std::fs::rename(&temp_path, config_path)?;
+    cache.save()?;
+    println!("Server URL set to {server_url}");
+
+    Ok(())
+}
+
+fn transfer(
+    from: &str,
+    to: &str,
+    copy: bool,
+    keystore: impl KeyStore,
+    api: impl Api,
+) -> Result<()> {
+    let from = &shared::normalize_filename(from);
+    let to = &shared::normalize_filename(to);
+    shared::validate_filename(from)?;
+    shared::validate_filename(to)?;
+
+    let signing_key = keystore.get_signing_key()?;
+    let method = if copy { METHOD_COPY } else { METHOD_RENAME };
+    let request = SignableRequest::new(method, from.to_string(), signing_key.verifying_key())?;
+    let destination = SignableRequest::with_time(
+        method,
+        to.to_string(),
+        signing_key.verifying_key(),
+        request.time(),
+    );
     let request = request.sign(&signing_key)?;
+    let destination = destination.sign(&signing_key)?;
+    print_request_details(&request);
+
+    if copy {
+        api.copy(&request, &destination)?;
+    } else {
+        api.rename(&request, &destination)?;
+    }
+
+    println!("{to}");
 
-    println!("OK");
-    std::io::stdout().flush().ok();
+    Ok(())
+}
 
-    print!("Pushing file... ");
-    std::io::stdout().flush().ok();
+fn delete(filename: &str, keystore: impl KeyStore, api: impl Api) -> Result<()> {
+    let filename = &shared::normalize_filename(filename);
+    shared::validate_filename(filename)?;
+    let signing_key = keystore.get_signing_key()?;
+    let request = SignableRequest::new(
+        METHOD_DELETE,
+        filename.to_string(),
+        signing_key.verifying_key(),
+    )?
+    .sign(&signing_key)?;
+    print_request_details(&request);
 
-    file.seek(SeekFrom::Start(0))?;
+    api.delete(&request)?;
+
+    println!("{filename}");
+
+    Ok(())
+}
+
+/// Creates a link to `filename` and prints its URL on `server_url`.
+fn share(
+    filename: &str,
+    expires_in: Option<Duration>,
+    max_downloads: Option<u64>,
+    server_url: &Url,
+    keystore: impl KeyStore,
+    api: impl Api,
+) -> Result<()> {
+    let filename = &shared::normalize_filename(filename);
+    shared::validate_filename(filename)?;
+    let signing_key = keystore.get_signing_key()?;
+    let request = SignableRequest::new(
+        METHOD_SHARE,
+        filename.to_string(),
+        signing_key.verifying_key(),
+    )?
+    .sign(&signing_key)?;
+    print_request_details(&request);
+
+    let share = api.share(
+        &request,
+        expires_in.map(|expires_in| expires_in.as_secs()),
+        max_downloads,
+    )?;
+    let expires_at = UNIX_EPOCH + Duration::from_secs(share.expires_at);
+    match share.max_downloads {
+        Some(max_downloads) => progressln!(
+            "Link expires at {} or after {max_downloads} downloads",
+            humantime::format_rfc3339_seconds(expires_at)
+        ),
+        None => progressln!(
+            "Link expires at {}",
+            humantime::format_rfc3339_seconds(expires_at)
+        ),
+    }
+    let path = format!("{}/{}", METHOD_SHARED, share.token);
+    println!("{}", server_url.join(&path)?);
+
+    Ok(())
+}
 
-    api.push(&request, &file_signature, file)
+/// Prints the working share links as the token, the filename, the expiry time and the downloads
+/// served and left.
+fn list_shares(keystore: impl KeyStore, api: impl Api) -> Result<()> {
+    let signing_key = keystore.get_signing_key()?;
+    let request = SignableRequest::new(METHOD_SHARES, String::new(), signing_key.verifying_key())?
+        .sign(&signing_key)?;
+    print_request_details(&request);
+
+    for share in api.shares(&request)? {
+        let expires_at = UNIX_EPOCH + Duration::from_secs(share.expires_at);
+        let left = match share.max_downloads {
+            Some(max_downloads) => max_downloads.saturating_sub(share.downloads).to_string(),
+            None => "unlimited".to_string(),
+        };
+        println!(
+            "{}\t{}\t{}\t{} downloads, {left} left",
+            share.token,
+            share.filename,
+            humantime::format_rfc3339_seconds(expires_at),
+            share.downloads
+        );
+    }
+
+    Ok(())
 }
 
-fn pull(filename: &str, download_dir: impl AsRef<Path>, api: impl Api) -> Result<()> {
+/// Removes the share link with `token`, which may also be given as the whole link.
+fn revoke_share(token: &str, keystore: impl KeyStore, api: impl Api) -> Result<()> {
+    let token = token
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .unwrap_or(token);
+    let signing_key = keystore.get_signing_key()?;
+    let request = SignableRequest::new(
+        METHOD_REVOKE_SHARE,
+        token.to_string(),
+        signing_key.verifying_key(),
+    )?
+    .sign(&signing_key)?;
+    print_request_details(&request);
+
+    progress!("Revoking share link... ");
+    api.revoke_share(&request)?;
+    progressln!("OK");
+
+    Ok(())
+}
+
+/// What `pull` does when the destination file already exists.
+#[derive(Clone, Copy, PartialEq)]
+enum Overwrite {
+    Never,
+    Always,
+    /// Only when the server copy was uploaded after the local file was last modified.
+    IfNewer,
+    /// Ask on the terminal.
+    Ask,
+}
+
+impl Overwrite {
+    fn from_arg(arg: Option<&str>) -> Self {
+        match arg {
+            Some("always") => Self::Always,
+            Some("if-newer") => Self::IfNewer,
+            Some(_) => Self::Never,
+            None if std::io::stdin().is_terminal() => Self::Ask,
+            None => Self::Never,
+        }
+    }
+
+    /// Whether to replace `destination` with the server copy uploaded at `uploaded_at`.
+    fn allows(self, destination: &Path, uploaded_at: Option<u64>) -> Result<bool> {
+        match self {
+            Self::Never => Ok(false),
+            Self::Always => Ok(true),
+            Self::Ask => confirm(&format!(
+                "{} already exists. Overwrite? [y/N] ",
+                destination.display()
+            )),
+            Self::IfNewer => {
+                let Some(uploaded_at) = uploaded_at else {
+                    verbose!("Server doesn't report when the file was uploaded");
+                    return Ok(false);
+                };
+                let modified = std::fs::metadata(destination)?
+                    .modified()?
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |modified| modified.as_secs());
+                Ok(uploaded_at > modified)
+            }
+        }
+    }
+}
+
+fn pull(
+    filename: &str,
+    download_dir: impl AsRef<Path>,
+    staging_dir: Option<&Path>,
+    overwrite: Overwrite,
+    verify_digest: bool,
+    api: impl Api,
+) -> Result<()> {
+    let filename = &shared::normalize_filename(filename);
+    shared::validate_filename(filename)?;
     let signing_key = Keyring.get_signing_key()?;
-    let request = SignableRequest::new(filename.to_string(), signing_key.verifying_key())?;
+    let request = SignableRequest::new(
+        METHOD_DOWNLOAD,
+        filename.to_string(),
+        signing_key.verifying_key(),
+    )?;
     let request = request.sign(&signing_key)?;
-    let mut temp_file = NamedTempFile::new()?;
+    print_request_details(&request);
 
-    print!("Downloading file... ");
-    std::io::stdout().flush().ok();
+    let new_name = download_dir.as_ref().join(request.filename());
+    assert!(new_name.starts_with(download_dir));
+    // Nested filenames get their directories recreated under the download directory
+    std::fs::create_dir_all(new_name.parent().expect("Download path has a parent"))?;
 
-    let file_signature_from_server = api.pull(&request, temp_file.as_file())?;
+    let Some(stored) = api.signature(&sign_request(METHOD_SIGNATURE, filename, &signing_key)?)?
+    else {
+        return Err(ServerError::new(
+            StatusCode::NOT_FOUND,
+            format!("{filename} not found on the server"),
+        )
+        .into());
+    };
+    if new_name.exists() && !overwrite.allows(&new_name, stored.uploaded_at)? {
+        progressln!("Skipping {filename}, {} already exists", new_name.display());
+        return Ok(());
+    }
+    let server_signature = bs58::encode(stored.signature.to_bytes()).into_string();
 
-    println!("OK");
-    std::io::stdout().flush().ok();
+    let part_name = match staging_dir {
+        Some(staging_dir) => staged_partial_path(staging_dir, request.filename(), &new_name),
+        None => partial_path(&new_name),
+    };
+    let state_name = resume_state_path(&part_name);
+    let mut part_file = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&part_name)?;
+    let mut offset = part_file.metadata()?.len();
 
-    print!("Calculating signature... ");
-    std::io::stdout().flush().ok();
-    let digest = calc_digest(temp_file.as_file_mut())?;
+    if offset > 0 {
+        match ResumeState::load(&state_name) {
+            Some(state) if state.signature == server_signature => verbose!(
+                "Partial download has {offset} bytes, {} recorded by the last run",
+                state.received
+            ),
+            Some(_) => {
+                verbose!("File changed on the server since the partial download, restarting");
+                offset = 0;
+            }
+            None => {
+                verbose!("Partial download has no resume state, restarting");
+                offset = 0;
+            }
+        }
+        if offset == 0 {
+            part_file.set_len(0)?;
+        }
+    }
+    let mut state = ResumeState {
+        signature: server_signature,
+        received: offset,
+    };
+    state.save(&state_name)?;
+
+    let expected = match api.outboard(&sign_request(METHOD_OUTBOARD, filename, &signing_key)?)? {
+        Some(outboard) => {
+            let root = outboard
+                .root()
+                .map_err(|err| VerificationError(format!("Invalid outboard: {err}")))?;
+            if signing_key.sign_digest(root) != stored.signature {
+                return Err(VerificationError(
+                    "Outboard doesn't match the file signature".to_string(),
+                )
+                .into());
+            }
+            verbose!("Outboard matches the file signature, verifying the download against it");
+            Some(ExpectedBlocks::Outboard(outboard))
+        }
+        None => api
+            .block_hashes(&sign_request(METHOD_BLOCK_HASHES, filename, &signing_key)?)?
+            .map(ExpectedBlocks::Hashes),
+    };
+    if expected.is_none() {
+        verbose!("Server has no block hashes, the file is verified only after the download");
+    }
+
+    if offset > 0 {
+        progress!("Resuming download from byte {offset}... ");
+    } else {
+        progress!("Downloading file... ");
+    }
+    let started = Instant::now();
+
+    let file_signature_from_server =
+        match api.pull(&request, &mut part_file, offset, expected.as_ref()) {
+            Ok(file_signature) => file_signature,
+            Err(err) => {
+                // Records what's kept for the next run, errors are less relevant than `err`
+                state.received = part_file.metadata().map_or(0, |metadata| metadata.len());
+                let _ = state.save(&state_name);
+                return Err(err);
+            }
+        };
+
+    progressln!("OK");
+    verbose!("File downloaded in {:?}", started.elapsed());
+
+    progress!("Calculating signature... ");
+    let started = Instant::now();
+    let digest = calc_digest(&mut part_file)?;
+    let digest_mismatch = verify_digest
+        && match &stored.digest {
+            Some(server_digest) => *server_digest != hasher::encode_hex(digest.clone()),
+            None => {
+                verbose!("Server has no digest of the file, checking only the signature");
+                false
+            }
+        };
     let file_signature = signing_key.sign_digest(digest);
 
-    if file_signature != file_signature_from_server {
-        bail!("Signature mismatch");
+    let mismatch = if file_signature != file_signature_from_server {
+        Some("Signature mismatch")
+    } else if digest_mismatch {
+        Some("Digest mismatch, the server stored a valid signature for other contents")
+    } else {
+        None
+    };
+    if let Some(mismatch) = mismatch {
+        drop(part_file);
+        std::fs::remove_file(&part_name)?;
+        std::fs::remove_file(&state_name)?;
+        return Err(VerificationError(mismatch.to_string()).into());
     }
 
-    println!("OK");
-    std::io::stdout().flush().ok();
+    progressln!("OK");
+    verbose!("Signature verified in {:?}", started.elapsed());
+
+    drop(part_file);
+    move_download(&part_name, &new_name)?;
+    std::fs::remove_file(&state_name)?;
+
+    println!("{}", new_name.display());
+
+    Ok(())
+}
+
+/// Downloads the file like [`pull`] without looking up or checking its signature. The download
+/// isn't resumable, since the partial file can't be matched to the stored one.
+fn pull_unverified(
+    filename: &str,
+    download_dir: impl AsRef<Path>,
+    staging_dir: Option<&Path>,
+    overwrite: Overwrite,
+    api: impl Api,
+) -> Result<()> {
+    let filename = &shared::normalize_filename(filename);
+    shared::validate_filename(filename)?;
+    let signing_key = Keyring.get_signing_key()?;
+    let request = SignableRequest::new(
+        METHOD_DOWNLOAD,
+        filename.to_string(),
+        signing_key.verifying_key(),
+    )?;
+    let request = request.sign(&signing_key)?;
+    print_request_details(&request);
 
-    std::fs::create_dir_all(download_dir.as_ref())?;
     let new_name = download_dir.as_ref().join(request.filename());
     assert!(new_name.starts_with(download_dir));
-    temp_file.persist(&new_name)?;
+    std::fs::create_dir_all(new_name.parent().expect("Download path has a parent"))?;
+    if new_name.exists() && !overwrite.allows(&new_name, None)? {
+        progressln!("Skipping {filename}, {} already exists", new_name.display());
+        return Ok(());
+    }
+
+    let part_name = match staging_dir {
+        Some(staging_dir) => staged_partial_path(staging_dir, request.filename(), &new_name),
+        None => partial_path(&new_name),
+    };
+    let mut part_file = File::create(&part_name)?;
+    progress!("Downloading file... ");
+    let started = Instant::now();
+    api.pull_unverified(&request, &mut part_file)?;
+    progressln!("OK");
+    verbose!("File downloaded in {:?}", started.elapsed());
+
+    drop(part_file);
+    move_download(&part_name, &new_name)?;
+    // A resume state left by an earlier pull no longer matches the partial file
+    let state_name = resume_state_path(&part_name);
+    if state_name.exists() {
+        std::fs::remove_file(&state_name)?;
+    }
+
+    eprintln!(
+        "WARNING: {} was saved without verifying its signature, its integrity is unknown",
+        new_name.display()
+    );
+    println!("{}", new_name.display());
 
-    println!("File saved to {:?}", new_name);
+    Ok(())
+}
+
+fn check(filename: &str, keystore: impl KeyStore, api: impl Api) -> Result<()> {
+    let filename = &shared::normalize_filename(filename);
+    shared::validate_filename(filename)?;
+    let signing_key = keystore.get_signing_key()?;
+    let request = SignableRequest::new(
+        METHOD_DOWNLOAD,
+        filename.to_string(),
+        signing_key.verifying_key(),
+    )?;
+    let request = request.sign(&signing_key)?;
+    print_request_details(&request);
+
+    progress!("Downloading and hashing file... ");
+    let started = Instant::now();
+
+    let (digest, file_signature_from_server) = api.pull_digest(&request)?;
+    if signing_key.sign_digest(digest) != file_signature_from_server {
+        progressln!("FAILED");
+        return Err(VerificationError(format!("{filename} doesn't match its signature")).into());
+    }
+
+    progressln!("OK");
+    verbose!("File verified in {:?}", started.elapsed());
+    println!("OK {filename}");
+
+    Ok(())
+}
+
+/// Signs a request to `method` for `filename`, for commands making requests to several methods.
+fn sign_request(method: &str, filename: &str, signing_key: &SigningKey) -> Result<SignedRequest> {
+    SignableRequest::new(method, filename.to_string(), signing_key.verifying_key())?
+        .sign(signing_key)
+}
+
+fn print_request_details(request: &SignedRequest) {
+    verbose!(
+        "Request: method: {}, filename: {}, pubkey: {}, time: {}, signature: {}",
+        request.method(),
+        request.filename(),
+        bs58::encode(request.pubkey()).into_string(),
+        request.time(),
+        bs58::encode(request.signature().to_bytes()).into_string()
+    );
+}
+
+fn parse_pubkey(pubkey_b58: &str) -> Result<VerifyingKey> {
+    Ok(VerifyingKey::try_from(
+        bs58::decode(pubkey_b58).into_vec()?.as_slice(),
+    )?)
+}
+
+/// Parses an RFC 3339 time or a duration before now into seconds since the Unix epoch.
+fn parse_time(time: &str) -> Result<u64> {
+    let time = match humantime::parse_rfc3339_weak(time) {
+        Ok(time) => time,
+        Err(_) => SystemTime::now()
+            .checked_sub(humantime::parse_duration(time)?)
+            .ok_or(anyhow!("Duration is too long: {time}"))?,
+    };
+    Ok(time.duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+/// Parses a digest in the hex format `hash` prints it in.
+fn parse_digest(digest_hex: &str) -> Result<Prehashed> {
+    let bytes = (0..digest_hex.len())
+        .step_by(2)
+        .map(|index| {
+            digest_hex
+                .get(index..index + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+        })
+        .collect::<Option<Vec<u8>>>()
+        .ok_or(anyhow!("Digest must be hex encoded"))?;
+    Prehashed::from_slice(&bytes)
+}
 
+/// Reads the file passphrase given with `--passphrase-file`, the first line of the file. It's
+/// sent in a header, so it has to be printable ASCII.
+fn passphrase_arg(matches: &ArgMatches) -> Result<Option<String>> {
+    let Some(path) = matches.get_one::<String>("passphrase-file") else {
+        return Ok(None);
+    };
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Unable to read passphrase file {path}"))?;
+    let passphrase = contents.lines().next().unwrap_or_default();
+    if passphrase.is_empty() {
+        bail!("Passphrase file {path} starts with an empty line");
+    }
+    if !passphrase.chars().all(|c| c == ' ' || c.is_ascii_graphic()) {
+        bail!("Passphrase must be printable ASCII");
+    }
+    Ok(Some(passphrase.to_string()))
+}
+
+fn parse_signature(signature_b58: &str) -> Result<Signature> {
+    Ok(Signature::from_slice(
+        &bs58::decode(signature_b58).into_vec()?,
+    )?)
+}
+
+/// Partially downloaded files are kept next to the destination so an interrupted `pull` can be
+/// resumed by the next run, along with their [`ResumeState`].
+fn partial_path(path: &Path) -> PathBuf {
+    let mut part_name = OsString::from(path.as_os_str());
+    part_name.push(".part");
+    PathBuf::from(part_name)
+}
+
+/// Partial download path inside `staging_dir`, or next to `destination` when the staging
+/// directory can't be created.
+fn staged_partial_path(staging_dir: &Path, filename: &str, destination: &Path) -> PathBuf {
+    let path = partial_path(&staging_dir.join(filename));
+    let created = match path.parent() {
+        Some(parent) => std::fs::create_dir_all(parent),
+        None => Ok(()),
+    };
+    match created {
+        Ok(()) if path.starts_with(staging_dir) => path,
+        Ok(()) => partial_path(destination),
+        Err(err) => {
+            verbose!(
+                "Staging directory {} unusable, keeping the partial download next to the file: {err}",
+                staging_dir.display()
+            );
+            partial_path(destination)
+        }
+    }
+}
+
+/// Moves a verified download into place. A rename when both paths are on the same filesystem,
+/// otherwise the data is copied next to `destination` first, so the file still appears at once.
+fn move_download(part_name: &Path, destination: &Path) -> Result<()> {
+    if std::fs::rename(part_name, destination).is_ok() {
+        return Ok(());
+    }
+
+    verbose!("Can't rename the download into place, copying it");
+    let copy_name = partial_path(destination);
+    if copy_name != part_name {
+        std::fs::copy(part_name, &copy_name)?;
+        std::fs::remove_file(part_name)?;
+    }
+    std::fs::rename(&copy_name, destination)?;
     Ok(())
 }
 
-fn calc_digest(file: &mut File) -> Result<Hasher> {
+pub fn calc_digest(file: &mut File) -> Result<Hasher> {
     file.seek(SeekFrom::Start(0))?;
     let mut reader = BufReader::new(file);
     let mut hasher = Hasher::default();
@@ -131,35 +1449,400 @@ fn calc_digest(file: &mut File) -> Result<Hasher> {
 
 fn main() {
     let matches = cli().get_matches();
-    let config: Config = serde_json::from_reader(
-        File::open("client_config.json").expect("Unable to open config file"),
-    )
-    .expect("Unable to parse config file");
+    output::set_verbosity(if matches.get_flag("quiet") {
+        Verbosity::Quiet
+    } else if matches.get_flag("verbose") {
+        Verbosity::Verbose
+    } else {
+        Verbosity::Normal
+    });
+
+    if let Err(err) = run(&matches) {
+        eprintln!("Error: {err:#}");
+        std::process::exit(error::exit_code(&err));
+    }
+}
+
+fn run(matches: &ArgMatches) -> Result<()> {
+    let config_path = Path::new(
+        matches
+            .get_one::<String>("config")
+            .expect("Config file has a default"),
+    );
+    if let Some(("init", sub_matches)) = matches.subcommand() {
+        let server_url = sub_matches
+            .get_one::<String>("server-url")
+            .expect("Server URL has a default");
+        let server_url = Url::parse(server_url).context("Invalid server URL")?;
+        let download_dir = sub_matches
+            .get_one::<String>("download-dir")
+            .expect("Download directory has a default");
+        let config = Config::template(server_url, PathBuf::from(download_dir));
+        return init(config_path, &config, sub_matches.get_flag("force"))
+            .context("Failed to write config file");
+    }
+
+    let config_file = File::open(config_path).context(ConfigError("Unable to open config file"))?;
+    let mut config: Config =
+        serde_json::from_reader(config_file).context(ConfigError("Unable to parse config file"))?;
+    config.resolve_paths(config_path.parent().unwrap_or(Path::new("")));
+
+    // Zero falls back to the config like a missing flag
+    let timeout = matches
+        .get_one::<u64>("timeout")
+        .copied()
+        .filter(|&secs| secs > 0)
+        .or(config.timeout_secs)
+        .map(Duration::from_secs);
+    let server_pubkey = config
+        .expected_server_pubkey
+        .as_deref()
+        .map(parse_pubkey)
+        .transpose()
+        .context(ConfigError("Invalid expected_server_pubkey in config file"))?;
+    if config.download_buffer_size == Some(0) {
+        return Err(anyhow!("download_buffer_size must be positive"))
+            .context(ConfigError("Invalid config file"));
+    }
+    let server_url = config.server_url.clone();
+    let api = HttpClient::new(config.server_url, timeout)?
+        .with_server_pubkey(server_pubkey)
+        .with_download_buffer_size(
+            config
+                .download_buffer_size
+                .unwrap_or(api::DEFAULT_DOWNLOAD_BUFFER_SIZE),
+        );
+
+    if matches.subcommand_name() != Some("regenerate-keys")
+        && !Keyring.has_keypair().context("Failed to access keyring")?
+    {
+        return Err(NoKeypairError.into());
+    }
 
     match matches.subcommand() {
-        Some(("regenerate-keys", _)) => {
-            Keyring
-                .regenerate_keypair()
-                .expect("Error during keypair regeneration");
-            println!("New keypair generated successfully!");
-        }
+        Some(("regenerate-keys", sub_matches)) => regenerate_keys(
+            sub_matches.get_flag("yes"),
+            sub_matches.get_one::<String>("backup").map(Path::new),
+            Keyring,
+        )
+        .context("Error during keypair regeneration"),
         Some(("push", sub_matches)) => {
             let path = sub_matches
                 .get_one::<String>("PATH")
                 .expect("Path of file must be provided");
-            let path = PathBuf::from_str(path.as_str()).expect("Unable to parse path");
-            push(path, Keyring, HttpClient::new(config.server_url)).expect("Failed to upload file")
+            let path = PathBuf::from_str(path.as_str()).context("Unable to parse path")?;
+            let remote_name = sub_matches.get_one::<String>("name").map(String::as_str);
+            let remote_name = match sub_matches.get_one::<String>("prefix") {
+                Some(prefix) => Some(prefixed_filename(prefix, &path, remote_name)?),
+                None => remote_name.map(str::to_string),
+            };
+            let chunk_size = sub_matches
+                .get_one::<u64>("chunk-size")
+                .copied()
+                .or(sub_matches
+                    .get_flag("single-pass")
+                    .then_some(DEFAULT_CHUNK_SIZE));
+            let expected_signature = sub_matches
+                .get_one::<String>("if-match")
+                .map(|signature| parse_signature(signature))
+                .transpose()
+                .context("Unable to parse expected signature")?;
+            let digest = sub_matches
+                .get_one::<String>("digest")
+                .map(|digest| parse_digest(digest))
+                .transpose()
+                .context("Unable to parse digest")?;
+            let passphrase = passphrase_arg(sub_matches)?;
+            // Files pushed with a given digest weren't hashed, so their signature isn't cached
+            let cache_path = config.push_cache.as_deref().filter(|_| digest.is_none());
+            let metadata = std::fs::metadata(&path).ok();
+            // The outbox doesn't hold passphrases, so protected files are never queued
+            let outbox_path = config.outbox.as_deref().filter(|_| passphrase.is_none());
+            let api = api.with_file_passphrase(passphrase);
+            let result = push(
+                &path,
+                remote_name.as_deref(),
+                chunk_size,
+                digest.clone(),
+                &PushOptions {
+                    expected_signature,
+                    ..Default::default()
+                },
+                Keyring,
+                api,
+            );
+            let file_signature = match (result, outbox_path) {
+                (Err(err), Some(outbox_path)) if outbox::retryable(&err) => {
+                    let mut outbox = outbox::Outbox::load(outbox_path)?;
+                    outbox
+                        .queue(
+                            &path,
+                            remote_filename(&path, remote_name.as_deref())?,
+                            digest,
+                            chunk_size,
+                            expected_signature,
+                            &Keyring.get_signing_key()?,
+                        )
+                        .context("Failed to queue push")?;
+                    outbox.save().context("Failed to save outbox")?;
+                    progressln!(
+                        "Server unreachable: {err:#}\nPush queued, {} in the outbox. Run `cloud flush` to submit them",
+                        outbox.len()
+                    );
+                    return Ok(());
+                }
+                (result, _) => result.context("Failed to upload file")?,
+            };
+            if let (Some(cache_path), Some(metadata)) = (cache_path, metadata) {
+                let mut cache = PushCache::load(Some(cache_path));
+                let pubkey = Keyring.get_signing_key()?.verifying_key();
+                cache.record(&path, &metadata, &pubkey, &file_signature);
+                cache.save().context("Failed to save push cache")?;
+            }
+            Ok(())
+        }
+        Some(("flush", _)) => {
+            let outbox_path = config
+                .outbox
+                .as_deref()
+                .ok_or(anyhow!("outbox must be set to queue pushes"))
+                .context(ConfigError("Invalid config file"))?;
+            let mut outbox = outbox::Outbox::load(outbox_path)?;
+            let mut cache = PushCache::load(config.push_cache.as_deref());
+            let signing_key = Keyring.get_signing_key()?;
+            outbox::flush(&mut outbox, &mut cache, &signing_key, &api)
+                .context("Failed to flush outbox")
+        }
+        Some(("update-signature", sub_matches)) => {
+            let path = sub_matches
+                .get_one::<String>("PATH")
+                .expect("Path of file must be provided");
+            let expected_signature = sub_matches
+                .get_one::<String>("if-match")
+                .map(|signature| parse_signature(signature))
+                .transpose()
+                .context("Unable to parse expected signature")?;
+            update_signature(
+                path,
+                sub_matches.get_one::<String>("name").map(String::as_str),
+                &PushOptions {
+                    expected_signature,
+                    ..Default::default()
+                },
+                Keyring,
+                api,
+            )
+            .context("Failed to update signature")
+        }
+        Some(("hash", sub_matches)) => {
+            let path = sub_matches
+                .get_one::<String>("PATH")
+                .expect("Path of file must be provided");
+            hash(path, Keyring).context("Failed to hash file")
+        }
+        Some(("checksum", sub_matches)) => {
+            let path = sub_matches
+                .get_one::<String>("PATH")
+                .expect("Path of file must be provided");
+            let expected = sub_matches
+                .get_one::<String>("expect")
+                .expect("Expected signature must be provided");
+            let expected =
+                parse_signature(expected).context("Unable to parse expected signature")?;
+            checksum(path, &expected, Keyring).context("Failed to check file")
         }
         Some(("pull", sub_matches)) => {
             let filename = sub_matches
                 .get_one::<String>("FILENAME")
                 .expect("Filename must be provided");
+            let overwrite = Overwrite::from_arg(
+                sub_matches
+                    .get_one::<String>("overwrite")
+                    .map(String::as_str),
+            );
+            let api = api.with_file_passphrase(passphrase_arg(sub_matches)?);
+            if sub_matches.get_flag("no-verify") {
+                return pull_unverified(
+                    filename,
+                    config.download_dir,
+                    config.staging_dir.as_deref(),
+                    overwrite,
+                    api,
+                )
+                .context("Failed to download file");
+            }
             pull(
                 filename,
                 config.download_dir,
-                HttpClient::new(config.server_url),
+                config.staging_dir.as_deref(),
+                overwrite,
+                config.verify_digest,
+                api,
             )
-            .expect("Filed to download file")
+            .context("Failed to download file")
+        }
+        Some(("check", sub_matches)) => {
+            let filename = sub_matches
+                .get_one::<String>("FILENAME")
+                .expect("Filename must be provided");
+            let api = api.with_file_passphrase(passphrase_arg(sub_matches)?);
+            check(filename, Keyring, api).context("Failed to check file")
+        }
+        Some(("list", sub_matches)) => {
+            let since = sub_matches
+                .get_one::<String>("since")
+                .map(|since| parse_time(since))
+                .transpose()
+                .context("Unable to parse --since")?;
+            list(since, Keyring, api).context("Failed to list files")
+        }
+        Some(("prune", sub_matches)) => {
+            let before = sub_matches
+                .get_one::<String>("before")
+                .expect("Cutoff time must be provided");
+            let before = parse_time(before).context("Unable to parse --before")?;
+            prune(before, Keyring, api).context("Failed to prune files")
+        }
+        Some(("set-server", sub_matches)) => {
+            let server_url = sub_matches
+                .get_one::<String>("URL")
+                .expect("Server URL must be provided");
+            let server_url = Url::parse(server_url).context("Invalid server URL")?;
+            let new_api =
+                HttpClient::new(server_url.clone(), timeout)?.with_server_pubkey(server_pubkey);
+            let mut cache = PushCache::load(config.push_cache.as_deref());
+            set_server(config_path, &server_url, &mut cache, Keyring, new_api)
+                .context("Failed to change server URL")
+        }
+        Some(("admin", sub_matches)) => match sub_matches.subcommand() {
+            Some(("users", _)) => admin_users(Keyring, api).context("Failed to list users"),
+            Some(("trim", _)) => admin_trim(Keyring, api).context("Failed to trim storage"),
+            Some((cmd, _)) => unimplemented!("admin {cmd}"),
+            None => unreachable!(),
+        },
+        Some((command @ ("rename" | "copy"), sub_matches)) => {
+            let from = sub_matches
+                .get_one::<String>("FROM")
+                .expect("Source filename must be provided");
+            let to = sub_matches
+                .get_one::<String>("TO")
+                .expect("Destination filename must be provided");
+            transfer(from, to, command == "copy", Keyring, api)
+                .with_context(|| format!("Failed to {command} file"))
+        }
+        Some(("delete", sub_matches)) => {
+            let filename = sub_matches
+                .get_one::<String>("FILENAME")
+                .expect("Filename must be provided");
+            delete(filename, Keyring, api).context("Failed to delete file")
+        }
+        Some(("bench", sub_matches)) => {
+            let size = *sub_matches
+                .get_one::<u64>("size")
+                .expect("Size has a default");
+            let rounds = *sub_matches
+                .get_one::<u32>("rounds")
+                .expect("Rounds have a default");
+            let signing_key = Keyring.get_signing_key()?;
+            bench::bench(&server_url, size, rounds, &signing_key, &api)
+                .context("Failed to benchmark server")
+        }
+        Some(("share", sub_matches)) => {
+            let filename = sub_matches
+                .get_one::<String>("FILENAME")
+                .expect("Filename must be provided");
+            let expires_in = sub_matches
+                .get_one::<String>("expires-in")
+                .map(|expires_in| humantime::parse_duration(expires_in))
+                .transpose()
+                .context("Unable to parse --expires-in")?;
+            let max_downloads = sub_matches.get_one::<u64>("max-downloads").copied();
+            share(
+                filename,
+                expires_in,
+                max_downloads,
+                &server_url,
+                Keyring,
+                api,
+            )
+            .context("Failed to share file")
+        }
+        Some(("shares", sub_matches)) => match sub_matches.subcommand() {
+            Some(("list", _)) => list_shares(Keyring, api).context("Failed to list share links"),
+            Some(("revoke", sub_matches)) => {
+                let token = sub_matches
+                    .get_one::<String>("TOKEN")
+                    .expect("Token must be provided");
+                revoke_share(token, Keyring, api).context("Failed to revoke share link")
+            }
+            Some((cmd, _)) => unimplemented!("shares {cmd}"),
+            None => unreachable!(),
+        },
+        Some(("batch", sub_matches)) => {
+            let paths = sub_matches
+                .get_many::<String>("PATH")
+                .unwrap_or_default()
+                .map(PathBuf::from)
+                .collect::<Vec<_>>();
+            let deletes = sub_matches
+                .get_many::<String>("delete")
+                .unwrap_or_default()
+                .cloned()
+                .collect::<Vec<_>>();
+            let chunk_size = sub_matches
+                .get_one::<u64>("chunk-size")
+                .copied()
+                .unwrap_or(DEFAULT_CHUNK_SIZE);
+            let mut cache = PushCache::load(config.push_cache.as_deref());
+            batch(&paths, &deletes, chunk_size, &mut cache, Keyring, api)
+                .context("Failed to apply batch")
+        }
+        Some(("rekey", sub_matches)) => {
+            let old_key_path = sub_matches
+                .get_one::<String>("old-key")
+                .expect("Old key file must be provided");
+            let old_key =
+                keystore::read_key_file(old_key_path).context("Failed to read old key")?;
+            let new_key = Keyring.get_signing_key()?;
+            let temp_path =
+                std::env::temp_dir().join(format!("cloud-rekey-{}.tmp", std::process::id()));
+            let all_moved = rekey::rekey(&old_key, &new_key, &temp_path, &api)
+                .context("Failed to move files")?;
+            if !all_moved {
+                bail!("Some files couldn't be moved");
+            }
+            Ok(())
+        }
+        Some(("scan", sub_matches)) => {
+            let dir = sub_matches
+                .get_one::<String>("DIR")
+                .expect("Directory must be provided");
+            let jobs = *sub_matches
+                .get_one::<usize>("jobs")
+                .expect("Number of jobs must be provided");
+            let signing_key = Keyring.get_signing_key()?;
+            let follow_symlinks = sub_matches.get_flag("follow-symlinks");
+            let mut cache = PushCache::load(config.push_cache.as_deref());
+            let all_match = scan::scan(dir, follow_symlinks, jobs, &mut cache, &signing_key, &api)
+                .context("Failed to scan directory")?;
+            if !all_match {
+                return Err(VerificationError(
+                    "Some files don't match the ones in private cloud".to_string(),
+                )
+                .into());
+            }
+            Ok(())
+        }
+        Some(("diff", sub_matches)) => {
+            let dir = sub_matches
+                .get_one::<String>("DIR")
+                .expect("Directory must be provided");
+            let signing_key = Keyring.get_signing_key()?;
+            let follow_symlinks = sub_matches.get_flag("follow-symlinks");
+            let mut cache = PushCache::load(config.push_cache.as_deref());
+            diff::diff(dir, follow_symlinks, &mut cache, &signing_key, &api)
+                .context("Failed to compare directory")?
+                .print(sub_matches.get_flag("json"))
         }
         Some((cmd, _)) => unimplemented!("{cmd}"),
         None => unreachable!(),
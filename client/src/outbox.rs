@@ -0,0 +1,222 @@
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{BufWriter, ErrorKind, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use ed25519_dalek::ed25519::signature::digest::FixedOutput;
+use ed25519_dalek::{DigestSigner, Signature, SigningKey};
+use serde_derive::{Deserialize, Serialize};
+
+use shared::consts::METHOD_UPLOAD;
+use shared::hasher::Prehashed;
+use shared::{SignableRequest, SignedRequest};
+
+use crate::api::{Api, PushOptions};
+use crate::cache::PushCache;
+use crate::error::{ServerError, VerificationError};
+use crate::output::{progressln, verbose};
+use crate::{calc_digest, push_prehashed, PrehashedBody};
+
+/// Pushes that failed because the server couldn't be reached, saved so `flush` can submit them
+/// once it's back.
+#[derive(Default, Serialize, Deserialize)]
+pub struct Outbox {
+    pushes: Vec<QueuedPush>,
+    /// Where the outbox is saved.
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+#[derive(Serialize, Deserialize)]
+struct QueuedPush {
+    /// Request signed when the push was queued, recording the filename, the keypair and when. It's
+    /// stale by the time the push is submitted, so a fresh one is signed then.
+    request: SignedRequest,
+    /// Absolute path of the local file.
+    path: PathBuf,
+    /// Base58 signature of the file when the push was queued. A file changed since isn't pushed.
+    file_signature: String,
+    /// Base58 signature the stored file must have for the push to replace it.
+    #[serde(default)]
+    expected_signature: Option<String>,
+    #[serde(default)]
+    chunk_size: Option<u64>,
+}
+
+impl Outbox {
+    /// Reads the outbox saved at `path`, empty if there's none. Unlike the push cache, an
+    /// unreadable outbox is an error, so queued pushes aren't silently dropped.
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut outbox = match File::open(path) {
+            Ok(file) => serde_json::from_reader::<_, Self>(file)
+                .with_context(|| format!("Unable to parse outbox {}", path.display()))?,
+            Err(err) if err.kind() == ErrorKind::NotFound => Self::default(),
+            Err(err) => return Err(err.into()),
+        };
+        outbox.path = path.to_path_buf();
+        Ok(outbox)
+    }
+
+    /// Queues a push of the file at `path` under `filename`. `digest` is the one given to `push`,
+    /// the file is hashed otherwise.
+    pub fn queue(
+        &mut self,
+        path: &Path,
+        filename: String,
+        digest: Option<Prehashed>,
+        chunk_size: Option<u64>,
+        expected_signature: Option<Signature>,
+        signing_key: &SigningKey,
+    ) -> Result<()> {
+        let file_signature = match digest {
+            Some(digest) => signing_key.sign_digest(digest),
+            None => signing_key.sign_digest(calc_digest(&mut File::open(path)?)?),
+        };
+        let request = SignableRequest::new(METHOD_UPLOAD, filename, signing_key.verifying_key())?;
+        self.pushes.push(QueuedPush {
+            request: request.sign(signing_key)?,
+            path: std::fs::canonicalize(path)?,
+            file_signature: encode_signature(&file_signature),
+            expected_signature: expected_signature.as_ref().map(encode_signature),
+            chunk_size,
+        });
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.pushes.len()
+    }
+
+    /// Writes the outbox back, replacing the previous file atomically.
+    pub fn save(&self) -> Result<()> {
+        let mut temp_path = OsString::from(self.path.as_os_str());
+        temp_path.push(".tmp");
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.into_inner()?.sync_all()?;
+        std::fs::rename(&temp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+/// Submits the queued pushes in the order they were queued, recording the pushed files in
+/// `cache`. Stops at the first push failing because the server is unreachable or erroring,
+/// leaving it and the ones after it queued. Pushes the server rejects, and the ones of files
+/// changed or removed since, are dropped from the outbox and reported.
+pub fn flush(
+    outbox: &mut Outbox,
+    cache: &mut PushCache,
+    signing_key: &SigningKey,
+    api: &impl Api,
+) -> Result<()> {
+    if outbox.pushes.is_empty() {
+        progressln!("Outbox is empty");
+        return Ok(());
+    }
+
+    let mut dropped = 0;
+    while let Some(queued) = outbox.pushes.first() {
+        let filename = queued.request.filename().to_string();
+        match submit(queued, cache, signing_key, api) {
+            Ok(()) => println!("{filename}"),
+            Err(err) if retryable(&err) => {
+                // Keep the files pushed before the failure from being pushed again
+                cache.save().context("Failed to save push cache")?;
+                return Err(err.context(format!(
+                    "Failed to push {filename}, {} pushes left in the outbox",
+                    outbox.pushes.len()
+                )));
+            }
+            Err(err) => {
+                progressln!("Dropping the push of {filename} from the outbox: {err:#}");
+                dropped += 1;
+            }
+        }
+        outbox.pushes.remove(0);
+        outbox.save()?;
+    }
+    cache.save().context("Failed to save push cache")?;
+
+    if dropped > 0 {
+        bail!("{dropped} queued pushes failed, push the files again");
+    }
+    Ok(())
+}
+
+/// Pushes the file of `queued` with a freshly signed request, as long as it still has the
+/// signature it was queued with.
+fn submit(
+    queued: &QueuedPush,
+    cache: &mut PushCache,
+    signing_key: &SigningKey,
+    api: &impl Api,
+) -> Result<()> {
+    if *queued.request.pubkey() != signing_key.verifying_key() {
+        bail!("The push was queued with another keypair");
+    }
+    verbose!(
+        "Push of {} queued at {}",
+        queued.path.display(),
+        queued.request.time()
+    );
+
+    let mut file = File::open(&queued.path)?;
+    let metadata = file.metadata()?;
+    let digest = calc_digest(&mut file)?;
+    let file_signature = signing_key.sign_digest(digest.clone());
+    if encode_signature(&file_signature) != queued.file_signature {
+        return Err(VerificationError(format!(
+            "{} changed since the push was queued",
+            queued.path.display()
+        ))
+        .into());
+    }
+    file.seek(SeekFrom::Start(0))?;
+
+    let expected_signature = queued
+        .expected_signature
+        .as_deref()
+        .map(|signature| {
+            let bytes = bs58::decode(signature).into_vec()?;
+            Signature::from_slice(&bytes).map_err(|err| anyhow!(err))
+        })
+        .transpose()
+        .context("Invalid expected signature in outbox")?;
+    let body = PrehashedBody {
+        reader: file,
+        size: metadata.len(),
+        digest: Prehashed::from_slice(&digest.finalize_fixed())?,
+    };
+    push_prehashed(
+        queued.request.filename(),
+        body,
+        queued.chunk_size,
+        &PushOptions {
+            expected_signature,
+            ..Default::default()
+        },
+        signing_key,
+        api,
+    )?;
+    cache.record(
+        &queued.path,
+        &metadata,
+        &signing_key.verifying_key(),
+        &file_signature,
+    );
+    Ok(())
+}
+
+/// Whether the push may succeed as is later, because the server couldn't be reached or failed
+/// rather than rejecting it.
+pub fn retryable(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<reqwest::Error>().is_some()
+        || err
+            .downcast_ref::<ServerError>()
+            .is_some_and(|err| err.status().is_server_error())
+}
+
+fn encode_signature(signature: &Signature) -> String {
+    bs58::encode(signature.to_bytes()).into_string()
+}
@@ -0,0 +1,54 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// Controls how much diagnostic output goes to stderr. Command results always go to stdout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+
+pub fn set_verbosity(verbosity: Verbosity) {
+    VERBOSITY.store(verbosity as u8, Ordering::Relaxed);
+}
+
+pub fn verbosity() -> Verbosity {
+    match VERBOSITY.load(Ordering::Relaxed) {
+        0 => Verbosity::Quiet,
+        1 => Verbosity::Normal,
+        _ => Verbosity::Verbose,
+    }
+}
+
+/// Prints progress to stderr without a newline, unless `--quiet` is set.
+macro_rules! progress {
+    ($($arg:tt)*) => {
+        if $crate::output::verbosity() >= $crate::output::Verbosity::Normal {
+            eprint!($($arg)*);
+            std::io::Write::flush(&mut std::io::stderr()).ok();
+        }
+    };
+}
+
+/// Prints a line of progress to stderr, unless `--quiet` is set.
+macro_rules! progressln {
+    ($($arg:tt)*) => {
+        if $crate::output::verbosity() >= $crate::output::Verbosity::Normal {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+/// Prints a line of details to stderr, only when `--verbose` is set.
+macro_rules! verbose {
+    ($($arg:tt)*) => {
+        if $crate::output::verbosity() >= $crate::output::Verbosity::Verbose {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+pub(crate) use {progress, progressln, verbose};
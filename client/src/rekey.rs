@@ -0,0 +1,111 @@
+use std::fs::File;
+use std::io::{Seek, SeekFrom};
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use ed25519_dalek::ed25519::signature::digest::FixedOutput;
+use ed25519_dalek::{DigestSigner, SigningKey};
+
+use shared::consts::*;
+use shared::{SignableRequest, SignedRequest};
+
+use crate::api::{Api, PushOptions};
+use crate::calc_digest;
+use crate::output::{progress, progressln};
+
+/// Moves every file stored under `old_key` to `new_key`. Each file is downloaded into `temp_path`,
+/// verified, re-signed and uploaded under the new pubkey, and the old copy is only deleted once
+/// the server reports the new signature. Returns `true` when all files were moved.
+pub fn rekey(
+    old_key: &SigningKey,
+    new_key: &SigningKey,
+    temp_path: &Path,
+    api: &impl Api,
+) -> Result<bool> {
+    // Moving files onto the same pubkey would delete them right after the no-op upload
+    if old_key.verifying_key() == new_key.verifying_key() {
+        bail!("The old key is the same as the current one");
+    }
+
+    let files = api.list(&sign(METHOD_LIST, String::new(), old_key)?, None)?;
+    progressln!("Moving {} files to the new keypair", files.len());
+
+    let mut errors = 0;
+    for file in &files {
+        progress!("{}... ", file.filename);
+        match rekey_file(&file.filename, old_key, new_key, temp_path, api) {
+            Ok(()) => progressln!("OK"),
+            Err(err) => {
+                progressln!("FAILED");
+                errors += 1;
+                println!("ERROR {}: {err}", file.filename);
+            }
+        }
+    }
+    std::fs::remove_file(temp_path).ok();
+
+    println!(
+        "Moved {} of {} files, {errors} errors",
+        files.len() - errors,
+        files.len()
+    );
+
+    Ok(errors == 0)
+}
+
+fn rekey_file(
+    filename: &str,
+    old_key: &SigningKey,
+    new_key: &SigningKey,
+    temp_path: &Path,
+    api: &impl Api,
+) -> Result<()> {
+    let mut file = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(temp_path)?;
+
+    let old_signature = api.pull(
+        &sign(METHOD_DOWNLOAD, filename.to_string(), old_key)?,
+        &mut file,
+        0,
+        None,
+    )?;
+    let digest = calc_digest(&mut file)?;
+    if old_key.sign_digest(digest.clone()) != old_signature {
+        bail!("Signature mismatch");
+    }
+    let new_signature = new_key.sign_digest(digest.clone());
+
+    // A previous interrupted run may have uploaded the file already, anything else is kept intact
+    match api.signature(&sign(METHOD_SIGNATURE, filename.to_string(), new_key)?)? {
+        Some(stored) if stored.signature == new_signature => {}
+        Some(_) => bail!("A different file with this name exists under the new pubkey"),
+        None => {
+            file.seek(SeekFrom::Start(0))?;
+            let size = file.metadata()?.len();
+            api.push(
+                &sign(METHOD_UPLOAD, filename.to_string(), new_key)?,
+                &new_signature,
+                file,
+                size,
+                &PushOptions {
+                    file_digest: Some(digest.finalize_fixed().to_vec()),
+                    ..Default::default()
+                },
+            )?;
+            let stored = api.signature(&sign(METHOD_SIGNATURE, filename.to_string(), new_key)?)?;
+            if stored.map(|stored| stored.signature) != Some(new_signature) {
+                bail!("Uploaded file doesn't match");
+            }
+        }
+    }
+
+    api.delete(&sign(METHOD_DELETE, filename.to_string(), old_key)?)
+}
+
+fn sign(method: &str, filename: String, signing_key: &SigningKey) -> Result<SignedRequest> {
+    SignableRequest::new(method, filename, signing_key.verifying_key())?.sign(signing_key)
+}
@@ -0,0 +1,45 @@
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use serde_derive::{Deserialize, Serialize};
+
+/// Saved next to a `.part` file, so a later `pull` only resumes the partial download if the file
+/// on the server is still the one it came from.
+#[derive(Serialize, Deserialize)]
+pub struct ResumeState {
+    /// Base58 signature of the file stored on the server when the download started.
+    pub signature: String,
+    /// Bytes of the file received when the state was saved.
+    pub received: u64,
+}
+
+impl ResumeState {
+    /// Reads the state saved for the partial download, `None` if there's none or it's unreadable.
+    pub fn load(path: &Path) -> Option<Self> {
+        let file = File::open(path).ok()?;
+        serde_json::from_reader(file).ok()
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// The state of the partial download at `part_path` is kept in a `.json` file next to it.
+pub fn resume_state_path(part_path: &Path) -> PathBuf {
+    let mut state_name = OsString::from(part_path.as_os_str());
+    state_name.push(".json");
+    PathBuf::from(state_name)
+}
+
+/// Whether `path` is a partial download or its resume state rather than a file of the user.
+pub fn is_partial_download(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    name.ends_with(".part") || name.ends_with(".part.json")
+}
@@ -0,0 +1,228 @@
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use anyhow::{anyhow, bail, Result};
+use ed25519_dalek::{DigestSigner, Signature, SigningKey};
+
+use shared::consts::METHOD_SIGNATURES;
+use shared::listing::signed_body_name;
+use shared::SignableRequest;
+
+use crate::api::Api;
+use crate::cache::PushCache;
+use crate::calc_digest;
+use crate::output::{progressln, verbose};
+use crate::resume::is_partial_download;
+
+/// Filenames looked up per `signatures` request.
+const SIGNATURES_BATCH: usize = 1000;
+
+enum ScanResult {
+    Ok,
+    Mismatch,
+    MissingOnServer,
+    Error(anyhow::Error),
+}
+
+/// Compares every file in `dir` against the signature stored on the server, using `jobs` parallel
+/// workers to hash them. The signatures are fetched beforehand in batches. Files with a signature
+/// in `cache` aren't hashed, and the ones found to match are added to it. Returns `true` when all
+/// files match.
+pub fn scan(
+    dir: impl AsRef<Path>,
+    follow_symlinks: bool,
+    jobs: usize,
+    cache: &mut PushCache,
+    signing_key: &SigningKey,
+    api: &(impl Api + Sync),
+) -> Result<bool> {
+    let paths = local_files(dir, follow_symlinks)?;
+
+    progressln!("Scanning {} files using {jobs} workers", paths.len());
+
+    let filenames = paths
+        .iter()
+        .map(|path| filename(path))
+        .collect::<Result<Vec<_>>>()?;
+    let server_signatures = fetch_signatures(&filenames, signing_key, api)?;
+    let pubkey = signing_key.verifying_key();
+    let cached = paths
+        .iter()
+        .map(|path| {
+            let metadata = std::fs::metadata(path).ok();
+            (metadata, cache.signature(path, &pubkey))
+        })
+        .collect::<Vec<_>>();
+
+    let next_index = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::with_capacity(paths.len()));
+
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.max(1) {
+            scope.spawn(|| loop {
+                let index = next_index.fetch_add(1, Ordering::Relaxed);
+                let Some(path) = paths.get(index) else {
+                    break;
+                };
+                let result = scan_file(
+                    path,
+                    &filenames[index],
+                    server_signatures[index],
+                    cached[index].1,
+                    signing_key,
+                )
+                .unwrap_or_else(ScanResult::Error);
+                results.lock().unwrap().push((index, result));
+            });
+        }
+    });
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(index, _)| *index);
+
+    let (mut ok, mut mismatched, mut missing, mut errors) = (0, 0, 0, 0);
+    for (index, result) in results {
+        let path = paths[index].display();
+        match result {
+            ScanResult::Ok => {
+                ok += 1;
+                verbose!("OK {path}");
+                if let ((Some(metadata), None), Some(signature)) =
+                    (&cached[index], server_signatures[index])
+                {
+                    cache.record(&paths[index], metadata, &pubkey, &signature);
+                }
+            }
+            ScanResult::Mismatch => {
+                mismatched += 1;
+                println!("MISMATCH {path}");
+            }
+            ScanResult::MissingOnServer => {
+                missing += 1;
+                println!("MISSING {path}");
+            }
+            ScanResult::Error(err) => {
+                errors += 1;
+                println!("ERROR {path}: {err}");
+            }
+        }
+    }
+
+    println!(
+        "Scanned {} files: {ok} ok, {mismatched} mismatched, {missing} missing on server, {errors} errors",
+        paths.len()
+    );
+    cache.save()?;
+
+    Ok(mismatched == 0 && missing == 0 && errors == 0)
+}
+
+/// Lists the files directly in `dir` in name order, leaving out partial downloads. Symlinks are
+/// skipped unless `follow_symlinks` is set, in which case the files they point to are listed under
+/// the name of the link, even when they are outside `dir`. Broken and looping links are skipped.
+pub fn local_files(dir: impl AsRef<Path>, follow_symlinks: bool) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        let is_file = if file_type.is_symlink() && follow_symlinks {
+            match std::fs::metadata(&path) {
+                Ok(metadata) => metadata.is_file(),
+                Err(err) => {
+                    verbose!("Skipping symlink {}: {err}", path.display());
+                    false
+                }
+            }
+        } else {
+            file_type.is_file()
+        };
+        if is_file && !is_partial_download(&path) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Looks up the stored signatures of the files in batches. Invalid filenames aren't looked up and
+/// get `None`, like files missing on the server.
+pub fn fetch_signatures(
+    filenames: &[String],
+    signing_key: &SigningKey,
+    api: &impl Api,
+) -> Result<Vec<Option<Signature>>> {
+    let mut signatures = vec![None; filenames.len()];
+    let valid = (0..filenames.len())
+        .filter(|&index| shared::validate_filename(&filenames[index]).is_ok())
+        .collect::<Vec<_>>();
+
+    for batch in valid.chunks(SIGNATURES_BATCH) {
+        let batch_filenames = batch
+            .iter()
+            .map(|&index| &filenames[index])
+            .collect::<Vec<_>>();
+        let body = serde_json::to_vec(&batch_filenames)?;
+        let request = SignableRequest::new(
+            METHOD_SIGNATURES,
+            signed_body_name(&body),
+            signing_key.verifying_key(),
+        )?
+        .sign(signing_key)?;
+        let entries = api.signatures(&request, body)?;
+        if entries.len() != batch.len() {
+            bail!(
+                "Server returned {} signatures for {} files",
+                entries.len(),
+                batch.len()
+            );
+        }
+        for (&index, entry) in batch.iter().zip(entries) {
+            signatures[index] = entry
+                .signature
+                .map(|signature| {
+                    Signature::from_slice(&bs58::decode(signature).into_vec()?)
+                        .map_err(anyhow::Error::from)
+                })
+                .transpose()?;
+        }
+        verbose!("Fetched {} signatures", batch.len());
+    }
+
+    Ok(signatures)
+}
+
+/// Remote filename of a local file.
+pub fn filename(path: &Path) -> Result<String> {
+    Ok(shared::normalize_filename(
+        &path
+            .file_name()
+            .ok_or(anyhow!("Filename not found in the path"))?
+            .to_string_lossy(),
+    ))
+}
+
+fn scan_file(
+    path: &Path,
+    filename: &str,
+    server_signature: Option<Signature>,
+    cached_signature: Option<Signature>,
+    signing_key: &SigningKey,
+) -> Result<ScanResult> {
+    shared::validate_filename(filename)?;
+    let Some(server_signature) = server_signature else {
+        return Ok(ScanResult::MissingOnServer);
+    };
+
+    let signature = match cached_signature {
+        Some(signature) => signature,
+        None => signing_key.sign_digest(calc_digest(&mut File::open(path)?)?),
+    };
+    if signature == server_signature {
+        Ok(ScanResult::Ok)
+    } else {
+        Ok(ScanResult::Mismatch)
+    }
+}
@@ -0,0 +1,177 @@
+use std::fs::File;
+use std::io::{BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+
+use anyhow::{bail, Result};
+
+use shared::chunks::{BlockHasher, BlockHashes};
+use shared::outboard::{Outboard, OutboardHasher};
+
+use crate::error::VerificationError;
+
+/// What the blocks of a download are checked against.
+#[derive(Debug, Clone)]
+pub enum ExpectedBlocks {
+    /// Block hashes, only as trustworthy as the server reporting them.
+    Hashes(BlockHashes),
+    /// Chaining values already checked against the file signature.
+    Outboard(Outboard),
+}
+
+impl ExpectedBlocks {
+    fn block_size(&self) -> u64 {
+        match self {
+            Self::Hashes(block_hashes) => block_hashes.block_size,
+            Self::Outboard(outboard) => outboard.group_size,
+        }
+    }
+
+    fn hashes(&self) -> &[String] {
+        match self {
+            Self::Hashes(block_hashes) => &block_hashes.hashes,
+            Self::Outboard(outboard) => &outboard.chaining_values,
+        }
+    }
+
+    /// Hasher of the blocks starting at block `first_block`, the block size must not be zero.
+    fn hasher(&self, first_block: u64) -> Result<BlockHashing> {
+        Ok(match self {
+            Self::Hashes(block_hashes) => {
+                BlockHashing::Hashes(BlockHasher::new(block_hashes.block_size))
+            }
+            Self::Outboard(outboard) => {
+                outboard.check_group_size()?;
+                BlockHashing::Outboard(OutboardHasher::new(outboard.group_size, first_block))
+            }
+        })
+    }
+}
+
+enum BlockHashing {
+    Hashes(BlockHasher),
+    Outboard(OutboardHasher),
+}
+
+impl BlockHashing {
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Hashes(hasher) => hasher.update(data),
+            Self::Outboard(hasher) => hasher.update(data),
+        }
+    }
+
+    fn hashes(&self) -> &[String] {
+        match self {
+            Self::Hashes(hasher) => hasher.hashes(),
+            Self::Outboard(hasher) => hasher.hashes(),
+        }
+    }
+
+    fn finish(self) -> Vec<String> {
+        match self {
+            Self::Hashes(hasher) => hasher.finish().hashes,
+            Self::Outboard(hasher) => hasher.finish().chaining_values,
+        }
+    }
+}
+
+/// Writes downloaded data to the file while checking every completed block against the hashes
+/// or chaining values stored on the server, failing at the first mismatching block.
+pub struct BlockVerifier<'a> {
+    writer: BufWriter<&'a mut File>,
+    expected: &'a ExpectedBlocks,
+    block_hasher: BlockHashing,
+    first_block: usize,
+    checked: usize,
+}
+
+impl<'a> BlockVerifier<'a> {
+    /// Starts verifying at `offset`, where the download continues. The part of its block already
+    /// in `file` is read back and hashed first.
+    pub fn new(file: &'a mut File, expected: &'a ExpectedBlocks, offset: u64) -> Result<Self> {
+        let block_size = expected.block_size();
+        if block_size == 0 {
+            bail!("Invalid block size");
+        }
+        let block_start = offset - offset % block_size;
+        let mut block_hasher = expected.hasher(offset / block_size)?;
+        if offset > block_start {
+            let mut head = vec![0; (offset - block_start) as usize];
+            file.seek(SeekFrom::Start(block_start))?;
+            file.read_exact(&mut head)?;
+            block_hasher.update(&head);
+        }
+        file.seek(SeekFrom::Start(offset))?;
+
+        Ok(Self {
+            writer: BufWriter::new(file),
+            expected,
+            block_hasher,
+            first_block: (offset / block_size) as usize,
+            checked: 0,
+        })
+    }
+
+    /// Length of the file prefix made of verified blocks.
+    pub fn verified_len(&self) -> u64 {
+        (self.first_block + self.checked) as u64 * self.expected.block_size()
+    }
+
+    /// Checks the last partial block and that no blocks are missing.
+    pub fn finish(mut self) -> Result<()> {
+        self.writer.flush()?;
+        let hashes = self.block_hasher.finish();
+        check_blocks(self.expected, self.first_block, &hashes, &mut self.checked)?;
+
+        let blocks = self.first_block + hashes.len();
+        if blocks != self.expected.hashes().len() {
+            return Err(VerificationError(format!(
+                "Downloaded {blocks} blocks, expected {}",
+                self.expected.hashes().len()
+            ))
+            .into());
+        }
+        Ok(())
+    }
+}
+
+impl Write for BlockVerifier<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let size = self.writer.write(buf)?;
+        self.block_hasher.update(&buf[..size]);
+
+        check_blocks(
+            self.expected,
+            self.first_block,
+            self.block_hasher.hashes(),
+            &mut self.checked,
+        )
+        .map_err(|err| std::io::Error::new(ErrorKind::InvalidData, err))?;
+
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Compares the not yet `checked` of `hashes`, which start at block `first_block`, with the
+/// expected ones, counting every matching block in `checked`.
+fn check_blocks(
+    expected: &ExpectedBlocks,
+    first_block: usize,
+    hashes: &[String],
+    checked: &mut usize,
+) -> Result<()> {
+    for hash in &hashes[*checked..] {
+        let block = first_block + *checked;
+        if expected.hashes().get(block) != Some(hash) {
+            return Err(VerificationError(format!(
+                "Block {block} doesn't match the stored hash, download aborted"
+            ))
+            .into());
+        }
+        *checked += 1;
+    }
+    Ok(())
+}
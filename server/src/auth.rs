@@ -0,0 +1,111 @@
+use std::future::Future;
+use std::time::Duration;
+
+use anyhow::Result;
+use ed25519_dalek::VerifyingKey;
+use log::{error, info};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use warp::http::StatusCode;
+
+use crate::error::HttpError;
+use crate::CONFIG;
+
+const AUTHORIZATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Decides whether a pubkey may call a method. Called after the request signature is verified and
+/// before anything is read from or written to the storage.
+pub trait Authorizer {
+    /// Fails with 403 to deny the request. `filename` is empty for methods not addressing a file.
+    fn authorize(
+        &self,
+        pubkey: &VerifyingKey,
+        method: &str,
+        filename: &str,
+    ) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// Allows every request with a valid signature, the default.
+pub struct AllowAll;
+
+impl Authorizer for AllowAll {
+    async fn authorize(
+        &self,
+        _pubkey: &VerifyingKey,
+        _method: &str,
+        _filename: &str,
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Asks an external service by POSTing the request details as JSON to `authorization_url`. Any
+/// 2xx response allows the request, everything else including an unreachable service denies it.
+pub struct HttpAuthorizer {
+    client: reqwest::Client,
+    url: String,
+}
+
+#[derive(Serialize)]
+struct AuthorizationRequest<'a> {
+    pubkey: String,
+    method: &'a str,
+    filename: &'a str,
+}
+
+impl HttpAuthorizer {
+    pub fn new(url: String) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(AUTHORIZATION_TIMEOUT)
+            .build()?;
+        Ok(Self { client, url })
+    }
+}
+
+impl Authorizer for HttpAuthorizer {
+    async fn authorize(&self, pubkey: &VerifyingKey, method: &str, filename: &str) -> Result<()> {
+        let request = AuthorizationRequest {
+            pubkey: bs58::encode(pubkey.as_bytes()).into_string(),
+            method,
+            filename,
+        };
+
+        match self.client.post(&self.url).json(&request).send().await {
+            Ok(response) if response.status().is_success() => Ok(()),
+            Ok(response) => {
+                info!("Authorization denied with status {}", response.status());
+                Err(HttpError::new(StatusCode::FORBIDDEN, "Request denied").into())
+            }
+            Err(err) => {
+                error!("Authorization service error: {err}");
+                Err(HttpError::new(
+                    StatusCode::FORBIDDEN,
+                    "Request denied: authorization service unavailable",
+                )
+                .into())
+            }
+        }
+    }
+}
+
+/// The authorizer selected by the server config.
+pub enum ConfiguredAuthorizer {
+    AllowAll(AllowAll),
+    Http(HttpAuthorizer),
+}
+
+impl Authorizer for ConfiguredAuthorizer {
+    async fn authorize(&self, pubkey: &VerifyingKey, method: &str, filename: &str) -> Result<()> {
+        match self {
+            Self::AllowAll(authorizer) => authorizer.authorize(pubkey, method, filename).await,
+            Self::Http(authorizer) => authorizer.authorize(pubkey, method, filename).await,
+        }
+    }
+}
+
+pub static AUTHORIZER: Lazy<ConfiguredAuthorizer> = Lazy::new(|| match &CONFIG.authorization_url {
+    Some(url) => ConfiguredAuthorizer::Http(
+        HttpAuthorizer::new(url.clone()).expect("Failed to create authorization client"),
+    ),
+    None => ConfiguredAuthorizer::AllowAll(AllowAll),
+});
@@ -0,0 +1,572 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use ed25519_dalek::{SecretKey, SigningKey, VerifyingKey, PUBLIC_KEY_LENGTH};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+const CONFIG_FILE: &str = "server_config.json";
+
+/// Environment variable naming the config file to load instead of `server_config.json` in the
+/// working directory. Not a config field override, despite the prefix.
+const CONFIG_FILE_VAR: &str = "CLOUD_SERVER_CONFIG_FILE";
+
+/// Environment variables with this prefix override the config file fields, e.g.
+/// `CLOUD_SERVER_LISTEN_ADDR=0.0.0.0:3030` overrides `listen_addr`.
+const ENV_PREFIX: &str = "CLOUD_SERVER_";
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ServerConfig {
+    #[serde(default = "default_listen_addr")]
+    pub listen_addr: SocketAddr,
+    /// Largest file that can be stored, limits the body of `upload` and the total size in the
+    /// manifest of `upload-manifest`. Downloads aren't limited.
+    #[serde(default = "default_max_file_size")]
+    pub max_file_size: u64,
+    /// Largest body of `upload-chunk`. Defaults to `max_file_size`.
+    #[serde(default)]
+    pub max_chunk_size: Option<u64>,
+    /// Largest body of `upload-manifest` and `signatures`, which only hold lists of chunks or
+    /// filenames.
+    #[serde(default = "default_max_manifest_size")]
+    pub max_manifest_size: u64,
+    pub storage_path: PathBuf,
+    /// Downloads of up to this many bytes, whole files or ranges, are read into memory and sent
+    /// in one piece instead of being streamed, which saves syscalls for small files.
+    #[serde(default = "default_max_buffered_download_size")]
+    pub max_buffered_download_size: u64,
+    /// Upper bound on the time a single upload may take, regardless of how steadily the body
+    /// arrives. Unlimited when not set.
+    #[serde(default)]
+    pub max_upload_duration_secs: Option<u64>,
+    /// How often temp files left behind by uploads, e.g. after a crash, are removed while the
+    /// server runs, starting at startup. A temp file is left behind once it hasn't been written
+    /// to for `max_upload_duration_secs`, or for a day when that isn't set. 0 disables the sweep.
+    #[serde(default = "default_temp_sweep_interval_secs")]
+    pub temp_sweep_interval_secs: u64,
+    /// How often expired and used up share links are removed while the server runs, starting at
+    /// startup. They stop working either way. 0 disables the sweep.
+    #[serde(default = "default_share_sweep_interval_secs")]
+    pub share_sweep_interval_secs: u64,
+    /// Set to `false` to run no maintenance in the background at all, whatever the intervals,
+    /// for minimal deployments.
+    #[serde(default = "default_true")]
+    pub background_tasks: bool,
+    /// Most background tasks running at once, so maintenance doesn't contend with requests for
+    /// I/O. When more are due, the temp file sweep starts before the share link sweep.
+    #[serde(default = "default_max_background_tasks")]
+    pub max_background_tasks: NonZeroUsize,
+    /// Longest time a share link may stay valid, also its lifetime when the client doesn't ask
+    /// for one.
+    #[serde(default = "default_max_share_duration_secs")]
+    pub max_share_duration_secs: u64,
+    /// Share links a single pubkey may have working at once, further ones fail with 429.
+    /// Unlimited when not set.
+    #[serde(default)]
+    pub max_shares_per_pubkey: Option<u64>,
+    /// Share links a single pubkey may create within an hour, further ones fail with 429.
+    /// Counted in memory, so restarting the server resets it. Unlimited when not set.
+    #[serde(default)]
+    pub max_shares_per_hour: Option<u64>,
+    /// Store filenames percent-encoded so names differing only in case don't collide on
+    /// case-insensitive filesystems. Changes the on-disk names, so it can't be toggled for an
+    /// existing storage without renaming the files.
+    #[serde(default)]
+    pub encode_filenames: bool,
+    /// Convert filenames to Unicode Normalization Form C before using them, so a name composed
+    /// differently on another platform, like NFD from macOS, resolves to the same file. Files
+    /// already stored under other forms are only reachable by NFC names after renaming them.
+    #[serde(default)]
+    pub normalize_filenames: bool,
+    /// Total size of the files a single pubkey may store. Uploads that would exceed it fail with
+    /// 507. Unlimited when not set. Can be overridden per pubkey in `pubkeys`.
+    #[serde(default)]
+    pub max_bytes_per_pubkey: Option<u64>,
+    /// Number of files a single pubkey may store. Uploads of new files beyond it fail with 413,
+    /// replacing existing files is still allowed. Unlimited when not set. Can be overridden per
+    /// pubkey in `pubkeys`.
+    #[serde(default)]
+    pub max_files_per_pubkey: Option<u64>,
+    /// Size of the thread pool hashing uploads with the `parallel-hashing` feature. Defaults to
+    /// the number of CPU cores.
+    #[serde(default)]
+    pub hashing_threads: Option<usize>,
+    /// Trust the digest sent by the client in the `file-digest` header of uploads instead of
+    /// hashing the data. Only the signature over the claimed digest is checked, so a buggy or
+    /// malicious client can store data not matching its signature. Such files are marked as
+    /// unverified and have no block hashes, clients still notice the mismatch when downloading.
+    /// Off by default.
+    #[serde(default)]
+    pub trust_client_digest: bool,
+    /// Check uploads as far as possible before reading their body. The `file-digest` header
+    /// becomes required and the file signature is verified against it, and the quota,
+    /// `no_overwrite`, `If-Match` and empty file checks run against the declared
+    /// `Content-Length`. Clients sending `Expect: 100-continue` transmit no data when the upload
+    /// is rejected. The data is still hashed and must match the declared digest, unless
+    /// `trust_client_digest` is set.
+    #[serde(default)]
+    pub precheck_uploads: bool,
+    /// Write uploads to a staging directory inside the pubkey directory instead of the system
+    /// temp directory. Keeps each pubkey's data on one subtree, so the final rename is atomic
+    /// and filesystem quotas or mounts per pubkey account for uploads in progress.
+    #[serde(default)]
+    pub per_pubkey_staging: bool,
+    /// Keep the file listing of every pubkey in memory after it's first read, used by `list`,
+    /// quota checks and `admin-users`. Changes made through the server invalidate it, but files
+    /// changed directly in the storage directory aren't noticed until restart.
+    #[serde(default)]
+    pub cache_listings: bool,
+    /// Refuse to replace stored files, for write-once storage. Uploads, copies and renames onto
+    /// an existing filename fail with 409.
+    #[serde(default)]
+    pub no_overwrite: bool,
+    /// Reserve disk space for each upload up front from its `content-length`, which keeps large
+    /// files less fragmented and fails uploads that don't fit with 507 before any data is read.
+    /// Only done on Linux filesystems supporting `fallocate`, uploads elsewhere go on without it.
+    #[serde(default)]
+    pub preallocate_uploads: bool,
+    /// Also record the Blake3 chaining value of every 1 MiB group of verified files, served by
+    /// the `outboard` endpoint. Clients check them against the file signature and then verify
+    /// each group of a download on its own, resumed ones included. Costs about 50 bytes of metadata
+    /// per MiB stored. Files stored before it was set get them once their signature is updated.
+    #[serde(default)]
+    pub store_outboards: bool,
+    /// Reject uploads of empty files with 400 instead of storing them.
+    #[serde(default)]
+    pub reject_empty_uploads: bool,
+    /// Extensions files may be stored with, compared case-insensitively without the leading dot,
+    /// e.g. `["tar", "gz"]`. An empty string allows files without an extension. Files with any
+    /// other extension are rejected with 415. All extensions are allowed when not set.
+    #[serde(default)]
+    pub allowed_extensions: Option<Vec<String>>,
+    /// Extensions files may not be stored with, rejected with 415 like extensions missing from
+    /// `allowed_extensions`.
+    #[serde(default)]
+    pub blocked_extensions: Vec<String>,
+    /// Let `prune` delete files without a known upload time, like the ones stored by older
+    /// servers. They are kept by default.
+    #[serde(default)]
+    pub prune_files_without_upload_time: bool,
+    /// Requests taking longer than this many milliseconds are logged with a warning including the
+    /// pubkey, filename and the body bytes received and sent. Requests are timed until the whole
+    /// response body is sent, or the client goes away.
+    #[serde(default = "default_slow_request_threshold_ms")]
+    pub slow_request_threshold_ms: u64,
+    /// Log the method, path and every header of each request along with the status and headers
+    /// of its response, for debugging proxies that strip or rewrite headers. Logged at debug
+    /// level, which has to be enabled in `log_config.yml`. Requests matching no method aren't
+    /// logged. Only file passphrases are redacted and the output is verbose, so it's off by
+    /// default.
+    #[serde(default)]
+    pub log_headers: bool,
+    /// Most connections open at once, including idle keep-alive ones. Further connections get a
+    /// 503 and are closed until one of the open ones is. Unlimited when not set.
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+    /// Set to `false` to close HTTP/1.1 connections after each response instead of keeping them
+    /// open for further requests.
+    #[serde(default = "default_true")]
+    pub http1_keep_alive: bool,
+    /// HTTP/1.1 connections taking longer than this many seconds to send the headers of a
+    /// request once they started are closed. Idle keep-alive connections aren't timed out, they
+    /// are bounded by `max_connections`. Not limited when not set.
+    #[serde(default)]
+    pub http1_header_read_timeout_secs: Option<u64>,
+    /// Most requests served at once on an HTTP/2 connection, hyper's default when not set.
+    #[serde(default)]
+    pub http2_max_concurrent_streams: Option<u32>,
+    /// Seconds between pings sent on idle HTTP/2 connections. Connections not answering a ping
+    /// within `http2_keep_alive_timeout_secs` are closed. No pings are sent when not set.
+    #[serde(default)]
+    pub http2_keep_alive_interval_secs: Option<u64>,
+    /// Seconds to wait for the answer to a keep-alive ping, hyper's default of 20 when not set.
+    #[serde(default)]
+    pub http2_keep_alive_timeout_secs: Option<u64>,
+    /// Oldest client version accepted, e.g. `"0.2.0"`. Requests from older clients, or clients
+    /// not reporting their version, fail with 426. All versions are accepted when not set.
+    #[serde(default)]
+    pub min_client_version: Option<semver::Version>,
+    /// Base58 pubkeys allowed to call the admin methods.
+    #[serde(default)]
+    pub admin_pubkeys: Vec<String>,
+    /// Only allow methods reading files, everything else fails with 403. Can be set per pubkey.
+    #[serde(default)]
+    pub read_only: bool,
+    /// Whether pubkeys missing from `pubkeys` or not setting `allowed` there may use the server.
+    /// Requests of denied pubkeys fail with 403.
+    #[serde(default = "default_true")]
+    pub allow_unlisted_pubkeys: bool,
+    /// Settings of single pubkeys keyed by their Base58 encoding, overriding the global ones.
+    #[serde(default)]
+    pub pubkeys: HashMap<String, PubkeyConfig>,
+    /// Base58 secret key the server signs the responses of `list`, `admin-users`, `signature`,
+    /// `block-hashes` and `outboard` with, so clients pinning its pubkey can detect impersonation
+    /// and tampered responses. Responses aren't signed when not set.
+    #[serde(default, deserialize_with = "deserialize_signing_key")]
+    pub signing_key: Option<SigningKey>,
+    /// URL of an external service deciding whether to allow each request, see
+    /// `auth::HttpAuthorizer`. All requests with valid signatures are allowed when not set.
+    #[serde(default)]
+    pub authorization_url: Option<String>,
+    /// URL that upload, download and failure events are POSTed to as JSON, see
+    /// `events::HttpEventSink`. Events are dropped when not set.
+    #[serde(default)]
+    pub event_url: Option<String>,
+    /// Arrangement of the files under each pubkey directory. Changing it moves the stored files
+    /// on the next start.
+    #[serde(default)]
+    pub layout: StorageLayout,
+}
+
+/// Entry of `pubkeys`, every field set overrides the global setting for the pubkey.
+#[derive(Debug, serde::Deserialize)]
+pub struct PubkeyConfig {
+    /// Overrides `max_bytes_per_pubkey`. A global quota can be changed but not lifted.
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+    /// Overrides `max_files_per_pubkey`. A global limit can be changed but not lifted.
+    #[serde(default)]
+    pub max_files: Option<u64>,
+    /// Overrides `read_only`.
+    #[serde(default)]
+    pub read_only: Option<bool>,
+    /// Overrides `allow_unlisted_pubkeys`.
+    #[serde(default)]
+    pub allowed: Option<bool>,
+}
+
+/// Settings in effect for a single pubkey, see [`ServerConfig::for_pubkey`].
+#[derive(Debug, Clone, Copy)]
+pub struct PubkeySettings {
+    pub max_bytes: Option<u64>,
+    pub max_files: Option<u64>,
+    pub read_only: bool,
+    pub allowed: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageLayout {
+    /// `<pubkey>/<filename>`
+    #[default]
+    Flat,
+    /// `<pubkey>/<hh>/<filename>`, where `hh` is the first byte of the filename hash in hex, so
+    /// many files don't end up in a single directory.
+    Sharded,
+}
+
+impl FromStr for StorageLayout {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "flat" => Ok(Self::Flat),
+            "sharded" => Ok(Self::Sharded),
+            _ => bail!("Unknown storage layout: {s}"),
+        }
+    }
+}
+
+impl Display for StorageLayout {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Flat => write!(f, "flat"),
+            Self::Sharded => write!(f, "sharded"),
+        }
+    }
+}
+
+fn default_listen_addr() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 3030))
+}
+
+fn default_max_file_size() -> u64 {
+    10_000_000_000
+}
+
+fn default_max_manifest_size() -> u64 {
+    10_000_000
+}
+
+fn default_max_buffered_download_size() -> u64 {
+    256 * 1024
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_slow_request_threshold_ms() -> u64 {
+    5_000
+}
+
+fn default_temp_sweep_interval_secs() -> u64 {
+    60 * 60
+}
+
+fn default_share_sweep_interval_secs() -> u64 {
+    60 * 60
+}
+
+fn default_max_background_tasks() -> NonZeroUsize {
+    NonZeroUsize::MIN
+}
+
+fn default_max_share_duration_secs() -> u64 {
+    7 * 24 * 60 * 60
+}
+
+fn deserialize_signing_key<'de, D>(deserializer: D) -> Result<Option<SigningKey>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let Some(secret) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    let bytes = bs58::decode(secret)
+        .into_vec()
+        .map_err(serde::de::Error::custom)?;
+    let secret = SecretKey::try_from(bytes.as_slice())
+        .map_err(|_| serde::de::Error::custom("Invalid signing key length"))?;
+    Ok(Some(SigningKey::from_bytes(&secret)))
+}
+
+impl ServerConfig {
+    /// Settings for `pubkey`: its entry in `pubkeys` where set, the global ones otherwise.
+    pub fn for_pubkey(&self, pubkey: &VerifyingKey) -> PubkeySettings {
+        let overrides = self
+            .pubkeys
+            .get(&bs58::encode(pubkey.as_bytes()).into_string());
+        PubkeySettings {
+            max_bytes: overrides
+                .and_then(|overrides| overrides.max_bytes)
+                .or(self.max_bytes_per_pubkey),
+            max_files: overrides
+                .and_then(|overrides| overrides.max_files)
+                .or(self.max_files_per_pubkey),
+            read_only: overrides
+                .and_then(|overrides| overrides.read_only)
+                .unwrap_or(self.read_only),
+            allowed: overrides
+                .and_then(|overrides| overrides.allowed)
+                .unwrap_or(self.allow_unlisted_pubkeys),
+        }
+    }
+
+    /// Loads the config like [`ServerConfig::read`], then creates the storage directory and makes
+    /// `storage_path` absolute.
+    pub fn load() -> Result<Self> {
+        let mut config = Self::read()?;
+        std::fs::create_dir_all(&config.storage_path).with_context(|| {
+            format!(
+                "Failed to create storage directory {}",
+                config.storage_path.display()
+            )
+        })?;
+        config.storage_path = config.storage_path.canonicalize().with_context(|| {
+            format!(
+                "Failed to canonicalize storage path {}",
+                config.storage_path.display()
+            )
+        })?;
+
+        Ok(config)
+    }
+
+    /// Reads the config with the following precedence: environment variables, then the config
+    /// file (which may be absent), then the defaults. A relative `storage_path` in the config file
+    /// is resolved against the directory of the file, one from the environment against the
+    /// working directory. Changes nothing on disk.
+    pub fn read() -> Result<Self> {
+        let config_file = std::env::var_os(CONFIG_FILE_VAR)
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(CONFIG_FILE));
+        Self::read_from(&config_file, std::env::vars())
+    }
+
+    /// Reads the config like [`ServerConfig::read`] from `config_file`, with `vars` in place of
+    /// the environment variables.
+    fn read_from(
+        config_file: &Path,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self> {
+        let mut fields = match std::fs::read_to_string(config_file) {
+            Ok(content) => serde_json::from_str::<Map<String, Value>>(&content)
+                .context("Failed to parse server config file")?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Map::new(),
+            Err(err) => Err(err).context("Failed to read server config file")?,
+        };
+        if let Some(Value::String(storage_path)) = fields.get_mut("storage_path") {
+            let config_dir = config_file.parent().unwrap_or(Path::new(""));
+            *storage_path = config_dir
+                .join(&*storage_path)
+                .to_string_lossy()
+                .into_owned();
+        }
+
+        for (name, value) in vars {
+            if name == CONFIG_FILE_VAR {
+                continue;
+            }
+            if let Some(field) = name.strip_prefix(ENV_PREFIX) {
+                // Values that aren't valid JSON (paths, addresses) are taken as plain strings
+                let value = serde_json::from_str(&value).unwrap_or(Value::String(value));
+                fields.insert(field.to_lowercase(), value);
+            }
+        }
+
+        serde_json::from_value(Value::Object(fields)).context("Invalid server config")
+    }
+
+    /// Settings that parse but can't work, like limits no request can meet, malformed pubkeys
+    /// and URLs, or a storage path that isn't a directory. Empty when none are found.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.storage_path.exists() && !self.storage_path.is_dir() {
+            problems.push(format!(
+                "storage_path {} is not a directory",
+                self.storage_path.display()
+            ));
+        }
+
+        for (name, value) in [
+            ("max_file_size", Some(self.max_file_size)),
+            ("max_chunk_size", self.max_chunk_size),
+            ("max_manifest_size", Some(self.max_manifest_size)),
+            (
+                "max_share_duration_secs",
+                Some(self.max_share_duration_secs),
+            ),
+            ("max_upload_duration_secs", self.max_upload_duration_secs),
+            (
+                "max_connections",
+                self.max_connections.map(|max| max as u64),
+            ),
+            (
+                "hashing_threads",
+                self.hashing_threads.map(|threads| threads as u64),
+            ),
+            (
+                "http2_max_concurrent_streams",
+                self.http2_max_concurrent_streams.map(u64::from),
+            ),
+        ] {
+            if value == Some(0) {
+                problems.push(format!("{name} is 0, so no request could ever meet it"));
+            }
+        }
+        if self
+            .max_chunk_size
+            .is_some_and(|max_chunk_size| max_chunk_size > self.max_file_size)
+        {
+            problems.push("max_chunk_size is larger than max_file_size".to_string());
+        }
+        if let Some(allowed_extensions) = &self.allowed_extensions {
+            for extension in &self.blocked_extensions {
+                if allowed_extensions
+                    .iter()
+                    .any(|allowed| allowed.eq_ignore_ascii_case(extension))
+                {
+                    problems.push(format!(
+                        "Extension {extension:?} is both in allowed_extensions and blocked_extensions"
+                    ));
+                }
+            }
+        }
+
+        for pubkey in &self.admin_pubkeys {
+            if !is_valid_pubkey(pubkey) {
+                problems.push(format!("admin_pubkeys: {pubkey:?} is not a Base58 pubkey"));
+            }
+        }
+        for pubkey in self.pubkeys.keys() {
+            if !is_valid_pubkey(pubkey) {
+                problems.push(format!("pubkeys: {pubkey:?} is not a Base58 pubkey"));
+            }
+        }
+        for (name, url) in [
+            ("authorization_url", &self.authorization_url),
+            ("event_url", &self.event_url),
+        ] {
+            if let Some(Err(err)) = url.as_deref().map(reqwest::Url::parse) {
+                problems.push(format!("{name} is not a valid URL: {err}"));
+            }
+        }
+        problems
+    }
+}
+
+fn is_valid_pubkey(pubkey_b58: &str) -> bool {
+    bs58::decode(pubkey_b58)
+        .into_vec()
+        .ok()
+        .and_then(|bytes| <[u8; PUBLIC_KEY_LENGTH]>::try_from(bytes).ok())
+        .is_some_and(|bytes| VerifyingKey::from_bytes(&bytes).is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    /// Writes `content` as a config file in a directory of its own, returning its path.
+    fn config_file(content: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cloud-config-{:x}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(CONFIG_FILE);
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn env_overrides_config_file() {
+        let file = config_file(
+            r#"{"storage_path": "storage", "max_file_size": 100, "no_overwrite": false}"#,
+        );
+        let config = ServerConfig::read_from(
+            &file,
+            vars(&[
+                ("CLOUD_SERVER_MAX_FILE_SIZE", "200"),
+                ("CLOUD_SERVER_NO_OVERWRITE", "true"),
+                ("OTHER_MAX_FILE_SIZE", "300"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(config.max_file_size, 200);
+        assert!(config.no_overwrite);
+        assert_eq!(config.storage_path, file.parent().unwrap().join("storage"));
+
+        let config = ServerConfig::read_from(&file, Vec::new()).unwrap();
+        assert_eq!(config.max_file_size, 100);
+        assert!(!config.no_overwrite);
+        std::fs::remove_dir_all(file.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn env_values_that_arent_json_are_strings() {
+        let file = config_file(r#"{"storage_path": "storage"}"#);
+        let config = ServerConfig::read_from(
+            &file,
+            vars(&[
+                ("CLOUD_SERVER_STORAGE_PATH", "/srv/cloud"),
+                ("CLOUD_SERVER_LISTEN_ADDR", "0.0.0.0:4040"),
+                ("CLOUD_SERVER_EVENT_URL", "{not json"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(config.storage_path, Path::new("/srv/cloud"));
+        assert_eq!(config.listen_addr, "0.0.0.0:4040".parse().unwrap());
+        assert_eq!(config.event_url.as_deref(), Some("{not json"));
+        std::fs::remove_dir_all(file.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,104 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures_util::Stream;
+use log::{info, warn};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Sent on connections beyond the limit before closing them, there's no request routing yet.
+const UNAVAILABLE_RESPONSE: &[u8] = b"HTTP/1.1 503 Service Unavailable\r\n\
+    content-type: text/plain\r\n\
+    content-length: 20\r\n\
+    connection: close\r\n\
+    \r\n\
+    Too many connections";
+
+/// A connection counted against `max_connections` until it's closed.
+pub struct LimitedConnection {
+    stream: TcpStream,
+    _permit: OwnedSemaphorePermit,
+}
+
+/// Accepts connections from `listener` while fewer than `max_connections` are open, including
+/// idle keep-alive ones. Connections beyond the limit get a 503 and are closed right away.
+pub fn limit_connections(
+    listener: TcpListener,
+    max_connections: usize,
+) -> impl Stream<Item = io::Result<LimitedConnection>> {
+    let semaphore = Arc::new(Semaphore::new(max_connections));
+    let saturated = Arc::new(AtomicBool::new(false));
+    let rejected = Arc::new(AtomicU64::new(0));
+
+    futures_util::stream::unfold(listener, move |listener| {
+        let semaphore = semaphore.clone();
+        let saturated = saturated.clone();
+        let rejected = rejected.clone();
+        async move {
+            loop {
+                let mut stream = match listener.accept().await {
+                    Ok((stream, _addr)) => stream,
+                    Err(err) => return Some((Err(err), listener)),
+                };
+
+                match semaphore.clone().try_acquire_owned() {
+                    Ok(permit) => {
+                        if saturated.swap(false, Ordering::Relaxed) {
+                            info!(
+                                "Accepting connections again, {} rejected at the limit",
+                                rejected.swap(0, Ordering::Relaxed)
+                            );
+                        }
+                        let connection = LimitedConnection {
+                            stream,
+                            _permit: permit,
+                        };
+                        return Some((Ok(connection), listener));
+                    }
+                    Err(_) => {
+                        if !saturated.swap(true, Ordering::Relaxed) {
+                            warn!("Connection limit of {max_connections} reached, rejecting new connections");
+                        }
+                        rejected.fetch_add(1, Ordering::Relaxed);
+                        tokio::spawn(async move {
+                            stream.write_all(UNAVAILABLE_RESPONSE).await.ok();
+                            stream.shutdown().await.ok();
+                        });
+                    }
+                }
+            }
+        }
+    })
+}
+
+impl AsyncRead for LimitedConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for LimitedConnection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
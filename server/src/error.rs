@@ -0,0 +1,55 @@
+use std::fmt::{Display, Formatter};
+
+use warp::http::StatusCode;
+
+/// Error that should be reported to the client with a specific status code instead of the generic
+/// `500 Internal Server Error`.
+#[derive(Debug)]
+pub struct HttpError {
+    status: StatusCode,
+    message: String,
+}
+
+impl HttpError {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+}
+
+impl Display for HttpError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+/// The client stopped sending the body of an upload, usually because it disconnected or was
+/// cancelled. Not a server fault, so it's logged without alarm.
+#[derive(Debug)]
+pub struct UploadInterrupted(pub warp::Error);
+
+impl UploadInterrupted {
+    pub fn status(&self) -> StatusCode {
+        StatusCode::BAD_REQUEST
+    }
+}
+
+impl Display for UploadInterrupted {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Upload interrupted by the client: {}", self.0)
+    }
+}
+
+impl std::error::Error for UploadInterrupted {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.0)
+    }
+}
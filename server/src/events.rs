@@ -0,0 +1,142 @@
+use std::future::Future;
+use std::time::Duration;
+
+use anyhow::Result;
+use ed25519_dalek::VerifyingKey;
+use log::warn;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::CONFIG;
+
+const EVENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Something that happened to a file, reported to the [`EventSink`].
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event {
+    /// The request signature checked out and the data is about to be read. `size` is the size
+    /// declared by the client.
+    UploadStarted {
+        pubkey: String,
+        filename: String,
+        size: u64,
+    },
+    /// The file is stored under its name.
+    UploadFinalized {
+        pubkey: String,
+        filename: String,
+        size: u64,
+    },
+    /// The file is about to be sent, `size` is the size of the whole file even for a range.
+    DownloadStarted {
+        pubkey: String,
+        filename: String,
+        size: u64,
+    },
+    /// The client stopped sending an upload, usually by disconnecting or cancelling it.
+    /// Reported instead of [`Event::RequestFailed`], since it's not a server fault.
+    UploadInterrupted {
+        method: &'static str,
+        pubkey: String,
+        filename: String,
+    },
+    /// An upload or download failed. `pubkey` and `filename` are the ones sent by the client,
+    /// the request signature may not have been verified.
+    RequestFailed {
+        method: &'static str,
+        pubkey: String,
+        filename: String,
+        status: u16,
+        message: String,
+    },
+}
+
+impl Event {
+    pub fn upload_started(pubkey: &VerifyingKey, filename: &str, size: u64) -> Self {
+        Self::UploadStarted {
+            pubkey: bs58::encode(pubkey.as_bytes()).into_string(),
+            filename: filename.to_string(),
+            size,
+        }
+    }
+
+    pub fn upload_finalized(pubkey: &VerifyingKey, filename: &str, size: u64) -> Self {
+        Self::UploadFinalized {
+            pubkey: bs58::encode(pubkey.as_bytes()).into_string(),
+            filename: filename.to_string(),
+            size,
+        }
+    }
+
+    pub fn download_started(pubkey: &VerifyingKey, filename: &str, size: u64) -> Self {
+        Self::DownloadStarted {
+            pubkey: bs58::encode(pubkey.as_bytes()).into_string(),
+            filename: filename.to_string(),
+            size,
+        }
+    }
+}
+
+/// Gets notified of uploads, downloads and their failures, for metrics, notifications or custom
+/// bookkeeping. Called inline by the handlers, so implementations should return quickly and move
+/// slow work out of the request.
+pub trait EventSink {
+    fn handle(&self, event: Event) -> impl Future<Output = ()> + Send;
+}
+
+/// Ignores all events, the default.
+pub struct NoEvents;
+
+impl EventSink for NoEvents {
+    async fn handle(&self, _event: Event) {}
+}
+
+/// POSTs each event as JSON to `event_url`, without holding up the request for the response.
+/// Failed deliveries are logged and dropped.
+pub struct HttpEventSink {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl HttpEventSink {
+    pub fn new(url: String) -> Result<Self> {
+        let client = reqwest::Client::builder().timeout(EVENT_TIMEOUT).build()?;
+        Ok(Self { client, url })
+    }
+}
+
+impl EventSink for HttpEventSink {
+    async fn handle(&self, event: Event) {
+        let request = self.client.post(&self.url).json(&event);
+        tokio::spawn(async move {
+            match request.send().await {
+                Ok(response) if response.status().is_success() => {}
+                Ok(response) => warn!("Event delivery failed with status {}", response.status()),
+                Err(err) => warn!("Event delivery failed: {err}"),
+            }
+        });
+    }
+}
+
+/// The event sink selected by the server config.
+pub enum ConfiguredEventSink {
+    None(NoEvents),
+    Http(HttpEventSink),
+}
+
+impl EventSink for ConfiguredEventSink {
+    async fn handle(&self, event: Event) {
+        match self {
+            Self::None(sink) => sink.handle(event).await,
+            Self::Http(sink) => sink.handle(event).await,
+        }
+    }
+}
+
+pub static EVENT_SINK: Lazy<ConfiguredEventSink> = Lazy::new(|| match &CONFIG.event_url {
+    Some(url) => ConfiguredEventSink::Http(
+        HttpEventSink::new(url.clone()).expect("Failed to create event client"),
+    ),
+    None => ConfiguredEventSink::None(NoEvents),
+});
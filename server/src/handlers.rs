@@ -1,172 +1,1851 @@
 use anyhow::Result;
-use ed25519_dalek::ed25519::signature::digest::Update;
-use ed25519_dalek::{DigestVerifier, Signature, VerifyingKey};
-use futures_util::{Stream, StreamExt, TryFutureExt};
-use http::header::CONTENT_TYPE;
+use ed25519_dalek::{Signature, VerifyingKey, PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH};
+use futures_util::{Stream, StreamExt};
+use http::header::{
+    ACCEPT_RANGES, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE,
+};
 use http::HeaderName;
 use log::{error, info};
-use shared::consts::PARAM_FILE_SIGNATURE;
-use shared::hasher::Hasher;
+use serde::Serialize;
+use shared::chunks::{encode_chunk_hash, BatchPlan, ChunkManifest};
+use shared::consts::*;
+use shared::hasher::{Hasher, Prehashed, DIGEST_LENGTH};
+use shared::listing::{signed_body_name, SignatureEntry};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::io::{ErrorKind, SeekFrom};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, Take};
 use tokio_util::codec::{BytesCodec, FramedRead};
 use warp::http::{HeaderValue, StatusCode};
+use warp::hyper::body::Bytes;
 use warp::hyper::Body;
 use warp::reply::Response;
 use warp::{Buf, Reply};
 
-use shared::SignableRequest;
+use shared::{response, SignableRequest, SignatureScheme};
 
+use crate::auth::{Authorizer, AUTHORIZER};
+use crate::error::{HttpError, UploadInterrupted};
+use crate::events::{Event, EventSink, EVENT_SINK};
+use crate::hashing::BatchHasher;
+use crate::metadata::{read_metadata, FileMetadata};
 use crate::storage::FileWriter;
-use crate::{storage, CONFIG};
+use crate::{passphrase, shares, storage, CONFIG};
 
 pub async fn download(
-    filename: HeaderValue,
-    pubkey: HeaderValue,
-    time: HeaderValue,
-    request_signature: HeaderValue,
+    auth: AuthHeaders,
+    range: Option<String>,
+    passphrase: Option<String>,
 ) -> Response {
-    process_result(download_internal(filename, pubkey, time, request_signature).await)
+    let (pubkey, filename) = (auth.pubkey.clone(), auth.filename.clone());
+    let result = download_internal(auth, range, passphrase).await;
+    process_transfer_result(METHOD_DOWNLOAD, &pubkey, &filename, result).await
 }
 
 async fn download_internal(
-    filename: HeaderValue,
-    pubkey: HeaderValue,
-    time: HeaderValue,
-    request_signature: HeaderValue,
+    auth: AuthHeaders,
+    range: Option<String>,
+    passphrase: Option<String>,
 ) -> Result<Response> {
-    let filename = filename.to_str()?;
-    let pubkey = pubkey.to_str()?;
-    let time = u64::from_str(time.to_str()?)?;
-    let request_signature = request_signature.to_str()?;
-
-    info!("Download: {filename}, pubkey: {pubkey}, time: {time}, request signature: {request_signature}");
-
-    let request_signature = Signature::from_slice(&bs58::decode(request_signature).into_vec()?)?;
-    let pubkey = VerifyingKey::try_from(bs58::decode(pubkey).into_vec()?.as_slice())?;
-    let download_request = SignableRequest::with_time(filename.to_string(), pubkey, time);
-
-    download_request.check_signature(&request_signature)?;
-
-    let (file_path, signature_path) = storage::get_file_paths(
-        &CONFIG.storage_path,
+    let download_request = verify_request(METHOD_DOWNLOAD, auth).await?;
+    let response = with_quota_headers(http::Response::builder(), download_request.pubkey()).await?;
+    let (file, metadata) = open_file(
         download_request.pubkey(),
         download_request.filename(),
+        passphrase,
     )
     .await?;
+    file_response(
+        download_request.pubkey(),
+        download_request.filename(),
+        file,
+        &metadata,
+        range,
+        response,
+    )
+    .await
+}
+
+/// Opens the file for [`file_response`]. Files protected by a passphrase are only opened once
+/// `passphrase` matches it.
+async fn open_file(
+    pubkey: &VerifyingKey,
+    filename: &str,
+    passphrase: Option<String>,
+) -> Result<(tokio::fs::File, FileMetadata)> {
+    let (file_path, signature_path) =
+        storage::get_file_paths(&CONFIG.storage_path, pubkey, filename).await?;
 
-    let signature = tokio::fs::read(signature_path).await?;
-    let stream = tokio::fs::File::open(file_path)
-        .map_ok(|file| FramedRead::new(file, BytesCodec::new()))
-        .try_flatten_stream();
+    let metadata = read_metadata(&signature_path).await?;
+    passphrase::check(metadata.passphrase_hash.as_deref(), passphrase).await?;
+    match tokio::fs::File::open(&file_path).await {
+        Ok(file) => Ok((file, metadata)),
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            Err(incomplete_file_error(&signature_path).await)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
 
-    let body = Body::wrap_stream(stream);
+/// Serves the opened file, or the single range of it asked for, with its signature in the
+/// headers.
+async fn file_response(
+    pubkey: &VerifyingKey,
+    filename: &str,
+    mut file: tokio::fs::File,
+    metadata: &FileMetadata,
+    range: Option<String>,
+    response: http::response::Builder,
+) -> Result<Response> {
+    let signature = metadata.signature()?;
+    let scheme = metadata.scheme()?;
+    let file_len = file.metadata().await?.len();
+    EVENT_SINK
+        .handle(Event::download_started(pubkey, filename, file_len))
+        .await;
 
-    Ok(http::Response::builder()
+    let response = response
         .header(
             CONTENT_TYPE,
             HeaderValue::from_static("application/octet-stream"),
         )
+        .header(ACCEPT_RANGES, HeaderValue::from_static("bytes"))
         .header(
             HeaderName::from_static(PARAM_FILE_SIGNATURE),
-            HeaderValue::from_str(&bs58::encode(&signature).into_string())?,
+            HeaderValue::from_str(&bs58::encode(signature.to_bytes()).into_string())?,
         )
+        .header(
+            HeaderName::from_static(PARAM_FILE_SIGNATURE_SCHEME),
+            scheme.as_str(),
+        );
+
+    let Some(range) = range else {
+        let body = file_body(file.take(file_len), file_len).await?;
+        return Ok(response.header(CONTENT_LENGTH, file_len).body(body)?);
+    };
+
+    let (start, end) = match parse_range(&range, file_len) {
+        RangeRequest::Satisfiable(start, end) => (start, end),
+        RangeRequest::Unsatisfiable => {
+            info!("Range not satisfiable: {range}, file length: {file_len}");
+            return Ok(response
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(CONTENT_RANGE, format!("bytes */{file_len}"))
+                .body(Body::empty())?);
+        }
+        RangeRequest::Ignored => {
+            info!("Ignoring unsupported range: {range}");
+            let body = file_body(file.take(file_len), file_len).await?;
+            return Ok(response.header(CONTENT_LENGTH, file_len).body(body)?);
+        }
+    };
+
+    info!("Serving range {start}-{end} of {file_len} bytes");
+
+    file.seek(SeekFrom::Start(start)).await?;
+    let body = file_body(file.take(end - start + 1), end - start + 1).await?;
+
+    Ok(response
+        .status(StatusCode::PARTIAL_CONTENT)
+        .header(CONTENT_RANGE, format!("bytes {start}-{end}/{file_len}"))
+        .header(CONTENT_LENGTH, end - start + 1)
         .body(body)?)
 }
 
-pub async fn upload(
-    filename: HeaderValue,
-    pubkey: HeaderValue,
-    time: HeaderValue,
-    request_signature: HeaderValue,
-    file_signature: HeaderValue,
-    body: impl Stream<Item = Result<impl Buf, warp::Error>> + Unpin,
+/// Body sending the `len` bytes of `file`. Up to `max_buffered_download_size` bytes are read
+/// into memory and sent at once, larger bodies are streamed.
+async fn file_body(mut file: Take<tokio::fs::File>, len: u64) -> Result<Body> {
+    if len > CONFIG.max_buffered_download_size {
+        return Ok(Body::wrap_stream(FramedRead::new(file, BytesCodec::new())));
+    }
+    let mut buffer = Vec::with_capacity(len as usize);
+    file.read_to_end(&mut buffer).await?;
+    Ok(Body::from(buffer))
+}
+
+pub async fn share(
+    auth: AuthHeaders,
+    expires_in: Option<u64>,
+    max_downloads: Option<u64>,
 ) -> Response {
-    process_result(
-        upload_internal(
+    process_result(share_internal(auth, expires_in, max_downloads).await)
+}
+
+/// Creates a link downloading the file without authentication for `expires_in` seconds, up to
+/// `max_share_duration_secs` which is also the default, or `max_downloads` times.
+async fn share_internal(
+    auth: AuthHeaders,
+    expires_in: Option<u64>,
+    max_downloads: Option<u64>,
+) -> Result<impl Reply> {
+    let request_signature = auth.request_signature.clone();
+    let share_request = verify_request(METHOD_SHARE, auth).await?;
+
+    let expires_in = expires_in.unwrap_or(CONFIG.max_share_duration_secs);
+    if expires_in == 0 || expires_in > CONFIG.max_share_duration_secs {
+        return Err(HttpError::new(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Share links must expire within 1 to {} seconds",
+                CONFIG.max_share_duration_secs
+            ),
+        )
+        .into());
+    }
+    if max_downloads == Some(0) {
+        return Err(HttpError::new(
+            StatusCode::BAD_REQUEST,
+            "Share links must allow at least one download",
+        )
+        .into());
+    }
+
+    let (file_path, signature_path) = storage::get_file_paths(
+        &CONFIG.storage_path,
+        share_request.pubkey(),
+        share_request.filename(),
+    )
+    .await?;
+    read_metadata(&signature_path).await?;
+    if !tokio::fs::try_exists(&file_path).await? {
+        return Err(incomplete_file_error(&signature_path).await);
+    }
+
+    let share = shares::create(
+        &CONFIG.storage_path,
+        share_request.pubkey(),
+        share_request.filename(),
+        Duration::from_secs(expires_in),
+        max_downloads,
+    )
+    .await?;
+
+    signed_json(&request_signature, &share)
+}
+
+pub async fn list_shares(auth: AuthHeaders) -> Response {
+    process_result(list_shares_internal(auth).await)
+}
+
+/// Lists the caller's working share links, signed like `list`.
+async fn list_shares_internal(auth: AuthHeaders) -> Result<impl Reply> {
+    let request_signature = auth.request_signature.clone();
+    let list_request = verify_request(METHOD_SHARES, auth).await?;
+    let shares = shares::list(&CONFIG.storage_path, list_request.pubkey()).await?;
+    info!("Listed {} share links", shares.len());
+
+    signed_json(&request_signature, &shares)
+}
+
+pub async fn revoke_share(auth: AuthHeaders) -> Response {
+    process_result(revoke_share_internal(auth).await)
+}
+
+/// Removes one of the caller's share links, whose token is signed in place of the filename.
+async fn revoke_share_internal(auth: AuthHeaders) -> Result<impl Reply> {
+    let revoke_request = verify_request(METHOD_REVOKE_SHARE, auth).await?;
+    let revoked = shares::revoke(
+        &CONFIG.storage_path,
+        revoke_request.pubkey(),
+        revoke_request.filename(),
+    )
+    .await?;
+    if !revoked {
+        return Err(HttpError::new(StatusCode::NOT_FOUND, "Share link not found").into());
+    }
+
+    Ok(StatusCode::OK)
+}
+
+pub async fn shared_download(
+    token: String,
+    range: Option<String>,
+    passphrase: Option<String>,
+) -> Response {
+    process_result(shared_download_internal(token, range, passphrase).await)
+}
+
+/// Serves the file of a share link like `download`, to anyone holding the token, and the
+/// passphrase of a protected file. The pubkey isn't revealed, the file signature is. Every
+/// request served counts as a download, range requests included. Ones the owner's pubkey isn't
+/// authorized for or with a wrong passphrase don't.
+async fn shared_download_internal(
+    token: String,
+    range: Option<String>,
+    passphrase: Option<String>,
+) -> Result<Response> {
+    let not_found = || HttpError::new(StatusCode::NOT_FOUND, "Share link not found or expired");
+    let share = shares::get(&CONFIG.storage_path, &token)
+        .await?
+        .ok_or_else(not_found)?;
+    info!(
+        "{METHOD_SHARED}: {}, token: {}",
+        share.filename,
+        shares::logged_token(&token)
+    );
+
+    let pubkey = share.pubkey()?;
+    check_access(&pubkey, METHOD_DOWNLOAD)?;
+    AUTHORIZER
+        .authorize(&pubkey, METHOD_SHARED, &share.filename)
+        .await?;
+    let (file, metadata) = open_file(&pubkey, &share.filename, passphrase).await?;
+    // Revoked or used up by another download meanwhile
+    shares::claim_download(&CONFIG.storage_path, &token)
+        .await?
+        .ok_or_else(not_found)?;
+
+    let mut response = http::Response::builder();
+    if let Some(disposition) = content_disposition(&share.filename) {
+        response = response.header(CONTENT_DISPOSITION, disposition);
+    }
+    file_response(&pubkey, &share.filename, file, &metadata, range, response).await
+}
+
+/// Suggests the last component of `filename` as the name to save a shared file under. Left out
+/// for names that can't be quoted as is.
+fn content_disposition(filename: &str) -> Option<String> {
+    let name = filename.rsplit('/').next().unwrap_or(filename);
+    let quotable = name
+        .chars()
+        .all(|c| c == ' ' || c.is_ascii_graphic() && !matches!(c, '"' | '\\'));
+    (!name.is_empty() && quotable).then(|| format!("attachment; filename=\"{name}\""))
+}
+
+pub async fn signature(auth: AuthHeaders) -> Response {
+    process_result(signature_internal(auth).await)
+}
+
+async fn signature_internal(auth: AuthHeaders) -> Result<Response> {
+    let request_signature = auth.request_signature.clone();
+    let signature_request = verify_request(METHOD_SIGNATURE, auth).await?;
+
+    let (file_path, signature_path) = storage::get_file_paths(
+        &CONFIG.storage_path,
+        signature_request.pubkey(),
+        signature_request.filename(),
+    )
+    .await?;
+
+    let metadata = read_metadata(&signature_path).await?;
+    let signature = metadata.signature()?;
+    if !tokio::fs::try_exists(&file_path).await? {
+        return Err(incomplete_file_error(&signature_path).await);
+    }
+
+    // The file signature is in a header, so it's signed in place of the body
+    let mut builder = http::Response::builder()
+        .header(
+            HeaderName::from_static(PARAM_FILE_SIGNATURE),
+            HeaderValue::from_str(&bs58::encode(signature.to_bytes()).into_string())?,
+        )
+        .header(
+            HeaderName::from_static(PARAM_FILE_SIGNATURE_SCHEME),
+            metadata.scheme()?.as_str(),
+        );
+    if let Some(uploaded_at) = metadata.uploaded_at {
+        builder = builder.header(HeaderName::from_static(PARAM_UPLOADED_AT), uploaded_at);
+    }
+    if let Some(digest) = &metadata.digest {
+        builder = builder.header(
+            HeaderName::from_static(PARAM_FILE_DIGEST_HEX),
+            HeaderValue::from_str(digest)?,
+        );
+    }
+    Ok(
+        with_response_signature(builder, &request_signature, &signature.to_bytes())?
+            .body(Body::empty())?,
+    )
+}
+
+pub async fn signatures(auth: AuthHeaders, body: Bytes) -> Response {
+    process_result(signatures_internal(auth, body).await)
+}
+
+/// Looks up the signatures and sizes of the files listed in the body. The request is signed with
+/// [`signed_body_name`] of the body in place of the filename.
+async fn signatures_internal(auth: AuthHeaders, body: Bytes) -> Result<Response> {
+    let request_signature = auth.request_signature.clone();
+    let signatures_request = verify_request(METHOD_SIGNATURES, auth).await?;
+    if signatures_request.filename() != signed_body_name(&body) {
+        return Err(HttpError::new(
+            StatusCode::UNAUTHORIZED,
+            "Request signature doesn't cover the body",
+        )
+        .into());
+    }
+
+    let filenames: Vec<String> = serde_json::from_slice(&body)
+        .map_err(|err| HttpError::new(StatusCode::BAD_REQUEST, format!("Invalid body: {err}")))?;
+    info!("Looking up {} signatures", filenames.len());
+
+    let mut entries = Vec::with_capacity(filenames.len());
+    for filename in filenames {
+        let stored_filename = storage_filename(&filename);
+        AUTHORIZER
+            .authorize(
+                signatures_request.pubkey(),
+                METHOD_SIGNATURE,
+                &stored_filename,
+            )
+            .await?;
+        let (file_path, signature_path) = storage::get_file_paths(
+            &CONFIG.storage_path,
+            signatures_request.pubkey(),
+            &stored_filename,
+        )
+        .await?;
+
+        let size = match tokio::fs::metadata(&file_path).await {
+            Ok(metadata) => Some(metadata.len()),
+            Err(err) if err.kind() == ErrorKind::NotFound => None,
+            Err(err) => return Err(err.into()),
+        };
+        // Files being replaced may briefly lack either part, they're reported as missing
+        let signature = match size {
+            Some(_) => read_metadata(&signature_path)
+                .await
+                .and_then(|metadata| metadata.signature())
+                .ok(),
+            None => None,
+        };
+        entries.push(SignatureEntry {
             filename,
-            pubkey,
-            time,
-            request_signature,
-            file_signature,
-            body,
+            signature: signature.map(|signature| bs58::encode(signature.to_bytes()).into_string()),
+            size: size.filter(|_| signature.is_some()),
+        });
+    }
+
+    signed_json(&request_signature, &entries)
+}
+
+pub async fn block_hashes(auth: AuthHeaders) -> Response {
+    process_result(block_hashes_internal(auth).await)
+}
+
+async fn block_hashes_internal(auth: AuthHeaders) -> Result<impl Reply> {
+    let request_signature = auth.request_signature.clone();
+    let block_hashes_request = verify_request(METHOD_BLOCK_HASHES, auth).await?;
+
+    let (file_path, signature_path) = storage::get_file_paths(
+        &CONFIG.storage_path,
+        block_hashes_request.pubkey(),
+        block_hashes_request.filename(),
+    )
+    .await?;
+
+    let metadata = read_metadata(&signature_path).await?;
+    if !tokio::fs::try_exists(&file_path).await? {
+        return Err(incomplete_file_error(&signature_path).await);
+    }
+    let block_hashes = metadata.block_hashes.ok_or(HttpError::new(
+        StatusCode::NOT_FOUND,
+        "No block hashes stored for the file",
+    ))?;
+
+    signed_json(&request_signature, &block_hashes)
+}
+
+pub async fn outboard(auth: AuthHeaders) -> Response {
+    process_result(outboard_internal(auth).await)
+}
+
+async fn outboard_internal(auth: AuthHeaders) -> Result<impl Reply> {
+    let request_signature = auth.request_signature.clone();
+    let outboard_request = verify_request(METHOD_OUTBOARD, auth).await?;
+
+    let (file_path, signature_path) = storage::get_file_paths(
+        &CONFIG.storage_path,
+        outboard_request.pubkey(),
+        outboard_request.filename(),
+    )
+    .await?;
+
+    let metadata = read_metadata(&signature_path).await?;
+    if !tokio::fs::try_exists(&file_path).await? {
+        return Err(incomplete_file_error(&signature_path).await);
+    }
+    let outboard = metadata.outboard.ok_or(HttpError::new(
+        StatusCode::NOT_FOUND,
+        "No outboard stored for the file",
+    ))?;
+
+    signed_json(&request_signature, &outboard)
+}
+
+pub async fn list(auth: AuthHeaders, since: Option<u64>, accept: Option<String>) -> Response {
+    process_result(list_internal(auth, since, accept).await)
+}
+
+/// Lists the caller's files, only the ones uploaded at or after `since` when it's set. The
+/// request is signed with an empty filename, which is ignored. Clients accepting NDJSON get the
+/// listing streamed instead.
+async fn list_internal(
+    auth: AuthHeaders,
+    since: Option<u64>,
+    accept: Option<String>,
+) -> Result<Response> {
+    let request_signature = auth.request_signature.clone();
+    let list_request = verify_request(METHOD_LIST, auth).await?;
+
+    let accepts_ndjson = accept.is_some_and(|accept| {
+        accept.split(',').any(|media_type| {
+            media_type.split(';').next().unwrap_or("").trim() == CONTENT_TYPE_NDJSON
+        })
+    });
+    if accepts_ndjson {
+        return Ok(streamed_listing(*list_request.pubkey(), since));
+    }
+
+    let mut files = storage::list_files(&CONFIG.storage_path, list_request.pubkey()).await?;
+    if let Some(since) = since {
+        info!("Listing files uploaded since {since}");
+        files = storage::retain_uploaded_since(list_request.pubkey(), files, since).await?;
+    }
+
+    signed_json(&request_signature, &files)
+}
+
+/// Sends the listing as one JSON entry per line while walking the storage, so it's never held
+/// in memory whole. Entries come in directory order. The response isn't signed since the body
+/// isn't known when the headers are sent, so clients pinning the server key ask for the buffered
+/// JSON listing instead. A failure midway aborts the body.
+fn streamed_listing(pubkey: VerifyingKey, since: Option<u64>) -> Response {
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let result = async {
+            let mut walker = storage::FileWalker::for_pubkey(&pubkey);
+            while let Some(file) = walker.next().await? {
+                if let Some(since) = since {
+                    if !storage::uploaded_since(&pubkey, &file, since).await? {
+                        continue;
+                    }
+                }
+                let mut line = serde_json::to_vec(&file)?;
+                line.push(b'\n');
+                sender.send_data(line.into()).await?;
+            }
+            anyhow::Ok(())
+        }
+        .await;
+        if let Err(err) = result {
+            error!("Streamed listing failed: {err}");
+            sender.abort();
+        }
+    });
+
+    let mut response = Response::new(body);
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(CONTENT_TYPE_NDJSON));
+    response
+}
+
+pub async fn admin_users(auth: AuthHeaders) -> Response {
+    process_result(admin_users_internal(auth).await)
+}
+
+/// Lists the pubkeys using the server. Signed like `list`, but only by an admin pubkey.
+async fn admin_users_internal(auth: AuthHeaders) -> Result<impl Reply> {
+    let request_signature = auth.request_signature.clone();
+    let admin_request = verify_request(METHOD_ADMIN_USERS, auth).await?;
+
+    let pubkey = bs58::encode(admin_request.pubkey().as_bytes()).into_string();
+    if !CONFIG.admin_pubkeys.contains(&pubkey) {
+        return Err(HttpError::new(StatusCode::FORBIDDEN, "Not an admin pubkey").into());
+    }
+
+    let pubkeys = storage::list_pubkeys(&CONFIG.storage_path).await?;
+
+    signed_json(&request_signature, &pubkeys)
+}
+
+pub async fn admin_trim(auth: AuthHeaders) -> Response {
+    process_result(admin_trim_internal(auth).await)
+}
+
+/// Removes empty directories and orphaned signature files from the storage. Signed like
+/// `admin-users`.
+async fn admin_trim_internal(auth: AuthHeaders) -> Result<impl Reply> {
+    let request_signature = auth.request_signature.clone();
+    let admin_request = verify_request(METHOD_ADMIN_TRIM, auth).await?;
+
+    let pubkey = bs58::encode(admin_request.pubkey().as_bytes()).into_string();
+    if !CONFIG.admin_pubkeys.contains(&pubkey) {
+        return Err(HttpError::new(StatusCode::FORBIDDEN, "Not an admin pubkey").into());
+    }
+
+    let report = storage::trim(&CONFIG.storage_path).await?;
+
+    signed_json(&request_signature, &report)
+}
+
+pub async fn prune(auth: AuthHeaders) -> Response {
+    process_result(prune_internal(auth).await)
+}
+
+/// Deletes the caller's files uploaded before a cutoff, which is signed in place of the filename
+/// as seconds since the Unix epoch. Responds with the number of files removed, signed like `list`.
+async fn prune_internal(auth: AuthHeaders) -> Result<impl Reply> {
+    let request_signature = auth.request_signature.clone();
+    let prune_request = verify_request(METHOD_PRUNE, auth).await?;
+    let before = u64::from_str(prune_request.filename()).map_err(|_| {
+        HttpError::new(
+            StatusCode::BAD_REQUEST,
+            "Cutoff time must be seconds since the Unix epoch",
         )
-        .await,
+    })?;
+    info!("Pruning files uploaded before {before}");
+
+    let report = storage::prune(prune_request.pubkey(), before).await?;
+    info!("Pruned {} files", report.removed);
+
+    signed_json(&request_signature, &report)
+}
+
+pub async fn rename(
+    auth: AuthHeaders,
+    destination: HeaderValue,
+    destination_signature: HeaderValue,
+) -> Response {
+    process_result(rename_internal(auth, destination, destination_signature).await)
+}
+
+async fn rename_internal(
+    auth: AuthHeaders,
+    destination: HeaderValue,
+    destination_signature: HeaderValue,
+) -> Result<impl Reply> {
+    // The destination is signed with the same scheme as the request
+    let scheme = auth.signature_scheme()?;
+    let rename_request = verify_request(METHOD_RENAME, auth).await?;
+    let destination = verify_destination(
+        METHOD_RENAME,
+        &rename_request,
+        scheme,
+        destination,
+        destination_signature,
+    )
+    .await?;
+
+    storage::rename_file(
+        rename_request.pubkey(),
+        rename_request.filename(),
+        &destination,
+    )
+    .await?;
+
+    Ok(StatusCode::OK)
+}
+
+pub async fn copy(
+    auth: AuthHeaders,
+    destination: HeaderValue,
+    destination_signature: HeaderValue,
+) -> Response {
+    process_result(copy_internal(auth, destination, destination_signature).await)
+}
+
+async fn copy_internal(
+    auth: AuthHeaders,
+    destination: HeaderValue,
+    destination_signature: HeaderValue,
+) -> Result<impl Reply> {
+    // The destination is signed with the same scheme as the request
+    let scheme = auth.signature_scheme()?;
+    let copy_request = verify_request(METHOD_COPY, auth).await?;
+    let destination = verify_destination(
+        METHOD_COPY,
+        &copy_request,
+        scheme,
+        destination,
+        destination_signature,
     )
+    .await?;
+
+    storage::copy_file(copy_request.pubkey(), copy_request.filename(), &destination)
+        .await
+        .map_err(map_storage_full)?;
+
+    Ok(StatusCode::OK)
 }
 
-async fn upload_internal(
-    filename: HeaderValue,
-    pubkey: HeaderValue,
-    time: HeaderValue,
-    request_signature: HeaderValue,
+pub async fn delete(auth: AuthHeaders, if_match: Option<String>) -> Response {
+    process_result(delete_internal(auth, if_match).await)
+}
+
+async fn delete_internal(auth: AuthHeaders, if_match: Option<String>) -> Result<impl Reply> {
+    let delete_request = verify_request(METHOD_DELETE, auth).await?;
+    let expected_signature = parse_if_match(if_match)?;
+
+    storage::delete_file(
+        delete_request.pubkey(),
+        delete_request.filename(),
+        expected_signature.as_ref(),
+    )
+    .await?;
+
+    Ok(StatusCode::OK)
+}
+
+pub async fn update_signature(
+    auth: AuthHeaders,
     file_signature: HeaderValue,
-    body: impl Stream<Item = Result<impl Buf, warp::Error>> + Unpin,
+    file_signature_scheme: Option<String>,
+    if_match: Option<String>,
+) -> Response {
+    process_result(
+        update_signature_internal(auth, file_signature, file_signature_scheme, if_match).await,
+    )
+}
+
+/// Stores a new signature for a file without uploading it again, once the signature checks out
+/// against the stored data.
+async fn update_signature_internal(
+    auth: AuthHeaders,
+    file_signature: HeaderValue,
+    file_signature_scheme: Option<String>,
+    if_match: Option<String>,
 ) -> Result<impl Reply> {
-    let filename = filename.to_str()?;
-    let pubkey = pubkey.to_str()?;
-    let time = u64::from_str(time.to_str()?)?;
-    let request_signature = request_signature.to_str()?;
+    let update_request = verify_request(METHOD_UPDATE_SIGNATURE, auth).await?;
+    let scheme = parse_file_signature_scheme(file_signature_scheme)?;
+
     let file_signature = file_signature.to_str()?;
+    info!("New file signature: {file_signature}");
+    let file_signature = decode_signature(PARAM_FILE_SIGNATURE, file_signature)?;
+    let expected_signature = parse_if_match(if_match)?;
+
+    storage::update_signature(
+        update_request.pubkey(),
+        update_request.filename(),
+        &file_signature,
+        scheme,
+        expected_signature.as_ref(),
+    )
+    .await?;
+
+    Ok(StatusCode::OK)
+}
+
+/// Checks that the destination filename is signed by the same key with the same time as the
+/// request itself, so both names are authorized.
+async fn verify_destination(
+    method: &str,
+    request: &SignableRequest,
+    scheme: SignatureScheme,
+    destination: HeaderValue,
+    destination_signature: HeaderValue,
+) -> Result<String> {
+    let destination = filename_header(PARAM_DESTINATION, &destination)?;
+    let destination_signature = destination_signature.to_str()?;
+
+    info!("Destination: {destination}, destination signature: {destination_signature}");
+
+    let destination_signature =
+        decode_signature(PARAM_DESTINATION_SIGNATURE, destination_signature)?;
+    SignableRequest::with_time(
+        method,
+        destination.to_string(),
+        *request.pubkey(),
+        request.time(),
+    )
+    .check_signature(&destination_signature, scheme)
+    .map_err(unauthorized)?;
+    let destination = storage_filename(destination);
+    AUTHORIZER
+        .authorize(request.pubkey(), method, &destination)
+        .await?;
+    check_extension(&destination)?;
+
+    Ok(destination)
+}
 
-    info!("Upload: {filename}, pubkey: {pubkey}, time: {time}, request signature: {request_signature}, file signature: {file_signature}");
+/// Adds the server signature of `body` when a signing key is configured. The request signature
+/// must already be verified.
+fn with_response_signature(
+    builder: http::response::Builder,
+    request_signature: &HeaderValue,
+    body: &[u8],
+) -> Result<http::response::Builder> {
+    let Some(signing_key) = &CONFIG.signing_key else {
+        return Ok(builder);
+    };
+    let request_signature =
+        Signature::from_slice(&bs58::decode(request_signature.to_str()?).into_vec()?)?;
+    let response_signature = response::sign_response(signing_key, &request_signature, body);
+    Ok(builder.header(
+        PARAM_RESPONSE_SIGNATURE,
+        bs58::encode(response_signature.to_bytes()).into_string(),
+    ))
+}
+
+/// Replies with `value` as JSON, signed like [`with_response_signature`].
+fn signed_json(request_signature: &HeaderValue, value: &impl Serialize) -> Result<Response> {
+    let body = serde_json::to_vec(value)?;
+    let builder = http::Response::builder().header(CONTENT_TYPE, "application/json");
+    Ok(with_response_signature(builder, request_signature, &body)?.body(Body::from(body))?)
+}
+
+/// Headers describing the file of an upload.
+pub struct FileHeaders {
+    pub signature: HeaderValue,
+    pub signature_scheme: Option<String>,
+    /// Passphrase downloads of the file will have to send.
+    pub passphrase: Option<String>,
+}
+
+/// Authentication headers sent with every request.
+pub struct AuthHeaders {
+    pub filename: HeaderValue,
+    pub pubkey: HeaderValue,
+    pub time: HeaderValue,
+    pub request_signature: HeaderValue,
+    pub signature_scheme: Option<HeaderValue>,
+    pub client_version: Option<HeaderValue>,
+}
+
+impl AuthHeaders {
+    /// Scheme of the request signature, pure Ed25519 unless the request says otherwise.
+    fn signature_scheme(&self) -> Result<SignatureScheme> {
+        let header = self
+            .signature_scheme
+            .as_ref()
+            .map(HeaderValue::to_str)
+            .transpose()?;
+        SignatureScheme::from_header(header, SignatureScheme::REQUEST_DEFAULT)
+            .map_err(|err| HttpError::new(StatusCode::BAD_REQUEST, err.to_string()).into())
+    }
+
+    /// Fails with 426 if the client is older than `min_client_version`.
+    fn check_client_version(&self) -> Result<()> {
+        let Some(min_version) = &CONFIG.min_client_version else {
+            return Ok(());
+        };
+        let version = self
+            .client_version
+            .as_ref()
+            .and_then(|version| version.to_str().ok())
+            .and_then(|version| semver::Version::parse(version).ok());
+        match version {
+            Some(version) if version >= *min_version => Ok(()),
+            Some(version) => Err(HttpError::new(
+                StatusCode::UPGRADE_REQUIRED,
+                format!("Client version {version} is no longer supported, please upgrade to {min_version} or newer"),
+            )
+            .into()),
+            None => Err(HttpError::new(
+                StatusCode::UPGRADE_REQUIRED,
+                format!("Client didn't report a valid version, please upgrade to {min_version} or newer"),
+            )
+            .into()),
+        }
+    }
+}
+
+/// Checks the signature of a request, which must have been made for `method`.
+async fn verify_request(method: &str, auth: AuthHeaders) -> Result<SignableRequest> {
+    auth.check_client_version()?;
+    let scheme = auth.signature_scheme()?;
+    let filename = filename_header(PARAM_FILENAME, &auth.filename)?;
+    let pubkey = auth.pubkey.to_str()?;
+    let time = u64::from_str(auth.time.to_str()?)?;
+    let request_signature = auth.request_signature.to_str()?;
 
-    let pubkey = VerifyingKey::try_from(bs58::decode(pubkey).into_vec()?.as_slice())?;
-    let request_signature = Signature::from_slice(&bs58::decode(request_signature).into_vec()?)?;
-    let file_signature = Signature::from_slice(&bs58::decode(file_signature).into_vec()?)?;
+    // The filename of `revoke-share` is the token of a working link
+    let logged_filename = match method {
+        METHOD_REVOKE_SHARE => Cow::Owned(shares::logged_token(filename)),
+        _ => Cow::Borrowed(filename),
+    };
+    info!("{method}: {logged_filename}, pubkey: {pubkey}, time: {time}, request signature: {request_signature} ({scheme})");
 
-    let upload_request = SignableRequest::with_time(filename.to_string(), pubkey, time);
+    let request_signature = decode_signature(PARAM_REQUEST_SIGNATURE, request_signature)?;
+    let pubkey =
+        VerifyingKey::try_from(decode_base58(PARAM_PUBKEY, pubkey, PUBLIC_KEY_LENGTH)?.as_slice())
+            .map_err(|_| invalid_header_error(PARAM_PUBKEY))?;
+    // Rebuilt with the method being called, so signatures made for other methods don't match
+    let request = SignableRequest::with_time(method, filename.to_string(), pubkey, time);
+
+    request
+        .check_signature(&request_signature, scheme)
+        .map_err(unauthorized)?;
+    // The signature covers the filename as sent, the stored name is used from here on
+    let request = SignableRequest::with_time(method, storage_filename(filename), pubkey, time);
+    check_access(request.pubkey(), method)?;
+    AUTHORIZER
+        .authorize(request.pubkey(), method, request.filename())
+        .await?;
+
+    Ok(request)
+}
+
+/// Methods that don't change the storage, the only ones allowed to read-only pubkeys.
+const READ_METHODS: &[&str] = &[
+    METHOD_DOWNLOAD,
+    METHOD_SIGNATURE,
+    METHOD_SIGNATURES,
+    METHOD_LIST,
+    METHOD_BLOCK_HASHES,
+    METHOD_OUTBOARD,
+    METHOD_ADMIN_USERS,
+    METHOD_SHARES,
+];
+
+/// Rejects requests of denied pubkeys, and requests of read-only pubkeys changing the storage.
+fn check_access(pubkey: &VerifyingKey, method: &str) -> Result<()> {
+    let settings = CONFIG.for_pubkey(pubkey);
+    if !settings.allowed {
+        return Err(HttpError::new(StatusCode::FORBIDDEN, "Pubkey is not allowed").into());
+    }
+    if settings.read_only && !READ_METHODS.contains(&method) {
+        return Err(HttpError::new(StatusCode::FORBIDDEN, "Pubkey is read-only").into());
+    }
+    Ok(())
+}
+
+/// Longest filename accepted in bytes, the Linux `PATH_MAX`. Longer ones are rejected with 400
+/// before their signature is checked, they couldn't be stored anyway.
+const MAX_FILENAME_LENGTH: usize = 4096;
+
+/// Reads the filename in `header` as UTF-8, since names aren't limited to ASCII.
+fn filename_header<'a>(header: &str, value: &'a HeaderValue) -> Result<&'a str> {
+    if value.len() > MAX_FILENAME_LENGTH {
+        return Err(HttpError::new(
+            StatusCode::BAD_REQUEST,
+            format!("{header} header is too long"),
+        )
+        .into());
+    }
+    std::str::from_utf8(value.as_bytes())
+        .map_err(|_| HttpError::new(StatusCode::BAD_REQUEST, "Filename is not valid UTF-8").into())
+}
+
+/// Normalizes a filename whose signature is verified if `normalize_filenames` is set.
+fn storage_filename(filename: &str) -> String {
+    if CONFIG.normalize_filenames {
+        shared::normalize_filename(filename)
+    } else {
+        filename.to_string()
+    }
+}
+
+/// Rejects a request whose signature or time doesn't check out.
+fn unauthorized(err: anyhow::Error) -> anyhow::Error {
+    HttpError::new(StatusCode::UNAUTHORIZED, err.to_string()).into()
+}
+
+/// Builds the error for a file whose signature exists without data, cleaning up the signature.
+async fn incomplete_file_error(signature_path: &Path) -> anyhow::Error {
+    storage::remove_orphaned_signature(signature_path).await;
+    HttpError::new(
+        StatusCode::CONFLICT,
+        "File is incomplete: its upload wasn't finalized",
+    )
+    .into()
+}
+
+/// How a `Range` header applies to a file.
+#[derive(Debug, PartialEq, Eq)]
+enum RangeRequest {
+    /// Served whole, as RFC 9110 asks of ranges the server can't parse or doesn't support, like
+    /// several ranges, suffix ranges or other units.
+    Ignored,
+    /// The bytes from the start to the end, both included.
+    Satisfiable(u64, u64),
+    /// A valid range starting past the end of the file, answered with 416.
+    Unsatisfiable,
+}
+
+/// Parses a single `bytes=<start>-[<end>]` range for a file of the given length.
+fn parse_range(range: &str, file_len: u64) -> RangeRequest {
+    let Some((start, end)) = range
+        .split_once('=')
+        .filter(|(unit, _)| unit.trim().eq_ignore_ascii_case("bytes"))
+        .and_then(|(_, range)| range.split_once('-'))
+    else {
+        return RangeRequest::Ignored;
+    };
+    let Ok(start) = u64::from_str(start.trim()) else {
+        return RangeRequest::Ignored;
+    };
+    let end = match end.trim() {
+        "" => None,
+        end => match u64::from_str(end) {
+            Ok(end) if end >= start => Some(end),
+            _ => return RangeRequest::Ignored,
+        },
+    };
+
+    if start >= file_len {
+        return RangeRequest::Unsatisfiable;
+    }
+    let last = file_len - 1;
+    RangeRequest::Satisfiable(start, end.map_or(last, |end| end.min(last)))
+}
+
+pub async fn upload(
+    auth: AuthHeaders,
+    file: FileHeaders,
+    file_digest: Option<String>,
+    if_match: Option<String>,
+    content_length: u64,
+    body: impl Stream<Item = Result<impl Buf, warp::Error>> + Unpin,
+) -> Response {
+    let (pubkey, filename) = (auth.pubkey.clone(), auth.filename.clone());
+    let result = upload_internal(auth, file, file_digest, if_match, content_length, body).await;
+    process_transfer_result(METHOD_UPLOAD, &pubkey, &filename, result).await
+}
+
+async fn upload_internal(
+    auth: AuthHeaders,
+    file: FileHeaders,
+    file_digest: Option<String>,
+    if_match: Option<String>,
+    content_length: u64,
+    body: impl Stream<Item = Result<impl Buf, warp::Error>> + Unpin,
+) -> Result<impl Reply> {
+    let upload_request = verify_request(METHOD_UPLOAD, auth).await?;
+    check_extension(upload_request.filename())?;
+    let scheme = parse_file_signature_scheme(file.signature_scheme)?;
+    let passphrase_hash = hash_passphrase(file.passphrase).await?;
+
+    let file_signature = file.signature.to_str()?;
+    info!("File signature: {file_signature}");
+    let file_signature = decode_signature(PARAM_FILE_SIGNATURE, file_signature)?;
+    let expected_signature = parse_if_match(if_match)?;
+
+    // The claimed digest is only used when the server is configured to trust or precheck it
+    let declared_digest = match file_digest {
+        Some(file_digest) if CONFIG.trust_client_digest || CONFIG.precheck_uploads => {
+            Some(parse_file_digest(&file_digest)?)
+        }
+        _ => None,
+    };
+    if let Some(declared_digest) = &declared_digest {
+        scheme.verify_file(
+            upload_request.pubkey(),
+            declared_digest.clone(),
+            &file_signature,
+        )?;
+        info!("File digest signature OK");
+    }
 
-    upload_request.check_signature(&request_signature)?;
+    // Nothing reads the body before this point, so hyper hasn't sent `100 Continue` yet
+    if CONFIG.precheck_uploads {
+        if declared_digest.is_none() {
+            return Err(HttpError::new(
+                StatusCode::PRECONDITION_REQUIRED,
+                format!("Uploads must declare the {PARAM_FILE_DIGEST}"),
+            )
+            .into());
+        }
+        if CONFIG.reject_empty_uploads && content_length == 0 {
+            return Err(empty_file_error());
+        }
+        storage::precheck_upload(
+            upload_request.pubkey(),
+            upload_request.filename(),
+            content_length,
+            expected_signature.as_ref(),
+        )
+        .await?;
+        info!("Upload precheck OK");
+    }
+    let file_digest = declared_digest.filter(|_| CONFIG.trust_client_digest);
+    if file_digest.is_some() {
+        info!("Trusting the file digest, skipping hashing");
+    }
 
     info!("Request signature OK. Started writing file.");
+    EVENT_SINK
+        .handle(Event::upload_started(
+            upload_request.pubkey(),
+            upload_request.filename(),
+            content_length,
+        ))
+        .await;
 
-    let mut hasher = Hasher::default();
-    let mut file_writer = FileWriter::new().await?;
-    match write_body(&mut file_writer, &mut hasher, body).await {
+    let mut hasher = BatchHasher::new(Hasher::default());
+    let mut file_writer = FileWriter::new(upload_request.pubkey())
+        .await
+        .map_err(|err| map_storage_full(err.into()))?;
+    if CONFIG.preallocate_uploads {
+        file_writer
+            .preallocate(content_length)
+            .map_err(|err| map_storage_full(err.into()))?;
+    }
+    if file_digest.is_some() {
+        file_writer.skip_verification();
+    }
+    let data_hasher = file_digest.is_none().then_some(&mut hasher);
+    let write_result = match CONFIG.max_upload_duration_secs {
+        Some(secs) => tokio::time::timeout(
+            Duration::from_secs(secs),
+            write_body(&mut file_writer, data_hasher, body),
+        )
+        .await
+        .unwrap_or_else(|_| {
+            Err(HttpError::new(
+                StatusCode::REQUEST_TIMEOUT,
+                format!("Upload took longer than {secs} seconds"),
+            )
+            .into())
+        }),
+        None => write_body(&mut file_writer, data_hasher, body).await,
+    };
+    if write_result.is_ok() && CONFIG.reject_empty_uploads && file_writer.written() == 0 {
+        file_writer.drop_temp_file().await?;
+        return Err(empty_file_error());
+    }
+    let written = file_writer.written();
+    match write_result {
         Ok(()) => {
-            pubkey.verify_digest(hasher, &file_signature)?;
+            if file_digest.is_none() {
+                scheme.verify_file(
+                    upload_request.pubkey(),
+                    hasher.finish().await,
+                    &file_signature,
+                )?;
+            }
             file_writer
                 .finalize(
                     upload_request.filename(),
                     upload_request.pubkey(),
                     &file_signature,
+                    scheme,
+                    expected_signature.as_ref(),
+                    passphrase_hash,
                 )
-                .await?;
+                .await
+                .map_err(map_storage_full)?;
+            EVENT_SINK
+                .handle(Event::upload_finalized(
+                    upload_request.pubkey(),
+                    upload_request.filename(),
+                    written,
+                ))
+                .await;
         }
         Err(err) => {
-            error!("File write error: {:?}", err);
+            log_write_error(&err, written);
             file_writer.drop_temp_file().await?;
-            return Err(err);
+            return Err(map_storage_full(err));
         }
     }
 
+    Ok(
+        with_quota_headers(http::Response::builder(), upload_request.pubkey())
+            .await?
+            .body(Body::empty())?,
+    )
+}
+
+pub async fn upload_chunk(
+    auth: AuthHeaders,
+    chunk_index: u32,
+    chunk_hash: String,
+    body: impl Stream<Item = Result<impl Buf, warp::Error>> + Unpin,
+) -> Response {
+    process_result(upload_chunk_internal(auth, chunk_index, chunk_hash, body).await)
+}
+
+async fn upload_chunk_internal(
+    auth: AuthHeaders,
+    chunk_index: u32,
+    chunk_hash: String,
+    body: impl Stream<Item = Result<impl Buf, warp::Error>> + Unpin,
+) -> Result<impl Reply> {
+    let upload_request = verify_request(METHOD_UPLOAD_CHUNK, auth).await?;
+    check_extension(upload_request.filename())?;
+
+    info!("Chunk index: {chunk_index}, chunk hash: {chunk_hash}");
+
+    // Validates the filename the same way as for the assembled file
+    storage::get_file_paths(
+        &CONFIG.storage_path,
+        upload_request.pubkey(),
+        upload_request.filename(),
+    )
+    .await?;
+
+    let mut hasher = BatchHasher::new(Hasher::default());
+    let mut file_writer = FileWriter::new(upload_request.pubkey())
+        .await
+        .map_err(|err| map_storage_full(err.into()))?;
+    if let Err(err) = write_body(&mut file_writer, Some(&mut hasher), body).await {
+        log_write_error(&err, file_writer.written());
+        file_writer.drop_temp_file().await?;
+        return Err(map_storage_full(err));
+    }
+
+    if encode_chunk_hash(hasher.finish().await) != chunk_hash {
+        file_writer.drop_temp_file().await?;
+        return Err(HttpError::new(StatusCode::BAD_REQUEST, "Chunk hash mismatch").into());
+    }
+
+    let chunks_dir = storage::get_chunks_dir(
+        &CONFIG.storage_path,
+        upload_request.pubkey(),
+        upload_request.filename(),
+    );
+    file_writer
+        .persist(&storage::get_chunk_path(&chunks_dir, chunk_index))
+        .await
+        .map_err(map_storage_full)?;
+
     Ok(StatusCode::OK)
 }
 
+pub async fn upload_manifest(
+    auth: AuthHeaders,
+    file: FileHeaders,
+    if_match: Option<String>,
+    manifest: ChunkManifest,
+) -> Response {
+    let (pubkey, filename) = (auth.pubkey.clone(), auth.filename.clone());
+    let result = upload_manifest_internal(auth, file, if_match, manifest).await;
+    process_transfer_result(METHOD_UPLOAD_MANIFEST, &pubkey, &filename, result).await
+}
+
+async fn upload_manifest_internal(
+    auth: AuthHeaders,
+    file: FileHeaders,
+    if_match: Option<String>,
+    manifest: ChunkManifest,
+) -> Result<impl Reply> {
+    let upload_request = verify_request(METHOD_UPLOAD_MANIFEST, auth).await?;
+    check_extension(upload_request.filename())?;
+    let scheme = parse_file_signature_scheme(file.signature_scheme)?;
+    let passphrase_hash = hash_passphrase(file.passphrase).await?;
+
+    let file_signature = file.signature.to_str()?;
+    info!(
+        "File signature: {file_signature}, total size: {}, chunks: {}",
+        manifest.total_size,
+        manifest.chunks.len()
+    );
+    let file_signature = decode_signature(PARAM_FILE_SIGNATURE, file_signature)?;
+    let expected_signature = parse_if_match(if_match)?;
+
+    EVENT_SINK
+        .handle(Event::upload_started(
+            upload_request.pubkey(),
+            upload_request.filename(),
+            manifest.total_size,
+        ))
+        .await;
+    let (file_writer, chunks_dir) = assemble_chunks(
+        upload_request.pubkey(),
+        upload_request.filename(),
+        &manifest,
+        &file_signature,
+        scheme,
+    )
+    .await?;
+    file_writer
+        .finalize(
+            upload_request.filename(),
+            upload_request.pubkey(),
+            &file_signature,
+            scheme,
+            expected_signature.as_ref(),
+            passphrase_hash,
+        )
+        .await
+        .map_err(map_storage_full)?;
+    EVENT_SINK
+        .handle(Event::upload_finalized(
+            upload_request.pubkey(),
+            upload_request.filename(),
+            manifest.total_size,
+        ))
+        .await;
+
+    tokio::fs::remove_dir_all(&chunks_dir).await?;
+
+    Ok(
+        with_quota_headers(http::Response::builder(), upload_request.pubkey())
+            .await?
+            .body(Body::empty())?,
+    )
+}
+
+/// Assembles a file from its uploaded chunks into a temp file, checking them against the manifest
+/// and the file signature. Returns the writer along with the chunks directory to remove once the
+/// file is stored.
+async fn assemble_chunks(
+    pubkey: &VerifyingKey,
+    filename: &str,
+    manifest: &ChunkManifest,
+    file_signature: &Signature,
+    scheme: SignatureScheme,
+) -> Result<(FileWriter, PathBuf)> {
+    if manifest.total_size > CONFIG.max_file_size {
+        return Err(HttpError::new(StatusCode::PAYLOAD_TOO_LARGE, "File is too large").into());
+    }
+    if manifest.total_size == 0 && CONFIG.reject_empty_uploads {
+        return Err(empty_file_error());
+    }
+
+    let chunks_dir = storage::get_chunks_dir(&CONFIG.storage_path, pubkey, filename);
+
+    let mut hasher = BatchHasher::new(Hasher::default());
+    let mut file_writer = FileWriter::new(pubkey)
+        .await
+        .map_err(|err| map_storage_full(err.into()))?;
+    let mut total_size = 0;
+    for (index, expected_hash) in (0..).zip(&manifest.chunks) {
+        let chunk_path = storage::get_chunk_path(&chunks_dir, index);
+        let chunk = match tokio::fs::File::open(&chunk_path).await {
+            Ok(chunk) => chunk,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                return Err(HttpError::new(
+                    StatusCode::BAD_REQUEST,
+                    format!("Chunk {index} is missing"),
+                )
+                .into());
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut chunk_hasher = BatchHasher::new(Hasher::default());
+        let mut chunk_stream = FramedRead::new(chunk, BytesCodec::new());
+        while let Some(data) = chunk_stream.next().await {
+            let data = data?;
+            hasher.update(&data).await;
+            chunk_hasher.update(&data).await;
+            file_writer
+                .append_chunk(&data)
+                .await
+                .map_err(|err| map_storage_full(err.into()))?;
+            total_size += data.len() as u64;
+        }
+
+        if encode_chunk_hash(chunk_hasher.finish().await) != *expected_hash {
+            return Err(HttpError::new(
+                StatusCode::BAD_REQUEST,
+                format!("Chunk {index} doesn't match the manifest"),
+            )
+            .into());
+        }
+    }
+
+    if total_size != manifest.total_size {
+        return Err(HttpError::new(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Assembled file size {total_size} doesn't match the manifest size {}",
+                manifest.total_size
+            ),
+        )
+        .into());
+    }
+
+    scheme.verify_file(pubkey, hasher.finish().await, file_signature)?;
+    Ok((file_writer, chunks_dir))
+}
+
+pub async fn batch(auth: AuthHeaders, body: Bytes) -> Response {
+    let (pubkey, filename) = (auth.pubkey.clone(), auth.filename.clone());
+    let result = batch_internal(auth, body).await;
+    process_transfer_result(METHOD_BATCH, &pubkey, &filename, result).await
+}
+
+/// Applies the [`BatchPlan`] in the body at once. The request is signed with [`signed_body_name`]
+/// of the body in place of the filename, the uploads are assembled from their uploaded chunks
+/// first and the files are only changed once all of them check out.
+async fn batch_internal(auth: AuthHeaders, body: Bytes) -> Result<impl Reply> {
+    let batch_request = verify_request(METHOD_BATCH, auth).await?;
+    if batch_request.filename() != signed_body_name(&body) {
+        return Err(HttpError::new(
+            StatusCode::UNAUTHORIZED,
+            "Request signature doesn't cover the body",
+        )
+        .into());
+    }
+
+    let plan: BatchPlan = serde_json::from_slice(&body)
+        .map_err(|err| HttpError::new(StatusCode::BAD_REQUEST, format!("Invalid body: {err}")))?;
+    info!(
+        "Batch of {} uploads and {} deletes",
+        plan.uploads.len(),
+        plan.deletes.len()
+    );
+    let pubkey = batch_request.pubkey();
+
+    let mut filenames = HashSet::new();
+    let mut deletes = Vec::with_capacity(plan.deletes.len());
+    for filename in &plan.deletes {
+        let filename = storage_filename(filename);
+        AUTHORIZER
+            .authorize(pubkey, METHOD_DELETE, &filename)
+            .await?;
+        deletes.push(filename);
+    }
+    let mut uploads = Vec::with_capacity(plan.uploads.len());
+    for upload in &plan.uploads {
+        let filename = storage_filename(&upload.filename);
+        AUTHORIZER
+            .authorize(pubkey, METHOD_UPLOAD, &filename)
+            .await?;
+        check_extension(&filename)?;
+        uploads.push(filename);
+    }
+    for filename in uploads.iter().chain(&deletes) {
+        if !filenames.insert(filename) {
+            return Err(HttpError::new(
+                StatusCode::BAD_REQUEST,
+                format!("File is listed more than once: {filename}"),
+            )
+            .into());
+        }
+    }
+
+    let mut staged = Vec::with_capacity(uploads.len());
+    let mut chunks_dirs = Vec::with_capacity(uploads.len());
+    for (upload, filename) in plan.uploads.iter().zip(uploads) {
+        EVENT_SINK
+            .handle(Event::upload_started(
+                pubkey,
+                &filename,
+                upload.manifest.total_size,
+            ))
+            .await;
+        let scheme = parse_file_signature_scheme(upload.file_signature_scheme.clone())?;
+        let signature = decode_signature(PARAM_FILE_SIGNATURE, &upload.file_signature)?;
+        let (writer, chunks_dir) =
+            assemble_chunks(pubkey, &filename, &upload.manifest, &signature, scheme).await?;
+        staged.push(storage::StagedFile {
+            filename,
+            writer,
+            signature,
+            scheme,
+        });
+        chunks_dirs.push(chunks_dir);
+    }
+
+    let finalized = staged
+        .iter()
+        .map(|file| Event::upload_finalized(pubkey, &file.filename, file.writer.written()))
+        .collect::<Vec<_>>();
+    storage::commit_batch(pubkey, staged, &deletes)
+        .await
+        .map_err(map_storage_full)?;
+    for event in finalized {
+        EVENT_SINK.handle(event).await;
+    }
+    for chunks_dir in chunks_dirs {
+        tokio::fs::remove_dir_all(&chunks_dir).await?;
+    }
+
+    Ok(with_quota_headers(http::Response::builder(), pubkey)
+        .await?
+        .body(Body::empty())?)
+}
+
+/// Adds the pubkey's storage usage and quota headers when a quota is configured.
+async fn with_quota_headers(
+    response: http::response::Builder,
+    pubkey: &VerifyingKey,
+) -> Result<http::response::Builder> {
+    let Some(quota) = CONFIG.for_pubkey(pubkey).max_bytes else {
+        return Ok(response);
+    };
+
+    let used = storage::used_bytes(&CONFIG.storage_path, pubkey).await?;
+    Ok(response
+        .header(HeaderName::from_static(PARAM_BYTES_USED), used)
+        .header(HeaderName::from_static(PARAM_BYTES_QUOTA), quota))
+}
+
+/// Parses the optional `If-Match` header holding the signature of the version the client expects
+/// to replace or delete.
+fn parse_if_match(if_match: Option<String>) -> Result<Option<Signature>> {
+    let Some(if_match) = if_match else {
+        return Ok(None);
+    };
+
+    info!("Expected current signature: {if_match}");
+    decode_signature(PARAM_IF_MATCH, if_match.trim().trim_matches('"')).map(Some)
+}
+
+/// Hashes the passphrase an upload is protected with, if it has one.
+async fn hash_passphrase(passphrase: Option<String>) -> Result<Option<String>> {
+    match passphrase {
+        Some(passphrase) => Ok(Some(passphrase::hash(passphrase).await?)),
+        None => Ok(None),
+    }
+}
+
+/// Scheme of the file signature of an upload, the default when the client didn't name one. Fails
+/// with 400 naming the supported schemes when it isn't one of them, so the client can pick another.
+fn parse_file_signature_scheme(scheme: Option<String>) -> Result<SignatureScheme> {
+    let unsupported = |scheme: &str| {
+        HttpError::new(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Unsupported file signature scheme: {scheme}, supported: {}",
+                SignatureScheme::list(SignatureScheme::FILE_SCHEMES)
+            ),
+        )
+    };
+    let parsed = SignatureScheme::from_header(scheme.as_deref(), SignatureScheme::FILE_DEFAULT)
+        .map_err(|_| unsupported(scheme.as_deref().unwrap_or_default()))?;
+    if !SignatureScheme::FILE_SCHEMES.contains(&parsed) {
+        return Err(unsupported(parsed.as_str()).into());
+    }
+    Ok(parsed)
+}
+
+/// Rejects storing a file under `filename` with 415 when its extension isn't allowed by
+/// `allowed_extensions` and `blocked_extensions`.
+fn check_extension(filename: &str) -> Result<()> {
+    if extension_allowed(
+        filename,
+        CONFIG.allowed_extensions.as_deref(),
+        &CONFIG.blocked_extensions,
+    ) {
+        return Ok(());
+    }
+    Err(HttpError::new(
+        StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        format!("File extension not allowed: {filename}"),
+    )
+    .into())
+}
+
+fn extension_allowed(filename: &str, allowed: Option<&[String]>, blocked: &[String]) -> bool {
+    let name = filename.rsplit('/').next().unwrap_or(filename);
+    let extension = match name.rsplit_once('.') {
+        // A leading dot starts a hidden name rather than an extension
+        Some((stem, extension)) if !stem.is_empty() => extension,
+        _ => "",
+    };
+    let matches = |listed: &String| {
+        listed
+            .trim_start_matches('.')
+            .eq_ignore_ascii_case(extension)
+    };
+
+    if !extension.is_empty() && blocked.iter().any(matches) {
+        return false;
+    }
+    allowed.is_none_or(|allowed| allowed.iter().any(matches))
+}
+
+fn empty_file_error() -> anyhow::Error {
+    HttpError::new(StatusCode::BAD_REQUEST, "Empty files are not accepted").into()
+}
+
+/// Parses the Base58 digest claimed by the client for the uploaded file.
+fn parse_file_digest(file_digest: &str) -> Result<Prehashed> {
+    info!("Claimed file digest: {file_digest}");
+    Prehashed::from_slice(&decode_base58(
+        PARAM_FILE_DIGEST,
+        file_digest.trim(),
+        DIGEST_LENGTH,
+    )?)
+    .map_err(|_| invalid_header_error(PARAM_FILE_DIGEST))
+}
+
+/// Decodes the Base58 value of `header`, which holds `len` bytes. Values longer than any encoding
+/// of that many bytes are rejected with 400 before decoding, so oversized headers can't make the
+/// server allocate for them.
+fn decode_base58(header: &str, value: &str, len: usize) -> Result<Vec<u8>> {
+    // Each Base58 character carries log2(58) > 5.85 bits, one character per leading zero byte
+    let max_len = len * 138 / 100 + 1;
+    if value.len() > max_len {
+        return Err(HttpError::new(
+            StatusCode::BAD_REQUEST,
+            format!("{header} header is too long"),
+        )
+        .into());
+    }
+    bs58::decode(value)
+        .into_vec()
+        .map_err(|_| invalid_header_error(header))
+}
+
+/// Decodes a Base58 signature sent in `header`, failing with 400 if it isn't one.
+fn decode_signature(header: &str, value: &str) -> Result<Signature> {
+    Signature::from_slice(&decode_base58(header, value, SIGNATURE_LENGTH)?)
+        .map_err(|_| invalid_header_error(header))
+}
+
+fn invalid_header_error(header: &str) -> anyhow::Error {
+    HttpError::new(StatusCode::BAD_REQUEST, format!("Invalid {header} header")).into()
+}
+
+/// Writes the body to the file, also feeding it to `hasher` unless it's `None`.
 async fn write_body(
     file_writer: &mut FileWriter,
-    hasher: &mut Hasher,
+    mut hasher: Option<&mut BatchHasher<Hasher>>,
     mut body: impl Stream<Item = Result<impl Buf, warp::Error>> + Unpin,
 ) -> Result<()> {
     while let Some(buf) = body.next().await {
-        let mut buf = buf?;
+        let mut buf = buf.map_err(UploadInterrupted)?;
         while buf.remaining() > 0 {
             let chunk = buf.chunk();
-            hasher.update(chunk);
+            // Hashed once it's in the file, so the hash always matches what's stored
             file_writer.append_chunk(chunk).await?;
+            if let Some(hasher) = hasher.as_deref_mut() {
+                hasher.update(chunk).await;
+            }
             buf.advance(chunk.len());
         }
     }
     Ok(())
 }
 
+/// Logs a failed upload body, client disconnects without alarm since they're routine.
+fn log_write_error(err: &anyhow::Error, written: u64) {
+    if err.is::<UploadInterrupted>() {
+        info!("{err} after {written} bytes");
+    } else {
+        error!("File write error after {written} bytes: {err:?}");
+    }
+}
+
+/// Replaces out-of-space I/O errors with `507 Insufficient Storage` so the client can tell them
+/// apart from other failures.
+fn map_storage_full(err: anyhow::Error) -> anyhow::Error {
+    let storage_full = err
+        .chain()
+        .filter_map(|err| err.downcast_ref::<std::io::Error>())
+        .any(|err| {
+            matches!(
+                err.kind(),
+                ErrorKind::StorageFull | ErrorKind::QuotaExceeded | ErrorKind::FileTooLarge
+            )
+        });
+
+    if storage_full {
+        HttpError::new(
+            StatusCode::INSUFFICIENT_STORAGE,
+            "Not enough storage space on the server to store the file",
+        )
+        .into()
+    } else {
+        err
+    }
+}
+
 fn process_result(result: Result<impl Reply>) -> Response {
     match result {
         Ok(res) => res.into_response(),
         Err(error) => {
-            error!("{}", error);
-            warp::reply::with_status(error.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
-                .into_response()
+            if error.is::<UploadInterrupted>() {
+                info!("{}", error);
+            } else {
+                error!("{}", error);
+            }
+            warp::reply::with_status(error.to_string(), error_status(&error)).into_response()
+        }
+    }
+}
+
+/// Like [`process_result`] for uploads and downloads, reporting failures to the event sink with
+/// the pubkey and filename headers of the request.
+async fn process_transfer_result(
+    method: &'static str,
+    pubkey: &HeaderValue,
+    filename: &HeaderValue,
+    result: Result<impl Reply>,
+) -> Response {
+    if let Err(error) = &result {
+        let pubkey = String::from_utf8_lossy(pubkey.as_bytes()).into_owned();
+        let filename = String::from_utf8_lossy(filename.as_bytes()).into_owned();
+        let event = if error.is::<UploadInterrupted>() {
+            Event::UploadInterrupted {
+                method,
+                pubkey,
+                filename,
+            }
+        } else {
+            Event::RequestFailed {
+                method,
+                pubkey,
+                filename,
+                status: error_status(error).as_u16(),
+                message: error.to_string(),
+            }
+        };
+        EVENT_SINK.handle(event).await;
+    }
+    process_result(result)
+}
+
+fn error_status(error: &anyhow::Error) -> StatusCode {
+    if let Some(error) = error.downcast_ref::<UploadInterrupted>() {
+        return error.status();
+    }
+    error
+        .downcast_ref::<HttpError>()
+        .map(HttpError::status)
+        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extensions(list: &[&str]) -> Vec<String> {
+        list.iter().map(|extension| extension.to_string()).collect()
+    }
+
+    #[test]
+    fn extension_allowed_by_allow_list() {
+        let allowed = extensions(&["txt", ".pdf"]);
+        assert!(extension_allowed("notes.txt", Some(&allowed), &[]));
+        assert!(extension_allowed("docs/report.pdf", Some(&allowed), &[]));
+        assert!(!extension_allowed("photo.jpg", Some(&allowed), &[]));
+        assert!(!extension_allowed("README", Some(&allowed), &[]));
+        assert!(extension_allowed("photo.jpg", None, &[]));
+    }
+
+    #[test]
+    fn extension_blocked_by_block_list() {
+        let blocked = extensions(&["exe", ".sh"]);
+        assert!(!extension_allowed("setup.exe", None, &blocked));
+        assert!(!extension_allowed("bin/run.sh", None, &blocked));
+        assert!(extension_allowed("notes.txt", None, &blocked));
+        // Hidden names and names without an extension aren't matched
+        assert!(extension_allowed(".exe", None, &blocked));
+        assert!(extension_allowed("exe", None, &blocked));
+
+        let allowed = extensions(&["exe"]);
+        assert!(!extension_allowed("setup.exe", Some(&allowed), &blocked));
+    }
+
+    #[test]
+    fn extension_matched_case_insensitively() {
+        let allowed = extensions(&["TXT"]);
+        let blocked = extensions(&["Exe"]);
+        assert!(extension_allowed("notes.txt", Some(&allowed), &blocked));
+        assert!(extension_allowed("NOTES.Txt", Some(&allowed), &blocked));
+        assert!(!extension_allowed("setup.EXE", None, &blocked));
+        assert!(!extension_allowed("setup.exe", None, &blocked));
+    }
+
+    fn assert_bad_request<T>(result: Result<T>, message: &str) {
+        let err = result.err().expect("should be rejected");
+        assert_eq!(error_status(&err), StatusCode::BAD_REQUEST);
+        assert_eq!(err.to_string(), message);
+    }
+
+    #[test]
+    fn filename_header_length_is_bounded() {
+        let longest = HeaderValue::from_str(&"a".repeat(MAX_FILENAME_LENGTH)).unwrap();
+        assert_eq!(
+            filename_header(PARAM_FILENAME, &longest).unwrap().len(),
+            MAX_FILENAME_LENGTH
+        );
+
+        let too_long = HeaderValue::from_str(&"a".repeat(MAX_FILENAME_LENGTH + 1)).unwrap();
+        assert_bad_request(
+            filename_header(PARAM_FILENAME, &too_long),
+            "filename header is too long",
+        );
+        assert_bad_request(
+            filename_header(PARAM_DESTINATION, &too_long),
+            "destination header is too long",
+        );
+    }
+
+    #[test]
+    fn signature_header_length_is_bounded() {
+        let signature = Signature::from_bytes(&[0xff; SIGNATURE_LENGTH]);
+        let encoded = bs58::encode(signature.to_bytes()).into_string();
+        assert_eq!(
+            decode_signature(PARAM_FILE_SIGNATURE, &encoded).unwrap(),
+            signature
+        );
+
+        // Leading ones decode to zero bytes, so the value would decode if it weren't cut short
+        let too_long = format!("{}{encoded}", "1".repeat(10_000));
+        assert_bad_request(
+            decode_signature(PARAM_FILE_SIGNATURE, &too_long),
+            "file-signature header is too long",
+        );
+        assert_bad_request(
+            decode_signature(PARAM_REQUEST_SIGNATURE, &"z".repeat(200)),
+            "request-signature header is too long",
+        );
+        assert_bad_request(
+            decode_signature(PARAM_REQUEST_SIGNATURE, &format!("0{}", &encoded[1..])),
+            "Invalid request-signature header",
+        );
+    }
+
+    fn mapped_status(err: std::io::Error) -> StatusCode {
+        let err = anyhow::Error::from(err).context("File write error");
+        error_status(&map_storage_full(err))
+    }
+
+    #[test]
+    fn storage_full_maps_to_507() {
+        for kind in [ErrorKind::StorageFull, ErrorKind::QuotaExceeded] {
+            assert_eq!(mapped_status(kind.into()), StatusCode::INSUFFICIENT_STORAGE);
+        }
+        assert_eq!(
+            mapped_status(ErrorKind::PermissionDenied.into()),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn enospc_maps_to_507() {
+        const ENOSPC: i32 = 28;
+        assert_eq!(
+            mapped_status(std::io::Error::from_raw_os_error(ENOSPC)),
+            StatusCode::INSUFFICIENT_STORAGE
+        );
+    }
+
+    #[test]
+    fn parse_range_serves_valid_ranges() {
+        assert_eq!(parse_range("bytes=0-", 10), RangeRequest::Satisfiable(0, 9));
+        assert_eq!(
+            parse_range("bytes=2-5", 10),
+            RangeRequest::Satisfiable(2, 5)
+        );
+        assert_eq!(
+            parse_range("bytes=9-9", 10),
+            RangeRequest::Satisfiable(9, 9)
+        );
+        assert_eq!(
+            parse_range("bytes=4-100", 10),
+            RangeRequest::Satisfiable(4, 9)
+        );
+        assert_eq!(
+            parse_range("Bytes= 3 - 4", 10),
+            RangeRequest::Satisfiable(3, 4)
+        );
+    }
+
+    #[test]
+    fn parse_range_rejects_ranges_past_the_end() {
+        assert_eq!(parse_range("bytes=10-", 10), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=20-30", 10), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), RangeRequest::Unsatisfiable);
+    }
+
+    #[test]
+    fn parse_range_ignores_unsupported_ranges() {
+        for range in [
+            "bytes=-500",
+            "bytes=0-1,4-5",
+            "items=0-",
+            "bytes=5-2",
+            "bytes=a-b",
+            "bytes=",
+            "bytes 0-1",
+            "",
+        ] {
+            assert_eq!(parse_range(range, 10), RangeRequest::Ignored, "{range}");
         }
     }
 }
@@ -0,0 +1,97 @@
+use ed25519_dalek::ed25519::signature::digest::Update;
+#[cfg(feature = "parallel-hashing")]
+use once_cell::sync::Lazy;
+
+#[cfg(feature = "parallel-hashing")]
+use crate::CONFIG;
+
+/// Amount of data gathered before it's hashed, so each hand-off to a blocking thread carries
+/// enough work to be worth it.
+const BATCH_SIZE: usize = 1024 * 1024;
+
+/// Dedicated pool, so hashing concurrent uploads doesn't compete with anything else using the
+/// global rayon pool.
+#[cfg(feature = "parallel-hashing")]
+static HASHING_POOL: Lazy<rayon::ThreadPool> = Lazy::new(|| {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(CONFIG.hashing_threads.unwrap_or(0))
+        .thread_name(|index| format!("hashing-{index}"))
+        .build()
+        .expect("Failed to build hashing thread pool")
+});
+
+/// Gathers the data fed to a hasher into batches and hashes each of them on a blocking thread, on
+/// the hashing pool when parallel hashing is enabled, so hashing never holds up the async
+/// runtime. The hasher is boxed, since it's moved to the blocking thread and back and Blake3
+/// hashers held across awaits make the upload futures overflow the stack of debug builds.
+#[derive(Debug)]
+pub struct BatchHasher<H> {
+    /// Only `None` while a batch is being hashed.
+    hasher: Option<Box<H>>,
+    batch: Vec<u8>,
+}
+
+impl<H: Update + Send + 'static> BatchHasher<H> {
+    pub fn new(hasher: H) -> Self {
+        Self {
+            hasher: Some(Box::new(hasher)),
+            batch: Vec::new(),
+        }
+    }
+
+    pub async fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let size = (BATCH_SIZE - self.batch.len()).min(data.len());
+            self.batch.extend_from_slice(&data[..size]);
+            if self.batch.len() == BATCH_SIZE {
+                self.flush().await;
+            }
+            data = &data[size..];
+        }
+    }
+
+    /// Hashes the data gathered so far and returns the hasher, e.g. to peek at the digest.
+    pub async fn hashed(&mut self) -> &H {
+        self.flush().await;
+        self.hasher
+            .as_deref()
+            .expect("Hasher lost to a cancelled batch")
+    }
+
+    /// Hashes the remaining data and returns the hasher.
+    pub async fn finish(mut self) -> H {
+        self.flush().await;
+        *self
+            .hasher
+            .take()
+            .expect("Hasher lost to a cancelled batch")
+    }
+
+    async fn flush(&mut self) {
+        if self.batch.is_empty() {
+            return;
+        }
+        let mut hasher = self
+            .hasher
+            .take()
+            .expect("Hasher lost to a cancelled batch");
+        let mut batch = std::mem::take(&mut self.batch);
+        let (hasher, batch) = tokio::task::spawn_blocking(move || {
+            update(&mut *hasher, &batch);
+            batch.clear();
+            (hasher, batch)
+        })
+        .await
+        .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()));
+        self.hasher = Some(hasher);
+        // Kept for the next batch, so it isn't allocated again
+        self.batch = batch;
+    }
+}
+
+fn update(hasher: &mut (impl Update + Send), data: &[u8]) {
+    #[cfg(feature = "parallel-hashing")]
+    HASHING_POOL.install(|| hasher.update(data));
+    #[cfg(not(feature = "parallel-hashing"))]
+    hasher.update(data);
+}
@@ -0,0 +1,152 @@
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use log::{info, warn};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use crate::metadata::{write_metadata, FileMetadata};
+
+/// Directory under the storage path holding the entries of installs in progress. Not a valid
+/// pubkey, so listings, trimming and the layout migration skip it.
+const JOURNAL_DIR: &str = ".journal";
+
+/// An upload about to be moved in place, recorded before its sidecar or data is touched. Renames
+/// are recorded the same way, with the source data as the temp file.
+#[derive(Serialize, Deserialize)]
+pub struct InstallEntry {
+    pub temp_path: PathBuf,
+    pub file_path: PathBuf,
+    pub signature_path: PathBuf,
+    /// Size of the uploaded data, tells whether the file in place is the new one once the temp
+    /// file is gone.
+    pub size: u64,
+    pub metadata: FileMetadata,
+    /// Sidecar of the file being replaced, restored if the install can't be completed.
+    pub previous: Option<FileMetadata>,
+}
+
+/// Entry of an install in progress, removed with [`JournalEntry::complete`]. Dropping it leaves
+/// the entry for the recovery pass.
+pub struct JournalEntry {
+    path: PathBuf,
+    signature_path: PathBuf,
+}
+
+/// Durably records `entry` before the install it describes starts.
+pub async fn begin(storage_path: &Path, entry: &InstallEntry) -> Result<JournalEntry> {
+    let dir = storage_path.join(JOURNAL_DIR);
+    tokio::fs::create_dir_all(&dir).await?;
+    let name = format!("{:016x}", rand::thread_rng().next_u64());
+    let path = dir.join(format!("{name}.json"));
+
+    // Written aside and renamed, so the recovery pass never reads a torn entry
+    let temp_path = dir.join(format!("{name}.tmp"));
+    tokio::fs::write(&temp_path, serde_json::to_vec(entry)?).await?;
+    tokio::fs::File::open(&temp_path).await?.sync_all().await?;
+    tokio::fs::rename(&temp_path, &path).await?;
+    sync_dir(&dir).await?;
+    Ok(JournalEntry {
+        path,
+        signature_path: entry.signature_path.clone(),
+    })
+}
+
+impl JournalEntry {
+    /// Flushes the installed sidecar and the renames to disk, then drops the entry.
+    pub async fn complete(self) -> Result<()> {
+        complete(&self.path, &self.signature_path).await
+    }
+}
+
+async fn complete(entry_path: &Path, signature_path: &Path) -> Result<()> {
+    match tokio::fs::File::open(signature_path).await {
+        Ok(file) => file.sync_all().await?,
+        Err(err) if err.kind() == ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+    }
+    if let Some(dir) = signature_path.parent() {
+        sync_dir(dir).await?;
+    }
+    tokio::fs::remove_file(entry_path).await?;
+    Ok(())
+}
+
+/// Finishes or undoes the installs interrupted by a crash, run before the server accepts
+/// requests. An install whose temp file is still there is carried out, one whose data is in
+/// place is completed by rewriting its sidecar. Otherwise the data can't be told apart and the
+/// previous sidecar is restored, or removed if the file is new. Returns the number of entries.
+pub async fn recover(storage_path: &Path) -> Result<usize> {
+    let dir = storage_path.join(JOURNAL_DIR);
+    let mut read_dir = match tokio::fs::read_dir(&dir).await {
+        Ok(read_dir) => read_dir,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut recovered = 0;
+    while let Some(dir_entry) = read_dir.next_entry().await? {
+        let entry_path = dir_entry.path();
+        if entry_path.extension().is_none_or(|ext| ext != "json") {
+            // Entries not renamed in place were never acted upon
+            tokio::fs::remove_file(&entry_path).await?;
+            continue;
+        }
+        let entry: InstallEntry = match serde_json::from_slice(&tokio::fs::read(&entry_path).await?)
+        {
+            Ok(entry) => entry,
+            Err(err) => {
+                warn!("Dropping unreadable journal entry {entry_path:?}: {err}");
+                tokio::fs::remove_file(&entry_path).await?;
+                continue;
+            }
+        };
+        replay(&entry).await?;
+        complete(&entry_path, &entry.signature_path).await?;
+        recovered += 1;
+    }
+    if recovered > 0 {
+        info!("Recovered {recovered} interrupted uploads");
+    }
+    Ok(recovered)
+}
+
+async fn replay(entry: &InstallEntry) -> Result<()> {
+    if tokio::fs::try_exists(&entry.temp_path).await? {
+        write_metadata(&entry.signature_path, &entry.metadata).await?;
+        tokio::fs::rename(&entry.temp_path, &entry.file_path).await?;
+        info!("Completed interrupted upload: {:?}", entry.file_path);
+        return Ok(());
+    }
+
+    let size = match tokio::fs::metadata(&entry.file_path).await {
+        Ok(metadata) => Some(metadata.len()),
+        Err(err) if err.kind() == ErrorKind::NotFound => None,
+        Err(err) => return Err(err.into()),
+    };
+    if size == Some(entry.size) {
+        write_metadata(&entry.signature_path, &entry.metadata).await?;
+        info!("Completed interrupted upload: {:?}", entry.file_path);
+        return Ok(());
+    }
+
+    match &entry.previous {
+        Some(previous) => write_metadata(&entry.signature_path, previous).await?,
+        None => match tokio::fs::remove_file(&entry.signature_path).await {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        },
+    }
+    warn!(
+        "Rolled back interrupted upload, its data is gone: {:?}",
+        entry.file_path
+    );
+    Ok(())
+}
+
+async fn sync_dir(dir: &Path) -> Result<()> {
+    tokio::fs::File::open(dir).await?.sync_all().await?;
+    Ok(())
+}
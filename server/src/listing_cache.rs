@@ -0,0 +1,46 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use ed25519_dalek::VerifyingKey;
+use once_cell::sync::Lazy;
+use shared::listing::FileEntry;
+
+/// Listings of the pubkeys, kept with `cache_listings` until a file of the pubkey changes.
+static CACHE: Lazy<Mutex<ListingCache>> = Lazy::new(Default::default);
+
+#[derive(Default)]
+struct ListingCache {
+    listings: HashMap<VerifyingKey, Vec<FileEntry>>,
+    /// Bumped by every invalidation, so a listing read while files were changing isn't cached.
+    generation: u64,
+}
+
+/// Returns the cached listing of `pubkey`, or the generation to pass to [`insert`] along with
+/// the listing read from the storage.
+pub fn get(pubkey: &VerifyingKey) -> Result<Vec<FileEntry>, u64> {
+    let cache = CACHE.lock().expect("Listing cache lock poisoned");
+    match cache.listings.get(pubkey) {
+        Some(files) => Ok(files.clone()),
+        None => Err(cache.generation),
+    }
+}
+
+/// Caches the listing unless any files changed since [`get`] returned `generation`.
+pub fn insert(pubkey: &VerifyingKey, generation: u64, files: Vec<FileEntry>) {
+    let mut cache = CACHE.lock().expect("Listing cache lock poisoned");
+    if cache.generation == generation {
+        cache.listings.insert(*pubkey, files);
+    }
+}
+
+/// Drops the cached listing of the pubkey when going out of scope, so it's dropped after the
+/// files change even if the change fails halfway.
+pub struct Invalidation<'a>(pub &'a VerifyingKey);
+
+impl Drop for Invalidation<'_> {
+    fn drop(&mut self) {
+        let mut cache = CACHE.lock().expect("Listing cache lock poisoned");
+        cache.listings.remove(self.0);
+        cache.generation += 1;
+    }
+}
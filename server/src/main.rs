@@ -1,75 +1,444 @@
-use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::convert::Infallible;
+use std::time::Duration;
 
-use log::info;
+use hyper::server::{accept, Builder};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::Server;
+use log::{debug, info, log_enabled, warn, Level};
 use once_cell::sync::Lazy;
-use warp::Filter;
+use tokio::net::TcpListener;
+use warp::http::{HeaderMap, HeaderValue, Method};
+use warp::path::FullPath;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
 
 use shared::consts::*;
 
+use crate::config::ServerConfig;
+use crate::handlers::{AuthHeaders, FileHeaders};
+use crate::scheduler::{Priority, Scheduler};
+
+mod auth;
+mod config;
+mod connections;
+mod error;
+mod events;
 mod handlers;
+mod hashing;
+mod journal;
+mod listing_cache;
+mod metadata;
+mod passphrase;
+mod request_timing;
+mod scheduler;
+mod shares;
 mod storage;
+#[cfg(feature = "web-ui")]
+mod web_ui;
+
+const LOG_CONFIG_FILE: &str = "log_config.yml";
+
+pub static CONFIG: Lazy<ServerConfig> =
+    Lazy::new(|| ServerConfig::load().expect("Failed to load server config"));
+
+fn auth_headers() -> impl Filter<Extract = (AuthHeaders,), Error = Rejection> + Clone {
+    // Methods that don't address a single file are signed with an empty filename
+    let filename = warp::header::value(PARAM_FILENAME)
+        .or(warp::any().map(|| HeaderValue::from_static("")))
+        .unify();
+
+    filename
+        .and(warp::header::value(PARAM_PUBKEY))
+        .and(warp::header::value(PARAM_TIME))
+        .and(warp::header::value(PARAM_REQUEST_SIGNATURE))
+        .and(warp::header::optional(PARAM_REQUEST_SIGNATURE_SCHEME))
+        .and(warp::header::optional(PARAM_CLIENT_VERSION))
+        .map(
+            |filename, pubkey, time, request_signature, signature_scheme, client_version| {
+                AuthHeaders {
+                    filename,
+                    pubkey,
+                    time,
+                    request_signature,
+                    signature_scheme,
+                    client_version,
+                }
+            },
+        )
+}
+
+fn file_headers() -> impl Filter<Extract = (FileHeaders,), Error = Rejection> + Clone {
+    warp::header::value(PARAM_FILE_SIGNATURE)
+        .and(warp::header::optional(PARAM_FILE_SIGNATURE_SCHEME))
+        .and(warp::header::optional(PARAM_FILE_PASSPHRASE))
+        .map(|signature, signature_scheme, passphrase| FileHeaders {
+            signature,
+            signature_scheme,
+            passphrase,
+        })
+}
+
+/// Logs the request line and headers with the headers of the response at debug level when
+/// `log_headers` is set. Only file passphrases are redacted, so the log holds every signature
+/// sent.
+fn log_headers(method: Method, path: FullPath, headers: HeaderMap, reply: impl Reply) -> Response {
+    let response = reply.into_response();
+    if CONFIG.log_headers && log_enabled!(Level::Debug) {
+        let format = |headers: &HeaderMap| {
+            headers
+                .iter()
+                .map(|(name, value)| match name.as_str() {
+                    PARAM_FILE_PASSPHRASE => format!("\n  {name}: <redacted>"),
+                    _ => format!("\n  {name}: {}", String::from_utf8_lossy(value.as_bytes())),
+                })
+                .collect::<String>()
+        };
+        debug!(
+            "{method} {}, request headers:{}\nresponse {}, headers:{}",
+            path.as_str(),
+            format(&headers),
+            response.status().as_u16(),
+            format(response.headers())
+        );
+    }
+    response
+}
+
+/// Applies the connection settings of the config, leaving hyper's defaults for the ones not set.
+/// Both HTTP/1.1 and HTTP/2 with prior knowledge are served on the same port.
+fn configure<I>(builder: Builder<I>) -> Builder<I> {
+    let mut builder = builder.http1_keepalive(CONFIG.http1_keep_alive);
+    if let Some(secs) = CONFIG.http1_header_read_timeout_secs {
+        builder = builder.http1_header_read_timeout(Duration::from_secs(secs));
+    }
+    if let Some(max_streams) = CONFIG.http2_max_concurrent_streams {
+        builder = builder.http2_max_concurrent_streams(max_streams);
+    }
+    if let Some(secs) = CONFIG.http2_keep_alive_interval_secs {
+        builder = builder.http2_keep_alive_interval(Duration::from_secs(secs));
+    }
+    if let Some(secs) = CONFIG.http2_keep_alive_timeout_secs {
+        builder = builder.http2_keep_alive_timeout(Duration::from_secs(secs));
+    }
+    builder
+}
+
+async fn shutdown() {
+    tokio::signal::ctrl_c()
+        .await
+        .expect("Failed to listen to shutdown signal");
+    info!("CTRL+C received");
+}
 
-#[derive(Debug, serde::Deserialize)]
-pub struct ServerConfig {
-    listen_addr: SocketAddr,
-    max_file_size: u64,
-    storage_path: PathBuf,
+fn background_tasks() -> Scheduler {
+    let mut scheduler = Scheduler::new(CONFIG.max_background_tasks.get());
+    scheduler.add(
+        "temp file sweep",
+        Duration::from_secs(CONFIG.temp_sweep_interval_secs),
+        Priority::High,
+        storage::sweep_stale_temp_files,
+    );
+    scheduler.add(
+        "share link sweep",
+        Duration::from_secs(CONFIG.share_sweep_interval_secs),
+        Priority::Low,
+        shares::sweep,
+    );
+    scheduler
 }
 
-pub static CONFIG: Lazy<ServerConfig> = Lazy::new(|| {
-    serde_json::from_str(
-        &std::fs::read_to_string("server_config.json").expect("Failed to read server config file"),
-    )
-    .map(|mut config: ServerConfig| {
-        config.storage_path = config
-            .storage_path
-            .canonicalize()
-            .expect("Failed to canonicalize storage path");
-        config
-    })
-    .expect("Failed to parse server config file")
-});
+/// Reads the server and logging configs and reports every problem found without starting the
+/// server, for checking a config before deploying it. Returns the exit code.
+async fn check_config() -> i32 {
+    let mut problems = Vec::new();
+    if let Err(err) = log4rs::config::load_config_file(LOG_CONFIG_FILE, Default::default()) {
+        problems.push(format!("{LOG_CONFIG_FILE}: {err:#}"));
+    }
+    match ServerConfig::read() {
+        Ok(config) => {
+            problems.extend(config.problems());
+            if config.storage_path.is_dir() {
+                if let Err(err) = storage::check_writable(&config.storage_path).await {
+                    problems.push(format!("{err:#}"));
+                }
+            }
+        }
+        Err(err) => problems.push(format!("{err:#}")),
+    }
+
+    if problems.is_empty() {
+        println!("Config OK");
+        return 0;
+    }
+    for problem in &problems {
+        println!("{problem}");
+    }
+    eprintln!("Config check failed, problems found: {}", problems.len());
+    1
+}
 
 #[tokio::main]
 async fn main() {
-    log4rs::init_file("log_config.yml", Default::default()).expect("Error initializing logging");
+    match std::env::args().nth(1).as_deref() {
+        None => {}
+        Some("--check-config") => std::process::exit(check_config().await),
+        Some(arg) => {
+            eprintln!("Unknown argument {arg}, the only one accepted is --check-config");
+            std::process::exit(2);
+        }
+    }
+
+    log4rs::init_file(LOG_CONFIG_FILE, Default::default()).expect("Error initializing logging");
+
+    storage::check_writable(&CONFIG.storage_path)
+        .await
+        .expect("Storage check failed");
+    journal::recover(&CONFIG.storage_path)
+        .await
+        .expect("Failed to recover interrupted uploads");
+    storage::migrate_sidecars(&CONFIG.storage_path)
+        .await
+        .expect("Failed to migrate signature sidecars");
+    storage::migrate_layout(&CONFIG.storage_path, CONFIG.layout)
+        .await
+        .expect("Failed to migrate storage layout");
+    if CONFIG.log_headers {
+        warn!(
+            "Logging the headers of every request and response at debug level, signatures included"
+        );
+    }
+    if CONFIG.background_tasks {
+        tokio::spawn(background_tasks().run());
+    } else {
+        info!("Background tasks are disabled");
+    }
 
     let download = warp::path(METHOD_DOWNLOAD)
-        .and(warp::header::value(PARAM_FILENAME))
-        .and(warp::header::value(PARAM_PUBKEY))
-        .and(warp::header::value(PARAM_TIME))
-        .and(warp::header::value(PARAM_REQUEST_SIGNATURE))
-        // .and(warp::header::headers_cloned())
+        .and(auth_headers())
+        .and(warp::header::optional::<String>("range"))
+        .and(warp::header::optional::<String>(PARAM_FILE_PASSPHRASE))
         .then(handlers::download);
 
+    let share = warp::post().and(
+        warp::path(METHOD_SHARE)
+            .and(auth_headers())
+            .and(warp::header::optional::<u64>(PARAM_EXPIRES_IN))
+            .and(warp::header::optional::<u64>(PARAM_MAX_DOWNLOADS))
+            .then(handlers::share),
+    );
+
+    let shares = warp::path(METHOD_SHARES)
+        .and(auth_headers())
+        .then(handlers::list_shares);
+
+    let revoke_share = warp::post().and(
+        warp::path(METHOD_REVOKE_SHARE)
+            .and(auth_headers())
+            .then(handlers::revoke_share),
+    );
+
+    // Public, the token in the path is the only credential
+    let shared = warp::get().and(
+        warp::path(METHOD_SHARED)
+            .and(warp::path::param::<String>())
+            .and(warp::path::end())
+            .and(warp::header::optional::<String>("range"))
+            .and(warp::header::optional::<String>(PARAM_FILE_PASSPHRASE))
+            .then(handlers::shared_download),
+    );
+
+    let signature = warp::path(METHOD_SIGNATURE)
+        .and(auth_headers())
+        .then(handlers::signature);
+
+    let block_hashes = warp::path(METHOD_BLOCK_HASHES)
+        .and(auth_headers())
+        .then(handlers::block_hashes);
+
+    let outboard = warp::path(METHOD_OUTBOARD)
+        .and(auth_headers())
+        .then(handlers::outboard);
+
+    let signatures = warp::post().and(
+        warp::path(METHOD_SIGNATURES)
+            .and(auth_headers())
+            .and(warp::body::content_length_limit(CONFIG.max_manifest_size))
+            .and(warp::body::bytes())
+            .then(handlers::signatures),
+    );
+
+    let list = warp::path(METHOD_LIST)
+        .and(auth_headers())
+        .and(warp::header::optional::<u64>(PARAM_SINCE))
+        .and(warp::header::optional::<String>("accept"))
+        .then(handlers::list);
+
+    let admin_users = warp::path(METHOD_ADMIN_USERS)
+        .and(auth_headers())
+        .then(handlers::admin_users);
+
+    let admin_trim = warp::post().and(
+        warp::path(METHOD_ADMIN_TRIM)
+            .and(auth_headers())
+            .then(handlers::admin_trim),
+    );
+
+    let prune = warp::post().and(
+        warp::path(METHOD_PRUNE)
+            .and(auth_headers())
+            .then(handlers::prune),
+    );
+
+    let rename = warp::post().and(
+        warp::path(METHOD_RENAME)
+            .and(auth_headers())
+            .and(warp::header::value(PARAM_DESTINATION))
+            .and(warp::header::value(PARAM_DESTINATION_SIGNATURE))
+            .then(handlers::rename),
+    );
+
+    let copy = warp::post().and(
+        warp::path(METHOD_COPY)
+            .and(auth_headers())
+            .and(warp::header::value(PARAM_DESTINATION))
+            .and(warp::header::value(PARAM_DESTINATION_SIGNATURE))
+            .then(handlers::copy),
+    );
+
+    let delete = warp::post().and(
+        warp::path(METHOD_DELETE)
+            .and(auth_headers())
+            .and(warp::header::optional::<String>(PARAM_IF_MATCH))
+            .then(handlers::delete),
+    );
+
+    let update_signature = warp::post().and(
+        warp::path(METHOD_UPDATE_SIGNATURE)
+            .and(auth_headers())
+            .and(warp::header::value(PARAM_FILE_SIGNATURE))
+            .and(warp::header::optional::<String>(
+                PARAM_FILE_SIGNATURE_SCHEME,
+            ))
+            .and(warp::header::optional::<String>(PARAM_IF_MATCH))
+            .then(handlers::update_signature),
+    );
+
     let upload = warp::post().and(
         warp::path(METHOD_UPLOAD)
-            .and(warp::header::value(PARAM_FILENAME))
-            .and(warp::header::value(PARAM_PUBKEY))
-            .and(warp::header::value(PARAM_TIME))
-            .and(warp::header::value(PARAM_REQUEST_SIGNATURE))
-            .and(warp::header::value(PARAM_FILE_SIGNATURE))
-            .and(warp::body::stream())
+            .and(auth_headers())
+            .and(file_headers())
+            .and(warp::header::optional::<String>(PARAM_FILE_DIGEST))
+            .and(warp::header::optional::<String>(PARAM_IF_MATCH))
             .and(warp::body::content_length_limit(CONFIG.max_file_size))
+            .and(warp::header::<u64>("content-length"))
+            .and(warp::body::stream())
             .then(handlers::upload),
     );
 
-    let routes = download.or(upload);
+    let upload_chunk = warp::post().and(
+        warp::path(METHOD_UPLOAD_CHUNK)
+            .and(auth_headers())
+            .and(warp::header::header(PARAM_CHUNK_INDEX))
+            .and(warp::header::header(PARAM_CHUNK_HASH))
+            .and(warp::body::stream())
+            .and(warp::body::content_length_limit(
+                CONFIG.max_chunk_size.unwrap_or(CONFIG.max_file_size),
+            ))
+            .then(handlers::upload_chunk),
+    );
 
-    let (addr, web_server) =
-        warp::serve(routes).bind_with_graceful_shutdown(CONFIG.listen_addr, async move {
-            tokio::signal::ctrl_c()
-                .await
-                .expect("Failed to listen to shutdown signal");
-            info!("CTRL+C received");
-        });
+    let upload_manifest = warp::post().and(
+        warp::path(METHOD_UPLOAD_MANIFEST)
+            .and(auth_headers())
+            .and(file_headers())
+            .and(warp::header::optional::<String>(PARAM_IF_MATCH))
+            .and(warp::body::content_length_limit(CONFIG.max_manifest_size))
+            .and(warp::body::json())
+            .then(handlers::upload_manifest),
+    );
 
-    let web_server_task = tokio::task::spawn(web_server);
+    let batch = warp::post().and(
+        warp::path(METHOD_BATCH)
+            .and(auth_headers())
+            .and(warp::body::content_length_limit(CONFIG.max_manifest_size))
+            .and(warp::body::bytes())
+            .then(handlers::batch),
+    );
+
+    // Boxed in groups, a single chain of every route nests deep enough to overflow the stack
+    // of debug builds
+    let read_routes = download
+        .or(signature)
+        .or(signatures)
+        .or(block_hashes)
+        .or(outboard)
+        .or(list)
+        .or(admin_users)
+        .boxed();
+    let write_routes = admin_trim
+        .or(prune)
+        .or(rename)
+        .or(copy)
+        .or(delete)
+        .or(update_signature)
+        .or(upload)
+        .or(upload_chunk)
+        .or(upload_manifest)
+        .or(batch)
+        .boxed();
+    let share_routes = share.or(shared).or(shares).or(revoke_share).boxed();
+    let routes = read_routes.or(write_routes).or(share_routes);
 
-    info!("Started web server on {addr}");
+    #[cfg(feature = "web-ui")]
+    let routes = routes.or(web_ui::route());
+    let routes = warp::method()
+        .and(warp::path::full())
+        .and(warp::header::headers_cloned())
+        .and(routes)
+        .map(log_headers);
+
+    let service = warp::service(routes);
+    let web_server_task = match CONFIG.max_connections {
+        Some(max_connections) => {
+            let listener = TcpListener::bind(CONFIG.listen_addr)
+                .await
+                .expect("Failed to bind listen address");
+            let addr = listener.local_addr().expect("Failed to get listen address");
+            let incoming = connections::limit_connections(listener, max_connections);
+            info!("Started web server on {addr}, accepting up to {max_connections} connections");
+            tokio::task::spawn(
+                configure(Server::builder(accept::from_stream(incoming)))
+                    .serve(make_service_fn(move |_| {
+                        let service = service.clone();
+                        async move {
+                            Ok::<_, Infallible>(service_fn(move |request| {
+                                request_timing::serve(service.clone(), request)
+                            }))
+                        }
+                    }))
+                    .with_graceful_shutdown(shutdown()),
+            )
+        }
+        None => {
+            let builder = Server::try_bind(&CONFIG.listen_addr)
+                .expect("Failed to bind listen address")
+                .tcp_nodelay(true);
+            let web_server = configure(builder).serve(make_service_fn(move |_| {
+                let service = service.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |request| {
+                        request_timing::serve(service.clone(), request)
+                    }))
+                }
+            }));
+            info!("Started web server on {}", web_server.local_addr());
+            tokio::task::spawn(web_server.with_graceful_shutdown(shutdown()))
+        }
+    };
 
-    tokio::join!(web_server_task).0.expect("Failed to run task");
+    if let Err(err) = tokio::join!(web_server_task).0.expect("Failed to run task") {
+        warn!("Web server failed: {err}");
+    }
 
     info!("Gracefully shut down");
 }
@@ -0,0 +1,202 @@
+use std::io::ErrorKind;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Result};
+use ed25519_dalek::{Signature, SIGNATURE_LENGTH};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use shared::chunks::BlockHashes;
+use shared::outboard::Outboard;
+use shared::SignatureScheme;
+use warp::http::StatusCode;
+
+use crate::error::HttpError;
+
+pub const METADATA_VERSION: u32 = 1;
+
+/// Ed25519 signature over the prehashed Blake3 digest of the file contents.
+pub const ALGORITHM_ED25519_BLAKE3: &str = "ed25519-blake3";
+
+/// Contents of the `.sig` sidecar stored next to every file.
+///
+/// Older servers wrote the raw 64 signature bytes instead, those files are still readable and get
+/// reported with no upload time.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileMetadata {
+    pub version: u32,
+    /// Scheme of the file signature, see [`FileMetadata::scheme`].
+    pub algorithm: String,
+    /// Argon2 hash, as a PHC string, of the passphrase downloads of the file must send. Not set
+    /// for files stored without one. Written as `null` then, and ahead of the signature, so a
+    /// sidecar cut short after the signature still tells whether the file is protected, see
+    /// [`FileMetadata::salvage`].
+    #[serde(default)]
+    pub passphrase_hash: Option<String>,
+    /// Base58 encoded file signature.
+    pub signature: String,
+    /// Seconds since the Unix epoch when the file was finalized.
+    #[serde(default)]
+    pub uploaded_at: Option<u64>,
+    /// Missing for files stored before block hashes were recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block_hashes: Option<BlockHashes>,
+    /// Blake3 chaining values of the contents, recorded with `store_outboards` for verified files
+    /// larger than one group.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outboard: Option<Outboard>,
+    /// Hex encoded Blake3 digest of the contents. Missing for unverified files and files stored
+    /// before digests were recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    /// Set for files stored with a digest claimed by the client, whose data was never checked
+    /// against the signature.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub unverified: bool,
+    /// Set when the sidecar couldn't be parsed and only the signature was recovered from it, see
+    /// [`FileMetadata::salvage`]. Writing the metadata back repairs the sidecar.
+    #[serde(skip)]
+    pub salvaged: bool,
+}
+
+impl FileMetadata {
+    pub fn new(signature: &Signature, scheme: SignatureScheme) -> Self {
+        Self {
+            version: METADATA_VERSION,
+            algorithm: algorithm(scheme).to_string(),
+            passphrase_hash: None,
+            signature: bs58::encode(signature.to_bytes()).into_string(),
+            uploaded_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .map(|time| time.as_secs()),
+            block_hashes: None,
+            outboard: None,
+            digest: None,
+            unverified: false,
+            salvaged: false,
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        // Serialized metadata is always longer than a bare signature
+        if bytes.len() == SIGNATURE_LENGTH {
+            let mut metadata = Self::new(
+                &Signature::from_slice(bytes)?,
+                SignatureScheme::FILE_DEFAULT,
+            );
+            metadata.uploaded_at = None;
+            return Ok(metadata);
+        }
+
+        let metadata: Self = match serde_json::from_slice(bytes) {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                return Self::salvage(bytes).ok_or_else(|| {
+                    anyhow!(err).context("File metadata is corrupt and holds no readable signature")
+                })
+            }
+        };
+        if metadata.version > METADATA_VERSION {
+            bail!("Unsupported metadata version: {}", metadata.version);
+        }
+        metadata.scheme()?;
+        Ok(metadata)
+    }
+
+    /// Recovers the signature from a sidecar that isn't valid metadata, e.g. one cut short by a
+    /// crash or with a field of the wrong type, so the file can still be served and verified. The
+    /// rest is dropped: the file gets no upload time, block hashes or digest, and the signature
+    /// is taken to be of the default scheme.
+    ///
+    /// The passphrase hash is recovered too, so the file doesn't lose its protection. Sidecars
+    /// are only salvaged when it can be read, `null` for files without one. Older servers wrote
+    /// it last and left it out when not set, so their sidecars aren't salvaged unless it made it
+    /// to the disk.
+    fn salvage(bytes: &[u8]) -> Option<Self> {
+        let text = String::from_utf8_lossy(bytes);
+        let signature = string_field(&text, "signature")?;
+        let signature = Signature::from_slice(&bs58::decode(signature).into_vec().ok()?).ok()?;
+        let passphrase_hash = nullable_string_field(&text, "passphrase_hash")?;
+
+        let mut metadata = Self::new(&signature, SignatureScheme::FILE_DEFAULT);
+        metadata.uploaded_at = None;
+        metadata.passphrase_hash = passphrase_hash.map(str::to_string);
+        metadata.salvaged = true;
+        Some(metadata)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(self)?)
+    }
+
+    /// Scheme the file signature was made with, the one it's verified with.
+    pub fn scheme(&self) -> Result<SignatureScheme> {
+        match self.algorithm.as_str() {
+            ALGORITHM_ED25519_BLAKE3 => Ok(SignatureScheme::Ed25519ph),
+            algorithm => bail!("Unsupported signature algorithm: {algorithm}"),
+        }
+    }
+
+    pub fn signature(&self) -> Result<Signature> {
+        Ok(Signature::from_slice(
+            &bs58::decode(&self.signature).into_vec()?,
+        )?)
+    }
+}
+
+/// Value of the string field `name` in the JSON `text`, found without parsing the rest. Values
+/// with escapes aren't supported, none of the fields read this way have any.
+fn string_field<'a>(text: &'a str, name: &str) -> Option<&'a str> {
+    let (_, rest) = text.split_once(&format!("\"{name}\""))?;
+    let rest = rest.trim_start().strip_prefix(':')?.trim_start();
+    let (value, _) = rest.strip_prefix('"')?.split_once('"')?;
+    Some(value)
+}
+
+/// Like [`string_field`] for a field that may be `null`, `None` unless one or the other is read.
+fn nullable_string_field<'a>(text: &'a str, name: &str) -> Option<Option<&'a str>> {
+    let (_, rest) = text.split_once(&format!("\"{name}\""))?;
+    if rest
+        .trim_start()
+        .strip_prefix(':')?
+        .trim_start()
+        .starts_with("null")
+    {
+        return Some(None);
+    }
+    string_field(text, name).map(Some)
+}
+
+/// Name `scheme` is recorded under in the metadata. The scheme names of the protocol leave out
+/// the digest, which the metadata spells out.
+fn algorithm(scheme: SignatureScheme) -> &'static str {
+    match scheme {
+        SignatureScheme::Ed25519ph => ALGORITHM_ED25519_BLAKE3,
+        // Never stored, only schemes of `SignatureScheme::FILE_SCHEMES` are accepted for files
+        SignatureScheme::Ed25519 => "ed25519",
+    }
+}
+
+/// Reads the sidecar in either format, failing with 404 if it doesn't exist. A corrupt sidecar
+/// still holding a readable signature is salvaged with a warning.
+pub async fn read_metadata(signature_path: &Path) -> Result<FileMetadata> {
+    match tokio::fs::read(signature_path).await {
+        Ok(bytes) => {
+            let metadata = FileMetadata::from_bytes(&bytes)?;
+            if metadata.salvaged {
+                warn!("Corrupt metadata, using only the signature recovered: {signature_path:?}");
+            }
+            Ok(metadata)
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            Err(HttpError::new(StatusCode::NOT_FOUND, "File not found").into())
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+pub async fn write_metadata(signature_path: &Path, metadata: &FileMetadata) -> Result<()> {
+    tokio::fs::write(signature_path, metadata.to_bytes()?).await?;
+    Ok(())
+}
@@ -0,0 +1,46 @@
+use anyhow::Result;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use rand::RngCore;
+use warp::http::StatusCode;
+
+use crate::error::HttpError;
+
+/// Hashes the passphrase a file is uploaded with into the PHC string stored in its metadata.
+/// Argon2 is slow on purpose, so it runs on a blocking thread.
+pub async fn hash(passphrase: String) -> Result<String> {
+    if passphrase.is_empty() {
+        return Err(HttpError::new(StatusCode::BAD_REQUEST, "File passphrase is empty").into());
+    }
+    tokio::task::spawn_blocking(move || {
+        let mut salt = [0; 16];
+        rand::thread_rng().fill_bytes(&mut salt);
+        let salt = SaltString::encode_b64(&salt)?;
+        let hash = Argon2::default().hash_password(passphrase.as_bytes(), &salt)?;
+        Ok(hash.to_string())
+    })
+    .await?
+}
+
+/// Lets a file protected by `passphrase_hash` be read only with the matching `passphrase`,
+/// failing with 401 when none was sent and 403 when it doesn't match. Files without a hash are
+/// always readable.
+pub async fn check(passphrase_hash: Option<&str>, passphrase: Option<String>) -> Result<()> {
+    let Some(passphrase_hash) = passphrase_hash.map(str::to_string) else {
+        return Ok(());
+    };
+    let Some(passphrase) = passphrase else {
+        return Err(HttpError::new(StatusCode::UNAUTHORIZED, "File requires a passphrase").into());
+    };
+    let matches = tokio::task::spawn_blocking(move || -> Result<bool> {
+        let passphrase_hash = PasswordHash::new(&passphrase_hash)?;
+        Ok(Argon2::default()
+            .verify_password(passphrase.as_bytes(), &passphrase_hash)
+            .is_ok())
+    })
+    .await??;
+    if !matches {
+        return Err(HttpError::new(StatusCode::FORBIDDEN, "Wrong file passphrase").into());
+    }
+    Ok(())
+}
@@ -0,0 +1,114 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures_util::StreamExt;
+use hyper::body::HttpBody;
+use hyper::header::CONTENT_LENGTH;
+use hyper::service::Service;
+use hyper::{Body, Request, Response};
+use log::warn;
+use shared::consts::{METHOD_REVOKE_SHARE, PARAM_FILENAME, PARAM_PUBKEY};
+
+use crate::{shares, CONFIG};
+
+/// A request being served, warned about when it's dropped along with its response body if it
+/// took longer than `slow_request_threshold_ms`.
+struct RequestTiming {
+    method: String,
+    pubkey: String,
+    filename: String,
+    status: u16,
+    started: Instant,
+    received: Arc<AtomicU64>,
+    sent: u64,
+}
+
+impl RequestTiming {
+    // A method rather than a field update, so the closure counting the bytes owns the whole
+    // timing and the request is logged once the body is dropped
+    fn add_sent(&mut self, len: usize) {
+        self.sent += len as u64;
+    }
+}
+
+impl Drop for RequestTiming {
+    fn drop(&mut self) {
+        let elapsed = self.started.elapsed();
+        if elapsed < Duration::from_millis(CONFIG.slow_request_threshold_ms) {
+            return;
+        }
+        warn!(
+            "Slow request: method: {}, pubkey: {}, filename: {}, bytes received: {}, bytes sent: {}, status: {}, duration: {elapsed:?}",
+            self.method,
+            self.pubkey,
+            self.filename,
+            self.received.load(Ordering::Relaxed),
+            self.sent,
+            self.status,
+        );
+    }
+}
+
+/// Serves `request` with `service`, timing it until the response body is sent or the client goes
+/// away, and counting the body bytes actually received and sent.
+pub async fn serve<S>(mut service: S, request: Request<Body>) -> Result<Response<Body>, S::Error>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+{
+    let started = Instant::now();
+    let header = |name| {
+        request
+            .headers()
+            .get(name)
+            .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+            .unwrap_or_else(|| "-".to_string())
+    };
+    // Only the first segment, the rest of a shared download path is its token
+    let method = request
+        .uri()
+        .path()
+        .trim_start_matches('/')
+        .split('/')
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    let (pubkey, mut filename) = (header(PARAM_PUBKEY), header(PARAM_FILENAME));
+    // The filename of `revoke-share` is the token of a working link
+    if method == METHOD_REVOKE_SHARE {
+        filename = shares::logged_token(&filename);
+    }
+
+    let received = Arc::new(AtomicU64::new(0));
+    let (parts, body) = request.into_parts();
+    let counter = received.clone();
+    let body = body.inspect(move |chunk| {
+        if let Ok(chunk) = chunk {
+            counter.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+        }
+    });
+    let response = service
+        .call(Request::from_parts(parts, Body::wrap_stream(body)))
+        .await?;
+
+    let (mut parts, body) = response.into_parts();
+    // The wrapped body has no known length, so it's kept in the header
+    if let Some(len) = body.size_hint().exact() {
+        parts.headers.entry(CONTENT_LENGTH).or_insert(len.into());
+    }
+    let mut timing = RequestTiming {
+        method,
+        pubkey,
+        filename,
+        status: parts.status.as_u16(),
+        started,
+        received,
+        sent: 0,
+    };
+    let body = body.inspect(move |chunk| {
+        if let Ok(chunk) = chunk {
+            timing.add_sent(chunk.len());
+        }
+    });
+    Ok(Response::from_parts(parts, Body::wrap_stream(body)))
+}
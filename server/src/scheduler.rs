@@ -0,0 +1,123 @@
+use std::cmp::Reverse;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::time::Duration;
+
+use anyhow::Result;
+use futures_util::future::BoxFuture;
+use futures_util::stream::FuturesUnordered;
+use futures_util::{FutureExt, StreamExt};
+use log::{debug, error, warn};
+use tokio::time::Instant;
+
+/// Order in which due tasks start when more are due than may run at once.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low,
+    High,
+}
+
+struct Task {
+    name: &'static str,
+    interval: Duration,
+    priority: Priority,
+    run: Box<dyn Fn() -> BoxFuture<'static, Result<()>> + Send + Sync>,
+    next_run: Instant,
+    running: bool,
+}
+
+/// Runs the periodic maintenance of the server, at most `max_concurrent` tasks at once so it
+/// never takes more than that share of the I/O away from requests. Each task runs again its
+/// interval after the previous run finished, so a slow run delays the next instead of piling
+/// up.
+pub struct Scheduler {
+    max_concurrent: usize,
+    tasks: Vec<Task>,
+}
+
+impl Scheduler {
+    pub fn new(max_concurrent: usize) -> Self {
+        Self {
+            max_concurrent,
+            tasks: Vec::new(),
+        }
+    }
+
+    /// Adds a task running every `interval`, starting right away. A zero interval disables it.
+    pub fn add<F, Fut>(
+        &mut self,
+        name: &'static str,
+        interval: Duration,
+        priority: Priority,
+        run: F,
+    ) where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        if interval.is_zero() {
+            debug!("Background task {name} is disabled");
+            return;
+        }
+        self.tasks.push(Task {
+            name,
+            interval,
+            priority,
+            run: Box::new(move || run().boxed()),
+            next_run: Instant::now(),
+            running: false,
+        });
+    }
+
+    /// Runs the tasks until the server stops. Failed and panicking runs are logged and retried
+    /// at the next interval.
+    pub async fn run(mut self) {
+        if self.tasks.is_empty() {
+            return;
+        }
+        let mut running = FuturesUnordered::new();
+        loop {
+            // Due tasks start highest priority first, then the longest overdue
+            let now = Instant::now();
+            let mut due = (0..self.tasks.len())
+                .filter(|&index| !self.tasks[index].running && self.tasks[index].next_run <= now)
+                .collect::<Vec<_>>();
+            due.sort_by_key(|&index| {
+                let task = &self.tasks[index];
+                (Reverse(task.priority), task.next_run)
+            });
+            for index in due
+                .into_iter()
+                .take(self.max_concurrent.saturating_sub(running.len()))
+            {
+                let task = &mut self.tasks[index];
+                task.running = true;
+                let name = task.name;
+                let future = AssertUnwindSafe((task.run)()).catch_unwind();
+                running.push(async move {
+                    match future.await {
+                        Ok(Ok(())) => {}
+                        Ok(Err(err)) => warn!("Background task {name} failed: {err:#}"),
+                        Err(_) => error!("Background task {name} panicked"),
+                    }
+                    index
+                });
+            }
+
+            let next_run = self
+                .tasks
+                .iter()
+                .filter(|task| !task.running)
+                .map(|task| task.next_run)
+                .min();
+            let can_start = running.len() < self.max_concurrent;
+            tokio::select! {
+                Some(index) = running.next() => {
+                    let task = &mut self.tasks[index];
+                    task.running = false;
+                    task.next_run = Instant::now() + task.interval;
+                }
+                _ = tokio::time::sleep_until(next_run.unwrap_or(now)), if can_start && next_run.is_some() => {}
+            }
+        }
+    }
+}
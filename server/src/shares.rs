@@ -0,0 +1,345 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use ed25519_dalek::{VerifyingKey, PUBLIC_KEY_LENGTH};
+use log::{info, warn};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use shared::listing::ShareEntry;
+use tokio::sync::Mutex;
+use warp::http::StatusCode;
+
+use crate::error::HttpError;
+use crate::CONFIG;
+
+/// Directory under the storage path holding a file per share link. Not a valid pubkey, so
+/// listings, trimming and the layout migration skip it.
+const SHARES_DIR: &str = ".shares";
+
+/// Random bytes in a token, Base58 encoded in the link.
+const TOKEN_LENGTH: usize = 16;
+
+/// Characters of a token written to the log, enough to tell links apart but not to use them.
+const LOGGED_TOKEN_LENGTH: usize = 6;
+
+/// Window `max_shares_per_hour` counts the links created in.
+const RATE_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Serializes the changes to the links, and holds when each pubkey created its links within
+/// [`RATE_WINDOW`]. Kept in memory, so the rate limit starts over when the server restarts.
+static SHARES_LOCK: Mutex<BTreeMap<[u8; PUBLIC_KEY_LENGTH], VecDeque<Instant>>> =
+    Mutex::const_new(BTreeMap::new());
+
+/// A file downloadable without authentication until `expires_at`, or `max_downloads` times.
+#[derive(Serialize, Deserialize)]
+pub struct Share {
+    /// Base58 pubkey of the file's owner.
+    pub pubkey: String,
+    pub filename: String,
+    pub created_at: u64,
+    pub expires_at: u64,
+    /// Downloads served so far.
+    #[serde(default)]
+    pub downloads: u64,
+    /// Downloads after which the link stops working, unlimited when not set.
+    #[serde(default)]
+    pub max_downloads: Option<u64>,
+}
+
+impl Share {
+    pub fn pubkey(&self) -> Result<VerifyingKey> {
+        let bytes: [u8; PUBLIC_KEY_LENGTH] = bs58::decode(&self.pubkey)
+            .into_vec()?
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Invalid pubkey length"))?;
+        Ok(VerifyingKey::from_bytes(&bytes)?)
+    }
+
+    /// Whether the link stopped working, either expired or used up.
+    fn expired(&self, now: u64) -> bool {
+        now >= self.expires_at
+            || self
+                .max_downloads
+                .is_some_and(|max_downloads| self.downloads >= max_downloads)
+    }
+
+    fn entry(self, token: String) -> ShareEntry {
+        ShareEntry {
+            token,
+            filename: self.filename,
+            created_at: self.created_at,
+            expires_at: self.expires_at,
+            downloads: self.downloads,
+            max_downloads: self.max_downloads,
+        }
+    }
+}
+
+/// Creates a link to `filename` of `pubkey` expiring after `expires_in`, or once downloaded
+/// `max_downloads` times. Fails with 429 when the pubkey has `max_shares_per_pubkey` working
+/// links or created `max_shares_per_hour` links within the last hour.
+pub async fn create(
+    storage_path: &Path,
+    pubkey: &VerifyingKey,
+    filename: &str,
+    expires_in: Duration,
+    max_downloads: Option<u64>,
+) -> Result<ShareEntry> {
+    let mut recent = SHARES_LOCK.lock().await;
+    let created = recent.entry(pubkey.to_bytes()).or_default();
+    while created
+        .front()
+        .is_some_and(|time| time.elapsed() >= RATE_WINDOW)
+    {
+        created.pop_front();
+    }
+    if let Some(max_per_hour) = CONFIG.max_shares_per_hour {
+        if created.len() as u64 >= max_per_hour {
+            return Err(HttpError::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                format!("Share link rate limit reached: {max_per_hour} links per hour"),
+            )
+            .into());
+        }
+    }
+
+    let pubkey_b58 = bs58::encode(pubkey.as_bytes()).into_string();
+    if let Some(max_shares) = CONFIG.max_shares_per_pubkey {
+        let now = now();
+        let active = read_shares(storage_path)
+            .await?
+            .into_iter()
+            .filter(|(_, share)| share.pubkey == pubkey_b58 && !share.expired(now))
+            .count();
+        if active as u64 >= max_shares {
+            return Err(HttpError::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                format!("Share link limit reached: {active} of {max_shares} links active"),
+            )
+            .into());
+        }
+    }
+
+    let dir = storage_path.join(SHARES_DIR);
+    tokio::fs::create_dir_all(&dir).await?;
+    let mut bytes = [0; TOKEN_LENGTH];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let token = bs58::encode(bytes).into_string();
+
+    let created_at = now();
+    let share = Share {
+        pubkey: pubkey_b58,
+        filename: filename.to_string(),
+        created_at,
+        expires_at: created_at.saturating_add(expires_in.as_secs()),
+        downloads: 0,
+        max_downloads,
+    };
+    write_share(&dir, &token, &share).await?;
+    created.push_back(Instant::now());
+    info!(
+        "Shared {filename} until {}, token: {}",
+        share.expires_at,
+        logged_token(&token)
+    );
+
+    Ok(share.entry(token))
+}
+
+/// Start of `token` to log in its place, since anyone reading the log could use the whole of it.
+pub fn logged_token(token: &str) -> String {
+    let prefix = token.chars().take(LOGGED_TOKEN_LENGTH).collect::<String>();
+    format!("{prefix}...")
+}
+
+/// The link with `token`, `None` if there's none or it stopped working. Unlike
+/// [`claim_download`], doesn't count a download.
+pub async fn get(storage_path: &Path, token: &str) -> Result<Option<Share>> {
+    let Some(path) = share_path(storage_path, token) else {
+        return Ok(None);
+    };
+    Ok(read_share(&path)
+        .await?
+        .filter(|share| !share.expired(now())))
+}
+
+/// Counts a download of the link with `token` and returns it, `None` if there's none or it
+/// stopped working. Links that stopped working are removed, including the ones used up by this
+/// download.
+pub async fn claim_download(storage_path: &Path, token: &str) -> Result<Option<Share>> {
+    let Some(path) = share_path(storage_path, token) else {
+        return Ok(None);
+    };
+    let _guard = SHARES_LOCK.lock().await;
+    let Some(mut share) = read_share(&path).await? else {
+        return Ok(None);
+    };
+    let now = now();
+    if share.expired(now) {
+        remove_file(&path).await?;
+        return Ok(None);
+    }
+
+    share.downloads += 1;
+    if share.expired(now) {
+        info!("Share link used up, removing it: {}", logged_token(token));
+        remove_file(&path).await?;
+    } else {
+        write_share(&storage_path.join(SHARES_DIR), token, &share).await?;
+    }
+    Ok(Some(share))
+}
+
+/// Working links of `pubkey`, oldest first.
+pub async fn list(storage_path: &Path, pubkey: &VerifyingKey) -> Result<Vec<ShareEntry>> {
+    let pubkey_b58 = bs58::encode(pubkey.as_bytes()).into_string();
+    let now = now();
+    let mut shares = read_shares(storage_path)
+        .await?
+        .into_iter()
+        .filter(|(_, share)| share.pubkey == pubkey_b58 && !share.expired(now))
+        .map(|(token, share)| share.entry(token))
+        .collect::<Vec<_>>();
+    shares.sort_by(|a, b| (a.created_at, &a.token).cmp(&(b.created_at, &b.token)));
+    Ok(shares)
+}
+
+/// Removes the link with `token` if `pubkey` created it, so it stops working right away.
+/// Returns whether it was found, links of other pubkeys counting as missing.
+pub async fn revoke(storage_path: &Path, pubkey: &VerifyingKey, token: &str) -> Result<bool> {
+    let Some(path) = share_path(storage_path, token) else {
+        return Ok(false);
+    };
+    let _guard = SHARES_LOCK.lock().await;
+    let Some(share) = read_share(&path).await? else {
+        return Ok(false);
+    };
+    if share.pubkey != bs58::encode(pubkey.as_bytes()).into_string() {
+        return Ok(false);
+    }
+    remove_file(&path).await?;
+    info!(
+        "Revoked share link of {}: {}",
+        share.filename,
+        logged_token(token)
+    );
+    Ok(true)
+}
+
+/// Removes the links that stopped working, run every `share_sweep_interval_secs`.
+pub async fn sweep() -> Result<()> {
+    let removed = sweep_expired(&CONFIG.storage_path).await?;
+    if removed > 0 {
+        info!("Removed {removed} expired or used up share links");
+    }
+    Ok(())
+}
+
+/// Removes the links that stopped working and the unreadable ones. Returns how many were
+/// removed.
+pub async fn sweep_expired(storage_path: &Path) -> Result<usize> {
+    let _guard = SHARES_LOCK.lock().await;
+    let mut read_dir = match tokio::fs::read_dir(storage_path.join(SHARES_DIR)).await {
+        Ok(read_dir) => read_dir,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err.into()),
+    };
+
+    let now = now();
+    let mut removed = 0;
+    while let Some(entry) = read_dir.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        let expired = match read_share(&path).await {
+            Ok(share) => share.is_none_or(|share| share.expired(now)),
+            Err(err) => {
+                let token = path.file_stem().unwrap_or_default().to_string_lossy();
+                warn!(
+                    "Removing unreadable share link {}: {err}",
+                    logged_token(&token)
+                );
+                true
+            }
+        };
+        if expired {
+            remove_file(&path).await?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Tokens and contents of all readable links, whether working or not.
+async fn read_shares(storage_path: &Path) -> Result<Vec<(String, Share)>> {
+    let mut read_dir = match tokio::fs::read_dir(storage_path.join(SHARES_DIR)).await {
+        Ok(read_dir) => read_dir,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut shares = Vec::new();
+    while let Some(entry) = read_dir.next_entry().await? {
+        let path = entry.path();
+        let Some(token) = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(".json"))
+        else {
+            continue;
+        };
+        // Unreadable links are left for the sweep
+        if let Ok(Some(share)) = read_share(&path).await {
+            shares.push((token.to_string(), share));
+        }
+    }
+    Ok(shares)
+}
+
+/// Path of the link with `token`, `None` if it isn't a token this server could have minted.
+fn share_path(storage_path: &Path, token: &str) -> Option<PathBuf> {
+    // Longer tokens can't decode to the expected length, so they aren't decoded at all
+    if token.len() > TOKEN_LENGTH * 138 / 100 + 1 {
+        return None;
+    }
+    let bytes = bs58::decode(token).into_vec().ok()?;
+    (bytes.len() == TOKEN_LENGTH).then(|| {
+        storage_path
+            .join(SHARES_DIR)
+            .join(format!("{}.json", bs58::encode(bytes).into_string()))
+    })
+}
+
+async fn read_share(path: &Path) -> Result<Option<Share>> {
+    match tokio::fs::read(path).await {
+        Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Writes the link aside and renames it in place, so it's never read half written.
+async fn write_share(dir: &Path, token: &str, share: &Share) -> Result<()> {
+    let temp_path = dir.join(format!("{token}.tmp"));
+    tokio::fs::write(&temp_path, serde_json::to_vec(share)?).await?;
+    tokio::fs::rename(&temp_path, dir.join(format!("{token}.json"))).await?;
+    Ok(())
+}
+
+async fn remove_file(path: &Path) -> Result<()> {
+    match tokio::fs::remove_file(path).await {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
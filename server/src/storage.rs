@@ -1,31 +1,148 @@
+use std::collections::{BTreeMap, HashSet};
 use std::env::temp_dir;
-use std::io::ErrorKind;
+use std::io::{ErrorKind, SeekFrom};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 
-use anyhow::{anyhow, bail, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use ed25519_dalek::ed25519::signature::digest::{FixedOutput, Update};
 use ed25519_dalek::{Signature, VerifyingKey};
-use log::info;
+use log::{info, warn};
 use once_cell::sync::Lazy;
-use rand::RngCore;
+use rand::{Rng, RngCore};
+use shared::chunks::{encode_chunk_hash, BlockHasher};
+use shared::hasher::{self, Hasher};
+use shared::listing::{FileEntry, PruneReport, PubkeyEntry, TrimReport};
+use shared::outboard::{self, OutboardHasher};
+use shared::SignatureScheme;
 use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::Mutex;
+use warp::http::StatusCode;
 
+use crate::config::StorageLayout;
+use crate::error::HttpError;
+use crate::hashing::BatchHasher;
+use crate::journal::{self, InstallEntry};
+use crate::listing_cache;
+use crate::metadata::{read_metadata, write_metadata, FileMetadata};
 use crate::CONFIG;
 
 const TEMP_PREFIX: &str = "cloud-uploading";
 
+/// File in the storage directory recording the layout the files are arranged in.
+const LAYOUT_MARKER: &str = ".layout";
+
+/// File in the storage directory recording that the sidecars are named after the full name of
+/// their file, see [`migrate_sidecars`].
+const SIDECAR_MARKER: &str = ".sidecars";
+
+/// Extension of the metadata sidecars, appended to the full name of their file.
+const SIDECAR_EXTENSION: &str = "sig";
+
+/// Directory under the storage path where chunks are kept until their file is assembled. Can't
+/// clash with pubkey directories since the dot isn't a base58 character.
+const CHUNKS_DIR: &str = ".chunks";
+
+/// Directory under each pubkey directory where uploads are written with `per_pubkey_staging` and
+/// files replaced by a batch are kept until it's committed. Hidden from listings, and no filename
+/// may map to it.
+const STAGING_DIR: &str = ".staging";
+
+/// Signature files without data younger than this may belong to an upload being finalized right
+/// now, so they are not treated as orphaned.
+const ORPHANED_SIGNATURE_AGE: Duration = Duration::from_secs(60);
+
+/// Temp files not written to for this long are left behind, unless `max_upload_duration_secs`
+/// is set.
+const STALE_TEMP_FILE_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
 static TEMP_DIR: Lazy<PathBuf> = Lazy::new(temp_dir);
 
+/// Numbers the temp files created by this process.
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+static FINALIZE_LOCK: Mutex<()> = Mutex::const_new(());
+
+/// Size of the blocks hashed separately so downloads can be verified as they arrive.
+const VERIFICATION_BLOCK_SIZE: u64 = 1024 * 1024;
+
+/// Hashes of the stored contents recorded in the metadata of verified files.
+#[derive(Debug)]
+struct ContentHashers {
+    file: Hasher,
+    blocks: BlockHasher,
+    /// Only set with `store_outboards`. Boxed, since the writer is held across awaits and a third
+    /// Blake3 hasher inline makes the upload futures overflow the stack of debug builds.
+    outboard: Option<Box<OutboardHasher>>,
+}
+
+impl ContentHashers {
+    fn new(store_outboard: bool) -> Self {
+        Self {
+            file: Hasher::default(),
+            blocks: BlockHasher::new(VERIFICATION_BLOCK_SIZE),
+            outboard: store_outboard
+                .then(|| Box::new(OutboardHasher::new(outboard::GROUP_SIZE, 0))),
+        }
+    }
+
+    fn record(self, metadata: &mut FileMetadata) {
+        metadata.digest = Some(hasher::encode_hex(self.file));
+        metadata.block_hashes = Some(self.blocks.finish());
+        // A single group is the root of the tree, it has no chaining value of its own
+        metadata.outboard = self
+            .outboard
+            .map(|outboard| outboard.finish())
+            .filter(|outboard| outboard.chaining_values.len() > 1);
+    }
+}
+
+impl Update for ContentHashers {
+    fn update(&mut self, data: &[u8]) {
+        Update::update(&mut self.file, data);
+        self.blocks.update(data);
+        if let Some(outboard) = &mut self.outboard {
+            outboard.update(data);
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct FileWriter {
     temp_file: Option<(File, PathBuf)>,
+    /// `None` once the upload is marked as unverified.
+    hashers: Option<BatchHasher<ContentHashers>>,
+    written: u64,
 }
 
 impl FileWriter {
-    pub async fn new() -> std::io::Result<Self> {
+    /// Creates the writer with the temp file in the staging directory of `pubkey`.
+    pub async fn new(pubkey: &VerifyingKey) -> std::io::Result<Self> {
+        let staging_dir = staging_dir(&CONFIG.storage_path, pubkey);
+        tokio::fs::create_dir_all(&staging_dir).await?;
+        let mut writer =
+            Self::with_random_source(&staging_dir, || rand::thread_rng().gen()).await?;
+        if CONFIG.store_outboards {
+            writer.hashers = Some(BatchHasher::new(ContentHashers::new(true)));
+        }
+        Ok(writer)
+    }
+
+    /// Creates the writer with the temp file in `dir`, taking its name suffixes from
+    /// `next_random` and retrying while the generated name is already taken. The names also carry
+    /// a counter, so they never repeat within the process, the random part keeps them apart from
+    /// names left by other processes.
+    pub async fn with_random_source(
+        dir: &Path,
+        mut next_random: impl FnMut() -> u128 + Send,
+    ) -> std::io::Result<Self> {
         let (temp_file, temp_filename) = loop {
-            let number = rand::thread_rng().next_u32();
-            let filename = TEMP_DIR.join(format!("{}-{}.tmp", TEMP_PREFIX, number));
+            let counter = TEMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+            let number = next_random();
+            let filename = dir.join(format!("{TEMP_PREFIX}-{counter}-{number:032x}.tmp"));
             match File::options()
                 .create_new(true)
                 .write(true)
@@ -33,19 +150,65 @@ impl FileWriter {
                 .await
             {
                 Ok(file) => break (file, filename),
-                Err(err) if err.kind() == ErrorKind::AlreadyExists => continue,
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+                    warn!("Temp file already exists, retrying: {filename:?}");
+                    continue;
+                }
                 Err(err) => return Err(err),
             }
         };
 
         Ok(Self {
             temp_file: Some((temp_file, temp_filename)),
+            hashers: Some(BatchHasher::new(ContentHashers::new(false))),
+            written: 0,
         })
     }
 
+    /// Reserves `len` bytes of disk space for the temp file without changing its size, so the
+    /// upload fails early when they don't fit. Skipped where reserving space isn't supported.
+    pub fn preallocate(&self, len: u64) -> std::io::Result<()> {
+        match &self.temp_file {
+            Some((temp_file, _)) if len > 0 => preallocate(temp_file, len),
+            _ => Ok(()),
+        }
+    }
+
+    /// Number of bytes appended so far.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// Stores the file as unverified and without block hashes or digest, for uploads whose data
+    /// isn't hashed.
+    pub fn skip_verification(&mut self) {
+        self.hashers = None;
+    }
+
+    /// Appends `data` to the temp file, then feeds it to the hashers, so they and [`written`]
+    /// always cover exactly the bytes in the file. A failed write is cut back off the file.
+    ///
+    /// [`written`]: FileWriter::written
     pub async fn append_chunk(&mut self, data: &[u8]) -> std::io::Result<()> {
         match &mut self.temp_file {
-            Some((temp_file, _temp_filename)) => temp_file.write_all(data).await,
+            Some((temp_file, _temp_filename)) => {
+                // Flushed so errors of this chunk's write surface here rather than on the next one
+                let result = match temp_file.write_all(data).await {
+                    Ok(()) => temp_file.flush().await,
+                    Err(err) => Err(err),
+                };
+                if let Err(err) = result {
+                    if let Err(truncate_err) = truncate(temp_file, self.written).await {
+                        warn!("Failed to cut a partial write off the temp file: {truncate_err}");
+                    }
+                    return Err(err);
+                }
+                if let Some(hashers) = &mut self.hashers {
+                    hashers.update(data).await;
+                }
+                self.written += data.len() as u64;
+                Ok(())
+            }
             None => Err(ErrorKind::NotFound.into()),
         }
     }
@@ -55,20 +218,55 @@ impl FileWriter {
         filename: &str,
         pubkey: &VerifyingKey,
         signature: &Signature,
+        scheme: SignatureScheme,
+        expected_signature: Option<&Signature>,
+        passphrase_hash: Option<String>,
     ) -> Result<()> {
+        let (file_path, signature_path) =
+            get_file_paths(&CONFIG.storage_path, pubkey, filename).await?;
+        let _invalidation = listing_cache::Invalidation(pubkey);
         if let Some((temp_file, temp_filename)) = self.temp_file.take() {
             temp_file.sync_all().await?;
-            let (file_path, signature_path) =
-                get_file_paths(&CONFIG.storage_path, pubkey, filename).await?;
-            tokio::fs::create_dir_all(
-                file_path
-                    .parent()
-                    .ok_or(anyhow!("Unable to get parent directory"))?,
+
+            // Keeps the precondition check and the replacement atomic relative to other uploads
+            let _guard = FINALIZE_LOCK.lock().await;
+            if let Err(err) = check_expected_signature(&signature_path, expected_signature).await {
+                tokio::fs::remove_file(temp_filename).await?;
+                return Err(err);
+            }
+
+            if CONFIG.no_overwrite && tokio::fs::try_exists(&file_path).await? {
+                tokio::fs::remove_file(temp_filename).await?;
+                return Err(file_exists_error());
+            }
+
+            if let Err(err) =
+                check_limits(pubkey, &file_path, temp_file.metadata().await?.len()).await
+            {
+                tokio::fs::remove_file(temp_filename).await?;
+                return Err(err);
+            }
+
+            install(
+                &temp_filename,
+                self.hashers.take(),
+                &file_path,
+                &signature_path,
+                signature,
+                scheme,
+                passphrase_hash,
             )
             .await?;
-            tokio::fs::write(signature_path, signature.to_vec()).await?;
-            tokio::fs::rename(temp_filename, &file_path).await?;
-            info!("File written to: {file_path:?}");
+        }
+        Ok(())
+    }
+
+    /// Moves the written data to `path` without any signature, used for uploaded chunks.
+    pub async fn persist(mut self, path: &Path) -> Result<()> {
+        if let Some((temp_file, temp_filename)) = self.temp_file.take() {
+            temp_file.sync_all().await?;
+            create_parent_dir(path).await?;
+            tokio::fs::rename(temp_filename, path).await?;
         }
         Ok(())
     }
@@ -89,16 +287,1304 @@ impl Drop for FileWriter {
     }
 }
 
+#[cfg(target_os = "linux")]
+fn preallocate(file: &File, len: u64) -> std::io::Result<()> {
+    use rustix::fs::{fallocate, FallocateFlags};
+    use rustix::io::Errno;
+
+    match fallocate(file, FallocateFlags::KEEP_SIZE, 0, len) {
+        Ok(()) => Ok(()),
+        Err(Errno::OPNOTSUPP | Errno::NOSYS) => {
+            info!("Filesystem doesn't support preallocation, skipping it");
+            Ok(())
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn preallocate(_file: &File, _len: u64) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Cuts the file back to `len` bytes and moves its position there, dropping a partial write.
+async fn truncate(file: &mut File, len: u64) -> std::io::Result<()> {
+    file.set_len(len).await?;
+    file.seek(SeekFrom::Start(len)).await?;
+    Ok(())
+}
+
+/// Writes the metadata of an upload and moves its temp file in place, journaled so a crash in
+/// between is recovered on the next start. With `passphrase_hash`, downloads of the file must
+/// send the passphrase.
+async fn install(
+    temp_filename: &Path,
+    hashers: Option<BatchHasher<ContentHashers>>,
+    file_path: &Path,
+    signature_path: &Path,
+    signature: &Signature,
+    scheme: SignatureScheme,
+    passphrase_hash: Option<String>,
+) -> Result<()> {
+    create_parent_dir(file_path).await?;
+    let mut metadata = FileMetadata::new(signature, scheme);
+    metadata.passphrase_hash = passphrase_hash;
+    match hashers {
+        Some(hashers) => hashers.finish().await.record(&mut metadata),
+        None => metadata.unverified = true,
+    }
+    let entry = InstallEntry {
+        temp_path: temp_filename.to_path_buf(),
+        file_path: file_path.to_path_buf(),
+        signature_path: signature_path.to_path_buf(),
+        size: tokio::fs::metadata(temp_filename).await?.len(),
+        metadata,
+        previous: read_metadata(signature_path).await.ok(),
+    };
+    let journal_entry = journal::begin(&CONFIG.storage_path, &entry).await?;
+    write_metadata(signature_path, &entry.metadata).await?;
+    tokio::fs::rename(temp_filename, file_path).await?;
+    journal_entry.complete().await?;
+    info!("File written to: {file_path:?}");
+    Ok(())
+}
+
+/// Upload of a batch, written and verified but not in place yet.
+pub struct StagedFile {
+    pub filename: String,
+    pub writer: FileWriter,
+    pub signature: Signature,
+    pub scheme: SignatureScheme,
+}
+
+/// Applies the uploads and deletes of a batch together. The files they replace or delete are
+/// moved to the staging directory of the pubkey first and moved back if any step fails, so a
+/// failed batch leaves the files as they were. A crash in the middle of the commit can still
+/// leave it partially applied, with the moved files left in the staging directory.
+pub async fn commit_batch(
+    pubkey: &VerifyingKey,
+    uploads: Vec<StagedFile>,
+    deletes: &[String],
+) -> Result<()> {
+    let _guard = FINALIZE_LOCK.lock().await;
+    let _invalidation = listing_cache::Invalidation(pubkey);
+
+    let mut upload_paths = Vec::with_capacity(uploads.len());
+    let mut sizes = Vec::with_capacity(uploads.len());
+    for upload in &uploads {
+        let (file_path, signature_path) =
+            get_file_paths(&CONFIG.storage_path, pubkey, &upload.filename).await?;
+        if CONFIG.no_overwrite && tokio::fs::try_exists(&file_path).await? {
+            return Err(file_exists_error());
+        }
+        sizes.push(upload.writer.written());
+        upload_paths.push((file_path, signature_path));
+    }
+    let mut delete_paths = Vec::with_capacity(deletes.len());
+    for filename in deletes {
+        let (file_path, signature_path) =
+            get_file_paths(&CONFIG.storage_path, pubkey, filename).await?;
+        if !tokio::fs::try_exists(&file_path).await? {
+            return Err(HttpError::new(
+                StatusCode::NOT_FOUND,
+                format!("File to delete not found: {filename}"),
+            )
+            .into());
+        }
+        delete_paths.push((file_path, signature_path));
+    }
+    let new_files = upload_paths
+        .iter()
+        .zip(sizes)
+        .map(|((file_path, _), size)| (file_path.as_path(), size))
+        .collect::<Vec<_>>();
+    let deleted_files = delete_paths
+        .iter()
+        .map(|(file_path, _)| file_path.as_path())
+        .collect::<Vec<_>>();
+    check_batch_limits(pubkey, &new_files, &deleted_files).await?;
+
+    let backup_dir = CONFIG
+        .storage_path
+        .join(bs58::encode(pubkey.as_bytes()).into_string())
+        .join(STAGING_DIR);
+    tokio::fs::create_dir_all(&backup_dir).await?;
+    let batch_id = rand::thread_rng().next_u32();
+
+    let mut moved = Vec::new();
+    let mut installed = Vec::new();
+    let mut result = Ok(());
+    let targets = upload_paths.iter().chain(&delete_paths);
+    'moving: for (file_path, signature_path) in targets {
+        for path in [file_path, signature_path] {
+            let backup_path = backup_dir.join(format!("batch-{batch_id}-{}.bak", moved.len()));
+            match tokio::fs::rename(path, &backup_path).await {
+                Ok(()) => moved.push((path.clone(), backup_path)),
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => {
+                    result = Err(err.into());
+                    break 'moving;
+                }
+            }
+        }
+    }
+    if result.is_ok() {
+        for (mut upload, (file_path, signature_path)) in uploads.into_iter().zip(&upload_paths) {
+            let Some((temp_file, temp_filename)) = upload.writer.temp_file.take() else {
+                continue;
+            };
+            result = match temp_file.sync_all().await {
+                Ok(()) => {
+                    install(
+                        &temp_filename,
+                        upload.writer.hashers.take(),
+                        file_path,
+                        signature_path,
+                        &upload.signature,
+                        upload.scheme,
+                        None,
+                    )
+                    .await
+                }
+                Err(err) => Err(err.into()),
+            };
+            installed.push((file_path, signature_path));
+            if result.is_err() {
+                tokio::fs::remove_file(temp_filename).await.ok();
+                break;
+            }
+        }
+    }
+
+    if let Err(err) = result {
+        warn!("Batch failed, rolling back: {err}");
+        for (file_path, signature_path) in installed {
+            tokio::fs::remove_file(file_path).await.ok();
+            tokio::fs::remove_file(signature_path).await.ok();
+        }
+        for (path, backup_path) in moved.into_iter().rev() {
+            tokio::fs::rename(&backup_path, &path).await?;
+        }
+        return Err(err);
+    }
+
+    for (_, backup_path) in moved {
+        tokio::fs::remove_file(backup_path).await?;
+    }
+    info!(
+        "Batch committed: {} uploads, {} deletes",
+        upload_paths.len(),
+        delete_paths.len()
+    );
+    Ok(())
+}
+
 pub async fn get_file_paths(
     storage_path: impl AsRef<Path>,
     pubkey: &VerifyingKey,
     filename: &str,
 ) -> Result<(PathBuf, PathBuf)> {
-    let pubkey = bs58::encode(pubkey.as_bytes()).into_string();
-    let path = storage_path.as_ref().join(pubkey).join(filename);
+    let pubkey_dir = storage_path
+        .as_ref()
+        .join(bs58::encode(pubkey.as_bytes()).into_string());
+    let (path, signature_path) = layout_file_paths(&pubkey_dir, filename, CONFIG.layout)?;
     if !path.starts_with(storage_path.as_ref()) {
         bail!("Trying to get path outside storage directory")
     }
-    let signature_path = path.with_extension("sig");
+    adopt_legacy_sidecar(&path, &signature_path).await?;
     Ok((path, signature_path))
 }
+
+/// Returns the paths of the file and its signature within the pubkey directory.
+fn layout_file_paths(
+    pubkey_dir: &Path,
+    filename: &str,
+    layout: StorageLayout,
+) -> Result<(PathBuf, PathBuf)> {
+    shared::validate_filename(filename)
+        .map_err(|err| HttpError::new(StatusCode::BAD_REQUEST, err.to_string()))?;
+
+    let mut path = pubkey_dir.to_path_buf();
+    if layout == StorageLayout::Sharded {
+        let mut hasher = Hasher::default();
+        hasher.update(filename.as_bytes());
+        path.push(format!("{:02x}", hasher.finalize_fixed()[0]));
+    }
+    let path = if CONFIG.encode_filenames {
+        filename
+            .split('/')
+            .map(encode_component)
+            .fold(path, |path, component| path.join(component))
+    } else {
+        path.join(filename)
+    };
+    if !path.starts_with(pubkey_dir) {
+        bail!("Trying to get path outside pubkey directory")
+    }
+    if path.starts_with(pubkey_dir.join(STAGING_DIR)) {
+        return Err(HttpError::new(StatusCode::BAD_REQUEST, "Filename is reserved").into());
+    }
+    // Sidecars are named after their file, so a file named like one would take its place
+    if is_sidecar(&path) {
+        return Err(HttpError::new(
+            StatusCode::BAD_REQUEST,
+            format!("Filenames ending in .{SIDECAR_EXTENSION} are reserved"),
+        )
+        .into());
+    }
+    let signature_path = sidecar_path(&path);
+    Ok((path, signature_path))
+}
+
+/// Path of the sidecar of the file at `path`, its full name with `.sig` appended.
+fn sidecar_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{SIDECAR_EXTENSION}"));
+    PathBuf::from(name)
+}
+
+/// Path older servers stored the sidecar of the file at `path` at, its extension replaced with
+/// `.sig`. Files differing only in their extension shared it.
+fn legacy_sidecar_path(path: &Path) -> PathBuf {
+    path.with_extension(SIDECAR_EXTENSION)
+}
+
+fn is_sidecar(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == SIDECAR_EXTENSION)
+}
+
+/// Moves the sidecar of the file at `path` from its legacy name to `signature_path`, for files
+/// of older servers that [`migrate_sidecars`] didn't see, e.g. ones restored from a backup. The
+/// legacy sidecar is left alone unless `path` is the only file it can belong to.
+async fn adopt_legacy_sidecar(path: &Path, signature_path: &Path) -> Result<()> {
+    let legacy_path = legacy_sidecar_path(path);
+    if legacy_path == signature_path
+        || tokio::fs::try_exists(signature_path).await?
+        || !tokio::fs::try_exists(&legacy_path).await?
+    {
+        return Ok(());
+    }
+    let claimants = legacy_claimants(&legacy_path).await?;
+    if claimants.len() != 1 || claimants[0] != path {
+        return Ok(());
+    }
+
+    // Linked rather than renamed, so a sidecar installed by an upload meanwhile isn't replaced
+    match tokio::fs::hard_link(&legacy_path, signature_path).await {
+        Ok(()) => {}
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => return Ok(()),
+        Err(err) => return Err(err.into()),
+    }
+    match tokio::fs::remove_file(&legacy_path).await {
+        Ok(()) => {}
+        Err(err) if err.kind() == ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+    }
+    info!("Moved legacy sidecar {legacy_path:?} to {signature_path:?}");
+    Ok(())
+}
+
+/// Files next to `legacy_path` whose sidecar older servers stored there. A file without an
+/// extension is among them, its sidecar still has that name.
+async fn legacy_claimants(legacy_path: &Path) -> Result<Vec<PathBuf>> {
+    let Some(dir) = legacy_path.parent() else {
+        return Ok(Vec::new());
+    };
+    let mut claimants = Vec::new();
+    let mut read_dir = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = read_dir.next_entry().await? {
+        let path = entry.path();
+        if entry.file_type().await?.is_file()
+            && !is_sidecar(&path)
+            && legacy_sidecar_path(&path) == legacy_path
+        {
+            claimants.push(path);
+        }
+    }
+    Ok(claimants)
+}
+
+fn file_exists_error() -> anyhow::Error {
+    HttpError::new(
+        StatusCode::CONFLICT,
+        "File already exists and overwriting is disabled",
+    )
+    .into()
+}
+
+/// Renames the file together with its signature, replacing the destination if it exists, unless
+/// `no_overwrite` is set. Journaled like an upload with the source data as its temp file, so a
+/// crash midway is completed on the next start instead of leaving the data without its sidecar.
+pub async fn rename_file(pubkey: &VerifyingKey, from: &str, to: &str) -> Result<()> {
+    let (from_path, from_signature_path) =
+        get_file_paths(&CONFIG.storage_path, pubkey, from).await?;
+    let (to_path, to_signature_path) = get_file_paths(&CONFIG.storage_path, pubkey, to).await?;
+
+    let _guard = FINALIZE_LOCK.lock().await;
+    let _invalidation = listing_cache::Invalidation(pubkey);
+    let size = match tokio::fs::metadata(&from_path).await {
+        Ok(metadata) => metadata.len(),
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            return Err(HttpError::new(StatusCode::NOT_FOUND, "File not found").into());
+        }
+        Err(err) => return Err(err.into()),
+    };
+    let metadata = read_metadata(&from_signature_path).await?;
+    if CONFIG.no_overwrite && tokio::fs::try_exists(&to_path).await? {
+        return Err(file_exists_error());
+    }
+    if from_path == to_path {
+        return Ok(());
+    }
+
+    create_parent_dir(&to_path).await?;
+    let entry = InstallEntry {
+        temp_path: from_path.clone(),
+        file_path: to_path.clone(),
+        signature_path: to_signature_path.clone(),
+        size,
+        metadata,
+        previous: read_metadata(&to_signature_path).await.ok(),
+    };
+    let journal_entry = journal::begin(&CONFIG.storage_path, &entry).await?;
+    write_metadata(&to_signature_path, &entry.metadata).await?;
+    tokio::fs::rename(&from_path, &to_path).await?;
+    // Left behind by a crash, the source sidecar is swept as orphaned once its data is gone
+    match tokio::fs::remove_file(&from_signature_path).await {
+        Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+        _ => {}
+    }
+    journal_entry.complete().await?;
+    info!("File renamed: {from_path:?} -> {to_path:?}");
+
+    Ok(())
+}
+
+/// Copies the file together with its signature and passphrase, replacing the destination if it
+/// exists, unless `no_overwrite` is set. The copied data is checked against the digest stored
+/// with the source, or its signature for files stored before digests were, so a source damaged
+/// on disk isn't copied under a signature it no longer matches. Unverified files stay unverified.
+pub async fn copy_file(pubkey: &VerifyingKey, from: &str, to: &str) -> Result<()> {
+    let (from_path, from_signature_path) =
+        get_file_paths(&CONFIG.storage_path, pubkey, from).await?;
+
+    let mut source = match File::open(&from_path).await {
+        Ok(source) => source,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            return Err(HttpError::new(StatusCode::NOT_FOUND, "File not found").into());
+        }
+        Err(err) => return Err(err.into()),
+    };
+    let metadata = read_metadata(&from_signature_path).await?;
+    let signature = metadata.signature()?;
+    let scheme = metadata.scheme()?;
+
+    let mut file_writer = FileWriter::new(pubkey).await?;
+    if metadata.unverified {
+        file_writer.skip_verification();
+    }
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let size = source.read(&mut buffer).await?;
+        if size == 0 {
+            break;
+        }
+        file_writer.append_chunk(&buffer[..size]).await?;
+    }
+
+    if let Some(hashers) = &mut file_writer.hashers {
+        let hashers = hashers.hashed().await;
+        let matches = match &metadata.digest {
+            Some(digest) => hasher::encode_hex(hashers.file.clone()) == *digest,
+            None => scheme
+                .verify_file(pubkey, hashers.file.clone(), &signature)
+                .is_ok(),
+        };
+        if !matches {
+            bail!("Stored data doesn't match its signature: {from_path:?}");
+        }
+    }
+
+    file_writer
+        .finalize(
+            to,
+            pubkey,
+            &signature,
+            scheme,
+            None,
+            metadata.passphrase_hash.clone(),
+        )
+        .await
+}
+
+/// Deletes the file together with its signature. With `expected_signature`, the file is only
+/// deleted if it's the version with that signature.
+pub async fn delete_file(
+    pubkey: &VerifyingKey,
+    filename: &str,
+    expected_signature: Option<&Signature>,
+) -> Result<()> {
+    let (file_path, signature_path) =
+        get_file_paths(&CONFIG.storage_path, pubkey, filename).await?;
+
+    let _guard = FINALIZE_LOCK.lock().await;
+    check_expected_signature(&signature_path, expected_signature).await?;
+    let _invalidation = listing_cache::Invalidation(pubkey);
+    // Data goes first, so an interruption leaves an orphaned signature that gets cleaned up later
+    match tokio::fs::remove_file(&file_path).await {
+        Ok(()) => {}
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            return Err(HttpError::new(StatusCode::NOT_FOUND, "File not found").into());
+        }
+        Err(err) => return Err(err.into()),
+    }
+    match tokio::fs::remove_file(&signature_path).await {
+        Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+        _ => {}
+    }
+    info!("File deleted: {file_path:?}");
+
+    Ok(())
+}
+
+/// Deletes the files of `pubkey` uploaded before `before`, in seconds since the Unix epoch, along
+/// with their signatures. Files without a known upload time are deleted only with
+/// `prune_files_without_upload_time`.
+pub async fn prune(pubkey: &VerifyingKey, before: u64) -> Result<PruneReport> {
+    let files = list_files(&CONFIG.storage_path, pubkey).await?;
+
+    let _guard = FINALIZE_LOCK.lock().await;
+    let _invalidation = listing_cache::Invalidation(pubkey);
+    let mut report = PruneReport::default();
+    for file in files {
+        let (file_path, signature_path) =
+            get_file_paths(&CONFIG.storage_path, pubkey, &file.filename).await?;
+        let uploaded_at = read_metadata(&signature_path)
+            .await
+            .ok()
+            .and_then(|metadata| metadata.uploaded_at);
+        let expired = match uploaded_at {
+            Some(uploaded_at) => uploaded_at < before,
+            None => CONFIG.prune_files_without_upload_time,
+        };
+        if !expired {
+            continue;
+        }
+
+        match tokio::fs::remove_file(&file_path).await {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::NotFound => continue,
+            Err(err) => return Err(err.into()),
+        }
+        match tokio::fs::remove_file(&signature_path).await {
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+        info!("File pruned: {file_path:?}");
+        report.removed += 1;
+    }
+
+    Ok(report)
+}
+
+/// Replaces the stored signature of a file whose data didn't change, e.g. after a re-key. The
+/// data is hashed again and `signature` must be valid for it, so the new signature can't be
+/// stored for different bytes. Block hashes, the digest and the outboard are recorded on the way, and a file
+/// stored as unverified becomes verified. The upload time is kept.
+pub async fn update_signature(
+    pubkey: &VerifyingKey,
+    filename: &str,
+    signature: &Signature,
+    scheme: SignatureScheme,
+    expected_signature: Option<&Signature>,
+) -> Result<()> {
+    let (file_path, signature_path) =
+        get_file_paths(&CONFIG.storage_path, pubkey, filename).await?;
+    let metadata = read_metadata(&signature_path).await?;
+
+    // Hashed without holding the lock, the stored signature tells if the file changed meanwhile
+    let mut file = match File::open(&file_path).await {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            return Err(HttpError::new(StatusCode::NOT_FOUND, "File not found").into());
+        }
+        Err(err) => return Err(err.into()),
+    };
+    let mut hashers = BatchHasher::new(ContentHashers::new(CONFIG.store_outboards));
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let size = file.read(&mut buffer).await?;
+        if size == 0 {
+            break;
+        }
+        hashers.update(&buffer[..size]).await;
+    }
+    let hashers = hashers.finish().await;
+    scheme
+        .verify_file(pubkey, hashers.file.clone(), signature)
+        .map_err(|_| {
+            HttpError::new(
+                StatusCode::BAD_REQUEST,
+                "Signature doesn't match the stored file",
+            )
+        })?;
+
+    let _guard = FINALIZE_LOCK.lock().await;
+    check_expected_signature(&signature_path, expected_signature).await?;
+    if read_metadata(&signature_path).await?.signature != metadata.signature {
+        return Err(HttpError::new(
+            StatusCode::CONFLICT,
+            "File changed while its signature was being updated",
+        )
+        .into());
+    }
+    let _invalidation = listing_cache::Invalidation(pubkey);
+    let mut new_metadata = FileMetadata::new(signature, scheme);
+    new_metadata.uploaded_at = metadata.uploaded_at;
+    new_metadata.passphrase_hash = metadata.passphrase_hash;
+    hashers.record(&mut new_metadata);
+    write_metadata(&signature_path, &new_metadata).await?;
+    info!("Signature updated: {signature_path:?}");
+
+    Ok(())
+}
+
+/// Runs the checks of [`FileWriter::finalize`] for an upload of `size` bytes before its body is
+/// read, so clients waiting for `100 Continue` don't send data that would be rejected. `finalize`
+/// checks again, since other requests may change the files meanwhile.
+pub async fn precheck_upload(
+    pubkey: &VerifyingKey,
+    filename: &str,
+    size: u64,
+    expected_signature: Option<&Signature>,
+) -> Result<()> {
+    let (file_path, signature_path) =
+        get_file_paths(&CONFIG.storage_path, pubkey, filename).await?;
+    check_expected_signature(&signature_path, expected_signature).await?;
+    if CONFIG.no_overwrite && tokio::fs::try_exists(&file_path).await? {
+        return Err(file_exists_error());
+    }
+    check_limits(pubkey, &file_path, size).await
+}
+
+/// Fails with 412 unless the stored file has `expected_signature`, if it's set. Callers acting on
+/// the file must hold `FINALIZE_LOCK`, so it doesn't change before they do.
+async fn check_expected_signature(
+    signature_path: &Path,
+    expected_signature: Option<&Signature>,
+) -> Result<()> {
+    let Some(expected_signature) = expected_signature else {
+        return Ok(());
+    };
+    let current_signature = read_metadata(signature_path)
+        .await
+        .and_then(|metadata| metadata.signature())
+        .ok();
+    if current_signature.as_ref() != Some(expected_signature) {
+        return Err(HttpError::new(
+            StatusCode::PRECONDITION_FAILED,
+            "Stored file doesn't match the expected version",
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Removes a signature file whose data file is missing, which happens when the server crashes in
+/// the middle of `FileWriter::finalize`. Returns whether it was removed.
+pub async fn remove_orphaned_signature(signature_path: &Path) -> bool {
+    let age = match tokio::fs::metadata(signature_path).await {
+        Ok(metadata) => metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.elapsed().ok()),
+        Err(_) => return false,
+    };
+
+    if age.is_some_and(|age| age > ORPHANED_SIGNATURE_AGE) {
+        warn!("Removing orphaned signature file: {signature_path:?}");
+        match tokio::fs::remove_file(signature_path).await {
+            Ok(()) => return true,
+            Err(err) => warn!("Failed to remove orphaned signature file {signature_path:?}: {err}"),
+        }
+    }
+    false
+}
+
+/// Removes the temp files left behind by uploads, run every `temp_sweep_interval_secs`.
+pub async fn sweep_stale_temp_files() -> Result<()> {
+    let max_age = CONFIG
+        .max_upload_duration_secs
+        .map_or(STALE_TEMP_FILE_AGE, Duration::from_secs);
+    let removed = sweep_temp_files(&CONFIG.storage_path, max_age).await?;
+    if removed > 0 {
+        info!("Removed {removed} temp files left behind by uploads");
+    }
+    Ok(())
+}
+
+/// Removes the temp files of uploads not written to for `max_age` from the system temp directory
+/// and the staging directories of all pubkeys. Uploads still in progress keep writing to theirs,
+/// so only the ones abandoned without cleanup are old enough. Returns how many were removed.
+pub async fn sweep_temp_files(storage_path: &Path, max_age: Duration) -> Result<usize> {
+    let mut dirs = vec![TEMP_DIR.clone()];
+    let mut read_dir = tokio::fs::read_dir(storage_path).await?;
+    while let Some(entry) = read_dir.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if entry.file_type().await?.is_dir() && decode_pubkey(&name).is_some() {
+            dirs.push(entry.path().join(STAGING_DIR));
+        }
+    }
+
+    let mut removed = 0;
+    for dir in dirs {
+        let mut read_dir = match tokio::fs::read_dir(&dir).await {
+            Ok(read_dir) => read_dir,
+            Err(err) if err.kind() == ErrorKind::NotFound => continue,
+            Err(err) => return Err(err.into()),
+        };
+        while let Some(entry) = read_dir.next_entry().await? {
+            if !entry.file_name().to_string_lossy().starts_with(TEMP_PREFIX) {
+                continue;
+            }
+            // Files that vanished or can't be read are left for the next sweep
+            let age = entry
+                .metadata()
+                .await
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|time| time.elapsed().ok());
+            if age.is_some_and(|age| age > max_age) {
+                let path = entry.path();
+                match tokio::fs::remove_file(&path).await {
+                    Ok(()) => {
+                        info!("Removed stale temp file: {path:?}");
+                        removed += 1;
+                    }
+                    Err(err) => warn!("Failed to remove stale temp file {path:?}: {err}"),
+                }
+            }
+        }
+    }
+    Ok(removed)
+}
+
+/// Removes orphaned signature files and then empty directories under every pubkey directory,
+/// including pubkey directories left empty. Staging directories are skipped since uploads write
+/// to them, so is the chunks directory. Holds the finalize lock, so no upload creates a directory
+/// while it's being removed.
+pub async fn trim(storage_path: impl AsRef<Path>) -> Result<TrimReport> {
+    let _guard = FINALIZE_LOCK.lock().await;
+    let mut report = TrimReport::default();
+
+    let mut read_dir = tokio::fs::read_dir(storage_path.as_ref()).await?;
+    while let Some(entry) = read_dir.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if entry.file_type().await?.is_dir() && decode_pubkey(&name).is_some() {
+            trim_dir(&entry.path(), &mut report).await?;
+        }
+    }
+
+    info!(
+        "Trimmed {} directories and {} signature files",
+        report.removed_dirs, report.removed_signatures
+    );
+    Ok(report)
+}
+
+/// Trims `dir` depth first, removing it too when nothing is left in it.
+async fn trim_dir(dir: &Path, report: &mut TrimReport) -> Result<()> {
+    let mut dirs = vec![(dir.to_path_buf(), false)];
+    while let Some((dir, visited)) = dirs.pop() {
+        if visited {
+            // Fails for directories that aren't empty, which are left alone
+            if tokio::fs::remove_dir(&dir).await.is_ok() {
+                info!("Removed empty directory: {dir:?}");
+                report.removed_dirs += 1;
+            }
+            continue;
+        }
+        dirs.push((dir.clone(), true));
+
+        let mut data_signatures = HashSet::new();
+        let mut signatures = Vec::new();
+        let mut read_dir = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = read_dir.next_entry().await? {
+            let path = entry.path();
+            let file_type = entry.file_type().await?;
+            if file_type.is_dir() {
+                if entry.file_name() != STAGING_DIR {
+                    dirs.push((path, false));
+                }
+            } else if is_sidecar(&path) {
+                signatures.push(path);
+            } else {
+                // Legacy sidecars not moved yet may still belong to the file
+                data_signatures.insert(sidecar_path(&path));
+                data_signatures.insert(legacy_sidecar_path(&path));
+            }
+        }
+        for signature_path in signatures {
+            if !data_signatures.contains(&signature_path)
+                && remove_orphaned_signature(&signature_path).await
+            {
+                report.removed_signatures += 1;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Lists all files stored under the pubkey, with filenames relative to the pubkey directory.
+pub async fn list_files(
+    storage_path: impl AsRef<Path>,
+    pubkey: &VerifyingKey,
+) -> Result<Vec<FileEntry>> {
+    let root = storage_path
+        .as_ref()
+        .join(bs58::encode(pubkey.as_bytes()).into_string());
+    if !CONFIG.cache_listings {
+        return list_layout_files(&root, CONFIG.layout).await;
+    }
+
+    match listing_cache::get(pubkey) {
+        Ok(files) => Ok(files),
+        Err(generation) => {
+            let files = list_layout_files(&root, CONFIG.layout).await?;
+            listing_cache::insert(pubkey, generation, files.clone());
+            Ok(files)
+        }
+    }
+}
+
+/// Fails unless a file can be created in the storage directory, so permission problems show up at
+/// startup rather than on the first upload.
+pub async fn check_writable(storage_path: &Path) -> Result<()> {
+    let probe_path = storage_path.join(".write-test");
+    let result = async {
+        tokio::fs::write(&probe_path, b"").await?;
+        tokio::fs::remove_file(&probe_path).await
+    }
+    .await;
+    result.with_context(|| {
+        format!(
+            "Storage directory {} is not writable, check its owner and permissions",
+            storage_path.display()
+        )
+    })
+}
+
+/// Keeps the `files` uploaded at or after `since`, in seconds since the Unix epoch. Files with no
+/// known upload time, like the ones stored by older servers, are kept so incremental backups
+/// never miss them.
+pub async fn retain_uploaded_since(
+    pubkey: &VerifyingKey,
+    files: Vec<FileEntry>,
+    since: u64,
+) -> Result<Vec<FileEntry>> {
+    let mut retained = Vec::new();
+    for file in files {
+        if uploaded_since(pubkey, &file, since).await? {
+            retained.push(file);
+        }
+    }
+    Ok(retained)
+}
+
+/// Whether `file` is kept by [`retain_uploaded_since`].
+pub async fn uploaded_since(pubkey: &VerifyingKey, file: &FileEntry, since: u64) -> Result<bool> {
+    let (_, signature_path) = get_file_paths(&CONFIG.storage_path, pubkey, &file.filename).await?;
+    let uploaded_at = read_metadata(&signature_path)
+        .await
+        .ok()
+        .and_then(|metadata| metadata.uploaded_at);
+    Ok(uploaded_at.is_none_or(|uploaded_at| uploaded_at >= since))
+}
+
+/// Lists the files under the pubkey directory `root` arranged in the given layout.
+async fn list_layout_files(root: &Path, layout: StorageLayout) -> Result<Vec<FileEntry>> {
+    let mut files = Vec::new();
+    let mut walker = FileWalker::new(root, layout);
+    while let Some(file) = walker.next().await? {
+        files.push(file);
+    }
+    files.sort_by(|a, b| a.filename.cmp(&b.filename));
+    Ok(files)
+}
+
+/// Walks the files under the pubkey directory `root` arranged in `layout` one at a time, in
+/// directory order, so a listing can be sent without holding all of it.
+pub struct FileWalker {
+    root: PathBuf,
+    layout: StorageLayout,
+    dirs: Vec<PathBuf>,
+    read_dir: Option<tokio::fs::ReadDir>,
+}
+
+impl FileWalker {
+    pub fn new(root: &Path, layout: StorageLayout) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            layout,
+            dirs: vec![root.to_path_buf()],
+            read_dir: None,
+        }
+    }
+
+    /// Walks the files of `pubkey` in the configured storage.
+    pub fn for_pubkey(pubkey: &VerifyingKey) -> Self {
+        let root = CONFIG
+            .storage_path
+            .join(bs58::encode(pubkey.as_bytes()).into_string());
+        Self::new(&root, CONFIG.layout)
+    }
+
+    pub async fn next(&mut self) -> Result<Option<FileEntry>> {
+        loop {
+            let Some(read_dir) = &mut self.read_dir else {
+                let Some(dir) = self.dirs.pop() else {
+                    return Ok(None);
+                };
+                self.read_dir = match tokio::fs::read_dir(&dir).await {
+                    Ok(read_dir) => Some(read_dir),
+                    Err(err) if err.kind() == ErrorKind::NotFound => None,
+                    Err(err) => return Err(err.into()),
+                };
+                continue;
+            };
+            let Some(entry) = read_dir.next_entry().await? else {
+                self.read_dir = None;
+                continue;
+            };
+
+            let path = entry.path();
+            let file_type = entry.file_type().await?;
+            if file_type.is_dir() {
+                if path != self.root.join(STAGING_DIR) {
+                    self.dirs.push(path);
+                }
+            } else if file_type.is_file() && !is_sidecar(&path) {
+                let components = path
+                    .strip_prefix(&self.root)?
+                    .iter()
+                    .map(|component| component.to_string_lossy())
+                    .collect::<Vec<_>>();
+                // The shard directory isn't part of the filename
+                let components = match self.layout {
+                    StorageLayout::Flat => &components[..],
+                    StorageLayout::Sharded if components.len() > 1 => &components[1..],
+                    StorageLayout::Sharded => continue,
+                };
+                let filename = if CONFIG.encode_filenames {
+                    components
+                        .iter()
+                        .map(|component| decode_component(component))
+                        .collect::<Result<Vec<_>>>()?
+                        .join("/")
+                } else {
+                    components.join("/")
+                };
+                return Ok(Some(FileEntry {
+                    filename,
+                    size: entry.metadata().await?.len(),
+                }));
+            }
+        }
+    }
+}
+
+/// Lists the pubkeys having at least one stored file.
+pub async fn list_pubkeys(storage_path: impl AsRef<Path>) -> Result<Vec<PubkeyEntry>> {
+    let mut pubkeys = Vec::new();
+    let mut read_dir = tokio::fs::read_dir(storage_path.as_ref()).await?;
+    while let Some(entry) = read_dir.next_entry().await? {
+        if !entry.file_type().await?.is_dir() {
+            continue;
+        }
+        // Skips the chunks directory and anything else that isn't a pubkey directory
+        let name = entry.file_name().to_string_lossy().to_string();
+        let Some(pubkey) = decode_pubkey(&name) else {
+            continue;
+        };
+
+        let files = list_files(storage_path.as_ref(), &pubkey).await?;
+        if !files.is_empty() {
+            pubkeys.push(PubkeyEntry {
+                pubkey: name,
+                files: files.len() as u64,
+                size: files.iter().map(|file| file.size).sum(),
+            });
+        }
+    }
+
+    pubkeys.sort_by(|a, b| a.pubkey.cmp(&b.pubkey));
+    Ok(pubkeys)
+}
+
+fn decode_pubkey(pubkey: &str) -> Option<VerifyingKey> {
+    bs58::decode(pubkey)
+        .into_vec()
+        .ok()
+        .and_then(|bytes| VerifyingKey::try_from(bytes.as_slice()).ok())
+}
+
+/// Names the sidecars of storage written by older servers after the full name of their file.
+/// Those replaced the extension of the file instead, so files differing only in it shared a
+/// sidecar, the one of the file stored last. A sidecar only one file can belong to is renamed,
+/// one shared is copied to the files whose data matches it and the others are logged, left
+/// without a sidecar.
+///
+/// Runs before the layout migration, which moves sidecars under their new names.
+pub async fn migrate_sidecars(storage_path: &Path) -> Result<()> {
+    let marker_path = storage_path.join(SIDECAR_MARKER);
+    if tokio::fs::try_exists(&marker_path).await? {
+        return Ok(());
+    }
+
+    // Pubkey directories, including the ones of an interrupted layout migration
+    let mut dirs = Vec::new();
+    let mut read_dir = tokio::fs::read_dir(storage_path).await?;
+    while let Some(entry) = read_dir.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let pubkey = name.split('.').next().and_then(decode_pubkey);
+        if let Some(pubkey) = pubkey.filter(|_| !name.starts_with('.')) {
+            if entry.file_type().await?.is_dir() {
+                dirs.push((entry.path(), pubkey));
+            }
+        }
+    }
+
+    let mut migrated = 0;
+    while let Some((dir, pubkey)) = dirs.pop() {
+        let mut data_paths = HashSet::new();
+        let mut sidecars = HashSet::new();
+        let mut read_dir = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = read_dir.next_entry().await? {
+            let path = entry.path();
+            if entry.file_type().await?.is_dir() {
+                if entry.file_name() != STAGING_DIR {
+                    dirs.push((path, pubkey));
+                }
+            } else if is_sidecar(&path) {
+                sidecars.insert(path);
+            } else {
+                data_paths.insert(path);
+            }
+        }
+
+        let mut claimants = BTreeMap::<PathBuf, Vec<&PathBuf>>::new();
+        for path in &data_paths {
+            let legacy_path = legacy_sidecar_path(path);
+            if sidecars.contains(&legacy_path) && !sidecars.contains(&sidecar_path(path)) {
+                claimants.entry(legacy_path).or_default().push(path);
+            }
+        }
+        for (legacy_path, paths) in claimants {
+            // A file without an extension keeps the sidecar under its legacy name
+            let owned = data_paths.contains(&legacy_path.with_extension(""));
+            if let ([path], false) = (paths.as_slice(), owned) {
+                tokio::fs::rename(&legacy_path, sidecar_path(path)).await?;
+                migrated += 1;
+                continue;
+            }
+
+            let metadata = match read_metadata(&legacy_path).await {
+                Ok(metadata) => metadata,
+                Err(err) => {
+                    warn!("Leaving unreadable shared sidecar {legacy_path:?} in place: {err}");
+                    continue;
+                }
+            };
+            for path in paths {
+                if data_matches(path, &pubkey, &metadata).await? {
+                    tokio::fs::copy(&legacy_path, sidecar_path(path)).await?;
+                    migrated += 1;
+                } else {
+                    warn!("No sidecar left for {path:?}, {legacy_path:?} belongs to another file");
+                }
+            }
+            if !owned {
+                tokio::fs::remove_file(&legacy_path).await?;
+            }
+        }
+    }
+
+    tokio::fs::write(&marker_path, "").await?;
+    if migrated > 0 {
+        info!("Renamed {migrated} sidecars after the full name of their file");
+    }
+    Ok(())
+}
+
+/// Whether the data at `path` is the one `metadata` was written for, by its digest or else its
+/// signature.
+async fn data_matches(path: &Path, pubkey: &VerifyingKey, metadata: &FileMetadata) -> Result<bool> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Hasher::default();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let size = file.read(&mut buffer).await?;
+        if size == 0 {
+            break;
+        }
+        hasher.update(&buffer[..size]);
+    }
+    Ok(match &metadata.digest {
+        Some(digest) => hasher::encode_hex(hasher) == *digest,
+        None => metadata
+            .scheme()?
+            .verify_file(pubkey, hasher, &metadata.signature()?)
+            .is_ok(),
+    })
+}
+
+/// Moves the stored files to `layout` if the storage was last used with another one. Storage
+/// without the marker file was written by versions with only the flat layout.
+///
+/// Pubkey directories are first renamed to `<pubkey>.<old layout>` and then emptied into fresh
+/// pubkey directories, so moved files can't overwrite ones not moved yet and an interrupted
+/// migration is finished on the next start.
+pub async fn migrate_layout(storage_path: &Path, layout: StorageLayout) -> Result<()> {
+    let marker_path = storage_path.join(LAYOUT_MARKER);
+    let stored_layout = match tokio::fs::read_to_string(&marker_path).await {
+        Ok(marker) => StorageLayout::from_str(marker.trim())?,
+        Err(err) if err.kind() == ErrorKind::NotFound => StorageLayout::Flat,
+        Err(err) => return Err(err.into()),
+    };
+
+    if stored_layout != layout {
+        info!("Migrating storage from {stored_layout} to {layout} layout");
+        let mut read_dir = tokio::fs::read_dir(storage_path).await?;
+        while let Some(entry) = read_dir.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if entry.file_type().await?.is_dir() && decode_pubkey(&name).is_some() {
+                tokio::fs::rename(
+                    entry.path(),
+                    storage_path.join(format!("{name}.{stored_layout}")),
+                )
+                .await?;
+            }
+        }
+        tokio::fs::write(&marker_path, layout.to_string()).await?;
+    }
+
+    let mut read_dir = tokio::fs::read_dir(storage_path).await?;
+    while let Some(entry) = read_dir.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let Some((pubkey, old_layout)) = name.split_once('.') else {
+            continue;
+        };
+        let Ok(old_layout) = StorageLayout::from_str(old_layout) else {
+            continue;
+        };
+        if decode_pubkey(pubkey).is_none() {
+            continue;
+        }
+
+        let old_dir = entry.path();
+        let new_dir = storage_path.join(pubkey);
+        let files = list_layout_files(&old_dir, old_layout).await?;
+        for file in &files {
+            let (from_path, from_signature_path) =
+                layout_file_paths(&old_dir, &file.filename, old_layout)?;
+            let (to_path, to_signature_path) = layout_file_paths(&new_dir, &file.filename, layout)?;
+            create_parent_dir(&to_path).await?;
+            if tokio::fs::try_exists(&from_signature_path).await? {
+                tokio::fs::rename(&from_signature_path, &to_signature_path).await?;
+            }
+            tokio::fs::rename(&from_path, &to_path).await?;
+        }
+        tokio::fs::remove_dir_all(&old_dir).await?;
+        info!("Moved {} files of {pubkey} to {layout} layout", files.len());
+    }
+
+    Ok(())
+}
+
+/// Total size of the files stored under the pubkey.
+pub async fn used_bytes(storage_path: impl AsRef<Path>, pubkey: &VerifyingKey) -> Result<u64> {
+    Ok(list_files(storage_path, pubkey)
+        .await?
+        .iter()
+        .map(|file| file.size)
+        .sum())
+}
+
+/// Fails if storing `new_size` bytes at `file_path` would put the pubkey over its limits: with 413
+/// for a new file beyond the file count limit, with 507 for exceeding the byte quota.
+async fn check_limits(pubkey: &VerifyingKey, file_path: &Path, new_size: u64) -> Result<()> {
+    check_batch_limits(pubkey, &[(file_path, new_size)], &[]).await
+}
+
+/// Like [`check_limits`] for storing all of `uploads`, given as paths and sizes, and deleting the
+/// `deleted` paths at once.
+async fn check_batch_limits(
+    pubkey: &VerifyingKey,
+    uploads: &[(&Path, u64)],
+    deleted: &[&Path],
+) -> Result<()> {
+    let settings = CONFIG.for_pubkey(pubkey);
+    if settings.max_files.is_none() && settings.max_bytes.is_none() {
+        return Ok(());
+    }
+
+    let files = list_files(&CONFIG.storage_path, pubkey).await?;
+    let used = files.iter().map(|file| file.size).sum::<u64>();
+    let requested = uploads.iter().map(|(_, size)| size).sum::<u64>();
+    let (mut freed, mut removed, mut added) = (0, 0, 0);
+    for path in deleted {
+        if let Some(size) = stored_size(path).await? {
+            freed += size;
+            removed += 1;
+        }
+    }
+    // Overwriting an existing file doesn't add to the count. The sizes are read separately from
+    // the listing, so the subtractions saturate in case a file changed in between.
+    for (path, _) in uploads {
+        match stored_size(path).await? {
+            Some(size) => freed += size,
+            None => added += 1,
+        }
+    }
+
+    if let Some(max_files) = settings.max_files {
+        if added > 0 && (files.len() as u64 + added).saturating_sub(removed) > max_files {
+            return Err(HttpError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!(
+                    "File limit reached: {} of {max_files} files stored",
+                    files.len()
+                ),
+            )
+            .into());
+        }
+    }
+
+    let Some(quota) = settings.max_bytes else {
+        return Ok(());
+    };
+    if used.saturating_sub(freed) + requested > quota {
+        return Err(HttpError::new(
+            StatusCode::INSUFFICIENT_STORAGE,
+            format!(
+                "Storage quota exceeded: {used} of {quota} bytes used, {requested} more requested"
+            ),
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Size of the file at `path`, `None` if there's none.
+async fn stored_size(path: &Path) -> Result<Option<u64>> {
+    match tokio::fs::metadata(path).await {
+        Ok(metadata) => Ok(Some(metadata.len())),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Percent-encodes everything except lowercase ASCII letters, digits and a few punctuation
+/// characters, so distinct names never map to the same file on case-insensitive filesystems.
+fn encode_component(component: &str) -> String {
+    let mut encoded = String::with_capacity(component.len());
+    for byte in component.bytes() {
+        match byte {
+            b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b' ' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+fn decode_component(component: &str) -> Result<String> {
+    let mut decoded = Vec::with_capacity(component.len());
+    let mut bytes = component.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let hex = [
+                bytes
+                    .next()
+                    .ok_or(anyhow!("Truncated escape in {component}"))?,
+                bytes
+                    .next()
+                    .ok_or(anyhow!("Truncated escape in {component}"))?,
+            ];
+            decoded.push(u8::from_str_radix(std::str::from_utf8(&hex)?, 16)?);
+        } else {
+            decoded.push(byte);
+        }
+    }
+    Ok(String::from_utf8(decoded)?)
+}
+
+/// Returns the directory temp files of uploads by `pubkey` are written to. With
+/// `per_pubkey_staging` it's inside the pubkey directory, so it's on the same filesystem as the
+/// stored files and counts against filesystem quotas of that directory.
+fn staging_dir(storage_path: impl AsRef<Path>, pubkey: &VerifyingKey) -> PathBuf {
+    if CONFIG.per_pubkey_staging {
+        storage_path
+            .as_ref()
+            .join(bs58::encode(pubkey.as_bytes()).into_string())
+            .join(STAGING_DIR)
+    } else {
+        TEMP_DIR.clone()
+    }
+}
+
+/// Returns the directory holding the uploaded chunks of the file.
+pub fn get_chunks_dir(
+    storage_path: impl AsRef<Path>,
+    pubkey: &VerifyingKey,
+    filename: &str,
+) -> PathBuf {
+    let mut hasher = Hasher::default();
+    hasher.update(filename.as_bytes());
+    storage_path
+        .as_ref()
+        .join(CHUNKS_DIR)
+        .join(bs58::encode(pubkey.as_bytes()).into_string())
+        .join(encode_chunk_hash(hasher))
+}
+
+pub fn get_chunk_path(chunks_dir: &Path, index: u32) -> PathBuf {
+    chunks_dir.join(format!("{index}.chunk"))
+}
+
+async fn create_parent_dir(path: &Path) -> Result<()> {
+    tokio::fs::create_dir_all(
+        path.parent()
+            .ok_or(anyhow!("Unable to get parent directory"))?,
+    )
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn file_writer_retries_taken_temp_names() {
+        let dir = std::env::temp_dir().join(format!("cloud-test-{:x}", rand::random::<u64>()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+
+        let counter = TEMP_COUNTER.load(Ordering::Relaxed);
+        let taken = dir.join(format!("{TEMP_PREFIX}-{counter}-{:032x}.tmp", 0));
+        tokio::fs::write(&taken, b"taken").await.unwrap();
+
+        let mut numbers = 0..;
+        let writer = FileWriter::with_random_source(&dir, || numbers.next().unwrap())
+            .await
+            .unwrap();
+
+        let (_, temp_filename) = writer.temp_file.as_ref().unwrap();
+        let expected = dir.join(format!("{TEMP_PREFIX}-{}-{:032x}.tmp", counter + 1, 1));
+        assert_eq!(temp_filename, &expected);
+        assert_eq!(tokio::fs::read(&taken).await.unwrap(), b"taken");
+
+        drop(writer);
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,18 @@
+use once_cell::sync::Lazy;
+use warp::{Filter, Rejection, Reply};
+
+use shared::consts::CLIENT_VERSION;
+
+/// The page reports the version of the server it comes with, since they're released together.
+static INDEX_HTML: Lazy<String> = Lazy::new(|| {
+    include_str!("../assets/index.html").replace("{{CLIENT_VERSION}}", CLIENT_VERSION)
+});
+
+/// Serves the browser frontend at `/ui`. The page signs requests itself, so it only needs the
+/// regular `list` and `download` methods.
+pub fn route() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::get()
+        .and(warp::path("ui"))
+        .and(warp::path::end())
+        .map(|| warp::reply::html(INDEX_HTML.as_str()))
+}
@@ -0,0 +1,312 @@
+//! Runs the server binary against a storage directory of its own, and sends it requests signed
+//! the way the client signs them.
+
+// Each test binary uses its own share of the helpers
+#![allow(dead_code)]
+
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+use ed25519_dalek::ed25519::signature::digest::{FixedOutput, Update};
+use ed25519_dalek::{DigestSigner, Signature, SigningKey};
+use reqwest::header::CONTENT_LENGTH;
+use reqwest::{RequestBuilder, Response, StatusCode};
+use shared::consts::*;
+use shared::hasher::Hasher;
+use shared::listing::ShareEntry;
+use shared::SignableRequest;
+
+const LOG_CONFIG: &str = "\
+appenders:
+  stdout:
+    kind: console
+    encoder:
+      pattern: \"{l} {M}: {m}{n}\"
+root:
+  level: info
+  appenders:
+    - stdout
+";
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// A server process listening on a free local port, killed and cleaned up when dropped.
+pub struct TestServer {
+    child: Child,
+    dir: PathBuf,
+    addr: SocketAddr,
+    settings: Vec<(String, String)>,
+    url: String,
+    key: SigningKey,
+    client: reqwest::Client,
+}
+
+impl TestServer {
+    /// Starts the server with the default config except for `settings`, pairs of config field
+    /// names and JSON values passed as `CLOUD_SERVER_*` variables.
+    pub fn start(settings: &[(&str, &str)]) -> Self {
+        let dir =
+            std::env::temp_dir().join(format!("cloud-server-test-{:x}", rand::random::<u64>()));
+        std::fs::create_dir_all(dir.join("storage")).unwrap();
+        std::fs::write(dir.join("log_config.yml"), LOG_CONFIG).unwrap();
+
+        let addr = free_addr();
+        let settings = settings
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<Vec<_>>();
+        let mut server = Self {
+            child: spawn(&dir, addr, &settings),
+            dir,
+            addr,
+            settings,
+            url: format!("http://{addr}/"),
+            key: SigningKey::from_bytes(&rand::random()),
+            client: reqwest::Client::new(),
+        };
+        server.wait_until_listening(addr);
+        server
+    }
+
+    /// Stops the server and starts it again on the same storage, running its startup tasks.
+    pub fn restart(&mut self) {
+        self.child.kill().unwrap();
+        self.child.wait().unwrap();
+        self.child = spawn(&self.dir, self.addr, &self.settings);
+        // Pooled connections went down with the old process
+        self.client = reqwest::Client::new();
+        self.wait_until_listening(self.addr);
+    }
+
+    fn wait_until_listening(&mut self, addr: SocketAddr) {
+        let started = Instant::now();
+        while TcpStream::connect(addr).is_err() {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                panic!("Server exited with {status}, log:\n{}", self.log());
+            }
+            assert!(
+                started.elapsed() < STARTUP_TIMEOUT,
+                "Server didn't start listening, log:\n{}",
+                self.log()
+            );
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    /// What the server logged so far.
+    pub fn log(&self) -> String {
+        std::fs::read_to_string(self.dir.join("log.txt")).unwrap_or_default()
+    }
+
+    /// Directory the files of the test key are stored in.
+    pub fn pubkey_dir(&self) -> PathBuf {
+        self.dir.join("storage").join(self.pubkey())
+    }
+
+    pub fn pubkey(&self) -> String {
+        bs58::encode(self.key.verifying_key().as_bytes()).into_string()
+    }
+
+    pub fn key(&self) -> &SigningKey {
+        &self.key
+    }
+
+    /// Signs the requests from now on with `key`, acting as another user.
+    pub fn set_key(&mut self, key: SigningKey) {
+        self.key = key;
+    }
+
+    /// Starts a request to `method` signed for `filename`.
+    pub fn request(&self, method: &str, filename: &str) -> RequestBuilder {
+        self.request_signed_for(method, method, filename)
+    }
+
+    /// Starts a request to `method` carrying a signature made for `signed_method`, like one
+    /// captured from another request and replayed.
+    pub fn request_signed_for(
+        &self,
+        method: &str,
+        signed_method: &str,
+        filename: &str,
+    ) -> RequestBuilder {
+        let request = SignableRequest::new(
+            signed_method,
+            filename.to_string(),
+            self.key.verifying_key(),
+        )
+        .unwrap()
+        .sign(&self.key)
+        .unwrap();
+        self.client
+            .post(format!("{}{method}", self.url))
+            .header(PARAM_FILENAME, filename)
+            .header(PARAM_PUBKEY, self.pubkey())
+            .header(PARAM_TIME, request.time())
+            .header(
+                PARAM_REQUEST_SIGNATURE,
+                bs58::encode(request.signature().to_bytes()).into_string(),
+            )
+    }
+
+    /// Starts a `rename` or `copy` request from `filename` to `destination`.
+    pub fn transfer(&self, method: &str, filename: &str, destination: &str) -> RequestBuilder {
+        let destination_signature = self.sign_request(method, destination);
+        self.request(method, filename)
+            .header(PARAM_DESTINATION, destination)
+            .header(
+                PARAM_DESTINATION_SIGNATURE,
+                bs58::encode(destination_signature.to_bytes()).into_string(),
+            )
+    }
+
+    fn sign_request(&self, method: &str, filename: &str) -> Signature {
+        *SignableRequest::new(method, filename.to_string(), self.key.verifying_key())
+            .unwrap()
+            .sign(&self.key)
+            .unwrap()
+            .signature()
+    }
+
+    /// Starts an upload of `data`, for adding headers to it.
+    pub fn upload_request(&self, filename: &str, data: &[u8]) -> RequestBuilder {
+        self.request(METHOD_UPLOAD, filename)
+            .header(PARAM_FILE_SIGNATURE, self.file_signature(data))
+            // Sent even when empty, like the client does
+            .header(CONTENT_LENGTH, data.len())
+            .body(data.to_vec())
+    }
+
+    pub async fn upload(&self, filename: &str, data: &[u8]) -> Response {
+        self.upload_request(filename, data).send().await.unwrap()
+    }
+
+    /// Uploads `data`, failing the test unless it's stored.
+    pub async fn store(&self, filename: &str, data: &[u8]) {
+        let response = self.upload(filename, data).await;
+        assert_eq!(response.status(), StatusCode::OK, "{filename}");
+    }
+
+    pub async fn download(&self, filename: &str) -> Response {
+        self.request(METHOD_DOWNLOAD, filename)
+            .send()
+            .await
+            .unwrap()
+    }
+
+    /// Downloads the file, failing the test unless it's found.
+    pub async fn read(&self, filename: &str) -> Vec<u8> {
+        let response = self.download(filename).await;
+        assert_eq!(response.status(), StatusCode::OK, "{filename}");
+        response.bytes().await.unwrap().to_vec()
+    }
+
+    /// Names of the stored files, as listed by the server.
+    pub async fn list(&self) -> Vec<String> {
+        let response = self.request(METHOD_LIST, "").send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        response
+            .json::<Vec<serde_json::Value>>()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|file| file["filename"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    /// Creates a share link to the file, failing the test unless it's created.
+    pub async fn share(&self, filename: &str) -> ShareEntry {
+        let response = self.request(METHOD_SHARE, filename).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK, "{filename}");
+        response.json().await.unwrap()
+    }
+
+    /// Starts a download through the share link with `token`, sent without authentication.
+    pub fn shared(&self, token: &str) -> RequestBuilder {
+        self.client
+            .get(format!("{}{METHOD_SHARED}/{token}", self.url))
+    }
+
+    /// Base58 Ed25519ph signature of `data`, as sent in the `file-signature` header.
+    pub fn file_signature(&self, data: &[u8]) -> String {
+        let mut hasher = Hasher::default();
+        hasher.update(data);
+        bs58::encode(self.key.sign_digest(hasher).to_bytes()).into_string()
+    }
+
+    /// Base58 digest of `data`, as sent in the `file-digest` header.
+    pub fn file_digest(&self, data: &[u8]) -> String {
+        let mut hasher = Hasher::default();
+        hasher.update(data);
+        bs58::encode(hasher.finalize_fixed()).into_string()
+    }
+
+    /// Path of the stored data of `filename` in the flat layout.
+    pub fn data_path(&self, filename: &str) -> PathBuf {
+        self.pubkey_dir().join(filename)
+    }
+
+    /// Path of the metadata sidecar of `filename` in the flat layout.
+    pub fn signature_path(&self, filename: &str) -> PathBuf {
+        let mut path = self.data_path(filename).into_os_string();
+        path.push(".sig");
+        path.into()
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.child.kill().ok();
+        self.child.wait().ok();
+        std::fs::remove_dir_all(&self.dir).ok();
+    }
+}
+
+fn spawn(dir: &Path, addr: SocketAddr, settings: &[(String, String)]) -> Child {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_server"));
+    command
+        .current_dir(dir)
+        .env_clear()
+        .env("CLOUD_SERVER_LISTEN_ADDR", addr.to_string())
+        .env("CLOUD_SERVER_STORAGE_PATH", dir.join("storage"))
+        .env("CLOUD_SERVER_BACKGROUND_TASKS", "false")
+        .stdin(Stdio::null())
+        .stdout(
+            std::fs::File::options()
+                .create(true)
+                .append(true)
+                .open(dir.join("log.txt"))
+                .unwrap(),
+        )
+        .stderr(Stdio::inherit());
+    for (name, value) in settings {
+        command.env(format!("CLOUD_SERVER_{}", name.to_uppercase()), value);
+    }
+    command.spawn().expect("Failed to start the server")
+}
+
+fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+/// Every file under `dir`, relative to it.
+pub fn files_under(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(current) = dirs.pop() {
+        for entry in std::fs::read_dir(&current).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else {
+                files.push(path.strip_prefix(dir).unwrap().to_path_buf());
+            }
+        }
+    }
+    files.sort();
+    files
+}
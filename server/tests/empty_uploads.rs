@@ -0,0 +1,29 @@
+mod common;
+
+use common::TestServer;
+use reqwest::StatusCode;
+
+#[tokio::test]
+async fn empty_uploads_are_stored_by_default() {
+    let server = TestServer::start(&[]);
+    server.store("empty.txt", b"").await;
+    assert_eq!(server.read("empty.txt").await, b"");
+    assert_eq!(server.list().await, ["empty.txt"]);
+}
+
+#[tokio::test]
+async fn reject_empty_uploads_refuses_empty_files() {
+    let server = TestServer::start(&[("reject_empty_uploads", "true")]);
+    let response = server.upload("empty.txt", b"").await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(
+        response.text().await.unwrap(),
+        "Empty files are not accepted"
+    );
+    assert!(server.list().await.is_empty());
+
+    server.store("full.txt", b"data").await;
+    let response = server.upload("full.txt", b"").await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(server.read("full.txt").await, b"data");
+}
@@ -0,0 +1,18 @@
+mod common;
+
+use common::TestServer;
+
+const NFC: &str = "caf\u{e9}.txt";
+const NFD: &str = "cafe\u{301}.txt";
+
+#[tokio::test]
+async fn nfc_and_nfd_names_resolve_to_one_file() {
+    let server = TestServer::start(&[("normalize_filenames", "true")]);
+
+    server.store(NFD, b"first").await;
+    assert_eq!(server.read(NFC).await, b"first");
+
+    server.store(NFC, b"second").await;
+    assert_eq!(server.read(NFD).await, b"second");
+    assert_eq!(server.list().await, [NFC]);
+}
@@ -0,0 +1,37 @@
+mod common;
+
+use common::TestServer;
+use reqwest::StatusCode;
+use shared::consts::*;
+
+#[tokio::test]
+async fn new_files_beyond_the_file_limit_are_rejected() {
+    let server = TestServer::start(&[("max_files_per_pubkey", "3")]);
+    for filename in ["a.txt", "b.txt", "c.txt"] {
+        server.store(filename, filename.as_bytes()).await;
+    }
+
+    let response = server.upload("d.txt", b"d").await;
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    let response = server
+        .transfer(METHOD_COPY, "a.txt", "d.txt")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(server.list().await, ["a.txt", "b.txt", "c.txt"]);
+
+    // Replacing or renaming a stored file doesn't add one
+    server.store("a.txt", b"replaced").await;
+    let response = server
+        .transfer(METHOD_RENAME, "c.txt", "d.txt")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = server.request(METHOD_DELETE, "b.txt").send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    server.store("e.txt", b"e").await;
+    assert_eq!(server.list().await, ["a.txt", "d.txt", "e.txt"]);
+}
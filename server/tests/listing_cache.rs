@@ -0,0 +1,66 @@
+mod common;
+
+use common::TestServer;
+use reqwest::StatusCode;
+use shared::chunks::BatchPlan;
+use shared::consts::*;
+use shared::listing::signed_body_name;
+
+/// Stores a copy of `seed.txt` as `filename` without the server knowing, so it only shows up in
+/// listings read from the storage rather than the cache.
+fn store_behind_the_back(server: &TestServer, filename: &str) {
+    std::fs::copy(server.data_path("seed.txt"), server.data_path(filename)).unwrap();
+    std::fs::copy(
+        server.signature_path("seed.txt"),
+        server.signature_path(filename),
+    )
+    .unwrap();
+}
+
+#[tokio::test]
+async fn changes_drop_the_cached_listing() {
+    let server = TestServer::start(&[("cache_listings", "true")]);
+    server.store("seed.txt", b"seed").await;
+    assert_eq!(server.list().await, ["seed.txt"]);
+
+    store_behind_the_back(&server, "x1.txt");
+    assert_eq!(server.list().await, ["seed.txt"], "listing isn't cached");
+
+    server.store("a.txt", b"a").await;
+    assert_eq!(server.list().await, ["a.txt", "seed.txt", "x1.txt"]);
+
+    store_behind_the_back(&server, "x2.txt");
+    let response = server.request(METHOD_DELETE, "a.txt").send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(server.list().await, ["seed.txt", "x1.txt", "x2.txt"]);
+
+    store_behind_the_back(&server, "x3.txt");
+    let response = server
+        .transfer(METHOD_RENAME, "x1.txt", "r1.txt")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        server.list().await,
+        ["r1.txt", "seed.txt", "x2.txt", "x3.txt"]
+    );
+
+    store_behind_the_back(&server, "x4.txt");
+    let plan = BatchPlan {
+        uploads: Vec::new(),
+        deletes: vec!["x2.txt".to_string()],
+    };
+    let body = serde_json::to_vec(&plan).unwrap();
+    let response = server
+        .request(METHOD_BATCH, &signed_body_name(&body))
+        .body(body)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        server.list().await,
+        ["r1.txt", "seed.txt", "x3.txt", "x4.txt"]
+    );
+}
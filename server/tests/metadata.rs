@@ -0,0 +1,96 @@
+mod common;
+
+use common::TestServer;
+use reqwest::StatusCode;
+use shared::consts::*;
+
+const DATA: &[u8] = b"contents of a file whose sidecar got damaged";
+
+/// Stores `a.txt` and returns its sidecar and file signature.
+async fn store(server: &TestServer) -> (String, String) {
+    server.store("a.txt", DATA).await;
+    let sidecar = std::fs::read_to_string(server.signature_path("a.txt")).unwrap();
+    (sidecar, server.file_signature(DATA))
+}
+
+async fn assert_served(server: &TestServer, signature: &str) {
+    let response = server.download("a.txt").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[PARAM_FILE_SIGNATURE], signature);
+    assert_eq!(response.bytes().await.unwrap(), DATA);
+}
+
+#[tokio::test]
+async fn truncated_sidecar_is_served() {
+    let server = TestServer::start(&[]);
+    let (sidecar, signature) = store(&server).await;
+
+    // Cut right after the signature, as a crash while writing it would
+    let end = sidecar.find(&signature).unwrap() + signature.len() + 1;
+    assert!(end < sidecar.len());
+    std::fs::write(server.signature_path("a.txt"), &sidecar[..end]).unwrap();
+    assert_served(&server, &signature).await;
+}
+
+#[tokio::test]
+async fn truncated_sidecar_keeps_the_passphrase() {
+    let server = TestServer::start(&[]);
+    let response = server
+        .upload_request("a.txt", DATA)
+        .header(PARAM_FILE_PASSPHRASE, "secret")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let sidecar = std::fs::read_to_string(server.signature_path("a.txt")).unwrap();
+    let signature = server.file_signature(DATA);
+
+    let end = sidecar.find(&signature).unwrap() + signature.len() + 1;
+    std::fs::write(server.signature_path("a.txt"), &sidecar[..end]).unwrap();
+    let response = server.download("a.txt").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = server
+        .request(METHOD_DOWNLOAD, "a.txt")
+        .header(PARAM_FILE_PASSPHRASE, "secret")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn truncated_sidecar_of_older_servers_is_not_served() {
+    let server = TestServer::start(&[]);
+    let (_, signature) = store(&server).await;
+
+    // Older servers wrote the passphrase hash last, a cut may have dropped it
+    let sidecar = format!(
+        "{{\"version\":1,\"algorithm\":\"ed25519-blake3\",\"signature\":\"{signature}\",\"uploaded_at\":1,\"pass"
+    );
+    std::fs::write(server.signature_path("a.txt"), sidecar).unwrap();
+    let response = server.download("a.txt").await;
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+}
+
+#[tokio::test]
+async fn garbage_sidecar_is_served() {
+    let server = TestServer::start(&[]);
+    let (_, signature) = store(&server).await;
+
+    let garbage = format!(
+        "\0\0{{\"version\": \"one\", \"passphrase_hash\": null, \"signature\": \"{signature}\", \"uploaded_at\": [}}\u{fffd}"
+    );
+    std::fs::write(server.signature_path("a.txt"), garbage).unwrap();
+    assert_served(&server, &signature).await;
+}
+
+#[tokio::test]
+async fn sidecar_without_signature_is_not_served() {
+    let server = TestServer::start(&[]);
+    let (sidecar, signature) = store(&server).await;
+
+    let end = sidecar.find(&signature).unwrap() + 10;
+    std::fs::write(server.signature_path("a.txt"), &sidecar[..end]).unwrap();
+    let response = server.download("a.txt").await;
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+}
@@ -0,0 +1,72 @@
+mod common;
+
+use common::TestServer;
+use ed25519_dalek::ed25519::signature::digest::FixedOutput;
+use reqwest::header::RANGE;
+use reqwest::StatusCode;
+use shared::consts::*;
+use shared::outboard::{Outboard, OutboardHasher, GROUP_SIZE};
+
+fn data() -> Vec<u8> {
+    (0..2 * GROUP_SIZE as usize + 12345)
+        .map(|index| (index % 251) as u8)
+        .collect()
+}
+
+async fn outboard(server: &TestServer, filename: &str) -> Option<Outboard> {
+    let response = server
+        .request(METHOD_OUTBOARD, filename)
+        .send()
+        .await
+        .unwrap();
+    match response.status() {
+        StatusCode::OK => Some(response.json().await.unwrap()),
+        StatusCode::NOT_FOUND => None,
+        status => panic!("Unexpected status {status}"),
+    }
+}
+
+#[tokio::test]
+async fn outboards_match_the_signature_and_verify_ranges() {
+    let server = TestServer::start(&[("store_outboards", "true")]);
+    let data = data();
+    server.store("big.bin", &data).await;
+
+    let stored = outboard(&server, "big.bin").await.unwrap();
+    assert_eq!(stored.group_size, GROUP_SIZE);
+    assert_eq!(stored.chaining_values.len(), 3);
+    let root = bs58::encode(stored.root().unwrap().finalize_fixed()).into_string();
+    assert_eq!(root, server.file_digest(&data));
+
+    // A range starting at the second group verifies without the first one
+    let response = server
+        .request(METHOD_DOWNLOAD, "big.bin")
+        .header(RANGE, format!("bytes={GROUP_SIZE}-"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    let mut hasher = OutboardHasher::new(GROUP_SIZE, 1);
+    hasher.update(&response.bytes().await.unwrap());
+    assert_eq!(hasher.finish().chaining_values, stored.chaining_values[1..]);
+
+    let response = server
+        .transfer(METHOD_COPY, "big.bin", "copy.bin")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let copied = outboard(&server, "copy.bin").await.unwrap();
+    assert_eq!(copied.chaining_values, stored.chaining_values);
+}
+
+#[tokio::test]
+async fn outboards_are_only_stored_for_large_files_when_enabled() {
+    let server = TestServer::start(&[("store_outboards", "true")]);
+    server.store("small.bin", &[1; 1000]).await;
+    assert!(outboard(&server, "small.bin").await.is_none());
+
+    let server = TestServer::start(&[]);
+    server.store("big.bin", &data()).await;
+    assert!(outboard(&server, "big.bin").await.is_none());
+}
@@ -0,0 +1,54 @@
+mod common;
+
+use common::TestServer;
+use reqwest::StatusCode;
+use shared::consts::*;
+
+#[tokio::test]
+async fn existing_files_are_replaced_by_default() {
+    let server = TestServer::start(&[]);
+    server.store("a.txt", b"first").await;
+    server.store("a.txt", b"second").await;
+    assert_eq!(server.read("a.txt").await, b"second");
+
+    server.store("b.txt", b"copied").await;
+    let response = server
+        .transfer(METHOD_COPY, "b.txt", "a.txt")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(server.read("a.txt").await, b"copied");
+
+    server.store("c.txt", b"renamed").await;
+    let response = server
+        .transfer(METHOD_RENAME, "c.txt", "a.txt")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(server.read("a.txt").await, b"renamed");
+    assert_eq!(server.list().await, ["a.txt", "b.txt"]);
+}
+
+#[tokio::test]
+async fn no_overwrite_rejects_writes_onto_existing_files() {
+    let server = TestServer::start(&[("no_overwrite", "true")]);
+    server.store("a.txt", b"first").await;
+    server.store("b.txt", b"other").await;
+
+    let response = server.upload("a.txt", b"second").await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    for method in [METHOD_COPY, METHOD_RENAME] {
+        let response = server
+            .transfer(method, "b.txt", "a.txt")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT, "{method}");
+    }
+
+    assert_eq!(server.read("a.txt").await, b"first");
+    assert_eq!(server.read("b.txt").await, b"other");
+    server.store("c.txt", b"new").await;
+}
@@ -0,0 +1,71 @@
+mod common;
+
+use common::TestServer;
+use reqwest::{RequestBuilder, StatusCode};
+use shared::consts::*;
+
+async fn upload_protected(server: &TestServer, filename: &str, data: &[u8]) {
+    let response = server
+        .upload_request(filename, data)
+        .header(PARAM_FILE_PASSPHRASE, "secret")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+/// Sends `request` without a passphrase, with a wrong one and with the right one, checking it's
+/// only served with the last.
+async fn assert_protected(request: impl Fn() -> RequestBuilder, data: &[u8]) {
+    let response = request().send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = request()
+        .header(PARAM_FILE_PASSPHRASE, "wrong")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = request()
+        .header(PARAM_FILE_PASSPHRASE, "secret")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.bytes().await.unwrap(), data);
+}
+
+#[tokio::test]
+async fn downloads_need_the_passphrase() {
+    let server = TestServer::start(&[]);
+    upload_protected(&server, "a.txt", b"data").await;
+    assert_protected(|| server.request(METHOD_DOWNLOAD, "a.txt"), b"data").await;
+
+    // Copies keep the passphrase
+    let response = server
+        .transfer(METHOD_COPY, "a.txt", "b.txt")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_protected(|| server.request(METHOD_DOWNLOAD, "b.txt"), b"data").await;
+
+    // Uploading again without one removes it
+    server.store("a.txt", b"public").await;
+    assert_eq!(server.read("a.txt").await, b"public");
+
+    let response = server
+        .upload_request("c.txt", b"data")
+        .header(PARAM_FILE_PASSPHRASE, "")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn shared_downloads_need_the_passphrase() {
+    let server = TestServer::start(&[]);
+    upload_protected(&server, "a.txt", b"data").await;
+    let share = server.share("a.txt").await;
+    assert_protected(|| server.shared(&share.token), b"data").await;
+}
@@ -0,0 +1,44 @@
+mod common;
+
+use common::TestServer;
+use reqwest::header::{CONTENT_RANGE, RANGE};
+use reqwest::StatusCode;
+use shared::consts::*;
+
+async fn download_range(server: &TestServer, range: &str) -> (StatusCode, Vec<u8>) {
+    let response = server
+        .request(METHOD_DOWNLOAD, "a.txt")
+        .header(RANGE, range)
+        .send()
+        .await
+        .unwrap();
+    let status = response.status();
+    (status, response.bytes().await.unwrap().to_vec())
+}
+
+#[tokio::test]
+async fn ranges_are_served_or_ignored() {
+    let server = TestServer::start(&[]);
+    server.store("a.txt", b"0123456789").await;
+
+    assert_eq!(
+        download_range(&server, "bytes=2-4").await,
+        (StatusCode::PARTIAL_CONTENT, b"234".to_vec())
+    );
+    for range in ["bytes=-500", "bytes=0-1,4-5", "items=0-", "bytes=oops"] {
+        assert_eq!(
+            download_range(&server, range).await,
+            (StatusCode::OK, b"0123456789".to_vec()),
+            "{range}"
+        );
+    }
+
+    let response = server
+        .request(METHOD_DOWNLOAD, "a.txt")
+        .header(RANGE, "bytes=10-")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(response.headers()[CONTENT_RANGE], "bytes */10");
+}
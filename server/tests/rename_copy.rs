@@ -0,0 +1,152 @@
+mod common;
+
+use std::path::PathBuf;
+
+use common::{files_under, TestServer};
+use reqwest::StatusCode;
+use shared::consts::*;
+
+const LAYOUTS: &[&[(&str, &str)]] = &[
+    &[("layout", "\"flat\"")],
+    &[("layout", "\"sharded\"")],
+    &[("layout", "\"flat\""), ("encode_filenames", "true")],
+];
+
+/// Stored data files and sidecars of the test key, leaving out the staging directory.
+fn stored_files(server: &TestServer) -> Vec<PathBuf> {
+    files_under(&server.pubkey_dir())
+        .into_iter()
+        .filter(|path| !path.starts_with(".staging"))
+        .collect()
+}
+
+fn journal_entries(server: &TestServer) -> Vec<PathBuf> {
+    let journal = server.pubkey_dir().parent().unwrap().join(".journal");
+    if journal.exists() {
+        files_under(&journal)
+    } else {
+        Vec::new()
+    }
+}
+
+async fn digest(server: &TestServer, filename: &str) -> String {
+    let response = server
+        .request(METHOD_SIGNATURE, filename)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK, "{filename}");
+    response.headers()[PARAM_FILE_DIGEST_HEX]
+        .to_str()
+        .unwrap()
+        .to_string()
+}
+
+async fn transfer(server: &TestServer, method: &str, from: &str, to: &str) -> StatusCode {
+    server
+        .transfer(method, from, to)
+        .send()
+        .await
+        .unwrap()
+        .status()
+}
+
+#[tokio::test]
+async fn rename_moves_data_and_sidecar_in_every_layout() {
+    for settings in LAYOUTS {
+        let server = TestServer::start(settings);
+        server.store("dir/a.txt", b"renamed data").await;
+        server.store("b.txt", b"replaced").await;
+        let digest_before = digest(&server, "dir/a.txt").await;
+
+        let status = transfer(&server, METHOD_RENAME, "dir/a.txt", "other/c.txt").await;
+        assert_eq!(status, StatusCode::OK, "{settings:?}");
+        assert_eq!(server.read("other/c.txt").await, b"renamed data");
+        assert_eq!(digest(&server, "other/c.txt").await, digest_before);
+        let response = server.download("dir/a.txt").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND, "{settings:?}");
+
+        let status = transfer(&server, METHOD_RENAME, "other/c.txt", "b.txt").await;
+        assert_eq!(status, StatusCode::OK, "{settings:?}");
+        assert_eq!(server.read("b.txt").await, b"renamed data");
+
+        let status = transfer(&server, METHOD_RENAME, "b.txt", "b.txt").await;
+        assert_eq!(status, StatusCode::OK, "{settings:?}");
+        assert_eq!(server.read("b.txt").await, b"renamed data");
+
+        let status = transfer(&server, METHOD_RENAME, "missing.txt", "d.txt").await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "{settings:?}");
+
+        assert_eq!(server.list().await, ["b.txt"], "{settings:?}");
+        assert_eq!(stored_files(&server).len(), 2, "{settings:?}");
+        assert!(journal_entries(&server).is_empty(), "{settings:?}");
+    }
+}
+
+#[tokio::test]
+async fn copy_keeps_source_and_digest_in_every_layout() {
+    for settings in LAYOUTS {
+        let server = TestServer::start(settings);
+        server.store("dir/a.txt", b"copied data").await;
+        let digest_before = digest(&server, "dir/a.txt").await;
+
+        let status = transfer(&server, METHOD_COPY, "dir/a.txt", "other/b.txt").await;
+        assert_eq!(status, StatusCode::OK, "{settings:?}");
+        assert_eq!(server.read("dir/a.txt").await, b"copied data");
+        assert_eq!(server.read("other/b.txt").await, b"copied data");
+        assert_eq!(digest(&server, "other/b.txt").await, digest_before);
+
+        let status = transfer(&server, METHOD_COPY, "missing.txt", "c.txt").await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "{settings:?}");
+
+        assert_eq!(
+            server.list().await,
+            ["dir/a.txt", "other/b.txt"],
+            "{settings:?}"
+        );
+        assert_eq!(stored_files(&server).len(), 4, "{settings:?}");
+    }
+}
+
+#[tokio::test]
+async fn copy_of_file_without_data_is_not_found() {
+    let server = TestServer::start(&[]);
+    server.store("a.txt", b"data").await;
+    std::fs::remove_file(server.data_path("a.txt")).unwrap();
+
+    let status = transfer(&server, METHOD_COPY, "a.txt", "b.txt").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn copy_refuses_data_not_matching_its_digest() {
+    let server = TestServer::start(&[]);
+    server.store("a.txt", b"original").await;
+    std::fs::write(server.data_path("a.txt"), b"tampered").unwrap();
+
+    let status = transfer(&server, METHOD_COPY, "a.txt", "b.txt").await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(server.list().await, ["a.txt"]);
+}
+
+#[tokio::test]
+async fn copy_of_unverified_file_stays_unverified() {
+    let server = TestServer::start(&[("trust_client_digest", "true")]);
+    let data = b"trusted data";
+    let response = server
+        .request(METHOD_UPLOAD, "a.txt")
+        .header(PARAM_FILE_SIGNATURE, server.file_signature(data))
+        .header(PARAM_FILE_DIGEST, server.file_digest(data))
+        .body(data.to_vec())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let status = transfer(&server, METHOD_COPY, "a.txt", "b.txt").await;
+    assert_eq!(status, StatusCode::OK);
+    let sidecar = std::fs::read_to_string(server.signature_path("b.txt")).unwrap();
+    let sidecar: serde_json::Value = serde_json::from_str(&sidecar).unwrap();
+    assert_eq!(sidecar["unverified"], true);
+    assert!(sidecar.get("digest").is_none());
+}
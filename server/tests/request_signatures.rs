@@ -0,0 +1,44 @@
+mod common;
+
+use common::TestServer;
+use reqwest::StatusCode;
+use shared::consts::*;
+
+#[tokio::test]
+async fn signatures_only_authorize_their_method() {
+    let server = TestServer::start(&[]);
+    server.store("a.txt", b"data").await;
+
+    for (method, signed_method, filename) in [
+        (METHOD_DELETE, METHOD_DOWNLOAD, "a.txt"),
+        (METHOD_DELETE, METHOD_SIGNATURE, "a.txt"),
+        (METHOD_ADMIN_TRIM, METHOD_LIST, ""),
+    ] {
+        let response = server
+            .request_signed_for(method, signed_method, filename)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            response.status(),
+            StatusCode::UNAUTHORIZED,
+            "{signed_method} signature sent to {method}"
+        );
+    }
+    assert_eq!(server.read("a.txt").await, b"data");
+
+    // The destination signature of a rename doesn't authorize deleting the destination
+    let response = server
+        .transfer(METHOD_RENAME, "a.txt", "b.txt")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = server
+        .request_signed_for(METHOD_DELETE, METHOD_RENAME, "b.txt")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(server.read("b.txt").await, b"data");
+}
@@ -0,0 +1,178 @@
+mod common;
+
+use common::TestServer;
+use ed25519_dalek::SigningKey;
+use reqwest::StatusCode;
+use shared::consts::*;
+use shared::listing::ShareEntry;
+use warp::Filter;
+
+#[tokio::test]
+async fn share_links_need_a_signature_made_for_sharing() {
+    let server = TestServer::start(&[]);
+    server.store("a.txt", b"data").await;
+
+    // A download request captured by someone else can't be turned into a link
+    let response = server
+        .request_signed_for(METHOD_SHARE, METHOD_DOWNLOAD, "a.txt")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let share = server.share("a.txt").await;
+    let response = server.shared(&share.token).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.bytes().await.unwrap(), "data");
+}
+
+#[tokio::test]
+async fn shared_downloads_only_count_once_served() {
+    let server = TestServer::start(&[]);
+    let response = server
+        .upload_request("a.txt", b"data")
+        .header(PARAM_FILE_PASSPHRASE, "secret")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = server
+        .request(METHOD_SHARE, "a.txt")
+        .header(PARAM_MAX_DOWNLOADS, 1)
+        .send()
+        .await
+        .unwrap();
+    let share: ShareEntry = response.json().await.unwrap();
+
+    let response = server.shared(&share.token).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = server
+        .shared(&share.token)
+        .header(PARAM_FILE_PASSPHRASE, "wrong")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    // The only download allowed is still left
+    let response = server
+        .shared(&share.token)
+        .header(PARAM_FILE_PASSPHRASE, "secret")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.bytes().await.unwrap(), "data");
+    let response = server
+        .shared(&share.token)
+        .header(PARAM_FILE_PASSPHRASE, "secret")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn shared_downloads_are_authorized() {
+    // Denies downloads through share links only
+    let authorizer = warp::post()
+        .and(warp::body::json())
+        .map(|request: serde_json::Value| {
+            if request["method"] == METHOD_SHARED {
+                StatusCode::FORBIDDEN
+            } else {
+                StatusCode::OK
+            }
+        });
+    let (addr, serve) = warp::serve(authorizer).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(serve);
+
+    let server = TestServer::start(&[("authorization_url", &format!("http://{addr}/"))]);
+    server.store("a.txt", b"data").await;
+    let share = server.share("a.txt").await;
+    let response = server.shared(&share.token).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(server.read("a.txt").await, b"data");
+}
+
+#[tokio::test]
+async fn share_tokens_are_not_logged() {
+    // Every request is logged as slow as well
+    let server = TestServer::start(&[("slow_request_threshold_ms", "0")]);
+    server.store("a.txt", b"data").await;
+    let share = server.share("a.txt").await;
+    let response = server.shared(&share.token).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = server
+        .request(METHOD_REVOKE_SHARE, &share.token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let log = server.log();
+    assert!(log.contains(&format!("{}...", &share.token[..6])));
+    assert!(!log.contains(&share.token));
+}
+
+async fn list_shares(server: &TestServer) -> Vec<ShareEntry> {
+    let response = server.request(METHOD_SHARES, "").send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    response.json().await.unwrap()
+}
+
+async fn revoke(server: &TestServer, token: &str) -> StatusCode {
+    server
+        .request(METHOD_REVOKE_SHARE, token)
+        .send()
+        .await
+        .unwrap()
+        .status()
+}
+
+#[tokio::test]
+async fn links_are_listed_and_revoked_by_their_owner() {
+    let mut server = TestServer::start(&[]);
+    server.store("a.txt", b"a").await;
+    server.store("b.txt", b"b").await;
+    let first = server.share("a.txt").await;
+    let second = server.share("b.txt").await;
+    let response = server.shared(&first.token).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let shares = list_shares(&server).await;
+    let mut listed = shares
+        .iter()
+        .map(|share| {
+            (
+                share.token.as_str(),
+                share.filename.as_str(),
+                share.downloads,
+            )
+        })
+        .collect::<Vec<_>>();
+    listed.sort();
+    let mut expected = vec![
+        (first.token.as_str(), "a.txt", 1),
+        (second.token.as_str(), "b.txt", 0),
+    ];
+    expected.sort();
+    assert_eq!(listed, expected);
+
+    // Other pubkeys neither see nor revoke the links
+    let owner = server.key().clone();
+    server.set_key(SigningKey::from_bytes(&rand::random()));
+    assert!(list_shares(&server).await.is_empty());
+    assert_eq!(revoke(&server, &first.token).await, StatusCode::NOT_FOUND);
+    let response = server.shared(&first.token).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    server.set_key(owner);
+    assert_eq!(revoke(&server, &first.token).await, StatusCode::OK);
+    assert_eq!(revoke(&server, &first.token).await, StatusCode::NOT_FOUND);
+    let response = server.shared(&first.token).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let shares = list_shares(&server).await;
+    assert_eq!(shares.len(), 1);
+    assert_eq!(shares[0].token, second.token);
+}
@@ -0,0 +1,118 @@
+mod common;
+
+use common::{files_under, TestServer};
+use reqwest::StatusCode;
+use shared::consts::*;
+
+/// Names of the data files and sidecars in the pubkey directory.
+fn stored_files(server: &TestServer) -> Vec<String> {
+    files_under(&server.pubkey_dir())
+        .iter()
+        .map(|path| path.to_string_lossy().to_string())
+        .collect()
+}
+
+#[tokio::test]
+async fn files_differing_in_extension_keep_their_own_sidecars() {
+    let server = TestServer::start(&[]);
+    let response = server
+        .upload_request("report.pdf", b"pdf")
+        .header(PARAM_FILE_PASSPHRASE, "secret")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    server.store("report.txt", b"txt").await;
+
+    // Storing the text file used to replace the sidecar of the PDF, passphrase included
+    let response = server.download("report.pdf").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = server
+        .request(METHOD_DOWNLOAD, "report.pdf")
+        .header(PARAM_FILE_PASSPHRASE, "secret")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.bytes().await.unwrap(), "pdf");
+    assert_eq!(server.read("report.txt").await, b"txt");
+
+    // A file named like a sidecar would take the place of the one of `report`
+    let response = server.upload("report.sig", b"sig").await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(
+        stored_files(&server),
+        [
+            "report.pdf",
+            "report.pdf.sig",
+            "report.txt",
+            "report.txt.sig"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn legacy_sidecars_are_renamed_on_start() {
+    let mut server = TestServer::start(&[]);
+    for (filename, data) in [
+        ("notes.md", b"notes"),
+        ("report.pdf", b"pdf.."),
+        ("report.txt", b"txt.."),
+        ("readme", b"plain"),
+        ("readme.md", b"mdown"),
+    ] {
+        server.store(filename, data).await;
+    }
+
+    // As older servers stored them, the sidecar of the file stored last replacing the others
+    let legacy = |filename: &str, legacy_name: &str| {
+        std::fs::rename(
+            server.signature_path(filename),
+            server.pubkey_dir().join(legacy_name),
+        )
+        .unwrap()
+    };
+    legacy("notes.md", "notes.sig");
+    legacy("report.pdf", "report.sig");
+    std::fs::remove_file(server.signature_path("report.txt")).unwrap();
+    std::fs::remove_file(server.signature_path("readme.md")).unwrap();
+    std::fs::remove_file(server.pubkey_dir().parent().unwrap().join(".sidecars")).unwrap();
+    server.restart();
+
+    assert_eq!(server.read("notes.md").await, b"notes");
+    assert_eq!(server.read("report.pdf").await, b"pdf..");
+    assert_eq!(server.read("readme").await, b"plain");
+    // Their sidecars were lost to the shared one, which doesn't match their data
+    for filename in ["report.txt", "readme.md"] {
+        let response = server.download(filename).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND, "{filename}");
+    }
+    assert_eq!(
+        stored_files(&server),
+        [
+            "notes.md",
+            "notes.md.sig",
+            "readme",
+            "readme.md",
+            "readme.sig",
+            "report.pdf",
+            "report.pdf.sig",
+            "report.txt",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn legacy_sidecar_is_found_after_start() {
+    let server = TestServer::start(&[]);
+    server.store("a.txt", b"data").await;
+    std::fs::rename(
+        server.signature_path("a.txt"),
+        server.pubkey_dir().join("a.sig"),
+    )
+    .unwrap();
+
+    assert_eq!(server.read("a.txt").await, b"data");
+    assert!(server.signature_path("a.txt").exists());
+    assert!(!server.pubkey_dir().join("a.sig").exists());
+}
@@ -0,0 +1,50 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::TestServer;
+
+/// Waits for the server to log a line containing all of `parts`, since requests are logged once
+/// their response body is dropped, which may be after the client got all of it.
+async fn wait_for_line(server: &TestServer, parts: &[&str]) -> String {
+    let started = Instant::now();
+    loop {
+        let log = server.log();
+        if let Some(line) = log
+            .lines()
+            .find(|line| parts.iter().all(|part| line.contains(part)))
+        {
+            return line.to_string();
+        }
+        assert!(
+            started.elapsed() < Duration::from_secs(5),
+            "No line with {parts:?} in the log:\n{log}"
+        );
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+#[tokio::test]
+async fn slow_requests_log_the_bytes_transferred() {
+    let server = TestServer::start(&[("slow_request_threshold_ms", "0")]);
+    let data = vec![7; 300_000];
+    server.store("a.bin", &data).await;
+    assert_eq!(server.read("a.bin").await, data);
+
+    let upload = wait_for_line(
+        &server,
+        &["Slow request: method: upload,", "bytes received: 300000,"],
+    )
+    .await;
+    assert!(upload.contains("bytes sent: 0,"), "{upload}");
+    assert!(upload.contains(&server.pubkey()), "{upload}");
+    assert!(upload.contains("filename: a.bin,"), "{upload}");
+
+    let download = wait_for_line(
+        &server,
+        &["Slow request: method: download,", "bytes sent: 300000,"],
+    )
+    .await;
+    assert!(download.contains("bytes received: 0,"), "{download}");
+    assert!(download.contains("status: 200,"), "{download}");
+}
@@ -0,0 +1,95 @@
+use digest::Digest;
+use serde::{Deserialize, Serialize};
+
+use crate::hasher::Hasher;
+
+/// Describes how a file uploaded in chunks has to be assembled: chunks are concatenated in the
+/// listed order, each having the listed hash.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkManifest {
+    pub total_size: u64,
+    pub chunks: Vec<String>,
+}
+
+/// Uploads and deletes applied together: either all of them take effect or none. Each upload is
+/// assembled from chunks uploaded beforehand, like with a [`ChunkManifest`].
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BatchPlan {
+    pub uploads: Vec<BatchUpload>,
+    pub deletes: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchUpload {
+    pub filename: String,
+    /// Signature of the whole file, bs58 encoded like the `file-signature` header.
+    pub file_signature: String,
+    /// Scheme of `file_signature` like the `file-signature-scheme` header, the default file
+    /// scheme when not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_signature_scheme: Option<String>,
+    pub manifest: ChunkManifest,
+}
+
+/// Encodes the hash of a chunk the way it is sent in the `chunk-hash` header and in the manifest.
+pub fn encode_chunk_hash(hasher: Hasher) -> String {
+    bs58::encode(hasher.finalize()).into_string()
+}
+
+/// Hashes of consecutive fixed-size blocks of a stored file, the last block may be shorter. Lets
+/// a download be checked as it arrives, the file signature still has to be verified at the end.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockHashes {
+    pub block_size: u64,
+    pub hashes: Vec<String>,
+}
+
+/// Splits the data fed to it into blocks of `block_size` bytes and hashes each of them.
+#[derive(Debug, Clone)]
+pub struct BlockHasher {
+    block_size: u64,
+    hasher: Hasher,
+    block_len: u64,
+    hashes: Vec<String>,
+}
+
+impl BlockHasher {
+    pub fn new(block_size: u64) -> Self {
+        Self {
+            block_size,
+            hasher: Hasher::default(),
+            block_len: 0,
+            hashes: Vec::new(),
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let size = (self.block_size - self.block_len).min(data.len() as u64) as usize;
+            digest::Update::update(&mut self.hasher, &data[..size]);
+            self.block_len += size as u64;
+            if self.block_len == self.block_size {
+                let hasher = std::mem::take(&mut self.hasher);
+                self.hashes.push(encode_chunk_hash(hasher));
+                self.block_len = 0;
+            }
+            data = &data[size..];
+        }
+    }
+
+    /// Hashes of the blocks completed so far.
+    pub fn hashes(&self) -> &[String] {
+        &self.hashes
+    }
+
+    /// Hashes the remaining partial block, if any, and returns all hashes.
+    pub fn finish(mut self) -> BlockHashes {
+        if self.block_len > 0 {
+            self.hashes.push(encode_chunk_hash(self.hasher));
+        }
+        BlockHashes {
+            block_size: self.block_size,
+            hashes: self.hashes,
+        }
+    }
+}
@@ -1,8 +1,56 @@
 pub const METHOD_UPLOAD: &str = "upload";
 pub const METHOD_DOWNLOAD: &str = "download";
+pub const METHOD_SIGNATURE: &str = "signature";
+pub const METHOD_SIGNATURES: &str = "signatures";
+pub const METHOD_LIST: &str = "list";
+pub const METHOD_RENAME: &str = "rename";
+pub const METHOD_COPY: &str = "copy";
+pub const METHOD_DELETE: &str = "delete";
+pub const METHOD_UPDATE_SIGNATURE: &str = "update-signature";
+pub const METHOD_ADMIN_USERS: &str = "admin-users";
+pub const METHOD_ADMIN_TRIM: &str = "admin-trim";
+pub const METHOD_BLOCK_HASHES: &str = "block-hashes";
+pub const METHOD_OUTBOARD: &str = "outboard";
+pub const METHOD_UPLOAD_CHUNK: &str = "upload-chunk";
+pub const METHOD_UPLOAD_MANIFEST: &str = "upload-manifest";
+pub const METHOD_BATCH: &str = "batch";
+pub const METHOD_PRUNE: &str = "prune";
+pub const METHOD_SHARE: &str = "share";
+/// Lists the caller's working share links.
+pub const METHOD_SHARES: &str = "shares";
+/// Removes a share link, whose token is signed in place of the filename.
+pub const METHOD_REVOKE_SHARE: &str = "revoke-share";
+/// Public route downloading a shared file, as `shared/<token>`.
+pub const METHOD_SHARED: &str = "shared";
 
 pub const PARAM_FILENAME: &str = "filename";
 pub const PARAM_PUBKEY: &str = "pubkey";
 pub const PARAM_TIME: &str = "time";
 pub const PARAM_REQUEST_SIGNATURE: &str = "request-signature";
 pub const PARAM_FILE_SIGNATURE: &str = "file-signature";
+pub const PARAM_REQUEST_SIGNATURE_SCHEME: &str = "request-signature-scheme";
+pub const PARAM_FILE_SIGNATURE_SCHEME: &str = "file-signature-scheme";
+pub const PARAM_DESTINATION: &str = "destination";
+pub const PARAM_DESTINATION_SIGNATURE: &str = "destination-signature";
+pub const PARAM_FILE_DIGEST: &str = "file-digest";
+pub const PARAM_IF_MATCH: &str = "if-match";
+pub const PARAM_CHUNK_INDEX: &str = "chunk-index";
+pub const PARAM_CHUNK_HASH: &str = "chunk-hash";
+pub const PARAM_SINCE: &str = "since";
+pub const PARAM_UPLOADED_AT: &str = "uploaded-at";
+pub const PARAM_FILE_DIGEST_HEX: &str = "file-digest-hex";
+pub const PARAM_RESPONSE_SIGNATURE: &str = "response-signature";
+pub const PARAM_CLIENT_VERSION: &str = "client-version";
+pub const PARAM_BYTES_USED: &str = "x-bytes-used";
+pub const PARAM_BYTES_QUOTA: &str = "x-bytes-quota";
+pub const PARAM_EXPIRES_IN: &str = "expires-in";
+pub const PARAM_MAX_DOWNLOADS: &str = "max-downloads";
+/// Passphrase protecting a file, set on upload and required on download.
+pub const PARAM_FILE_PASSPHRASE: &str = "file-passphrase";
+
+/// Content type of listings sent as one JSON entry per line, asked for with the `accept` header.
+pub const CONTENT_TYPE_NDJSON: &str = "application/x-ndjson";
+
+/// Version the client reports in the `client-version` header, the one of this crate since it
+/// defines the protocol.
+pub const CLIENT_VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -1,7 +1,11 @@
+use anyhow::{bail, Result};
 use digest::generic_array::GenericArray;
 use digest::typenum::U64;
 use digest::{FixedOutput, HashMarker, Reset, Update};
 
+/// Length of the [`Hasher`] output in bytes.
+pub const DIGEST_LENGTH: usize = 64;
+
 #[derive(Debug, Clone)]
 pub struct Hasher {
     hasher: blake3::Hasher,
@@ -20,6 +24,9 @@ impl Default for Hasher {
 impl Update for Hasher {
     #[inline]
     fn update(&mut self, data: &[u8]) {
+        #[cfg(feature = "parallel-hashing")]
+        self.hasher.update_rayon(data);
+        #[cfg(not(feature = "parallel-hashing"))]
         self.hasher.update(data);
     }
 }
@@ -41,3 +48,49 @@ impl FixedOutput for Hasher {
         self.hasher.finalize_xof().fill(out);
     }
 }
+
+/// Lowercase hex of the digest, as printed by `hash` and sent by the server for stored files.
+pub fn encode_hex(digest: impl FixedOutput) -> String {
+    digest
+        .finalize_fixed()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Stands in for [`Hasher`] when the digest is already known, so a signature can be checked
+/// against a digest received from the client without the data. Any data fed to it is ignored.
+#[derive(Debug, Clone, Default)]
+pub struct Prehashed {
+    output: GenericArray<u8, U64>,
+}
+
+impl Prehashed {
+    /// Fails unless `digest` is exactly as long as the [`Hasher`] output.
+    pub fn from_slice(digest: &[u8]) -> Result<Self> {
+        if digest.len() != DIGEST_LENGTH {
+            bail!("Invalid digest length: {}", digest.len());
+        }
+        Ok(Self {
+            output: GenericArray::clone_from_slice(digest),
+        })
+    }
+}
+
+impl HashMarker for Prehashed {}
+
+impl Update for Prehashed {
+    #[inline]
+    fn update(&mut self, _data: &[u8]) {}
+}
+
+impl digest::OutputSizeUser for Prehashed {
+    type OutputSize = U64;
+}
+
+impl FixedOutput for Prehashed {
+    #[inline]
+    fn finalize_into(self, out: &mut GenericArray<u8, Self::OutputSize>) {
+        out.copy_from_slice(&self.output);
+    }
+}
@@ -1,39 +1,230 @@
+pub mod chunks;
 pub mod consts;
 pub mod hasher;
+pub mod listing;
+pub mod outboard;
+pub mod response;
 
 use anyhow::{anyhow, bail, Result};
-use borsh::io::Write;
-use borsh::BorshSerialize;
-use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use borsh::io::{ErrorKind, Read, Write};
+use borsh::{BorshDeserialize, BorshSerialize};
+use digest::typenum::U64;
+use digest::Update;
+use ed25519_dalek::{
+    DigestSigner, DigestVerifier, Signature, SignatureError, Signer, SigningKey, VerifyingKey,
+    PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH,
+};
+use hasher::Hasher;
+use std::fmt::{Display, Formatter};
 use std::ops::Deref;
-use std::time::SystemTime;
+use std::str::FromStr;
+use std::time::{SystemTime, SystemTimeError};
+use unicode_normalization::UnicodeNormalization;
 
-#[derive(Debug)]
+/// A request to one of the server methods, signed as a whole so a signature made for one method
+/// can't be replayed to another.
+#[derive(Debug, Clone)]
 pub struct SignableRequest {
+    method: String,
     filename: String,
     pubkey: VerifyingKey,
     time: u64,
 }
 
-#[derive(Debug)]
+/// A request with its signature. Serializes with serde as [`PortableRequest`] and with borsh as
+/// the signed request followed by the signature and scheme, so it can be stored and submitted
+/// later. Neither checks the signature when reading it back, see [`SignedRequest::verify`].
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(into = "PortableRequest", try_from = "PortableRequest")]
 pub struct SignedRequest {
     request: SignableRequest,
     signature: Signature,
+    scheme: SignatureScheme,
+}
+
+/// Serde form of [`SignedRequest`], with the keys and signature in Base58.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct PortableRequest {
+    /// Empty for requests saved before the method was signed.
+    #[serde(default)]
+    pub method: String,
+    pub filename: String,
+    pub pubkey: String,
+    pub time: u64,
+    pub signature: String,
+    pub scheme: String,
+}
+
+/// How a signature relates to the signed message, sent along with every signature so the
+/// verifier doesn't have to assume it. Requests without the scheme use the defaults of each
+/// signature kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureScheme {
+    /// Pure Ed25519 over the message. The default for request signatures, over the borsh
+    /// serialized request.
+    Ed25519,
+    /// Ed25519ph from RFC 8032 with the Blake3 digest of the message as the prehash. The default
+    /// and only supported scheme of file signatures, since it doesn't need the whole file in
+    /// memory.
+    Ed25519ph,
+}
+
+impl SignatureScheme {
+    pub const REQUEST_DEFAULT: Self = Self::Ed25519;
+    pub const FILE_DEFAULT: Self = Self::Ed25519ph;
+    /// Schemes file signatures may use, the file is only ever hashed as it streams so they all
+    /// have to work from its digest.
+    pub const FILE_SCHEMES: &'static [Self] = &[Self::Ed25519ph];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Ed25519 => "ed25519",
+            Self::Ed25519ph => "ed25519ph",
+        }
+    }
+
+    /// Checks the `signature` of a file against the Blake3 `digest` of its contents, either a
+    /// [`Hasher`] fed with them or a [`hasher::Prehashed`]. Fails for schemes not in
+    /// [`Self::FILE_SCHEMES`].
+    pub fn verify_file(
+        self,
+        pubkey: &VerifyingKey,
+        digest: impl digest::Digest<OutputSize = U64>,
+        signature: &Signature,
+    ) -> Result<()> {
+        match self {
+            Self::Ed25519ph => Ok(pubkey.verify_digest(digest, signature)?),
+            Self::Ed25519 => bail!("Unsupported file signature scheme: {self}"),
+        }
+    }
+
+    /// Lists `schemes` comma separated, for error messages.
+    pub fn list(schemes: &[Self]) -> String {
+        schemes
+            .iter()
+            .map(|scheme| scheme.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Parses the scheme header, falling back to `default` when it's missing.
+    pub fn from_header(header: Option<&str>, default: Self) -> Result<Self> {
+        match header {
+            None => Ok(default),
+            Some(scheme) => scheme.parse(),
+        }
+    }
+}
+
+impl FromStr for SignatureScheme {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ed25519" => Ok(Self::Ed25519),
+            "ed25519ph" => Ok(Self::Ed25519ph),
+            _ => bail!("Unknown signature scheme: {s}"),
+        }
+    }
+}
+
+impl Display for SignatureScheme {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
 }
 
 const MAX_CLIENT_TIME_DIFF: u64 = 60;
 
+/// How the time of a request is checked against the verifier's clock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeCheck {
+    /// Not checked, for requests captured earlier and verified offline.
+    Skip,
+    /// Within this many seconds of the clock either way.
+    Within(u64),
+}
+
+impl TimeCheck {
+    /// The window the server accepts requests in.
+    pub const DEFAULT: Self = Self::Within(MAX_CLIENT_TIME_DIFF);
+}
+
+impl Default for TimeCheck {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Why a request signature was rejected.
+#[derive(Debug)]
+pub enum RequestVerificationError {
+    /// The request time is this many seconds away from the verifier's clock.
+    TimeDifference(u64),
+    /// The verifier's clock is set before the Unix epoch.
+    Clock(SystemTimeError),
+    /// The signature doesn't match the request and its pubkey.
+    InvalidSignature(SignatureError),
+}
+
+impl Display for RequestVerificationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TimeDifference(time_diff) => write!(f, "Time difference is too high ({time_diff} seconds). Client's and server's clocks must be synchronized."),
+            Self::Clock(err) => write!(f, "System clock is invalid: {err}"),
+            Self::InvalidSignature(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for RequestVerificationError {}
+
+/// Converts the filename to Unicode Normalization Form C, so names looking the same but composed
+/// differently, like `café` typed on Linux and read from a macOS filesystem, are the same name.
+pub fn normalize_filename(filename: &str) -> String {
+    filename.nfc().collect()
+}
+
+/// Checks that the remote filename is a relative path that can't escape the pubkey directory.
+/// Nested names use `/` as a separator.
+pub fn validate_filename(filename: &str) -> Result<()> {
+    if filename.is_empty() {
+        bail!("Filename is empty");
+    }
+    if filename.contains(['\\', '\0']) {
+        bail!("Filename contains forbidden characters: {filename}");
+    }
+    if filename
+        .split('/')
+        .any(|component| component.is_empty() || component == "." || component == "..")
+    {
+        bail!("Filename must be a relative path without empty, `.` or `..` components: {filename}");
+    }
+    Ok(())
+}
+
 impl SignableRequest {
-    pub fn with_time(filename: String, pubkey: VerifyingKey, time: u64) -> Self {
+    pub fn with_time(method: &str, filename: String, pubkey: VerifyingKey, time: u64) -> Self {
         Self {
+            method: method.to_string(),
             filename,
             pubkey,
             time,
         }
     }
 
-    pub fn new(filename: String, pubkey: VerifyingKey) -> Result<Self> {
-        Ok(Self::with_time(filename, pubkey, Self::unix_time()?))
+    pub fn new(method: &str, filename: String, pubkey: VerifyingKey) -> Result<Self> {
+        Ok(Self::with_time(
+            method,
+            filename,
+            pubkey,
+            Self::unix_time()?,
+        ))
+    }
+
+    /// Server method the request is for, one of the `METHOD_*` constants.
+    pub fn method(&self) -> &str {
+        &self.method
     }
 
     pub fn filename(&self) -> &str {
@@ -49,26 +240,66 @@ impl SignableRequest {
     }
 
     pub fn sign(self, secret: &SigningKey) -> Result<SignedRequest> {
+        self.sign_with(secret, SignatureScheme::REQUEST_DEFAULT)
+    }
+
+    pub fn sign_with(self, secret: &SigningKey, scheme: SignatureScheme) -> Result<SignedRequest> {
         let msg = self.serialize_borsh()?;
-        let signature = secret.try_sign(&msg)?;
+        let signature = match scheme {
+            SignatureScheme::Ed25519 => secret.try_sign(&msg)?,
+            SignatureScheme::Ed25519ph => secret.try_sign_digest(Self::prehash(&msg))?,
+        };
 
         Ok(SignedRequest {
             request: self,
             signature,
+            scheme,
         })
     }
 
-    pub fn check_signature(&self, request_signature: &Signature) -> Result<()> {
-        let unix_time = Self::unix_time()?;
-        let time_diff = unix_time.abs_diff(self.time);
-        if time_diff > MAX_CLIENT_TIME_DIFF {
-            bail!("Time difference is too high ({time_diff} seconds). Client's and server's clocks must be synchronized.");
+    /// Checks `request_signature` and that the request time is within the window the server
+    /// accepts.
+    pub fn check_signature(
+        &self,
+        request_signature: &Signature,
+        scheme: SignatureScheme,
+    ) -> Result<()> {
+        Ok(self.verify_signature(request_signature, scheme, TimeCheck::DEFAULT)?)
+    }
+
+    /// Checks the request time as given by `time_check`, then `request_signature` against the
+    /// request and its pubkey.
+    pub fn verify_signature(
+        &self,
+        request_signature: &Signature,
+        scheme: SignatureScheme,
+        time_check: TimeCheck,
+    ) -> std::result::Result<(), RequestVerificationError> {
+        if let TimeCheck::Within(max_time_diff) = time_check {
+            let unix_time = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_err(RequestVerificationError::Clock)?
+                .as_secs();
+            let time_diff = unix_time.abs_diff(self.time);
+            if time_diff > max_time_diff {
+                return Err(RequestVerificationError::TimeDifference(time_diff));
+            }
         }
 
-        let msg = self.serialize_borsh()?;
-        self.pubkey.verify_strict(&msg, request_signature)?;
+        let msg = borsh::to_vec(self).expect("Borsh serialization into memory can't fail");
+        match scheme {
+            SignatureScheme::Ed25519 => self.pubkey.verify_strict(&msg, request_signature),
+            SignatureScheme::Ed25519ph => self
+                .pubkey
+                .verify_digest(Self::prehash(&msg), request_signature),
+        }
+        .map_err(RequestVerificationError::InvalidSignature)
+    }
 
-        Ok(())
+    fn prehash(msg: &[u8]) -> Hasher {
+        let mut hasher = Hasher::default();
+        hasher.update(msg);
+        hasher
     }
 
     fn unix_time() -> Result<u64> {
@@ -82,14 +313,28 @@ impl SignableRequest {
     }
 }
 
+impl BorshDeserialize for SignableRequest {
+    fn deserialize_reader<R: Read>(reader: &mut R) -> borsh::io::Result<Self> {
+        let method = String::deserialize_reader(reader)?;
+        let filename = String::deserialize_reader(reader)?;
+        let pubkey =
+            VerifyingKey::from_bytes(&<[u8; PUBLIC_KEY_LENGTH]>::deserialize_reader(reader)?)
+                .map_err(|err| borsh::io::Error::new(ErrorKind::InvalidData, err.to_string()))?;
+        let time = u64::deserialize_reader(reader)?;
+        Ok(Self::with_time(&method, filename, pubkey, time))
+    }
+}
+
 impl BorshSerialize for SignableRequest {
     fn serialize<W: Write>(&self, writer: &mut W) -> borsh::io::Result<()> {
         let Self {
+            method,
             filename,
             pubkey,
             time,
         } = self;
 
+        method.serialize(writer)?;
         filename.serialize(writer)?;
         pubkey.as_bytes().serialize(writer)?;
         time.serialize(writer)
@@ -100,6 +345,85 @@ impl SignedRequest {
     pub fn signature(&self) -> &Signature {
         &self.signature
     }
+
+    /// Checks the embedded signature against the embedded pubkey, and that the request time is
+    /// within the window the server accepts.
+    pub fn verify(&self) -> std::result::Result<(), RequestVerificationError> {
+        self.verify_with(TimeCheck::DEFAULT)
+    }
+
+    /// Checks the embedded signature against the embedded pubkey, and the request time as given
+    /// by `time_check`. [`TimeCheck::Skip`] verifies captured requests regardless of their age.
+    pub fn verify_with(
+        &self,
+        time_check: TimeCheck,
+    ) -> std::result::Result<(), RequestVerificationError> {
+        self.request
+            .verify_signature(&self.signature, self.scheme, time_check)
+    }
+
+    pub fn scheme(&self) -> SignatureScheme {
+        self.scheme
+    }
+}
+
+impl BorshSerialize for SignedRequest {
+    fn serialize<W: Write>(&self, writer: &mut W) -> borsh::io::Result<()> {
+        self.request.serialize(writer)?;
+        self.signature.to_bytes().serialize(writer)?;
+        self.scheme.as_str().serialize(writer)
+    }
+}
+
+impl BorshDeserialize for SignedRequest {
+    fn deserialize_reader<R: Read>(reader: &mut R) -> borsh::io::Result<Self> {
+        let request = SignableRequest::deserialize_reader(reader)?;
+        let signature =
+            Signature::from_bytes(&<[u8; SIGNATURE_LENGTH]>::deserialize_reader(reader)?);
+        let scheme =
+            String::deserialize_reader(reader)?
+                .parse()
+                .map_err(|err: anyhow::Error| {
+                    borsh::io::Error::new(ErrorKind::InvalidData, err.to_string())
+                })?;
+        Ok(Self {
+            request,
+            signature,
+            scheme,
+        })
+    }
+}
+
+impl From<SignedRequest> for PortableRequest {
+    fn from(request: SignedRequest) -> Self {
+        Self {
+            method: request.request.method,
+            filename: request.request.filename,
+            pubkey: bs58::encode(request.request.pubkey.as_bytes()).into_string(),
+            time: request.request.time,
+            signature: bs58::encode(request.signature.to_bytes()).into_string(),
+            scheme: request.scheme.as_str().to_string(),
+        }
+    }
+}
+
+impl TryFrom<PortableRequest> for SignedRequest {
+    type Error = anyhow::Error;
+
+    fn try_from(request: PortableRequest) -> Result<Self> {
+        let pubkey = VerifyingKey::try_from(bs58::decode(&request.pubkey).into_vec()?.as_slice())?;
+        let signature = Signature::from_slice(&bs58::decode(&request.signature).into_vec()?)?;
+        Ok(Self {
+            request: SignableRequest::with_time(
+                &request.method,
+                request.filename,
+                pubkey,
+                request.time,
+            ),
+            signature,
+            scheme: request.scheme.parse()?,
+        })
+    }
 }
 
 impl Deref for SignedRequest {
@@ -109,3 +433,174 @@ impl Deref for SignedRequest {
         &self.request
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consts::{METHOD_DELETE, METHOD_DOWNLOAD};
+
+    const TIME: u64 = 1_700_000_000;
+
+    fn signing_key() -> SigningKey {
+        SigningKey::from_bytes(&[7; 32])
+    }
+
+    fn request() -> SignableRequest {
+        SignableRequest::with_time(
+            METHOD_DOWNLOAD,
+            "notes/todo.txt".to_string(),
+            signing_key().verifying_key(),
+            TIME,
+        )
+    }
+
+    fn assert_known_answer(scheme: SignatureScheme, expected: &str) {
+        let signed = request().sign_with(&signing_key(), scheme).unwrap();
+        assert_eq!(signed.scheme(), scheme);
+        assert_eq!(
+            bs58::encode(signed.signature().to_bytes()).into_string(),
+            expected
+        );
+        signed.verify_with(TimeCheck::Skip).unwrap();
+    }
+
+    #[test]
+    fn ed25519_known_answer() {
+        assert_known_answer(
+            SignatureScheme::Ed25519,
+            "225k5zfSuojHQSuuSLjSjr3e3CE6ULSwdJd7wpPCebCj2cYrigPwWGVxWbJjHMgpoADAQt36YxyHNWBAfwBkbs6W",
+        );
+    }
+
+    #[test]
+    fn ed25519ph_known_answer() {
+        assert_known_answer(
+            SignatureScheme::Ed25519ph,
+            "24PFZEZC5PJ7uB91efyZQet37mT6jJZQe3XYaYkJwe1kfjTeH6qSoBs428odgLjsqXvvQY7eYDpWNLZ593nZYhNg",
+        );
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn signed_at(time: u64) -> SignedRequest {
+        SignableRequest::with_time(
+            METHOD_DOWNLOAD,
+            "a.txt".to_string(),
+            signing_key().verifying_key(),
+            time,
+        )
+        .sign(&signing_key())
+        .unwrap()
+    }
+
+    #[test]
+    fn verify_accepts_fresh_request() {
+        signed_at(now()).verify().unwrap();
+    }
+
+    #[test]
+    fn verify_rejects_bad_signature() {
+        let signed = signed_at(now());
+        let other = SigningKey::from_bytes(&[8; 32]);
+        let forged = SignedRequest {
+            signature: other.sign(b"something else"),
+            ..signed
+        };
+        assert!(matches!(
+            forged.verify(),
+            Err(RequestVerificationError::InvalidSignature(_))
+        ));
+    }
+
+    #[test]
+    fn verify_rejects_stale_request() {
+        let signed = signed_at(now() - MAX_CLIENT_TIME_DIFF - 10);
+        assert!(matches!(
+            signed.verify(),
+            Err(RequestVerificationError::TimeDifference(diff)) if diff > MAX_CLIENT_TIME_DIFF
+        ));
+        signed.verify_with(TimeCheck::Skip).unwrap();
+    }
+
+    #[test]
+    fn verify_rejects_future_request() {
+        let signed = signed_at(now() + MAX_CLIENT_TIME_DIFF + 10);
+        assert!(matches!(
+            signed.verify(),
+            Err(RequestVerificationError::TimeDifference(diff)) if diff > MAX_CLIENT_TIME_DIFF
+        ));
+    }
+
+    #[test]
+    fn verify_rejects_wrong_scheme() {
+        let signed = signed_at(now());
+        let relabeled = SignedRequest {
+            scheme: SignatureScheme::Ed25519ph,
+            ..signed
+        };
+        assert!(matches!(
+            relabeled.verify(),
+            Err(RequestVerificationError::InvalidSignature(_))
+        ));
+    }
+
+    #[test]
+    fn verify_rejects_other_method() {
+        let signed = signed_at(now());
+        let replayed = SignedRequest {
+            request: SignableRequest {
+                method: METHOD_DELETE.to_string(),
+                ..signed.request.clone()
+            },
+            ..signed
+        };
+        assert!(matches!(
+            replayed.verify(),
+            Err(RequestVerificationError::InvalidSignature(_))
+        ));
+    }
+
+    fn assert_same_request(decoded: &SignedRequest, signed: &SignedRequest) {
+        assert_eq!(decoded.method(), signed.method());
+        assert_eq!(decoded.filename(), signed.filename());
+        assert_eq!(decoded.pubkey(), signed.pubkey());
+        assert_eq!(decoded.time(), signed.time());
+        assert_eq!(decoded.signature(), signed.signature());
+        assert_eq!(decoded.scheme(), signed.scheme());
+        decoded.verify_with(TimeCheck::Skip).unwrap();
+    }
+
+    #[test]
+    fn signed_request_serde_round_trip() {
+        for scheme in [SignatureScheme::Ed25519, SignatureScheme::Ed25519ph] {
+            let signed = request().sign_with(&signing_key(), scheme).unwrap();
+            let json = serde_json::to_string(&signed).unwrap();
+            let decoded: SignedRequest = serde_json::from_str(&json).unwrap();
+            assert_same_request(&decoded, &signed);
+        }
+    }
+
+    #[test]
+    fn signed_request_borsh_round_trip() {
+        for scheme in [SignatureScheme::Ed25519, SignatureScheme::Ed25519ph] {
+            let signed = request().sign_with(&signing_key(), scheme).unwrap();
+            let bytes = borsh::to_vec(&signed).unwrap();
+            let decoded: SignedRequest = borsh::from_slice(&bytes).unwrap();
+            assert_same_request(&decoded, &signed);
+        }
+    }
+
+    #[test]
+    fn normalize_filename_unifies_nfc_and_nfd() {
+        let nfc = "caf\u{e9}/r\u{e9}sum\u{e9}.txt";
+        let nfd = "cafe\u{301}/re\u{301}sume\u{301}.txt";
+        assert_ne!(nfc, nfd);
+        assert_eq!(normalize_filename(nfd), nfc);
+        assert_eq!(normalize_filename(nfc), nfc);
+    }
+}
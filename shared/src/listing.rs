@@ -0,0 +1,69 @@
+use digest::Update;
+use serde::{Deserialize, Serialize};
+
+use crate::chunks::encode_chunk_hash;
+use crate::hasher::Hasher;
+
+/// Entry of the file list returned by the `list` method.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileEntry {
+    pub filename: String,
+    pub size: u64,
+}
+
+/// Entry of the pubkey list returned by the `admin-users` method.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PubkeyEntry {
+    pub pubkey: String,
+    pub files: u64,
+    pub size: u64,
+}
+
+/// Result of the `admin-trim` method.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TrimReport {
+    pub removed_dirs: u64,
+    pub removed_signatures: u64,
+}
+
+/// Result of the `prune` method.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PruneReport {
+    pub removed: u64,
+}
+
+/// Result of the `share` method.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShareEntry {
+    /// Token of the link, downloaded from `shared/<token>`.
+    pub token: String,
+    pub filename: String,
+    /// Seconds since the Unix epoch.
+    pub created_at: u64,
+    /// Seconds since the Unix epoch when the link stops working.
+    pub expires_at: u64,
+    /// Downloads served through the link so far.
+    #[serde(default)]
+    pub downloads: u64,
+    /// Downloads after which the link stops working, unlimited when not set.
+    #[serde(default)]
+    pub max_downloads: Option<u64>,
+}
+
+/// Entry of the list returned by the `signatures` method, in the order of the requested filenames.
+/// Both fields are `None` for files that aren't stored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignatureEntry {
+    pub filename: String,
+    /// Base58 file signature.
+    pub signature: Option<String>,
+    pub size: Option<u64>,
+}
+
+/// The `signatures` request is signed with this in place of the filename, so the request
+/// signature covers the JSON list of filenames in the body.
+pub fn signed_body_name(body: &[u8]) -> String {
+    let mut hasher = Hasher::default();
+    hasher.update(body);
+    encode_chunk_hash(hasher)
+}
@@ -0,0 +1,235 @@
+use anyhow::{bail, Result};
+use blake3::hazmat::{self, ChainingValue, HasherExt, Mode};
+use serde::{Deserialize, Serialize};
+
+use crate::hasher::{Prehashed, DIGEST_LENGTH};
+
+/// Size of the groups of Blake3 chunks the outboard records a chaining value for.
+pub const GROUP_SIZE: u64 = 1024 * 1024;
+
+/// Blake3 chaining values of consecutive groups of a file, the last group may be shorter. Unlike
+/// [`BlockHashes`], they are the leaves of the Blake3 tree of the whole file, so they can be
+/// merged up to its digest and checked against the file signature before any data arrives. Any
+/// group can then be verified on its own, e.g. the ranges of a resumed download.
+///
+/// Only recorded for files of more than one group, smaller files are verified as a whole.
+///
+/// [`BlockHashes`]: crate::chunks::BlockHashes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Outboard {
+    pub group_size: u64,
+    /// Base58 encoded chaining values, one per group.
+    pub chaining_values: Vec<String>,
+}
+
+impl Outboard {
+    /// Fails unless the group size is a power of two of whole Blake3 chunks.
+    pub fn check_group_size(&self) -> Result<()> {
+        if self.group_size < blake3::CHUNK_LEN as u64 || !self.group_size.is_power_of_two() {
+            bail!("Invalid outboard group size: {}", self.group_size);
+        }
+        Ok(())
+    }
+
+    /// Merges the chaining values into the digest of the whole file, the one its signature is
+    /// made over.
+    pub fn root(&self) -> Result<Prehashed> {
+        self.check_group_size()?;
+        let chaining_values = self
+            .chaining_values
+            .iter()
+            .map(|value| decode_chaining_value(value))
+            .collect::<Result<Vec<_>>>()?;
+        if chaining_values.len() < 2 {
+            bail!("Outboard has fewer than two groups");
+        }
+
+        let (left, right) = chaining_values.split_at(left_groups(chaining_values.len()));
+        let mut digest = [0; DIGEST_LENGTH];
+        hazmat::merge_subtrees_root_xof(&merge(left), &merge(right), Mode::Hash).fill(&mut digest);
+        Prehashed::from_slice(&digest)
+    }
+}
+
+/// Splits the data fed to it into groups of `group_size` bytes and records the chaining value of
+/// each of them.
+#[derive(Debug, Clone)]
+pub struct OutboardHasher {
+    group_size: u64,
+    next_group: u64,
+    hasher: blake3::Hasher,
+    group_len: u64,
+    chaining_values: Vec<String>,
+}
+
+impl OutboardHasher {
+    /// Starts hashing at group `first_group` of the file, `group_size` must be valid as checked
+    /// by [`Outboard::check_group_size`].
+    pub fn new(group_size: u64, first_group: u64) -> Self {
+        Self {
+            group_size,
+            next_group: first_group,
+            hasher: group_hasher(group_size, first_group),
+            group_len: 0,
+            chaining_values: Vec::new(),
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let size = (self.group_size - self.group_len).min(data.len() as u64) as usize;
+            #[cfg(feature = "parallel-hashing")]
+            self.hasher.update_rayon(&data[..size]);
+            #[cfg(not(feature = "parallel-hashing"))]
+            self.hasher.update(&data[..size]);
+            self.group_len += size as u64;
+            if self.group_len == self.group_size {
+                self.finish_group();
+            }
+            data = &data[size..];
+        }
+    }
+
+    fn finish_group(&mut self) {
+        let chaining_value = self.hasher.finalize_non_root();
+        self.chaining_values
+            .push(bs58::encode(chaining_value).into_string());
+        self.next_group += 1;
+        self.hasher = group_hasher(self.group_size, self.next_group);
+        self.group_len = 0;
+    }
+
+    /// Chaining values of the groups completed so far.
+    pub fn hashes(&self) -> &[String] {
+        &self.chaining_values
+    }
+
+    /// Hashes the remaining partial group, if any, and returns the chaining values of all groups
+    /// hashed.
+    pub fn finish(mut self) -> Outboard {
+        if self.group_len > 0 {
+            self.finish_group();
+        }
+        Outboard {
+            group_size: self.group_size,
+            chaining_values: self.chaining_values,
+        }
+    }
+}
+
+fn group_hasher(group_size: u64, group: u64) -> blake3::Hasher {
+    let mut hasher = blake3::Hasher::new();
+    hasher.set_input_offset(group * group_size);
+    hasher
+}
+
+fn decode_chaining_value(value: &str) -> Result<ChainingValue> {
+    let mut chaining_value = ChainingValue::default();
+    match bs58::decode(value).onto(&mut chaining_value) {
+        Ok(len) if len == chaining_value.len() => Ok(chaining_value),
+        _ => bail!("Invalid chaining value: {value}"),
+    }
+}
+
+/// Number of groups in the left subtree of `groups` groups, the largest power of two below it,
+/// as Blake3 splits the tree.
+fn left_groups(groups: usize) -> usize {
+    1 << (usize::BITS - 1 - (groups - 1).leading_zeros())
+}
+
+/// Merges the chaining values of a subtree that isn't the root.
+fn merge(chaining_values: &[ChainingValue]) -> ChainingValue {
+    if chaining_values.len() == 1 {
+        return chaining_values[0];
+    }
+    let (left, right) = chaining_values.split_at(left_groups(chaining_values.len()));
+    hazmat::merge_subtrees_non_root(&merge(left), &merge(right), Mode::Hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use digest::{FixedOutput, Update};
+
+    use super::*;
+    use crate::hasher::Hasher;
+
+    const SMALL_GROUP: u64 = 1024;
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|index| (index % 251) as u8).collect()
+    }
+
+    fn outboard(data: &[u8]) -> Outboard {
+        let mut hasher = OutboardHasher::new(SMALL_GROUP, 0);
+        // Uneven updates, so groups end inside them
+        for part in data.chunks(700) {
+            hasher.update(part);
+        }
+        hasher.finish()
+    }
+
+    fn digest(data: &[u8]) -> Vec<u8> {
+        let mut hasher = Hasher::default();
+        hasher.update(data);
+        hasher.finalize_fixed().to_vec()
+    }
+
+    #[test]
+    fn root_matches_file_digest() {
+        for groups in [2, 3, 4, 5, 7, 8, 13] {
+            for len in [
+                groups * SMALL_GROUP as usize,
+                groups * SMALL_GROUP as usize - 1000,
+            ] {
+                let data = data(len);
+                let outboard = outboard(&data);
+                assert_eq!(outboard.chaining_values.len(), groups, "{len}");
+                let root = outboard.root().unwrap().finalize_fixed();
+                assert_eq!(root.to_vec(), digest(&data), "{len}");
+            }
+        }
+    }
+
+    #[test]
+    fn root_matches_file_digest_with_default_group_size() {
+        let data = data(2 * GROUP_SIZE as usize + 12345);
+        let mut hasher = OutboardHasher::new(GROUP_SIZE, 0);
+        hasher.update(&data);
+        let root = hasher.finish().root().unwrap().finalize_fixed();
+        assert_eq!(root.to_vec(), digest(&data));
+    }
+
+    #[test]
+    fn groups_verify_on_their_own() {
+        let data = data(5 * SMALL_GROUP as usize + 10);
+        let outboard = outboard(&data);
+
+        let mut hasher = OutboardHasher::new(SMALL_GROUP, 3);
+        hasher.update(&data[3 * SMALL_GROUP as usize..]);
+        assert_eq!(
+            hasher.finish().chaining_values,
+            outboard.chaining_values[3..]
+        );
+
+        let mut tampered = data[SMALL_GROUP as usize..2 * SMALL_GROUP as usize].to_vec();
+        tampered[0] ^= 1;
+        let mut hasher = OutboardHasher::new(SMALL_GROUP, 1);
+        hasher.update(&tampered);
+        assert_ne!(hasher.hashes()[0], outboard.chaining_values[1]);
+    }
+
+    #[test]
+    fn invalid_outboards_are_rejected() {
+        let mut single = outboard(&data(3 * SMALL_GROUP as usize));
+        single.chaining_values.truncate(1);
+        assert!(single.root().is_err());
+
+        let mut garbled = outboard(&data(3 * SMALL_GROUP as usize));
+        garbled.chaining_values[1] = "0".to_string();
+        assert!(garbled.root().is_err());
+
+        let mut misaligned = outboard(&data(3 * SMALL_GROUP as usize));
+        misaligned.group_size = 1000;
+        assert!(misaligned.root().is_err());
+    }
+}
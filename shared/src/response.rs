@@ -0,0 +1,29 @@
+use anyhow::Result;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+
+/// Keeps response signatures from being valid for anything else signed with the server key.
+const RESPONSE_CONTEXT: &[u8] = b"private-cloud response";
+
+/// Signs a response body with the server key. The signature covers the signature of the request
+/// being answered, so a recorded response can't be replayed for another request.
+pub fn sign_response(
+    signing_key: &SigningKey,
+    request_signature: &Signature,
+    body: &[u8],
+) -> Signature {
+    signing_key.sign(&message(request_signature, body))
+}
+
+pub fn verify_response(
+    server_pubkey: &VerifyingKey,
+    request_signature: &Signature,
+    body: &[u8],
+    response_signature: &Signature,
+) -> Result<()> {
+    server_pubkey.verify_strict(&message(request_signature, body), response_signature)?;
+    Ok(())
+}
+
+fn message(request_signature: &Signature, body: &[u8]) -> Vec<u8> {
+    [RESPONSE_CONTEXT, &request_signature.to_bytes(), body].concat()
+}